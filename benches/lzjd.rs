@@ -3,6 +3,7 @@ extern crate criterion;
 
 use criterion::Criterion;
 use ::lzjd::LZDict;
+use ::lzjd::comparator::{Comparator, Metric};
 use ::lzjd::crc32::CRC32BuildHasher;
 use rand::prelude::*;
 
@@ -33,5 +34,26 @@ fn bench_dist(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, bench_dist);
+fn bench_similarity_skewed(c: &mut Criterion) {
+    c.bench_function("LZDict::similarity of a small and a full digest", |b| {
+        let build_hasher = CRC32BuildHasher;
+
+        let small = LZDict::from_bytes(&generate_byte_sequence()[..200], &build_hasher);
+        let full = LZDict::from_bytes(&generate_byte_sequence(), &build_hasher);
+        b.iter(move || small.similarity(&full))
+    });
+}
+
+fn bench_comparator(c: &mut Criterion) {
+    c.bench_function("Comparator::compare of two full digests", |b| {
+        let build_hasher = CRC32BuildHasher;
+
+        let dict_a = LZDict::from_bytes(&generate_byte_sequence(), &build_hasher);
+        let dict_b = LZDict::from_bytes(&generate_byte_sequence(), &build_hasher);
+        let comparator = Comparator::new(Metric::Similarity, 0.);
+        b.iter(move || comparator.compare(&dict_a, &dict_b))
+    });
+}
+
+criterion_group!(benches, bench_dist, bench_similarity_skewed, bench_comparator);
 criterion_main!(benches);
@@ -0,0 +1,36 @@
+//! Generates the server of the gRPC service described in proto/lzjd.proto
+//! when the `grpc` feature is enabled. The messages themselves are defined
+//! in src/grpc.rs, so no protoc is needed.
+
+#[cfg(feature = "grpc")]
+fn main() {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    let method = |name: &str, route_name: &str, input: &str, output: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route_name)
+            .input_type(format!("crate::grpc::{}", input))
+            .output_type(format!("crate::grpc::{}", output))
+            .codec_path("tonic::codec::ProstCodec")
+            .build()
+    };
+    let service = Service::builder()
+        .name("Lzjd")
+        .package("lzjd")
+        .method(method("digest", "Digest", "DigestRequest", "DigestReply"))
+        .method(method("get", "Get", "GetRequest", "DigestReply"))
+        .method(method(
+            "compare",
+            "Compare",
+            "CompareRequest",
+            "CompareReply",
+        ))
+        .method(method("search", "Search", "SearchRequest", "SearchReply"))
+        .build();
+    Builder::new().build_client(false).compile(&[service]);
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+#[cfg(not(feature = "grpc"))]
+fn main() {}
@@ -0,0 +1,5484 @@
+// `failure_derive` expands to impls nested in an anonymous const
+#![allow(non_local_definitions)]
+
+extern crate clap;
+extern crate lzjd;
+#[macro_use]
+extern crate failure_derive;
+
+#[cfg(feature = "archive")]
+use lzjd::archive::{digest_archive, ArchiveFormat};
+use lzjd::baseline::Baseline;
+use lzjd::bundle::{read_bundle, write_bundle, BundleInfo};
+use lzjd::cluster::{connected_components, StreamingClusters};
+use lzjd::index::DigestIndex;
+use lzjd::compare::{
+    compare_all, compare_pairs, compare_top, similar_pairs, similar_pairs_between, similarity_matrix,
+};
+use lzjd::entropy::{EntropyReader, DOMINANT_FRACTION};
+use lzjd::eval::{auc, best_threshold, homogeneity_completeness, roc, threshold_at_false_positive_rate};
+use lzjd::files::{digest_files, read_path_list, walk_directory, DirectoryOptions, ErrorPolicy};
+use lzjd::filter::{filter_files, FileFilter, FileType};
+use lzjd::label::path_to_label;
+use lzjd::lz_dict::{similarity_score, Encoding, K};
+use lzjd::pipeline::compare_digests;
+use lzjd::raw::RawReader;
+use lzjd::records::digest_lines;
+#[cfg(feature = "http")]
+use lzjd::download::{is_http_url, Downloader};
+#[cfg(feature = "s3")]
+use lzjd::object::{self, is_object_url, S3_SCHEME};
+#[cfg(feature = "async")]
+use lzjd::scan;
+use lzjd::sampling::Sampling;
+use lzjd::selftest::{self, reference_input, Check, REFERENCE_INPUTS};
+use lzjd::stats::{estimated_phrases, histogram, quantiles, sample_pairs};
+use lzjd::{DigestRecord, HashAlgorithm, LZDict, LZJDError, Match, Progress};
+#[cfg(any(feature = "server", feature = "grpc"))]
+use lzjd::{service::Service, store::Retention, DigestStore};
+#[cfg(feature = "remote")]
+use lzjd::client::Client;
+#[cfg(feature = "arrow")]
+use lzjd::columnar::{self, MatchWriter};
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::env;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{IsTerminal, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufRead, BufReader, BufWriter};
+use std::iter;
+use std::mem;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+#[cfg(any(feature = "server", feature = "grpc"))]
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use clap::{App, AppSettings, Arg, SubCommand};
+use console::{measure_text_width, pad_str, Alignment, Style};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use log::{debug, error, info, warn, Level, LevelFilter, Log, Metadata, Record};
+use notify::{EventKind, RecursiveMode, Watcher};
+use serde_json::json;
+use rayon::prelude::*;
+
+#[derive(Debug, Fail)]
+enum Error {
+    #[fail(display = "IO error: {}", err)]
+    Io {
+        #[cause]
+        err: io::Error,
+    },
+    #[fail(display = "ThreadPoolBuild error: {}", err)]
+    ThreadPoolBuild {
+        #[cause]
+        err: rayon::ThreadPoolBuildError,
+    },
+    #[fail(display = "{}", err)]
+    Lzjd {
+        #[cause]
+        err: LZJDError,
+    },
+    #[fail(display = "Watch error: {}", err)]
+    Notify {
+        #[cause]
+        err: notify::Error,
+    },
+    /// Not a failure of lzjd, but a comparison outcome that was asked to be reported
+    /// by exiting with OUTCOME_EXIT_CODE
+    #[fail(display = "{}", msg)]
+    Outcome { msg: String },
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io { err }
+    }
+}
+
+impl From<rayon::ThreadPoolBuildError> for Error {
+    fn from(err: rayon::ThreadPoolBuildError) -> Self {
+        Error::ThreadPoolBuild { err }
+    }
+}
+
+impl From<LZJDError> for Error {
+    fn from(err: LZJDError) -> Self {
+        Error::Lzjd { err }
+    }
+}
+
+impl From<notify::Error> for Error {
+    fn from(err: notify::Error) -> Self {
+        Error::Notify { err }
+    }
+}
+
+impl Error {
+    /// Wraps errors of lzjd in an error about a column of a line of the file at path
+    fn at_column(self, path: &Path, line: usize, column: usize) -> Self {
+        match self {
+            Error::Lzjd { err } => err.at_column(path, line, column).into(),
+            err => err,
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Writes log records to stderr, as plain lines or as JSON objects
+struct StderrLogger {
+    json: bool,
+}
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = if self.json {
+            let time = UNIX_EPOCH.elapsed().map_or(0., |time| time.as_secs_f64());
+            json!({
+                "time": time,
+                "level": record.level().as_str().to_lowercase(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            })
+            .to_string()
+        } else if record.level() == Level::Warn {
+            format!("Warning: {}", record.args())
+        } else {
+            record.args().to_string()
+        };
+        // Logging must not fail the command, so errors writing to stderr are ignored
+        let _ = writeln!(io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Defaults for flags read from a configuration file, by the long name of the flag
+#[derive(Debug, Default)]
+struct Config {
+    path: PathBuf,
+    values: HashMap<String, String>,
+}
+
+impl Config {
+    /// Reads the configuration file at path if given, or else the first of CONFIG_FILE_NAME
+    /// in the current directory and in the lzjd directory of the XDG configuration directory
+    /// that exists. Without a configuration file, no defaults are set.
+    fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_owned(),
+            None => {
+                let config_home = env::var_os("XDG_CONFIG_HOME")
+                    .filter(|dir| !dir.is_empty())
+                    .map(PathBuf::from)
+                    .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+                let candidates = iter::once(PathBuf::from(CONFIG_FILE_NAME))
+                    .chain(config_home.map(|dir| dir.join("lzjd").join(CONFIG_FILE_NAME)));
+                match candidates.into_iter().find(|path| path.is_file()) {
+                    Some(path) => path,
+                    None => return Ok(Self::default()),
+                }
+            }
+        };
+        let config = Self::parse(&fs::read_to_string(&path)?, &path)?;
+        debug!("Read settings from {}", path.display());
+        Ok(config)
+    }
+
+    fn parse(text: &str, path: &Path) -> Result<Self> {
+        let invalid = |msg: String| LZJDError::Msg {
+            msg: format!("Invalid configuration file {}: {}", path.display(), msg),
+        };
+        let table: toml::Table = text.parse().map_err(|e: toml::de::Error| invalid(e.message().to_owned()))?;
+        let mut values = HashMap::new();
+        for (key, value) in table {
+            if !CONFIG_KEYS.contains(&key.as_str()) {
+                return Err(invalid(format!("unknown setting '{}'", key)).into());
+            }
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                // Debug formatting keeps the decimal point that marks fractions
+                toml::Value::Float(value) => format!("{:?}", value),
+                _ => return Err(invalid(format!("{} must be a string or a number", key)).into()),
+            };
+            values.insert(key, value);
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            values,
+        })
+    }
+
+    /// Parses the setting called key, if the configuration file has one
+    fn get<T, F>(&self, key: &str, parse: F) -> Result<Option<T>>
+    where
+        F: FnOnce(&str) -> Result<T>,
+    {
+        self.values
+            .get(key)
+            .map(|value| {
+                parse(value).map_err(|e| {
+                    LZJDError::Msg {
+                        msg: format!("Invalid {} in {}: {}", key, self.path.display(), describe(&e)),
+                    }
+                    .into()
+                })
+            })
+            .transpose()
+    }
+}
+
+/// Key by which comparison results are sorted
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SortKey {
+    Similarity,
+    /// The names of both digests of a pair
+    Name,
+}
+
+impl FromStr for SortKey {
+    type Err = LZJDError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, LZJDError> {
+        match s {
+            "similarity" => Ok(SortKey::Similarity),
+            "name" => Ok(SortKey::Name),
+            _ => Err(LZJDError::from("Unknown sort key")),
+        }
+    }
+}
+
+/// Which pairs of comparison results are written, and how
+#[derive(Debug, Clone, Copy, Default)]
+struct PairFilter {
+    /// Whether pairs of digests with the same label are left out
+    skip_self: bool,
+    /// Whether the labels of each pair are written in sorted order
+    canonical: bool,
+    /// Whether pairs of labels that were written before, in either order, are left out
+    unique: bool,
+}
+
+impl PairFilter {
+    /// Applies the filter to matches, leaving out those of which the pair of labels is in seen
+    /// if unique pairs are asked for, and adding the pairs of the others to it
+    fn apply(
+        self,
+        matches: Vec<Match>,
+        seen: &mut HashSet<(String, String)>,
+    ) -> Vec<Match> {
+        matches
+            .into_iter()
+            .filter(|m| !self.skip_self || m.a != m.b)
+            .map(|Match { a, b, similarity }| {
+                if self.canonical && a > b {
+                    Match { a: b, b: a, similarity }
+                } else {
+                    Match { a, b, similarity }
+                }
+            })
+            .filter(|Match { a, b, .. }| {
+                if !self.unique {
+                    return true;
+                }
+                let (first, second) = if a <= b { (a, b) } else { (b, a) };
+                seen.insert((first.to_owned(), second.to_owned()))
+            })
+            .collect()
+    }
+}
+
+/// Comparison outcome that makes lzjd exit with OUTCOME_EXIT_CODE
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FailOn {
+    /// No pair of digests met the threshold
+    NoMatch,
+    /// Some pair of digests met the threshold
+    Match,
+}
+
+/// Output format of digests and comparison results
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    /// lzjd:name:digest lines, and name_a|name_b|similarity lines
+    Text,
+    /// A single JSON array of records
+    Json,
+    /// One JSON record per line
+    Ndjson,
+    /// RFC 4180 CSV with a header row
+    Csv,
+    /// Digest lines with a length-prefixed name like sdhash's sdbf lines,
+    /// and comparison lines as printed by sdhash
+    Sdhash,
+    /// Comparison results as an aligned table with colored scores,
+    /// and everything else as text
+    Pretty,
+    /// Digests and comparison results as a Parquet file
+    #[cfg(feature = "arrow")]
+    Parquet,
+}
+
+impl FromStr for Format {
+    type Err = LZJDError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, LZJDError> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "ndjson" => Ok(Format::Ndjson),
+            "csv" => Ok(Format::Csv),
+            "sdhash" => Ok(Format::Sdhash),
+            "pretty" => Ok(Format::Pretty),
+            #[cfg(feature = "arrow")]
+            "parquet" => Ok(Format::Parquet),
+            #[cfg(not(feature = "arrow"))]
+            "parquet" => Err(LZJDError::from("Parquet output needs the arrow feature of lzjd-cli")),
+            _ => Err(LZJDError::from("Unknown output format")),
+        }
+    }
+}
+
+/// Score reported for, and thresholded on, each compared pair
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Metric {
+    Similarity,
+    Distance,
+}
+
+impl Metric {
+    fn name(self) -> &'static str {
+        match self {
+            Metric::Similarity => "similarity",
+            Metric::Distance => "distance",
+        }
+    }
+
+    /// Converts a similarity into this metric
+    fn score(self, similarity: f64) -> f64 {
+        match self {
+            Metric::Similarity => similarity,
+            Metric::Distance => 1. - similarity,
+        }
+    }
+
+    /// Converts a similarity into this metric as an integer score between 0 and 100,
+    /// as written in the text formats
+    fn percentage(self, similarity: f64) -> u8 {
+        match self {
+            Metric::Similarity => similarity_score(similarity),
+            Metric::Distance => 100 - similarity_score(similarity),
+        }
+    }
+
+    /// Lowest similarity of which the score passes threshold. Thresholds apply to
+    /// the scores themselves, which are only rounded to percentages when written.
+    fn min_similarity(self, threshold: f64) -> f64 {
+        match self {
+            Metric::Similarity => threshold,
+            Metric::Distance => {
+                // 1 - threshold can miss the boundary by the rounding of the subtraction
+                let mut similarity = (1. - threshold).clamp(0., 1.);
+                while self.score(similarity) > threshold {
+                    similarity = similarity.next_up();
+                }
+                while similarity > 0. && self.score(similarity.next_down()) <= threshold {
+                    similarity = similarity.next_down();
+                }
+                similarity
+            }
+        }
+    }
+}
+
+impl FromStr for Metric {
+    type Err = LZJDError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, LZJDError> {
+        match s {
+            "similarity" => Ok(Metric::Similarity),
+            "distance" => Ok(Metric::Distance),
+            _ => Err(LZJDError::from("Unknown metric")),
+        }
+    }
+}
+
+/// Settings with which digests are created, recorded in the header
+/// of digest files. Only digests created with the same settings can
+/// be compared.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct DigestParams {
+    algorithm: HashAlgorithm,
+    /// Number of hashes kept per digest
+    k: usize,
+    /// Parts of each file that are digested
+    sampling: Sampling,
+    /// Whether high-entropy blocks are left out of digests
+    skip_high_entropy: bool,
+}
+
+impl Default for DigestParams {
+    fn default() -> Self {
+        Self {
+            algorithm: HashAlgorithm::default(),
+            k: K,
+            sampling: Sampling::Full,
+            skip_high_entropy: false,
+        }
+    }
+}
+
+/// Formats the header line of digest files, without the leading #.
+/// The sampling strategy is left out when files are digested in full,
+/// and the entropy setting when high-entropy blocks are kept.
+impl fmt::Display for DigestParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "lzjd algorithm={} k={}", self.algorithm, self.k)?;
+        if self.sampling != Sampling::Full {
+            write!(f, " sampling={}", self.sampling)?;
+        }
+        if self.skip_high_entropy {
+            f.write_str(" entropy=skip")?;
+        }
+        Ok(())
+    }
+}
+
+impl DigestParams {
+    /// Fails if digests created with self can not be compared with those created with other
+    fn check_compatible(self, other: Self) -> Result<()> {
+        if self.algorithm != other.algorithm {
+            return Err(LZJDError::Msg {
+                msg: format!(
+                    "Can not compare digests created with different hash algorithms ({} and {})",
+                    self.algorithm, other.algorithm
+                ),
+            }
+            .into());
+        }
+        if self.k != other.k {
+            return Err(LZJDError::Msg {
+                msg: format!(
+                    "Can not compare digests of different sizes (k={} and k={})",
+                    self.k, other.k
+                ),
+            }
+            .into());
+        }
+        if self.sampling != other.sampling {
+            // Digests of files smaller than the sampled size are still comparable
+            warn!(
+                "Comparing digests of differently sampled files ({} and {}), of which those of large files differ",
+                self.sampling, other.sampling
+            );
+        }
+        if self.skip_high_entropy != other.skip_high_entropy {
+            // Digests of files without high-entropy blocks are still comparable
+            warn!("Comparing digests with and without high-entropy blocks, of which those of compressed or encrypted files differ");
+        }
+        Ok(())
+    }
+
+    /// Returns the settings at which digests created with self can be compared with those
+    /// created with other, which have the smaller k of both. Digests must be truncated
+    /// to that k with truncate_digests.
+    fn negotiate(self, other: Self) -> Result<Self> {
+        let k = self.k.min(other.k);
+        DigestParams { k, ..self }.check_compatible(DigestParams { k, ..other })?;
+        if self.k != other.k {
+            warn!(
+                "Truncating digests of different sizes (k={} and k={}) to k={}",
+                self.k, other.k, k
+            );
+        }
+        Ok(DigestParams { k, ..self })
+    }
+}
+
+/// Keeps the k smallest hashes of each of dicts, see DigestParams::negotiate
+fn truncate_digests(dicts: Vec<DigestRecord>, k: usize) -> Vec<DigestRecord> {
+    dicts
+        .into_iter()
+        .map(|record| DigestRecord { dict: record.dict.truncated(k), ..record })
+        .collect()
+}
+
+/// Settings shared by the commands
+struct Options<'a> {
+    params: DigestParams,
+    /// Threshold on the metric, as a fraction
+    threshold: f64,
+    metric: Metric,
+    /// Number of best matches to show per digest, if not all
+    top: Option<usize>,
+    /// Order of comparison results, and whether it is reversed.
+    /// Results are written in the order they are found if not given.
+    sort: Option<(SortKey, bool)>,
+    /// Number of comparison results to show in total, if not all
+    limit: Option<usize>,
+    pairs: PairFilter,
+    error_policy: ErrorPolicy,
+    /// Files that are not rejected by filter are digested
+    filter: FileFilter,
+    /// Label of the digest of data read from stdin
+    stdin_label: &'a str,
+    format: Format,
+    /// Encoding of the digests written
+    encoding: Encoding,
+    /// Whether to show progress bars
+    progress: bool,
+    fail_on: Option<FailOn>,
+    /// Whether the pretty format may use colors
+    color: bool,
+    /// Number of directories listed and files read at once, if walking and
+    /// digesting concurrently rather than on the rayon pool
+    #[cfg(feature = "async")]
+    concurrency: Option<usize>,
+    /// Number of bytes above which downloads of URL inputs fail
+    #[cfg(feature = "http")]
+    download_limit: u64,
+    /// Time after which downloads of URL inputs fail if they do not progress
+    #[cfg(feature = "http")]
+    download_timeout: Duration,
+}
+
+/// Input path that is read from stdin
+const STDIN_PATH: &str = "-";
+
+/// Magic number at the start of zstd frames, by which compressed digest files are recognized
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Extension of the files that digests are written to compressed with zstd, as in `samples.lzjd.zst`
+const ZSTD_EXTENSION: &str = "zst";
+
+/// Start of the comment lines that record the size and modification time
+/// of the file of the digest on the next line in a database
+const FILE_INFO_PREFIX: &str = "# lzjd-file ";
+
+/// Exit code of comparisons of which the outcome is the one given with --fail-on-match
+/// or --fail-on-no-match, which is distinct from the exit code of errors, like grep's
+const OUTCOME_EXIT_CODE: i32 = 1;
+
+/// Name of the configuration file holding defaults for flags
+const CONFIG_FILE_NAME: &str = "lzjd.toml";
+
+/// Flags of which the configuration file can set the default
+const CONFIG_KEYS: [&str; 5] = ["hash-algorithm", "digest-size", "threshold", "threads", "format"];
+
+/// Error of commands that write neither digests nor comparison results with -f parquet
+#[cfg(feature = "arrow")]
+const PARQUET_UNSUPPORTED: &str = "Parquet output is only supported for digests and comparison results";
+
+/// Fractions of the way from the lowest similarity that passes the threshold up to 1
+/// from which the pretty format colors matches green and yellow, lower ones being red
+const PRETTY_BANDS: (f64, f64) = (0.75, 0.4);
+
+/// Number of pairs stats compares if none is given
+const STATS_SAMPLE_SIZE: usize = 10_000;
+
+/// Similarity threshold of dedupe if none is given
+const DEDUPE_THRESHOLD: f64 = 0.95;
+
+/// Similarity threshold of scan if none is given
+const SCAN_THRESHOLD: f64 = 0.7;
+
+/// Time without changes after which watch digests a file
+const WATCH_SETTLE_TIME: Duration = Duration::from_secs(1);
+
+/// Interval at which watch checks for files that have settled
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// The operation selected on the command line
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Command {
+    Hash,
+    Compare,
+    GenCompare,
+    Query,
+    Scan,
+    Watch,
+    Cluster,
+    Dedupe,
+    Matrix,
+    Stats,
+    Eval,
+    Selftest,
+    Bench,
+    DbMerge,
+    DbCheck,
+    DbExport,
+    DbImport,
+    #[cfg(any(feature = "server", feature = "grpc"))]
+    Serve,
+}
+
+/// The -r flag and the options controlling directory traversal
+fn deep_args<'a, 'b>() -> [Arg<'a, 'b>; 6] {
+    [
+        Arg::with_name("deep")
+            .short("r")
+            .long("deep")
+            .help("generate SDBFs from directories and files")
+            .takes_value(false),
+        include_arg(),
+        exclude_arg(),
+        follow_symlinks_arg(),
+        max_depth_arg(),
+        same_filesystem_arg(),
+    ]
+}
+
+fn include_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("include")
+        .long("include")
+        .help("with -r, only digest files of which the path matches PATTERN")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("PATTERN")
+}
+
+fn exclude_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("exclude")
+        .long("exclude")
+        .help("with -r, skip files and directories of which the path matches PATTERN")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("PATTERN")
+}
+
+fn follow_symlinks_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("follow-symlinks")
+        .long("follow-symlinks")
+        .help("with -r, descend into symbolically linked directories, failing on loops")
+        .takes_value(false)
+}
+
+fn max_depth_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("max-depth")
+        .long("max-depth")
+        .help("with -r, descend at most N directories below each input")
+        .takes_value(true)
+        .value_name("N")
+}
+
+fn same_filesystem_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("same-filesystem")
+        .long("same-filesystem")
+        .help("with -r, do not descend into directories on other file systems than the input")
+        .takes_value(false)
+}
+
+/// The options selecting which files to digest
+fn filter_args<'a, 'b>() -> [Arg<'a, 'b>; 5] {
+    [
+        Arg::with_name("files-from")
+            .long("files-from")
+            .help("also digest the files listed in FILE, one per line, or - to read the list from stdin")
+            .takes_value(true)
+            .value_name("FILE"),
+        Arg::with_name("null")
+            .short("0")
+            .long("null")
+            .help("paths in the --files-from list are separated by NUL bytes, as written by find -print0")
+            .takes_value(false)
+            .requires("files-from"),
+        Arg::with_name("min-size")
+            .long("min-size")
+            .help("skip files smaller than SIZE bytes, which may have a K, M, G or T suffix")
+            .takes_value(true)
+            .value_name("SIZE"),
+        Arg::with_name("max-size")
+            .long("max-size")
+            .help("skip files larger than SIZE bytes, which may have a K, M, G or T suffix")
+            .takes_value(true)
+            .value_name("SIZE"),
+        Arg::with_name("type")
+            .long("type")
+            .help("only digest files of these types, detected from their first bytes")
+            .takes_value(true)
+            .use_delimiter(true)
+            .possible_values(&["pe", "elf", "zip", "text"])
+            .value_name("TYPES"),
+    ]
+}
+
+fn threshold_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("threshold")
+        .short("t")
+        .long("threshold")
+        .help(
+            "only show results >= threshold, or <= threshold for distances, \
+             comparing scores before they are rounded to whole percentages. \
+             Either a fraction like 0.5, or a percentage like 50 or 49.5% \
+             [default: 1% similarity, 99% distance]",
+        )
+        .takes_value(true)
+        .value_name("THRESHOLD")
+}
+
+fn metric_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("metric")
+        .short("m")
+        .long("metric")
+        .help("score to report and apply the threshold to")
+        .takes_value(true)
+        .possible_values(&["similarity", "distance"])
+        .default_value("similarity")
+        .value_name("METRIC")
+}
+
+fn algorithm_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("hash-algorithm")
+        .long("hash-algorithm")
+        .help("hash algorithm used to digest files [default: murmur3, or that of --db]")
+        .takes_value(true)
+        .possible_values(&["murmur3", "crc32", "xxhash", "siphash"])
+        .value_name("ALGORITHM")
+}
+
+fn digest_size_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("digest-size")
+        .short("k")
+        .long("digest-size")
+        .help("number of hashes kept per digest [default: 1024, or that of --db]")
+        .takes_value(true)
+        .value_name("K")
+}
+
+/// The options to digest only part of large files, and to skip high-entropy parts
+fn sampling_args<'a, 'b>() -> [Arg<'a, 'b>; 3] {
+    [
+        Arg::with_name("max-bytes")
+            .long("max-bytes")
+            .help("only digest SIZE bytes of larger files, like 100M")
+            .takes_value(true)
+            .conflicts_with_all(&["raw", "tar", "zip", "remote"])
+            .value_name("SIZE"),
+        Arg::with_name("sampling")
+            .long("sampling")
+            .help("which bytes of larger files to digest: the first, or the first and last quarter and chunks spread in between")
+            .takes_value(true)
+            .possible_values(&["head", "spread"])
+            .requires("max-bytes")
+            .value_name("STRATEGY"),
+        Arg::with_name("skip-high-entropy")
+            .long("skip-high-entropy")
+            .help("leave blocks of high entropy, like compressed or encrypted data, out of digests")
+            .takes_value(false)
+            .conflicts_with_all(&["raw", "tar", "zip", "remote"]),
+    ]
+}
+
+fn merge_dirs_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("merge-dirs")
+        .long("merge-dirs")
+        .help("digest each input directory as a whole, by merging the digests of the files in it")
+        .takes_value(false)
+        .requires("deep")
+        .conflicts_with_all(&["db", "remote"])
+}
+
+/// The flags that remove common content from digests before comparing them
+fn baseline_args<'a, 'b>() -> [Arg<'a, 'b>; 2] {
+    [
+        Arg::with_name("baseline")
+            .long("baseline")
+            .help("SDBF file of known files, like a clean installation, of which the hashes are removed from digests before comparing them")
+            .takes_value(true)
+            .conflicts_with_all(&["pairs", "max-memory", "remote"])
+            .value_name("FILE"),
+        Arg::with_name("baseline-share")
+            .long("baseline-share")
+            .help("only remove hashes that occur in at least this fraction of the --baseline digests, like 50% [default: any]")
+            .takes_value(true)
+            .requires("baseline")
+            .value_name("FRACTION"),
+    ]
+}
+
+fn top_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("top")
+        .long("top")
+        .help("only show the N best matches above threshold of each digest")
+        .takes_value(true)
+        .value_name("N")
+}
+
+/// The flags that make the exit code reflect whether anything matched
+fn fail_on_args<'a, 'b>() -> [Arg<'a, 'b>; 2] {
+    [
+        Arg::with_name("fail-on-no-match")
+            .long("fail-on-no-match")
+            .help("exit with code 1 if no pair of digests meets the threshold")
+            .takes_value(false)
+            .conflicts_with("fail-on-match"),
+        Arg::with_name("fail-on-match")
+            .long("fail-on-match")
+            .help("exit with code 1 if any pair of digests meets the threshold")
+            .takes_value(false),
+    ]
+}
+
+/// The flags controlling the order and number of comparison results
+fn sort_args<'a, 'b>() -> [Arg<'a, 'b>; 3] {
+    [
+        Arg::with_name("sort")
+            .long("sort")
+            .help("sort results by similarity or by the names of the pairs, ascending, ties broken by name")
+            .takes_value(true)
+            .possible_values(&["similarity", "name"])
+            .value_name("KEY"),
+        Arg::with_name("desc")
+            .long("desc")
+            .help("sort results in descending order")
+            .takes_value(false)
+            .requires("sort"),
+        Arg::with_name("limit")
+            .long("limit")
+            .help("only show the first N results, after sorting")
+            .takes_value(true)
+            .value_name("N"),
+    ]
+}
+
+/// The flags controlling which pairs of labels comparison results are written for
+fn pair_args<'a, 'b>() -> [Arg<'a, 'b>; 3] {
+    [
+        Arg::with_name("no-self-pairs")
+            .long("no-self-pairs")
+            .help("leave out pairs of digests with the same label, like a file found in both digest files")
+            .takes_value(false),
+        Arg::with_name("canonical-pairs")
+            .long("canonical-pairs")
+            .help("write the labels of each pair in sorted order")
+            .takes_value(false),
+        Arg::with_name("unique-pairs")
+            .long("unique-pairs")
+            .help("leave out pairs of labels that were written before, in either order")
+            .takes_value(false),
+    ]
+}
+
+fn on_error_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("on-error")
+        .long("on-error")
+        .help("whether to fail or skip when a file or a line of a digest file cannot be read")
+        .takes_value(true)
+        .possible_values(&["fail", "skip"])
+        .default_value("fail")
+        .value_name("POLICY")
+}
+
+fn label_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("label")
+        .long("label")
+        .help("name of the digest of data read from stdin")
+        .takes_value(true)
+        .default_value(STDIN_PATH)
+        .value_name("LABEL")
+}
+
+/// The --remote option, if the client of `lzjd serve` is enabled
+fn remote_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    if !cfg!(feature = "remote") {
+        return vec![];
+    }
+    vec![Arg::with_name("remote")
+        .long("remote")
+        .help("send files to the lzjd server at URL, and use the digests stored there")
+        .takes_value(true)
+        .conflicts_with_all(&["hash-algorithm", "digest-size"])
+        .value_name("URL")]
+}
+
+/// The --concurrency option, if the tokio-based walking and digesting is enabled
+fn async_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    if !cfg!(feature = "async") {
+        return vec![];
+    }
+    vec![Arg::with_name("concurrency")
+        .long("concurrency")
+        .help("list directories and read files N at a time, for inputs on network file systems where each read takes long")
+        .takes_value(true)
+        .conflicts_with_all(&["raw", "remote"])
+        .value_name("N")]
+}
+
+/// The limits on downloads of http:// and https:// inputs, if digesting those is enabled
+fn http_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    if !cfg!(feature = "http") {
+        return vec![];
+    }
+    vec![
+        Arg::with_name("download-limit")
+            .long("download-limit")
+            .help("fail downloads of URL inputs that are larger than SIZE bytes. Accepts K, M, G and T suffixes")
+            .takes_value(true)
+            .default_value("1G")
+            .value_name("SIZE"),
+        Arg::with_name("download-timeout")
+            .long("download-timeout")
+            .help("fail downloads of URL inputs that take longer than SECONDS to connect or to send more data")
+            .takes_value(true)
+            .default_value("30")
+            .value_name("SECONDS"),
+    ]
+}
+
+/// The --tar and --zip options, if reading archives is enabled
+fn archive_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    if !cfg!(feature = "archive") {
+        return vec![];
+    }
+    vec![
+        Arg::with_name("tar")
+            .long("tar")
+            .help("read each input as a tar archive, and digest the files in it")
+            .takes_value(false)
+            .conflicts_with_all(&["zip", "raw", "db", "deep", "files-from"]),
+        Arg::with_name("zip")
+            .long("zip")
+            .help("read each input as a zip archive, and digest the files in it")
+            .takes_value(false)
+            .conflicts_with_all(&["raw", "db", "deep", "files-from"]),
+    ]
+}
+
+fn input_arg<'a, 'b>(help: &'a str) -> Arg<'a, 'b> {
+    Arg::with_name("input")
+        .help(help)
+        .value_name("INPUT")
+        .required(true)
+        .multiple(true)
+}
+
+/// The serve subcommand, with the listen options of the enabled front-ends
+#[cfg(any(feature = "server", feature = "grpc"))]
+fn serve_subcommand<'a, 'b>() -> App<'a, 'b> {
+    let subcommand = SubCommand::with_name("serve")
+        .about("serve digest and search endpoints, until interrupted")
+        .arg(
+            Arg::with_name("db")
+                .long("db")
+                .help("SDBF file to load digests from and append new digests to, which is created if it does not exist")
+                .takes_value(true)
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::with_name("ttl")
+                .long("ttl")
+                .help(
+                    "forget digests this many seconds after they were added, like 86400 to search \
+                     the digests of the last day. Digests loaded from --db count as added at startup",
+                )
+                .takes_value(true)
+                .value_name("SECONDS"),
+        )
+        .arg(
+            Arg::with_name("max-digests")
+                .long("max-digests")
+                .help("only keep the N most recently added digests, forgetting older ones")
+                .takes_value(true)
+                .value_name("N"),
+        )
+        .arg(algorithm_arg())
+        .arg(digest_size_arg());
+    #[cfg(feature = "server")]
+    let subcommand = subcommand.arg(
+        Arg::with_name("listen")
+            .long("listen")
+            .help("address to serve HTTP on")
+            .takes_value(true)
+            .default_value("127.0.0.1:8080")
+            .value_name("ADDR"),
+    );
+    #[cfg(feature = "grpc")]
+    let subcommand = subcommand.arg(
+        Arg::with_name("grpc-listen")
+            .long("grpc-listen")
+            .help("address to serve gRPC on")
+            .takes_value(true)
+            .required(cfg!(not(feature = "server")))
+            .value_name("ADDR"),
+    );
+    subcommand
+}
+
+fn main() {
+    let cpus = &thread::available_parallelism().map_or(1, |n| n.get()).to_string();
+
+    let app = App::new("LZJD")
+        .version("1.0")
+        .author("Henk Dieter Oordt <henkdieter@tweedegolf.com>")
+        .about("Calculates Lempel-Ziv Jaccard distance of input binaries. Based on jLZJD (https://github.com/EdwardRaff/jLZJD).")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("threads")
+                .short("p")
+                .long("threads")
+                .help("restrict compute threads to N threads")
+                .takes_value(true)
+                .default_value(cpus)
+                .value_name("THREADS")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .help("send output to files")
+                .takes_value(true)
+                .value_name("FILE")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .help("output format of digests and comparison results")
+                .takes_value(true)
+                .possible_values(&["text", "json", "ndjson", "csv", "sdhash", "pretty", "parquet"])
+                .default_value("text")
+                .value_name("FORMAT")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("encoding")
+                .long("encoding")
+                .help("encoding of the digests written; digests read are decoded in any of them")
+                .takes_value(true)
+                .possible_values(&["base64", "base64url", "hex"])
+                .default_value("base64")
+                .value_name("ENCODING")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .help("read defaults for flags from FILE [default: ./lzjd.toml, or lzjd/lzjd.toml in the XDG config directory]")
+                .takes_value(true)
+                .value_name("FILE")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .help("also log timings and the stages of the work with -v, and every file with -vv")
+                .multiple(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .help("only log errors")
+                .takes_value(false)
+                .conflicts_with("verbose")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .help("format of the messages logged to stderr")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .value_name("FORMAT")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("no-progress")
+                .long("no-progress")
+                .help("do not show progress bars, which are only shown if stdout is a terminal")
+                .takes_value(false)
+                .global(true),
+        )
+        // The flags below predate the subcommands, and are kept for compatibility
+        .args(&deep_args())
+        .args(&async_args())
+        .args(&http_args())
+        .arg(merge_dirs_arg())
+        .args(&filter_args())
+        .arg(
+            Arg::with_name("compare")
+                .short("c")
+                .long("compare")
+                .help("compare SDBFs in file, or two SDBF files (deprecated, use `lzjd compare`)")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("gen-compare")
+                .short("g")
+                .long("gen-compare")
+                .help("compare all pairs in source data (deprecated, use `lzjd gen-compare`)")
+                .takes_value(false),
+        )
+        .arg(threshold_arg())
+        .arg(metric_arg())
+        .arg(top_arg())
+        .arg(on_error_arg())
+        .arg(label_arg())
+        .arg(algorithm_arg())
+        .arg(digest_size_arg())
+        .args(&sampling_args())
+        .arg(input_arg("Sets the input file to use, or - for stdin").required_unless("files-from"))
+        .subcommand(
+            SubCommand::with_name("hash")
+                .about("generate SDBFs of files")
+                .args(&deep_args())
+                .args(&async_args())
+                .args(&http_args())
+                .arg(merge_dirs_arg())
+                .args(&filter_args())
+                .arg(on_error_arg())
+                .arg(label_arg())
+                .arg(algorithm_arg())
+                .arg(digest_size_arg())
+                .args(&sampling_args())
+                .args(&remote_args())
+                .args(&archive_args())
+                .arg(
+                    Arg::with_name("raw")
+                        .long("raw")
+                        .help("read inputs as block devices or disk images, reading sectors that can not be read as zeros")
+                        .takes_value(false)
+                        .conflicts_with_all(&["db", "remote", "deep", "files-from", "min-size", "max-size", "type"]),
+                )
+                .arg(
+                    Arg::with_name("per-line")
+                        .long("per-line")
+                        .help("digest each line of the inputs, like log files or NDJSON, as a record of its own, labeled by its input and line number")
+                        .takes_value(false)
+                        .conflicts_with_all(&["raw", "tar", "zip", "db", "remote", "deep", "files-from", "merge-dirs"]),
+                )
+                .arg(
+                    Arg::with_name("db")
+                        .long("db")
+                        .help("SDBF file to append digests to instead of writing them to the output, which is created if it does not exist")
+                        .takes_value(true)
+                        .conflicts_with("remote")
+                        .value_name("FILE"),
+                )
+                .arg(
+                    Arg::with_name("update")
+                        .long("update")
+                        .help("skip files of which the size and modification time are unchanged since they were added to --db, and replace the digests of changed files")
+                        .takes_value(false)
+                        .requires("db"),
+                )
+                .arg(input_arg("files to digest, or - for stdin").required_unless("files-from")),
+        )
+        .subcommand(
+            SubCommand::with_name("compare")
+                .about("compare SDBFs in file, or two SDBF files")
+                .arg(threshold_arg())
+                .arg(metric_arg())
+                .arg(top_arg())
+                .args(&fail_on_args())
+                .args(&sort_args())
+                .args(&pair_args())
+                .arg(
+                    Arg::with_name("pairs")
+                        .long("pairs")
+                        .help(
+                            "only compare the pairs of names on each line of FILE, separated by a tab or |. \
+                             Names are labels of digests in the SDBF files, or paths of files to digest",
+                        )
+                        .takes_value(true)
+                        .conflicts_with("top")
+                        .value_name("FILE"),
+                )
+                .arg(
+                    Arg::with_name("max-memory")
+                        .long("max-memory")
+                        .help(
+                            "read the digests in blocks that take about SIZE bytes of memory in total, \
+                             rather than reading them all at once. Accepts K, M, G and T suffixes",
+                        )
+                        .takes_value(true)
+                        .conflicts_with_all(&["top", "pairs"])
+                        .value_name("SIZE"),
+                )
+                .args(&baseline_args())
+                .arg(on_error_arg())
+                .arg(input_arg("one or two SDBF files").max_values(2).required_unless("pairs")),
+        )
+        .subcommand(
+            SubCommand::with_name("cluster")
+                .about("group digests in SDBF files that are similar, directly or through other digests")
+                .arg(threshold_arg())
+                .arg(metric_arg())
+                .arg(
+                    Arg::with_name("singletons")
+                        .long("singletons")
+                        .help("also output digests that are not similar to any other digest, as groups of one")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("online")
+                        .long("online")
+                        .help(
+                            "assign each digest to a cluster as it is read, joining the cluster of the most similar \
+                             first digest of a cluster or creating a new one, and write the cluster of every digest. \
+                             Reads stdin if INPUT is -, for as long as it is open",
+                        )
+                        .takes_value(false)
+                        .conflicts_with("singletons"),
+                )
+                .arg(on_error_arg())
+                .arg(input_arg("SDBF files")),
+        )
+        .subcommand(
+            SubCommand::with_name("dedupe")
+                .about("list groups of near-identical files, suggesting which file of each group to keep")
+                .args(&deep_args())
+                .args(&async_args())
+                .args(&http_args())
+                .args(&filter_args())
+                .arg(threshold_arg().help(
+                    "only group files >= threshold, or <= threshold for distances. \
+                     Either a fraction like 0.5, or a percentage like 50 or 49.5% \
+                     [default: 95% similarity, 5% distance]",
+                ))
+                .arg(metric_arg())
+                .arg(on_error_arg())
+                .arg(algorithm_arg())
+                .arg(digest_size_arg())
+                .args(&sampling_args())
+                .arg(input_arg("files to deduplicate").required_unless("files-from")),
+        )
+        .subcommand(
+            SubCommand::with_name("matrix")
+                .about("write the similarities of all pairs of digests in SDBF files as a matrix")
+                .arg(metric_arg())
+                .arg(
+                    Arg::with_name("phylip")
+                        .long("phylip")
+                        .help("write a PHYLIP distance matrix instead of CSV or JSON")
+                        .takes_value(false),
+                )
+                .arg(on_error_arg())
+                .arg(input_arg("SDBF files")),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("summarize the digests in SDBF files and the similarities of a sample of their pairs")
+                .arg(
+                    Arg::with_name("sample")
+                        .long("sample")
+                        .help("number of pairs of digests to compare, chosen at random [default: 10000]")
+                        .takes_value(true)
+                        .value_name("N"),
+                )
+                .arg(
+                    Arg::with_name("bins")
+                        .long("bins")
+                        .help("number of intervals of the similarity histogram")
+                        .takes_value(true)
+                        .default_value("10")
+                        .value_name("N"),
+                )
+                .arg(
+                    Arg::with_name("per-digest")
+                        .long("per-digest")
+                        .help("also output the number of hashes and estimated number of LZ phrases of every digest")
+                        .takes_value(false),
+                )
+                .arg(on_error_arg())
+                .arg(input_arg("SDBF files")),
+        )
+        .subcommand(
+            SubCommand::with_name("eval")
+                .about("measure how well similarities of digests in SDBF files separate files of the same family from others")
+                .arg(
+                    Arg::with_name("labels")
+                        .long("labels")
+                        .help("CSV file of which each line holds the label of a digest and the name of its family")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("FILE"),
+                )
+                .arg(
+                    Arg::with_name("max-false-positives")
+                        .long("max-false-positives")
+                        .help("also suggest the lowest threshold at which at most this fraction of the pairs of different families match")
+                        .takes_value(true)
+                        .default_value("1%")
+                        .value_name("RATE"),
+                )
+                .arg(
+                    Arg::with_name("roc")
+                        .long("roc")
+                        .help("write the ROC curve to FILE as CSV of threshold, true positive rate and false positive rate")
+                        .takes_value(true)
+                        .value_name("FILE"),
+                )
+                .arg(on_error_arg())
+                .arg(input_arg("SDBF files")),
+        )
+        .subcommand(
+            SubCommand::with_name("selftest")
+                .about("check digests of reference inputs against vectors committed with the source"),
+        )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .about("measure how fast digests are created and compared on this machine")
+                .arg(
+                    Arg::with_name("size")
+                        .long("size")
+                        .help("number of bytes of generated input to digest, like 100M")
+                        .takes_value(true)
+                        .default_value("100M")
+                        .value_name("SIZE"),
+                )
+                .arg(
+                    Arg::with_name("digests")
+                        .long("digests")
+                        .help("number of digests to split the input into, of which all pairs are compared")
+                        .takes_value(true)
+                        .default_value("1000")
+                        .value_name("N"),
+                )
+                .arg(algorithm_arg())
+                .arg(digest_size_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("db")
+                .about("maintain SDBF files")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("merge")
+                        .about("combine SDBF files created with the same settings, dropping duplicate entries")
+                        .arg(
+                            Arg::with_name("unique-digests")
+                                .long("unique-digests")
+                                .help("also drop entries of which the digest equals that of an earlier entry with another label")
+                                .takes_value(false),
+                        )
+                        .arg(input_arg("SDBF files to merge")),
+                )
+                .subcommand(
+                    SubCommand::with_name("check")
+                        .about("report corrupt and inconsistent entries in SDBF files")
+                        .arg(input_arg("SDBF files to check")),
+                )
+                .subcommand(
+                    SubCommand::with_name("export")
+                        .about("write the digests in SDBF files, along with an index of them, as a bundle with checksums")
+                        .arg(on_error_arg())
+                        .arg(input_arg("SDBF files to bundle")),
+                )
+                .subcommand(
+                    SubCommand::with_name("import")
+                        .about("check a bundle written by `db export` and write its digests as an SDBF file")
+                        .arg(input_arg("bundle file, or - for stdin").max_values(1)),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("gen-compare")
+                .about("generate SDBFs of files and compare all pairs")
+                .args(&deep_args())
+                .args(&async_args())
+                .args(&http_args())
+                .arg(merge_dirs_arg())
+                .args(&filter_args())
+                .arg(threshold_arg())
+                .arg(metric_arg())
+                .arg(top_arg())
+                .args(&fail_on_args())
+                .args(&sort_args())
+                .args(&pair_args())
+                .arg(on_error_arg())
+                .arg(label_arg())
+                .arg(algorithm_arg())
+                .arg(digest_size_arg())
+                .args(&sampling_args())
+                .args(&baseline_args())
+                .arg(
+                    Arg::with_name("labels")
+                        .long("labels")
+                        .help(
+                            "CSV file of which each line holds the label of an input and its expected group, \
+                             to report how well clusters of digests that meet the threshold match the groups",
+                        )
+                        .takes_value(true)
+                        .value_name("FILE"),
+                )
+                .arg(input_arg("files to digest and compare, or - for stdin").required_unless("files-from")),
+        )
+        .subcommand(
+            SubCommand::with_name("query")
+                .about("generate SDBFs of files and compare them with a database of SDBFs")
+                .arg(
+                    Arg::with_name("db")
+                        .long("db")
+                        .help("SDBF file to compare against, as written by `lzjd hash`")
+                        .takes_value(true)
+                        .required_unless("remote")
+                        .value_name("FILE"),
+                )
+                .args(&deep_args())
+                .args(&async_args())
+                .args(&http_args())
+                .args(&filter_args())
+                .arg(threshold_arg())
+                .arg(metric_arg())
+                .arg(top_arg())
+                .args(&fail_on_args())
+                .args(&sort_args())
+                .args(&pair_args())
+                .arg(on_error_arg())
+                .arg(label_arg())
+                .arg(algorithm_arg())
+                .arg(digest_size_arg())
+                .args(&sampling_args())
+                .args(&remote_args())
+                .args(&baseline_args())
+                .arg(input_arg("files to digest and look up, or - for stdin").required_unless("files-from")),
+        )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .about("report files that are similar to known-bad digests, exiting with code 1 if any is found")
+                .arg(
+                    Arg::with_name("blacklist")
+                        .long("blacklist")
+                        .help("SDBF file of known-bad files, as written by `lzjd hash`")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("FILE"),
+                )
+                .args(&deep_args())
+                .args(&async_args())
+                .args(&http_args())
+                .args(&filter_args())
+                .arg(threshold_arg().help(
+                    "only report files >= threshold, or <= threshold for distances. \
+                     Either a fraction like 0.5, or a percentage like 50 or 49.5% \
+                     [default: 70% similarity, 30% distance]",
+                ))
+                .arg(metric_arg())
+                .arg(top_arg().help("report the N best matching known-bad digests of each file [default: 1]"))
+                .args(&sort_args())
+                .arg(on_error_arg().default_value("skip"))
+                .arg(label_arg())
+                .arg(algorithm_arg())
+                .arg(digest_size_arg())
+                .args(&sampling_args())
+                .args(&baseline_args())
+                .arg(input_arg("files to scan, or - for stdin").required_unless("files-from")),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("generate SDBFs of files as they are created in directories, until interrupted")
+                .arg(
+                    Arg::with_name("db")
+                        .long("db")
+                        .help("SDBF file to append digests to, which is created if it does not exist")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("FILE"),
+                )
+                .arg(
+                    Arg::with_name("compare-against")
+                        .long("compare-against")
+                        .help("SDBF file of which matches with new files are reported")
+                        .takes_value(true)
+                        .value_name("FILE"),
+                )
+                // Skips --files-from and -0, which make no sense when watching
+                .args(&filter_args()[2..])
+                .arg(threshold_arg())
+                .arg(metric_arg())
+                .arg(on_error_arg())
+                .arg(algorithm_arg())
+                .arg(digest_size_arg())
+                .args(&sampling_args())
+                .arg(input_arg("directories to watch, including their subdirectories")),
+        );
+    #[cfg(any(feature = "server", feature = "grpc"))]
+    let app = app.subcommand(serve_subcommand());
+    let matches = app.get_matches();
+    init_logger(&matches);
+    if let Err(e) = run(&matches) {
+        if let Error::Outcome { .. } = e {
+            info!("{}", e);
+            process::exit(OUTCOME_EXIT_CODE);
+        }
+        error!("{}", e);
+        process::exit(-1);
+    }
+}
+
+/// Installs a StderrLogger with the verbosity and log format given on the command line.
+/// Global arguments are looked up in the subcommand, where clap puts them if given after it.
+fn init_logger(matches: &clap::ArgMatches) {
+    let mut args = matches;
+    while let (_, Some(sub_args)) = args.subcommand() {
+        args = sub_args;
+    }
+    let level = if args.is_present("quiet") {
+        LevelFilter::Error
+    } else {
+        match args.occurrences_of("verbose") {
+            0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    };
+    let json = args.value_of("log-format") == Some("json");
+    if log::set_boxed_logger(Box::new(StderrLogger { json })).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+fn run(matches: &clap::ArgMatches) -> Result<()> {
+    let (command, args) = match matches.subcommand() {
+        ("hash", Some(args)) => (Command::Hash, args),
+        ("compare", Some(args)) => (Command::Compare, args),
+        ("gen-compare", Some(args)) => (Command::GenCompare, args),
+        ("cluster", Some(args)) => (Command::Cluster, args),
+        ("dedupe", Some(args)) => (Command::Dedupe, args),
+        ("matrix", Some(args)) => (Command::Matrix, args),
+        ("stats", Some(args)) => (Command::Stats, args),
+        ("eval", Some(args)) => (Command::Eval, args),
+        ("selftest", Some(args)) => (Command::Selftest, args),
+        ("bench", Some(args)) => (Command::Bench, args),
+        ("query", Some(args)) => (Command::Query, args),
+        ("scan", Some(args)) => (Command::Scan, args),
+        ("watch", Some(args)) => (Command::Watch, args),
+        ("db", Some(args)) => match args.subcommand() {
+            ("merge", Some(args)) => (Command::DbMerge, args),
+            ("check", Some(args)) => (Command::DbCheck, args),
+            ("export", Some(args)) => (Command::DbExport, args),
+            ("import", Some(args)) => (Command::DbImport, args),
+            _ => unreachable!("clap requires a db subcommand"),
+        },
+        #[cfg(any(feature = "server", feature = "grpc"))]
+        ("serve", Some(args)) => (Command::Serve, args),
+        _ if matches.is_present("compare") => {
+            warn!("-c/--compare is deprecated, use `lzjd compare` instead");
+            (Command::Compare, matches)
+        }
+        _ if matches.is_present("gen-compare") => {
+            warn!("-g/--gen-compare is deprecated, use `lzjd gen-compare` instead");
+            (Command::GenCompare, matches)
+        }
+        _ => (Command::Hash, matches),
+    };
+
+    let config = Config::load(args.value_of_os("config").map(Path::new))?;
+    // Flags with a default value are present even if not given
+    let given = |name| args.occurrences_of(name) > 0;
+
+    let metric: Metric = args.value_of("metric").unwrap_or("similarity").parse()?;
+    let threshold = match args.value_of("threshold") {
+        Some(threshold) => parse_threshold(threshold)?,
+        // The threshold of near-identical files is unrelated to that of comparisons
+        None if command == Command::Dedupe => metric.score(DEDUPE_THRESHOLD),
+        None if command == Command::Scan => metric.score(SCAN_THRESHOLD),
+        None => match config.get("threshold", parse_threshold)? {
+            Some(threshold) => threshold,
+            None => metric.score(0.01),
+        },
+    };
+
+    let top = match args.value_of("top") {
+        Some(top) => Some(
+            top.parse::<usize>()
+                .map_err(|_| LZJDError::from("--top must be a non-negative integer"))?,
+        ),
+        None => None,
+    };
+    let sort = match args.value_of("sort") {
+        Some(key) => Some((key.parse::<SortKey>()?, args.is_present("desc"))),
+        None => None,
+    };
+    let limit = match args.value_of("limit") {
+        Some(limit) => Some(
+            limit
+                .parse::<usize>()
+                .map_err(|_| LZJDError::from("--limit must be a non-negative integer"))?,
+        ),
+        None => None,
+    };
+
+    let parse_threads = |p: &str| {
+        p.parse::<usize>()
+            .map_err(|_| LZJDError::from("--threads must be a non-negative integer").into())
+    };
+    let num_threads = match config.get("threads", parse_threads)? {
+        Some(num_threads) if !given("threads") => num_threads,
+        _ => args.value_of("threads").map(parse_threads).transpose()?.unwrap_or(4),
+    };
+
+    let error_policy: ErrorPolicy = args.value_of("on-error").unwrap_or("fail").parse()?;
+
+    let parse_algorithm = |algorithm: &str| Ok(algorithm.parse::<HashAlgorithm>()?);
+    let parse_k = |k: &str| match k.parse::<usize>() {
+        Ok(k) if k > 0 => Ok(k),
+        _ => Err(LZJDError::from("--digest-size must be a positive integer").into()),
+    };
+    let algorithm = args.value_of("hash-algorithm").map(parse_algorithm).transpose()?;
+    let k = args.value_of("digest-size").map(parse_k).transpose()?;
+    // The configuration file only sets the settings of new digests,
+    // so unlike flags, it does not conflict with those of existing digests
+    let default_params = DigestParams {
+        algorithm: match algorithm {
+            Some(algorithm) => algorithm,
+            None => config.get("hash-algorithm", parse_algorithm)?.unwrap_or_default(),
+        },
+        k: match k {
+            Some(k) => k,
+            None => config.get("digest-size", parse_k)?.unwrap_or(K),
+        },
+        sampling: match args.value_of("max-bytes").map(parse_size).transpose()? {
+            Some(max) if args.value_of("sampling") == Some("spread") => Sampling::Spread(max),
+            Some(max) => Sampling::Head(max),
+            None => Sampling::Full,
+        },
+        skip_high_entropy: args.is_present("skip-high-entropy"),
+    };
+
+    let filter = FileFilter {
+        min_size: args.value_of("min-size").map(parse_size).transpose()?,
+        max_size: args.value_of("max-size").map(parse_size).transpose()?,
+        types: args
+            .values_of("type")
+            .into_iter()
+            .flatten()
+            .map(FileType::from_str)
+            .collect::<std::result::Result<_, _>>()?,
+    };
+
+    let mut inputs: Vec<PathBuf> = args
+        .values_of_os("input")
+        .into_iter()
+        .flatten()
+        .map(PathBuf::from)
+        .collect();
+    if let Some(list_path) = args.value_of_os("files-from") {
+        let nul_delimited = args.is_present("null");
+        let mut list = if list_path == STDIN_PATH {
+            if inputs.iter().any(|path| path == Path::new(STDIN_PATH)) {
+                return Err(LZJDError::from("Can only read stdin once").into());
+            }
+            read_path_list(io::stdin().lock(), nul_delimited)?
+        } else {
+            read_path_list(BufReader::new(File::open(list_path)?), nul_delimited)?
+        };
+        inputs.append(&mut list);
+    }
+    #[cfg(feature = "async")]
+    let concurrency = match args.value_of("concurrency") {
+        Some(n) => match n.parse() {
+            Ok(n) if n > 0 => Some(n),
+            _ => return Err(LZJDError::from("--concurrency must be a positive integer").into()),
+        },
+        None => None,
+    };
+    // The files found in each input, or the input itself if it is not walked
+    let input_groups: Vec<(PathBuf, Vec<PathBuf>)> = if args.is_present("deep") {
+        let options = DirectoryOptions {
+            error_policy,
+            follow_links: args.is_present("follow-symlinks"),
+            max_depth: match args.value_of("max-depth") {
+                Some(depth) => Some(
+                    depth
+                        .parse()
+                        .map_err(|_| LZJDError::from("--max-depth must be a non-negative integer"))?,
+                ),
+                None => None,
+            },
+            same_file_system: args.is_present("same-filesystem"),
+            include: glob_set(args.values_of("include"))?,
+            exclude: glob_set(args.values_of("exclude"))?,
+            ..Default::default()
+        };
+        inputs
+            .into_iter()
+            .map(|path| {
+                if path == Path::new(STDIN_PATH) || is_remote_input(&path) {
+                    return Ok((path.clone(), vec![path]));
+                }
+                #[cfg(feature = "async")]
+                let walk = match concurrency {
+                    Some(n) => scan::block_on(scan::walk_directory(&path, &options, n))??,
+                    None => walk_directory(&path, &options)?,
+                };
+                #[cfg(not(feature = "async"))]
+                let walk = walk_directory(&path, &options)?;
+                report_failures(&walk.failures);
+                Ok((path, walk.items))
+            })
+            .collect::<std::result::Result<_, LZJDError>>()?
+    } else {
+        inputs.into_iter().map(|path| (path.clone(), vec![path])).collect()
+    };
+    let input_paths: Vec<PathBuf> = input_groups.iter().flat_map(|(_, paths)| paths.clone()).collect();
+
+    let format = match config.get("format", |format| format.parse::<Format>().map_err(Error::from))? {
+        Some(format) if !given("format") => format,
+        _ => args.value_of("format").unwrap_or("text").parse()?,
+    };
+    let mut options = Options {
+        params: default_params,
+        threshold,
+        metric,
+        top,
+        sort,
+        limit,
+        pairs: PairFilter {
+            skip_self: args.is_present("no-self-pairs"),
+            canonical: args.is_present("canonical-pairs"),
+            unique: args.is_present("unique-pairs"),
+        },
+        error_policy,
+        filter,
+        stdin_label: args.value_of("label").unwrap_or(STDIN_PATH),
+        format,
+        encoding: args.value_of("encoding").unwrap_or("base64").parse()?,
+        progress: !args.is_present("no-progress") && io::stdout().is_terminal(),
+        color: args.value_of("output").is_none() && console::colors_enabled(),
+        fail_on: if args.is_present("fail-on-match") || command == Command::Scan {
+            Some(FailOn::Match)
+        } else if args.is_present("fail-on-no-match") {
+            Some(FailOn::NoMatch)
+        } else {
+            None
+        },
+        #[cfg(feature = "async")]
+        concurrency,
+        #[cfg(feature = "http")]
+        download_limit: parse_size(args.value_of("download-limit").unwrap_or("1G"))?,
+        #[cfg(feature = "http")]
+        download_timeout: match args.value_of("download-timeout").unwrap_or("30").parse::<f64>() {
+            Ok(seconds) if seconds > 0. && seconds.is_finite() => Duration::from_secs_f64(seconds),
+            _ => return Err(LZJDError::from("--download-timeout must be a positive number of seconds").into()),
+        },
+    };
+
+    #[cfg(any(feature = "server", feature = "grpc"))]
+    {
+        if command == Command::Serve {
+            return serve(args, algorithm, k, default_params, num_threads);
+        }
+    }
+
+    let output_path = args.value_of("output").map(PathBuf::from);
+
+    // Use a dedicated pool rather than configuring the global one,
+    // which may already have been set up by an embedding application
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build()?;
+
+    let mut output = create_out_writer(&output_path)?;
+    let ran = pool.install(|| {
+        let mut writer = &mut output;
+        let baseline_share = match args.value_of("baseline-share") {
+            Some(share) => parse_threshold(share)?,
+            None => 0.,
+        };
+        let error_policy = options.error_policy;
+        let read_baseline = |params| match args.value_of_os("baseline") {
+            Some(path) => {
+                read_baseline(Path::new(path), baseline_share, params, error_policy).map(Some)
+            }
+            None => Ok(None),
+        };
+
+        if command == Command::Compare {
+            if let Some(pairs_path) = args.value_of_os("pairs") {
+                let pairs = read_pairs(Path::new(pairs_path))?;
+                let (params, dicts) = read_hashes_from_files(&input_paths, options.error_policy)?;
+                options.params = params;
+                return compare_named_pairs(dicts, &pairs, &options, &mut writer);
+            }
+            if input_paths.is_empty() || input_paths.len() > 2 {
+                return Err(LZJDError::from("Can only compare at most two indexes at a time!").into());
+            }
+            if let Some(max_memory) = args.value_of("max-memory") {
+                let max_memory = usize::try_from(parse_size(max_memory)?).unwrap_or(usize::MAX);
+                return compare_in_blocks(&input_paths[0], input_paths.get(1), max_memory, &options, &mut writer);
+            }
+
+            let (params_a, hashes_a) = read_hashes_from_file(&input_paths[0], options.error_policy)?;
+            let (params, hashes_b) = match input_paths.get(1) {
+                Some(path_b) => {
+                    let (params_b, hashes_b) = read_hashes_from_file(path_b, options.error_policy)?;
+                    let params = params_a.negotiate(params_b)?;
+                    (params, Some(truncate_digests(hashes_b, params.k)))
+                }
+                None => (params_a, None),
+            };
+            let baseline = read_baseline(params)?;
+            let hashes_a = truncate_digests(hashes_a, params.k);
+            let hashes_a: Rc<Vec<DigestRecord>> = Rc::from(subtract_baseline(hashes_a, baseline.as_ref()));
+
+            let hashes_b = match hashes_b {
+                Some(hashes_b) => Rc::from(subtract_baseline(hashes_b, baseline.as_ref())),
+                None => Rc::clone(&hashes_a),
+            };
+
+            compare(&hashes_a, &hashes_b, &options, &mut writer)?;
+        } else if command == Command::Cluster && args.is_present("online") {
+            cluster_online(&input_paths, &options, &mut writer)?;
+        } else if command == Command::Cluster {
+            let (_, dicts) = read_hashes_from_files(&input_paths, options.error_policy)?;
+            cluster(&dicts, args.is_present("singletons"), &options, &mut writer)?;
+        } else if command == Command::Dedupe {
+            if input_paths.iter().any(|path| path == Path::new(STDIN_PATH)) {
+                return Err(LZJDError::from("Can not deduplicate stdin").into());
+            }
+            let digests = digest_paths(&input_paths, &options)?;
+            dedupe(&digests, &options, &mut writer)?;
+        } else if command == Command::Matrix {
+            let (_, dicts) = read_hashes_from_files(&input_paths, options.error_policy)?;
+            matrix(&dicts, args.is_present("phylip"), &options, &mut writer)?;
+        } else if command == Command::Stats {
+            let positive = |name, default| match args.value_of(name) {
+                Some(n) => match n.parse::<usize>() {
+                    Ok(n) if n > 0 => Ok(n),
+                    _ => Err(LZJDError::from(format!("--{} must be a positive integer", name).as_str())),
+                },
+                None => Ok(default),
+            };
+            let sample = positive("sample", STATS_SAMPLE_SIZE)?;
+            let bins = positive("bins", 10)?;
+            let (params, dicts) = read_hashes_from_files(&input_paths, options.error_policy)?;
+            options.params = params;
+            stats(&dicts, sample, bins, args.is_present("per-digest"), &options, &mut writer)?;
+        } else if command == Command::Eval {
+            let labels = read_labels(Path::new(args.value_of("labels").unwrap()))?;
+            let max_false_positives = parse_threshold(args.value_of("max-false-positives").unwrap())?;
+            let (params, dicts) = read_hashes_from_files(&input_paths, options.error_policy)?;
+            options.params = params;
+            let roc_path = args.value_of("roc").map(Path::new);
+            eval(dicts, &labels, max_false_positives, roc_path, &options, &mut writer)?;
+        } else if command == Command::Bench {
+            let size = match parse_size(args.value_of("size").unwrap())? {
+                0 => return Err(LZJDError::from("--size must be positive").into()),
+                size => size,
+            };
+            let digests = match args.value_of("digests").unwrap().parse::<usize>() {
+                Ok(n) if n > 1 => n,
+                _ => return Err(LZJDError::from("--digests must be an integer larger than 1").into()),
+            };
+            let size = usize::try_from(size)
+                .map_err(|_| LZJDError::from("--size does not fit in memory on this platform"))?;
+            bench(size, digests, &options, &mut writer)?;
+        } else if command == Command::Selftest {
+            run_selftest(&options, &mut writer)?;
+        } else if command == Command::DbMerge {
+            let (params, dicts) = read_hashes_from_files(&input_paths, options.error_policy)?;
+            let dicts = merge(dicts, args.is_present("unique-digests"));
+            write_digests(&dicts, params, options.format, options.encoding, &mut writer)?;
+        } else if command == Command::DbExport {
+            let (params, dicts) = read_hashes_from_files(&input_paths, options.error_policy)?;
+            export_bundle(dicts, params, &mut writer)?;
+        } else if command == Command::DbImport {
+            let (params, dicts) = import_bundle(&input_paths[0])?;
+            write_digests(&dicts, params, options.format, options.encoding, &mut writer)?;
+        } else if command == Command::DbCheck {
+            let mut problems = vec![];
+            for path in &input_paths {
+                problems.extend(
+                    check_digest_file(path)?
+                        .into_iter()
+                        .map(|(line, problem)| (path_to_label(path).into_owned(), line, problem)),
+                );
+            }
+            write_problems(&problems, options.format, &mut writer)?;
+            if !problems.is_empty() {
+                writer.flush()?;
+                return Err(LZJDError::Msg {
+                    msg: format!("Found {} problems", problems.len()),
+                }
+                .into());
+            }
+        } else if command == Command::GenCompare {
+            let labels = match args.value_of_os("labels") {
+                Some(path) => Some(read_labels(Path::new(path))?),
+                None => None,
+            };
+            let baseline = read_baseline(options.params)?;
+            let dicts = if args.is_present("merge-dirs") {
+                hash_merged_dirs(&input_groups, &options)?
+            } else {
+                hash_files(&input_paths, &options)?
+            };
+            let dicts = subtract_baseline(dicts, baseline.as_ref());
+            let compared = compare(&dicts, &dicts, &options, &mut writer);
+            if let Some(labels) = labels {
+                // Reported even if compare fails on the outcome
+                report_clustering(&dicts, &labels, &options)?;
+            }
+            compared?;
+        } else if command == Command::Query {
+            #[cfg(feature = "remote")]
+            {
+                if let Some(url) = args.value_of("remote") {
+                    let matches = remote_query(&Client::new(url), &input_paths, &options)?;
+                    return write_matches(&matches, &options, &mut writer);
+                }
+            }
+            let (db_params, db) = read_hashes_from_file(Path::new(args.value_of_os("db").unwrap()), options.error_policy)?;
+            options.params = resolve_params(algorithm, k, default_params, Some(db_params))?;
+            let baseline = read_baseline(options.params)?;
+            let db = subtract_baseline(db, baseline.as_ref());
+            let queries = subtract_baseline(hash_files(&input_paths, &options)?, baseline.as_ref());
+
+            compare(&queries, &db, &options, &mut writer)?;
+        } else if command == Command::Scan {
+            let (blacklist_params, blacklist) = read_hashes_from_file(Path::new(args.value_of_os("blacklist").unwrap()), options.error_policy)?;
+            options.params = resolve_params(algorithm, k, default_params, Some(blacklist_params))?;
+            let baseline = read_baseline(options.params)?;
+            let blacklist = subtract_baseline(blacklist, baseline.as_ref());
+            let files = subtract_baseline(hash_files(&input_paths, &options)?, baseline.as_ref());
+
+            scan(&files, &blacklist, &options, &mut writer)?;
+        } else if command == Command::Watch {
+            let db_path = Path::new(args.value_of_os("db").unwrap());
+            let db_params = match fs::metadata(db_path) {
+                Ok(metadata) if metadata.len() > 0 => Some(read_hashes_from_file(db_path, options.error_policy)?.0),
+                _ => None,
+            };
+            options.params = resolve_params(algorithm, k, default_params, db_params)?;
+            let references = match args.value_of_os("compare-against") {
+                Some(path) => {
+                    let (params, references) = read_hashes_from_file(Path::new(path), options.error_policy)?;
+                    options.params.check_compatible(params)?;
+                    references
+                }
+                None => vec![],
+            };
+
+            watch(&input_paths, db_path, &references, &options, &mut writer)?;
+        } else {
+            if args.is_present("raw") {
+                let dicts = hash_raw(&input_paths, &options)?;
+                return write_digests(&dicts, options.params, options.format, options.encoding, &mut writer);
+            }
+            if args.is_present("per-line") {
+                let label = args.value_of("label").filter(|_| given("label"));
+                let dicts = hash_lines(&input_paths, label, &options)?;
+                return write_digests(&dicts, options.params, options.format, options.encoding, &mut writer);
+            }
+            if args.is_present("merge-dirs") {
+                let dicts = hash_merged_dirs(&input_groups, &options)?;
+                return write_digests(&dicts, options.params, options.format, options.encoding, &mut writer);
+            }
+            #[cfg(feature = "archive")]
+            {
+                let format = if args.is_present("tar") {
+                    Some(ArchiveFormat::Tar)
+                } else if args.is_present("zip") {
+                    Some(ArchiveFormat::Zip)
+                } else {
+                    None
+                };
+                if let Some(format) = format {
+                    let label = args.value_of("label").filter(|_| given("label"));
+                    let dicts = hash_archives(&input_paths, format, label, &options)?;
+                    return write_digests(&dicts, options.params, options.format, options.encoding, &mut writer);
+                }
+            }
+            #[cfg(feature = "remote")]
+            {
+                if let Some(url) = args.value_of("remote") {
+                    let (params, dicts) = remote_hash(&Client::new(url), &input_paths, &options)?;
+                    return write_digests(&dicts, params, options.format, options.encoding, &mut writer);
+                }
+            }
+            if let Some(db_path) = args.value_of_os("db").map(Path::new) {
+                let db_params = match fs::metadata(db_path) {
+                    Ok(metadata) if metadata.len() > 0 => Some(read_hashes_from_file(db_path, options.error_policy)?.0),
+                    _ => None,
+                };
+                options.params = resolve_params(algorithm, k, default_params, db_params)?;
+                return add_to_db(&input_paths, db_path, args.is_present("update"), &options);
+            }
+            let dicts = hash_files(&input_paths, &options)?;
+            write_digests(&dicts, options.params, options.format, options.encoding, &mut writer)?;
+        }
+
+        Ok(())
+    });
+    // The output is finished even if the command fails, so that
+    // outcomes that are reported by failing come with all of it
+    let finished = output.finish();
+    ran?;
+    finished?;
+    Ok(())
+}
+
+/// Serves digests over HTTP and/or gRPC until interrupted, optionally loading
+/// them from and appending new ones to the digest file given with --db
+#[cfg(any(feature = "server", feature = "grpc"))]
+fn serve(
+    args: &clap::ArgMatches,
+    algorithm: Option<HashAlgorithm>,
+    k: Option<usize>,
+    defaults: DigestParams,
+    num_threads: usize,
+) -> Result<()> {
+    let retention = Retention {
+        max_age: match args.value_of("ttl").map(str::parse::<f64>) {
+            Some(Ok(seconds)) if seconds > 0. && seconds.is_finite() => Some(Duration::from_secs_f64(seconds)),
+            Some(_) => return Err(LZJDError::from("--ttl must be a positive number of seconds").into()),
+            None => None,
+        },
+        max_len: match args.value_of("max-digests").map(str::parse::<usize>) {
+            Some(Ok(n)) if n > 0 => Some(n),
+            Some(_) => return Err(LZJDError::from("--max-digests must be a positive integer").into()),
+            None => None,
+        },
+    };
+    let store = DigestStore::new();
+    let mut db_params = None;
+    if let Some(db_path) = args.value_of_os("db").map(Path::new) {
+        if fs::metadata(db_path).is_ok_and(|metadata| metadata.len() > 0) {
+            let (params, dicts) = read_hashes_from_file(db_path, ErrorPolicy::FailFast)?;
+            db_params = Some(params);
+            for record in dicts {
+                store.insert(record.label, record.dict);
+            }
+        }
+    }
+    let params = resolve_params(algorithm, k, defaults, db_params)?;
+
+    let mut service = Service::new(store.with_retention(retention), params.algorithm, params.k);
+    if let Some(db_path) = args.value_of_os("db") {
+        check_appendable(Path::new(db_path))?;
+        let mut db = OpenOptions::new().create(true).append(true).open(db_path)?;
+        if db.metadata()?.len() == 0 {
+            writeln!(db, "# {}", params)?;
+        }
+        service = service.with_log(db);
+    }
+
+    let service = Arc::new(service);
+
+    #[cfg(feature = "grpc")]
+    let grpc = args.value_of("grpc-listen").map(|addr| {
+        info!("Serving gRPC on {}", addr);
+        let service = Arc::clone(&service);
+        let addr = addr.to_owned();
+        thread::spawn(move || {
+            if let Err(e) = lzjd::grpc::serve(service, &addr, num_threads) {
+                error!("{}", e);
+                process::exit(-1);
+            }
+        })
+    });
+    #[cfg(feature = "server")]
+    {
+        let addr = args.value_of("listen").unwrap();
+        info!("Listening on http://{}", addr);
+        lzjd::server::serve(service, addr, num_threads)?;
+    }
+    #[cfg(feature = "grpc")]
+    {
+        if let Some(grpc) = grpc {
+            let _ = grpc.join();
+        }
+    }
+    Ok(())
+}
+
+/// Size and modification time of a file, which are recorded in
+/// databases to detect which files changed since they were digested
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileInfo {
+    size: u64,
+    /// Nanoseconds since the Unix epoch
+    mtime: u128,
+}
+
+impl FileInfo {
+    fn of(path: &Path) -> Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |mtime| mtime.as_nanos());
+        Ok(Self {
+            size: metadata.len(),
+            mtime,
+        })
+    }
+
+    /// Parses a line written by the Display implementation,
+    /// returning None if it is any other kind of line
+    fn parse(line: &str) -> Option<Self> {
+        let (mut size, mut mtime) = (None, None);
+        for field in line.strip_prefix(FILE_INFO_PREFIX)?.split_whitespace() {
+            match field.split_once('=') {
+                Some(("size", value)) => size = value.parse().ok(),
+                Some(("mtime", value)) => mtime = value.parse().ok(),
+                _ => {}
+            }
+        }
+        Some(Self {
+            size: size?,
+            mtime: mtime?,
+        })
+    }
+}
+
+/// Writes the comment line that precedes a digest in a database
+impl fmt::Display for FileInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}size={} mtime={}", FILE_INFO_PREFIX, self.size, self.mtime)
+    }
+}
+
+/// Digests the files at paths and adds them to the database at db_path, along with their
+/// size and modification time. With update, files of which these did not change since they
+/// were added are skipped, and the entries of files that did change are replaced.
+fn add_to_db(paths: &[PathBuf], db_path: &Path, update: bool, options: &Options) -> Result<()> {
+    if paths.iter().any(|path| path == Path::new(STDIN_PATH)) {
+        return Err(LZJDError::from("Can not add stdin to a database").into());
+    }
+    let lines: Vec<String> = match open_digest_file(db_path, 0) {
+        Ok(db) => db.lines().collect::<io::Result<_>>()?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e.into()),
+    };
+
+    // The last recorded info of each label, and the lines of all its entries
+    let mut recorded: HashMap<String, (Option<FileInfo>, Vec<usize>)> = HashMap::new();
+    let mut info_line: Option<(FileInfo, usize)> = None;
+    for (n, line) in lines.iter().enumerate() {
+        if let Some(info) = FileInfo::parse(line) {
+            info_line = Some((info, n));
+            continue;
+        }
+        let info = info_line.take();
+        if let Some(label) = digest_line_label(line) {
+            let entry = recorded.entry(label).or_default();
+            entry.0 = info.map(|(info, _)| info);
+            entry.1.extend(info.map(|(_, n)| n));
+            entry.1.push(n);
+        }
+    }
+
+    let mut infos = HashMap::new();
+    let mut stale: HashSet<usize> = HashSet::new();
+    let mut changed = vec![];
+    for path in paths {
+        let info = FileInfo::of(path)?;
+        if update {
+            match recorded.get(path_to_label(path).as_ref()) {
+                Some((Some(recorded), _)) if *recorded == info => continue,
+                Some((_, lines)) => stale.extend(lines),
+                None => {}
+            }
+        }
+        infos.insert(path.to_owned(), info);
+        changed.push(path.to_owned());
+    }
+    let digests = digest_paths(&changed, options)?;
+
+    let write_entries = |db: &mut dyn Write| {
+        digests.iter().try_for_each(|(path, dict)| {
+            writeln!(db, "{}", infos[path])?;
+            writeln!(db, "lzjd:{}:{}", path_to_label(path), dict.to_encoded_string(options.encoding))
+        })
+    };
+    // Compressed databases are appended to in frames of their own
+    let compress = is_compressed(db_path)?;
+    if stale.is_empty() {
+        let file = OpenOptions::new().create(true).append(true).open(db_path)?;
+        let mut db = DigestWriter::new(file, compress)?;
+        if lines.is_empty() {
+            writeln!(db, "# {}", options.params)?;
+        }
+        write_entries(&mut db)?;
+        db.finish()?;
+    } else {
+        // Replace the database at once, so that it is never left half-written
+        let mut tmp_path = db_path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let mut db = DigestWriter::new(File::create(&tmp_path)?, compress)?;
+        for (n, line) in lines.iter().enumerate() {
+            if !stale.contains(&n) {
+                writeln!(db, "{}", line)?;
+            }
+        }
+        write_entries(&mut db)?;
+        db.finish()?.sync_all()?;
+        fs::rename(&tmp_path, db_path)?;
+    }
+
+    info!(
+        "Added {} files to {}, skipped {} unchanged files",
+        digests.len(),
+        db_path.display(),
+        paths.len() - changed.len()
+    );
+    Ok(())
+}
+
+/// Returns the label of the digest on line, or None if it is not a digest line
+fn digest_line_label(line: &str) -> Option<String> {
+    if let Some(entry) = parse_sdhash_line(line) {
+        return entry.ok().map(|(_, _, label)| label);
+    }
+    let (label, _) = line.trim().strip_prefix("lzjd:")?.rsplit_once(':')?;
+    Some(label.to_owned())
+}
+
+/// Combines the settings given on the command line with those of existing
+/// digests, which are used for any setting that was not given. Without
+/// existing digests, defaults are used instead.
+fn resolve_params(
+    algorithm: Option<HashAlgorithm>,
+    k: Option<usize>,
+    defaults: DigestParams,
+    existing: Option<DigestParams>,
+) -> Result<DigestParams> {
+    let existing = match existing {
+        Some(existing) => existing,
+        None => return Ok(defaults),
+    };
+    let requested = DigestParams {
+        algorithm: algorithm.unwrap_or(existing.algorithm),
+        k: k.unwrap_or(existing.k),
+        sampling: defaults.sampling,
+        skip_high_entropy: defaults.skip_high_entropy,
+    };
+    requested.check_compatible(existing)?;
+    Ok(requested)
+}
+
+/// Digests files created or modified below paths until interrupted, and appends
+/// their digests to the digest file at db_path. Files are digested once no changes
+/// to them have been seen for WATCH_SETTLE_TIME. Matches of new digests with
+/// references are written to writer.
+fn watch(
+    paths: &[PathBuf],
+    db_path: &Path,
+    references: &[DigestRecord],
+    options: &Options,
+    writer: &mut dyn Write,
+) -> Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    for path in paths {
+        watcher.watch(path, RecursiveMode::Recursive)?;
+    }
+
+    check_appendable(db_path)?;
+    let mut db = OpenOptions::new().create(true).append(true).open(db_path)?;
+    if db.metadata()?.len() == 0 {
+        writeln!(db, "# {}", options.params)?;
+    }
+    // Appending to the database would otherwise trigger digesting it
+    let db_path = db_path.canonicalize()?;
+    let min_similarity = options.metric.min_similarity(options.threshold);
+
+    // Changed paths, along with when they last changed
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    loop {
+        match receiver.recv_timeout(WATCH_POLL_INTERVAL) {
+            Ok(event) => {
+                let event = event?;
+                if let EventKind::Create(_) | EventKind::Modify(_) = event.kind {
+                    let now = Instant::now();
+                    pending.extend(event.paths.into_iter().map(|path| (path, now)));
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+
+        let mut settled = vec![];
+        pending.retain(|path, changed| {
+            if changed.elapsed() < WATCH_SETTLE_TIME {
+                return true;
+            }
+            if path.is_file() && path.canonicalize().ok().as_ref() != Some(&db_path) {
+                settled.push(path.to_owned());
+            }
+            false
+        });
+        if settled.is_empty() {
+            continue;
+        }
+        settled.sort();
+
+        let selected = filter_files(&settled, &options.filter, options.error_policy)?;
+        report_failures(&selected.failures);
+        let batch = digest_files(
+            &selected.items,
+            options.error_policy,
+            options.params.algorithm,
+            options.params.k,
+            options.params.sampling,
+            options.params.skip_high_entropy,
+            None,
+        )?;
+        report_failures(&batch.failures);
+        for (path, dict) in batch.items {
+            let label = path_to_label(&path).into_owned();
+            writeln!(db, "lzjd:{}:{}", label, dict.to_encoded_string(options.encoding))?;
+            db.flush()?;
+            info!("Digested {}", label);
+
+            if !references.is_empty() {
+                let dicts = [DigestRecord::new(label, dict)];
+                let matches = compare_all(&dicts, references, min_similarity, None, None)?;
+                write_matches(&matches, options, writer)?;
+                writer.flush()?;
+            }
+        }
+    }
+}
+
+/// Parses a number of bytes, optionally followed by a binary K, M, G or T multiplier
+fn parse_size(size: &str) -> Result<u64> {
+    let invalid = || LZJDError::Msg {
+        msg: format!("Invalid size '{}'", size),
+    };
+    let (number, shift) = match size.char_indices().last() {
+        Some((i, unit)) if unit.is_ascii_alphabetic() => {
+            let shift = match unit.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(invalid().into()),
+            };
+            (&size[..i], shift)
+        }
+        _ => (size, 0),
+    };
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| invalid().into())
+}
+
+/// Compiles the given glob patterns, returning None if there are none
+fn glob_set(patterns: Option<clap::Values>) -> Result<Option<GlobSet>> {
+    let patterns = match patterns {
+        Some(patterns) => patterns,
+        None => return Ok(None),
+    };
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).map_err(|e| LZJDError::Msg { msg: e.to_string() })?);
+    }
+    let set = builder.build().map_err(|e| LZJDError::Msg { msg: e.to_string() })?;
+    Ok(Some(set))
+}
+
+/// Reads the baseline of the hashes that occur in at least a fraction min_share of the digests
+/// in the file at path, which must have been created with settings compatible with params
+fn read_baseline(path: &Path, min_share: f64, params: DigestParams, policy: ErrorPolicy) -> Result<Baseline> {
+    let (baseline_params, dicts) = read_hashes_from_file(path, policy)?;
+    params.check_compatible(baseline_params)?;
+    let baseline = Baseline::new(dicts.iter().map(|record| &record.dict), min_share);
+    debug!(
+        "Removing {} hashes of {} baseline digests from digests before comparing them",
+        baseline.len(),
+        dicts.len()
+    );
+    Ok(baseline)
+}
+
+/// Removes the hashes in baseline, if given, from dicts
+fn subtract_baseline(dicts: Vec<DigestRecord>, baseline: Option<&Baseline>) -> Vec<DigestRecord> {
+    match baseline {
+        Some(baseline) => dicts
+            .into_iter()
+            .map(|record| DigestRecord { dict: baseline.subtract(&record.dict), ..record })
+            .collect(),
+        None => dicts,
+    }
+}
+
+/// Whether the digest file at path is compressed with zstd, by its contents if it
+/// exists, and otherwise by its extension
+fn is_compressed(path: &Path) -> io::Result<bool> {
+    let mut magic = vec![];
+    match File::open(path) {
+        Ok(file) => file.take(ZSTD_MAGIC.len() as u64).read_to_end(&mut magic)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+    Ok(match magic.len() {
+        0 => path.extension() == Some(OsStr::new(ZSTD_EXTENSION)),
+        _ => magic == ZSTD_MAGIC,
+    })
+}
+
+/// Opens the digest file at path from position on, decompressing it if it is compressed
+/// with zstd. Positions in compressed files are those of the decompressed data.
+fn open_digest_file(path: &Path, position: u64) -> io::Result<Box<dyn BufRead>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if !reader.fill_buf()?.starts_with(&ZSTD_MAGIC) {
+        reader.seek(SeekFrom::Start(position))?;
+        return Ok(Box::new(reader));
+    }
+    #[cfg(feature = "zstd")]
+    {
+        let mut reader = BufReader::new(zstd::Decoder::with_buffer(reader)?);
+        io::copy(&mut (&mut reader).take(position), &mut io::sink())?;
+        Ok(Box::new(reader))
+    }
+    #[cfg(not(feature = "zstd"))]
+    Err(io::Error::other("compressed with zstd, which this build can not read"))
+}
+
+/// Buffered writer of output and digest files, which compresses what is written with zstd
+/// if asked to, in a frame of its own. The output is only complete once finish is called,
+/// which fails if it could not be, like when the disk is full.
+enum DigestWriter<W: Write> {
+    Plain(BufWriter<W>),
+    #[cfg(feature = "zstd")]
+    Zstd(BufWriter<zstd::Encoder<'static, W>>),
+}
+
+impl<W: Write> DigestWriter<W> {
+    fn new(inner: W, compress: bool) -> Result<Self> {
+        if !compress {
+            return Ok(DigestWriter::Plain(BufWriter::new(inner)));
+        }
+        #[cfg(feature = "zstd")]
+        {
+            Ok(DigestWriter::Zstd(BufWriter::new(zstd::Encoder::new(inner, 0)?)))
+        }
+        #[cfg(not(feature = "zstd"))]
+        Err(LZJDError::from("Writing digest files compressed with zstd needs the zstd feature").into())
+    }
+
+    /// Writes out what is buffered and ends the zstd frame, returning the inner writer
+    fn finish(self) -> io::Result<W> {
+        let mut inner = match self {
+            DigestWriter::Plain(writer) => writer.into_inner().map_err(|e| e.into_error())?,
+            #[cfg(feature = "zstd")]
+            DigestWriter::Zstd(writer) => writer.into_inner().map_err(|e| e.into_error())?.finish()?,
+        };
+        inner.flush()?;
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            DigestWriter::Plain(writer) => writer.write(buf),
+            #[cfg(feature = "zstd")]
+            DigestWriter::Zstd(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            DigestWriter::Plain(writer) => writer.flush(),
+            #[cfg(feature = "zstd")]
+            DigestWriter::Zstd(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Fails if the digest database at path is compressed, as databases that are
+/// appended to for as long as the program runs can not be
+fn check_appendable(path: &Path) -> Result<()> {
+    if is_compressed(path)? {
+        return Err(LZJDError::Msg {
+            msg: format!("Can not keep appending to compressed database {}", path.display()),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Reads the digests in the file at path, along with the settings they
+/// were created with. Files without a header are assumed to contain
+/// digests created with the default settings, as written by earlier versions.
+/// Lines may also be JSON objects, which hold their own settings. With ErrorPolicy::Skip,
+/// malformed digest lines are skipped with a warning, but conflicting headers still fail.
+fn read_hashes_from_file(path: &Path, policy: ErrorPolicy) -> Result<(DigestParams, Vec<DigestRecord>)> {
+    let mut lines = DigestLines::open(path, 0, policy)?;
+    let mut dicts = vec![];
+    while let Some(entry) = lines.next_digest()? {
+        dicts.push(entry);
+    }
+    lines.report_skipped();
+    debug!("Read {} digests from {}", dicts.len(), path.display());
+    Ok((lines.params.unwrap_or_default(), dicts))
+}
+
+/// Reads the digests on the lines of a digest file one at a time. Malformed lines fail
+/// the read with an error about their line and column, or with ErrorPolicy::Skip, are
+/// skipped and reported. Headers that do not match the earlier lines are never skipped.
+struct DigestLines {
+    path: PathBuf,
+    reader: Box<dyn BufRead>,
+    policy: ErrorPolicy,
+    /// Settings found in the lines read so far
+    params: Option<DigestParams>,
+    /// Number of lines read, and offset in the file of the next line
+    line: usize,
+    position: u64,
+    /// Numbers of the lines that were skipped
+    skipped: Vec<usize>,
+}
+
+impl DigestLines {
+    /// Reads the digest file at path from position on
+    fn open(path: &Path, position: u64, policy: ErrorPolicy) -> Result<Self> {
+        let reader = open_digest_file(path, position).map_err(|e| LZJDError::from(e).in_file(path))?;
+        Ok(Self::new(path, reader, policy))
+    }
+
+    /// Reads the digest file at path from reader
+    fn new(path: &Path, reader: Box<dyn BufRead>, policy: ErrorPolicy) -> Self {
+        Self {
+            path: path.to_owned(),
+            reader,
+            policy,
+            params: None,
+            line: 0,
+            position: 0,
+            skipped: vec![],
+        }
+    }
+
+    /// Returns the digest on the next line that holds one, or None at the end of the file
+    fn next_digest(&mut self) -> Result<Option<DigestRecord>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let len = self
+                .reader
+                .read_line(&mut line)
+                .map_err(|e| LZJDError::from(e).at_line(&self.path, self.line + 1))?;
+            if len == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+            self.position += len as u64;
+            match parse_digest_line(&line, &mut self.params) {
+                Ok(Some(entry)) => return Ok(Some(entry)),
+                Ok(None) => {}
+                Err(e) => {
+                    let column = error_column(&line, &e);
+                    let e = e.at_column(&self.path, self.line, column);
+                    if self.policy == ErrorPolicy::FailFast || line.trim_start().starts_with('#') {
+                        return Err(e);
+                    }
+                    warn!("Skipped {}", e);
+                    self.skipped.push(self.line);
+                }
+            }
+        }
+    }
+
+    /// Logs the lines that were skipped, if any
+    fn report_skipped(&self) {
+        if self.skipped.is_empty() {
+            return;
+        }
+        let lines: Vec<String> = self.skipped.iter().map(|line| line.to_string()).collect();
+        warn!(
+            "Skipped {} malformed lines of {}: line {}",
+            lines.len(),
+            self.path.display(),
+            lines.join(", ")
+        );
+    }
+}
+
+/// Returns the column of line at which err, an error parsing it with parse_digest_line,
+/// occurred, counting from 1: where the digest starts if it could not be decoded, or
+/// where the line starts otherwise
+fn error_column(line: &str, err: &Error) -> usize {
+    let indent = line.len() - line.trim_start().len();
+    let offset = match err {
+        Error::Lzjd {
+            err: LZJDError::Digest { .. },
+        } => line.trim_end().rfind(':').map_or(indent, |colon| colon + 1),
+        _ => indent,
+    };
+    line[..offset].chars().count() + 1
+}
+
+/// Parses a line of a digest file, returning the digest and label on it if it is not
+/// a header, comment or empty line. Headers are checked against the settings of the
+/// earlier lines in params, which is set by the first header.
+fn parse_digest_line(line: &str, params: &mut Option<DigestParams>) -> Result<Option<DigestRecord>> {
+    let line = line.trim();
+    if let Some(header) = line.strip_prefix('#') {
+        if let Some(found) = parse_header(header) {
+            let found = found?;
+            match params {
+                Some(params) => params.check_compatible(found)?,
+                None => *params = Some(found),
+            }
+        }
+    } else if let Some(entry) = parse_json_line(line) {
+        let (found, record) = entry?;
+        match params {
+            Some(params) => params.check_compatible(found)?,
+            None => *params = Some(found),
+        }
+        return Ok(Some(record));
+    } else if let Some(entry) = parse_sdhash_line(line) {
+        let (algorithm, dict, name) = entry?;
+        let params = params.get_or_insert_with(DigestParams::default);
+        params.check_compatible(DigestParams { algorithm, ..*params })?;
+        return Ok(Some(DigestRecord::new(name, dict)));
+    } else if !line.is_empty() {
+        match line.strip_prefix("lzjd:").and_then(|rest| rest.rsplit_once(':')) {
+            Some((file_name, b64)) if !file_name.is_empty() => {
+                let dict = LZDict::from_encoded_string(b64).map_err(|e| e.for_digest(file_name))?;
+                return Ok(Some(DigestRecord::new(file_name, dict)));
+            }
+            _ => return Err(LZJDError::from("Could not parse line, expected lzjd:LABEL:DIGEST").into()),
+        }
+    }
+    Ok(None)
+}
+
+/// Reads the digests of a digest file in blocks of limited size,
+/// for comparing digest files that do not fit in memory
+struct DigestBlocks {
+    lines: DigestLines,
+    /// Estimated number of bytes of memory that a block may take
+    block_memory: usize,
+    /// Whether to report the lines skipped once the file is read, which blocks
+    /// reading the rest of a file that is also read by others do not
+    report: bool,
+    /// Decompressed copy of the file if it is compressed and read more than once
+    copy: Option<Rc<PlainCopy>>,
+}
+
+/// Decompressed copy of a digest file in the temporary directory,
+/// which is removed when it is dropped
+struct PlainCopy(PathBuf);
+
+impl Drop for PlainCopy {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Number of the next decompressed copy of a digest file, which makes its path unique
+static PLAIN_COPIES: AtomicUsize = AtomicUsize::new(0);
+
+impl DigestBlocks {
+    /// Reads the file at path, handling malformed lines by policy. If rest is called to
+    /// read the file again and it is compressed, it is decompressed once to a copy that
+    /// is read instead, so that reading from a position does not decompress it again.
+    fn open(path: &Path, policy: ErrorPolicy, block_memory: usize, rereads: bool) -> Result<Self> {
+        let copy = if rereads && is_compressed(path)? {
+            let number = PLAIN_COPIES.fetch_add(1, Ordering::Relaxed);
+            let copy = PlainCopy(env::temp_dir().join(format!("lzjd-blocks-{}-{}.lzjd", process::id(), number)));
+            let mut reader = open_digest_file(path, 0).map_err(|e| LZJDError::from(e).in_file(path))?;
+            io::copy(&mut reader, &mut File::create(&copy.0)?)?;
+            Some(Rc::new(copy))
+        } else {
+            None
+        };
+        let lines = match &copy {
+            Some(copy) => DigestLines::new(path, open_digest_file(&copy.0, 0)?, policy),
+            None => DigestLines::open(path, 0, policy)?,
+        };
+        Ok(Self {
+            lines,
+            block_memory,
+            report: true,
+            copy,
+        })
+    }
+
+    /// Reads the rest of the file from where self is, without reporting the lines skipped
+    fn rest(&self, block_memory: usize) -> Result<Self> {
+        let source = self.copy.as_ref().map_or(&self.lines.path, |copy| &copy.0);
+        let reader = open_digest_file(source, self.lines.position)?;
+        let mut lines = DigestLines::new(&self.lines.path, reader, self.lines.policy);
+        lines.params = self.lines.params;
+        lines.line = self.lines.line;
+        lines.position = self.lines.position;
+        Ok(Self {
+            lines,
+            block_memory,
+            report: false,
+            copy: self.copy.clone(),
+        })
+    }
+
+    /// Returns the next block of digests, or None at the end of the file
+    fn next_block(&mut self) -> Result<Option<Vec<DigestRecord>>> {
+        let mut block = vec![];
+        let mut memory = 0;
+        while memory < self.block_memory {
+            match self.lines.next_digest()? {
+                Some(entry) => {
+                    memory += digest_memory(&entry);
+                    block.push(entry);
+                }
+                None => break,
+            }
+        }
+        if block.is_empty() {
+            if self.report {
+                self.lines.report_skipped();
+                self.report = false;
+            }
+            return Ok(None);
+        }
+        Ok(Some(block))
+    }
+
+    fn params(&self) -> DigestParams {
+        self.lines.params.unwrap_or_default()
+    }
+}
+
+/// Estimates the number of bytes of memory a digest and its label take
+fn digest_memory(record: &DigestRecord) -> usize {
+    // Allocation sizes and the headers of the vector, string and map
+    let meta: usize = record.meta.iter().map(|(key, value)| key.len() + value.len()).sum();
+    record.dict.len() * mem::size_of::<i32>() + record.label.len() + meta + mem::size_of::<DigestRecord>()
+}
+
+/// Like compare, but reads the digests in blocks so that they take at most about max_memory
+/// bytes, rather than all at once. Without path_b, the digests in path_a are compared with
+/// each other, and the file is read once more for every block. With path_b, all digests
+/// in path_b are read at once, and those in path_a are compared with them block by block.
+/// Matches are written after every block of path_a, unless they must be sorted or aligned
+/// in a table, so that they need not all be held in memory.
+fn compare_in_blocks(
+    path_a: &Path,
+    path_b: Option<&PathBuf>,
+    max_memory: usize,
+    options: &Options,
+    writer: &mut dyn Write,
+) -> Result<()> {
+    let min_similarity = options.metric.min_similarity(options.threshold);
+    let bar = progress_bar(options.progress, "comparing {pos} pairs, {elapsed}");
+    let mut compared = 0;
+    let mut num_matches = 0;
+    let mut stream = if options.sort.is_some() || options.format == Format::Pretty {
+        None
+    } else {
+        Some(MatchStream::new(options, writer))
+    };
+    // Matches of all blocks, if they can not be written per block
+    let mut held = vec![];
+    let mut seen = HashSet::new();
+    // Writes or holds the matches of a block of path_a, in the same order as compare would
+    let mut emit = |mut matches: Vec<(_, Match)>| -> Result<()> {
+        matches.sort_by_key(|&(position, _)| position);
+        let matches = options.pairs.apply(matches.into_iter().map(|(_, entry)| entry).collect(), &mut seen);
+        num_matches += matches.len();
+        match &mut stream {
+            Some(stream) => stream.write(&matches)?,
+            None => held.extend(matches),
+        }
+        Ok(())
+    };
+    // Matches along with the positions of their digests. Pairs compared are counted after each block.
+    let mut add_matches = |matches: &mut Vec<_>,
+                           offset_a: usize,
+                           block_a: &[DigestRecord],
+                           offset_b: usize,
+                           block_b: &[DigestRecord]| {
+        let before = compared;
+        let mut report = |progress| {
+            if let Progress::PairsCompared { done, .. } = progress {
+                bar.set_position(before + done);
+            }
+        };
+        let pairs = similar_pairs_between(block_a, block_b, min_similarity, None, Some(&mut report))?;
+        compared += if std::ptr::eq(block_a, block_b) {
+            block_a.len() * block_a.len().saturating_sub(1) / 2
+        } else {
+            block_a.len() * block_b.len()
+        } as u64;
+        matches.extend(pairs.into_iter().map(|(i, j, similarity)| {
+            let (a, b) = (block_a[i].label.to_owned(), block_b[j].label.to_owned());
+            ((offset_a + i, offset_b + j), Match { a, b, similarity })
+        }));
+        Ok::<_, Error>(())
+    };
+
+    match path_b {
+        Some(path_b) => {
+            let (params_b, mut dicts_b) = read_hashes_from_file(path_b, options.error_policy)?;
+            let memory_b: usize = dicts_b.iter().map(digest_memory).sum();
+            if memory_b >= max_memory {
+                return Err(LZJDError::Msg {
+                    msg: format!(
+                        "The digests in {} take about {} of memory, more than --max-memory; \
+                         give the larger digest file first",
+                        path_b.display(),
+                        HumanBytes(memory_b as u64)
+                    ),
+                }
+                .into());
+            }
+            let mut blocks = DigestBlocks::open(path_a, options.error_policy, max_memory - memory_b, false)?;
+            let mut offset_a = 0;
+            let mut k = params_b.k;
+            while let Some(block_a) = blocks.next_block()? {
+                if offset_a == 0 {
+                    // The settings of path_a are known once its first block is read
+                    k = blocks.params().negotiate(params_b)?.k;
+                    dicts_b = truncate_digests(dicts_b, k);
+                }
+                let block_a = truncate_digests(block_a, k);
+                let mut matches = vec![];
+                add_matches(&mut matches, offset_a, &block_a, 0, &dicts_b)?;
+                emit(matches)?;
+                offset_a += block_a.len();
+            }
+        }
+        None => {
+            // Two blocks are in memory at once
+            let block_memory = max_memory / 2;
+            let mut outer = DigestBlocks::open(path_a, options.error_policy, block_memory, true)?;
+            let mut offset_a = 0;
+            while let Some(block_a) = outer.next_block()? {
+                let mut matches = vec![];
+                add_matches(&mut matches, offset_a, &block_a, offset_a, &block_a)?;
+                let mut inner = outer.rest(block_memory)?;
+                let mut offset_b = offset_a + block_a.len();
+                while let Some(block_b) = inner.next_block()? {
+                    add_matches(&mut matches, offset_a, &block_a, offset_b, &block_b)?;
+                    offset_b += block_b.len();
+                }
+                emit(matches)?;
+                offset_a += block_a.len();
+            }
+        }
+    }
+    bar.finish_and_clear();
+    debug!("Compared {} pairs in blocks, {} matches", compared, num_matches);
+
+    match stream {
+        Some(stream) => stream.finish(),
+        None => write_matches(&held, options, writer),
+    }
+}
+
+/// Reads the digests in the files at paths, which must have been created with compatible
+/// settings. Digests of files with different digest sizes are truncated to the smallest.
+fn read_hashes_from_files(paths: &[PathBuf], policy: ErrorPolicy) -> Result<(DigestParams, Vec<DigestRecord>)> {
+    let mut params: Option<DigestParams> = None;
+    let mut dicts = vec![];
+    for path in paths {
+        let (found, mut file_dicts) = read_hashes_from_file(path, policy)?;
+        params = match params {
+            Some(params) => Some(params.negotiate(found)?),
+            None => Some(found),
+        };
+        dicts.append(&mut file_dicts);
+    }
+    let params = params.unwrap_or_default();
+    Ok((params, truncate_digests(dicts, params.k)))
+}
+
+/// Checks every line of the digest file at path, returning the line numbers of the
+/// problems found along with a description of each. Only fails if path can not be read.
+fn check_digest_file(path: &Path) -> Result<Vec<(usize, String)>> {
+    let mut problems = vec![];
+    // The settings in the first header, and the line it is on
+    let mut header: Option<(DigestParams, usize)> = None;
+    // The line on which each label is first seen
+    let mut labels: HashMap<String, usize> = HashMap::new();
+    for (n, line) in open_digest_file(path, 0)?.lines().enumerate() {
+        let n = n + 1;
+        let line = line?;
+        let line = line.trim();
+        let mut problem = |problem: String| problems.push((n, problem));
+
+        if let Some(found) = line.strip_prefix('#').and_then(parse_header) {
+            match (found, header) {
+                (Err(e), _) => problem(format!("invalid header: {}", describe(&e))),
+                (Ok(found), Some((params, first))) if found != params => problem(format!(
+                    "header conflicts with the header on line {}",
+                    first
+                )),
+                (Ok(found), None) if !labels.is_empty() => {
+                    problem("header follows digests".to_owned());
+                    header = Some((found, n));
+                }
+                (Ok(found), None) => header = Some((found, n)),
+                _ => {}
+            }
+            continue;
+        }
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let params = header.map(|(params, _)| params).unwrap_or_default();
+
+        let (dict, label) = if let Some(entry) = parse_sdhash_line(line) {
+            match entry {
+                Ok((algorithm, dict, label)) => {
+                    if algorithm != params.algorithm {
+                        problem(format!(
+                            "digest created with {}, but the file is for {}",
+                            algorithm, params.algorithm
+                        ));
+                    }
+                    (dict, label)
+                }
+                Err(e) => {
+                    problem(format!("invalid digest: {}", describe(&e)));
+                    continue;
+                }
+            }
+        } else {
+            let entry = line.strip_prefix("lzjd:").and_then(|rest| rest.rsplit_once(':'));
+            match entry {
+                Some((label, b64)) => match LZDict::from_encoded_string(b64) {
+                    Ok(dict) => (dict, label.to_owned()),
+                    Err(e) => {
+                        problem(format!("invalid digest: {}", describe(&e.into())));
+                        continue;
+                    }
+                },
+                None => {
+                    problem("not a digest line".to_owned());
+                    continue;
+                }
+            }
+        };
+
+        if let Err(e) = dict.verify(params.k) {
+            problem(describe(&e.into()));
+        }
+        match labels.get(&label) {
+            Some(first) => problem(format!("duplicate label, first seen on line {}", first)),
+            None => {
+                labels.insert(label, n);
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// Describes e for use in another message, leaving out the
+/// "Error: " prefix of plain messages
+fn describe(e: &Error) -> String {
+    match e {
+        Error::Lzjd {
+            err: LZJDError::Msg { msg },
+        } => msg.to_owned(),
+        e => e.to_string(),
+    }
+}
+
+/// Measures how many bytes per second are digested by a single thread and by all threads
+/// of the current pool, and how many pairs of digests are compared per second, using size
+/// bytes of generated input that is split into the given number of digests
+fn bench(size: usize, digests: usize, options: &Options, writer: &mut dyn Write) -> Result<()> {
+    let DigestParams { algorithm, k, .. } = options.params;
+    let threads = rayon::current_num_threads();
+    let with_threads = match threads {
+        1 => "1 thread".to_owned(),
+        n => format!("{} threads", n),
+    };
+    info!("Generating {} of input", HumanBytes(size as u64));
+    // xorshift64, as the input should not compress much
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    let data: Vec<u8> = (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 32) as u8
+        })
+        .collect();
+    let chunks: Vec<&[u8]> = data.chunks(size.div_ceil(digests).max(1)).collect();
+    let rate = |amount: f64, start: Instant| amount / start.elapsed().as_secs_f64().max(1e-9);
+
+    // A single thread digests as many chunks as each thread does below
+    let share = &chunks[..chunks.len().div_ceil(threads)];
+    info!("Digesting {} with 1 thread", HumanBytes(share.iter().map(|chunk| chunk.len() as u64).sum()));
+    let start = Instant::now();
+    for chunk in share {
+        algorithm.digest_reader_with_k(*chunk, k)?;
+    }
+    let single_rate = rate(share.iter().map(|chunk| chunk.len()).sum::<usize>() as f64, start);
+
+    info!("Digesting {} with {}", HumanBytes(size as u64), with_threads);
+    let start = Instant::now();
+    let dicts = chunks
+        .par_iter()
+        .enumerate()
+        .map(|(i, chunk)| Ok(DigestRecord::new(i.to_string(), algorithm.digest_reader_with_k(*chunk, k)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let digest_rate = rate(size as f64, start);
+
+    let pairs = dicts.len() * dicts.len().saturating_sub(1) / 2;
+    info!("Comparing {} pairs of digests with {}", pairs, with_threads);
+    let start = Instant::now();
+    // Digests of random data hardly share hashes, so collecting the results takes no time.
+    // Their signatures do not rule them out at this threshold, so every pair is compared.
+    similar_pairs(&dicts, f64::MIN_POSITIVE, None, None)?;
+    let compare_rate = rate(pairs as f64, start);
+
+    match options.format {
+        #[cfg(feature = "arrow")]
+        Format::Parquet => return Err(LZJDError::from(PARQUET_UNSUPPORTED).into()),
+        Format::Text | Format::Sdhash | Format::Pretty => {
+            writer.write_fmt(format_args!(
+                "hash algorithm {}, digest size {}, {} input in {} digests\n",
+                algorithm,
+                k,
+                HumanBytes(size as u64),
+                dicts.len()
+            ))?;
+            writer.write_fmt(format_args!("digesting, 1 thread: {:.1} MB/s\n", single_rate / 1e6))?;
+            writer.write_fmt(format_args!("digesting, {}: {:.1} MB/s\n", with_threads, digest_rate / 1e6))?;
+            writer.write_fmt(format_args!("comparing, {}: {:.0} comparisons/s\n", with_threads, compare_rate))?;
+        }
+        Format::Json | Format::Ndjson => {
+            let record = json!({
+                "algorithm": algorithm.name(),
+                "k": k,
+                "threads": threads,
+                "bytes": size,
+                "digests": dicts.len(),
+                "single_thread_bytes_per_second": single_rate,
+                "bytes_per_second": digest_rate,
+                "comparisons_per_second": compare_rate,
+            });
+            write_json(iter::once(record), options.format, writer)?;
+        }
+        Format::Csv => {
+            writer.write_all(b"algorithm,k,threads,bytes,digests,single_thread_bytes_per_second,bytes_per_second,comparisons_per_second\r\n")?;
+            writer.write_fmt(format_args!(
+                "{},{},{},{},{},{:.0},{:.0},{:.0}\r\n",
+                algorithm,
+                k,
+                threads,
+                size,
+                dicts.len(),
+                single_rate,
+                digest_rate,
+                compare_rate
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs the checks of the self-test, along with checks that digests read back from
+/// digest files in the text and sdhash formats equal those written, and writes the outcomes
+fn run_selftest(options: &Options, writer: &mut dyn Write) -> Result<()> {
+    let mut checks = selftest::run()?;
+
+    let dicts = REFERENCE_INPUTS
+        .iter()
+        .map(|&name| {
+            let dict = HashAlgorithm::default().digest_reader(&reference_input(name).unwrap_or_default()[..])?;
+            Ok(DigestRecord::new(name, dict))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for (format, name) in [(Format::Text, "text"), (Format::Sdhash, "sdhash")] {
+        let mut file = vec![];
+        write_digests(&dicts, DigestParams::default(), format, Encoding::Base64, &mut file)?;
+        let mut params = None;
+        let read = String::from_utf8_lossy(&file)
+            .lines()
+            .filter_map(|line| parse_digest_line(line, &mut params).transpose())
+            .collect::<Result<Vec<_>>>();
+        let same = |read: &[DigestRecord]| {
+            read.len() == dicts.len()
+                && read.iter().zip(&dicts).all(|(a, b)| *a.dict == *b.dict && a.label == b.label)
+        };
+        checks.push(Check {
+            name: format!("{} digest file round trip", name),
+            failure: match read {
+                Ok(read) if same(&read) && params == Some(DigestParams::default()) => None,
+                Ok(_) => Some("digests read back differ from those written".to_owned()),
+                Err(e) => Some(e.to_string()),
+            },
+        });
+    }
+
+    match options.format {
+        #[cfg(feature = "arrow")]
+        Format::Parquet => return Err(LZJDError::from(PARQUET_UNSUPPORTED).into()),
+        Format::Text | Format::Sdhash | Format::Pretty => checks.iter().try_for_each(|check| match &check.failure {
+            None => writer.write_fmt(format_args!("ok: {}\n", check.name)),
+            Some(failure) => writer.write_fmt(format_args!("FAILED: {}: {}\n", check.name, failure)),
+        })?,
+        Format::Json | Format::Ndjson => {
+            let records = checks
+                .iter()
+                .map(|check| json!({ "check": check.name, "passed": check.passed(), "failure": check.failure }));
+            write_json(records, options.format, writer)?;
+        }
+        Format::Csv => {
+            writer.write_all(b"check,passed,failure\r\n")?;
+            checks.iter().try_for_each(|check| {
+                writer.write_fmt(format_args!(
+                    "{},{},{}\r\n",
+                    csv_field(&check.name),
+                    check.passed(),
+                    csv_field(check.failure.as_deref().unwrap_or(""))
+                ))
+            })?;
+        }
+    }
+
+    let failed = checks.iter().filter(|check| !check.passed()).count();
+    if failed > 0 {
+        writer.flush()?;
+        return Err(LZJDError::Msg {
+            msg: format!("{} of {} self-test checks failed", failed, checks.len()),
+        }
+        .into());
+    }
+    info!("All {} self-test checks passed", checks.len());
+    Ok(())
+}
+
+/// Writes the problems found by check_digest_file as file, line and description
+fn write_problems(problems: &[(String, usize, String)], format: Format, writer: &mut dyn Write) -> Result<()> {
+    match format {
+        #[cfg(feature = "arrow")]
+        Format::Parquet => return Err(LZJDError::from(PARQUET_UNSUPPORTED).into()),
+        Format::Text | Format::Sdhash | Format::Pretty => problems.iter().try_for_each(|(file, line, problem)| {
+            writer.write_fmt(format_args!("{}:{}: {}\n", file, line, problem))
+        })?,
+        Format::Json | Format::Ndjson => {
+            let records = problems
+                .iter()
+                .map(|(file, line, problem)| json!({ "file": file, "line": line, "problem": problem }));
+            write_json(records, format, writer)?;
+        }
+        Format::Csv => {
+            writer.write_all(b"file,line,problem\r\n")?;
+            problems.iter().try_for_each(|(file, line, problem)| {
+                writer.write_fmt(format_args!("{},{},{}\r\n", csv_field(file), line, csv_field(problem)))
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Parses the digest settings from a digest file header line,
+/// returning None if it is a comment rather than a header
+fn parse_header(header: &str) -> Option<Result<DigestParams>> {
+    let mut fields = header.split_whitespace();
+    if fields.next() != Some("lzjd") {
+        return None;
+    }
+    Some(fields.try_fold(DigestParams::default(), |mut params, field| {
+        match field.split_once('=') {
+            Some(("algorithm", algorithm)) => params.algorithm = algorithm.parse()?,
+            Some(("sampling", sampling)) => params.sampling = sampling.parse()?,
+            Some(("entropy", entropy)) => params.skip_high_entropy = parse_entropy(entropy)?,
+            Some(("k", k)) => {
+                params.k = k
+                    .parse()
+                    .map_err(|_| LZJDError::from("Invalid digest size in header"))?
+            }
+            // Ignore settings added by later versions
+            _ => {}
+        }
+        Ok(params)
+    }))
+}
+
+/// Parses the entropy setting of digest files, which is whether high-entropy blocks are skipped
+fn parse_entropy(entropy: &str) -> Result<bool> {
+    match entropy {
+        "skip" => Ok(true),
+        "keep" => Ok(false),
+        _ => Err(LZJDError::Msg {
+            msg: format!("Unknown entropy setting '{}', expected 'skip' or 'keep'", entropy),
+        }
+        .into()),
+    }
+}
+
+/// Parses a digest line written in the sdhash format,
+/// returning None if line is in a different format
+fn parse_sdhash_line(line: &str) -> Option<Result<(HashAlgorithm, LZDict, String)>> {
+    let rest = line.strip_prefix("lzjd:03:")?;
+    let (name_len, rest) = rest.split_at(rest.find(':')?);
+    let name_len: usize = name_len.parse().ok()?;
+    let name = rest.get(1..name_len + 1)?;
+    let algorithm = rest.get(name_len + 2..)?.split(':').next()?;
+    let b64 = &rest[rest.rfind(':')? + 1..];
+    Some(
+        algorithm
+            .parse()
+            .and_then(|algorithm| Ok((algorithm, LZDict::from_encoded_string(b64)?, name.to_owned())))
+            .map_err(Error::from),
+    )
+}
+
+/// Fields of the JSON records of digests that are not part of their meta
+const JSON_DIGEST_FIELDS: [&str; 8] = ["name", "digest", "algorithm", "hash", "k", "sampling", "entropy", "high_entropy"];
+
+/// Parses a digest line written as a JSON object, like the records of `-f ndjson` or digests
+/// exported by other LZJD implementations or databases, returning None if line is not an object.
+/// Objects hold a name and a base64 digest, and optionally the hash algorithm as algorithm
+/// or hash, the digest size as k and the sampling, which default to the default settings.
+/// Other fields with string values are kept as the meta of the record.
+fn parse_json_line(line: &str) -> Option<Result<(DigestParams, DigestRecord)>> {
+    if !line.starts_with('{') {
+        return None;
+    }
+    let invalid = |problem: &str| LZJDError::Msg {
+        msg: format!("Invalid JSON digest ({}): {}", problem, line),
+    };
+    let parse = || -> Result<(DigestParams, DigestRecord)> {
+        let record: serde_json::Value = serde_json::from_str(line).map_err(|e| invalid(&e.to_string()))?;
+        let field = |name| record.get(name).and_then(serde_json::Value::as_str);
+        let name = field("name").ok_or_else(|| invalid("no name"))?;
+        let digest = field("digest").ok_or_else(|| invalid("no digest"))?;
+        let mut params = DigestParams::default();
+        if let Some(algorithm) = field("algorithm").or_else(|| field("hash")) {
+            params.algorithm = algorithm.parse()?;
+        }
+        if let Some(k) = record.get("k") {
+            params.k = match k.as_u64() {
+                Some(k) if k > 0 => usize::try_from(k).map_err(|_| invalid("k is too large"))?,
+                _ => return Err(invalid("k is not a positive integer").into()),
+            };
+        }
+        if let Some(sampling) = field("sampling") {
+            params.sampling = sampling.parse()?;
+        }
+        if let Some(entropy) = field("entropy") {
+            params.skip_high_entropy = parse_entropy(entropy)?;
+        }
+        let record = DigestRecord {
+            meta: record
+                .as_object()
+                .into_iter()
+                .flatten()
+                .filter(|(key, _)| !JSON_DIGEST_FIELDS.contains(&key.as_str()))
+                .filter_map(|(key, value)| Some((key.to_owned(), value.as_str()?.to_owned())))
+                .collect(),
+            ..DigestRecord::new(name, LZDict::from_encoded_string(digest)?)
+        };
+        Ok((params, record))
+    };
+    Some(parse())
+}
+
+/// Perform comparisons of the given digests lists. If each list points to
+/// the same object, only the above-diagonal elements of the comparison
+/// matrix will be performed, unless only the top matches of each digest
+/// are requested
+fn compare(
+    dicts_a: &[DigestRecord],
+    dicts_b: &[DigestRecord],
+    options: &Options,
+    writer: &mut dyn Write,
+) -> Result<()> {
+    let bar = progress_bar(options.progress, "comparing [{bar:40}] {pos}/{len} pairs, ETA {eta}");
+    let mut report = |progress| {
+        if let Progress::PairsCompared { done, total } = progress {
+            bar.set_length(total);
+            bar.set_position(done);
+        }
+    };
+    let compared = compare_with_progress(dicts_a, dicts_b, options, &mut report, writer);
+    bar.finish_and_clear();
+    compared
+}
+
+/// Like compare, but reports progress to report. Unless the matches are sorted or
+/// pretty-printed, they are written batch by batch as they are found, rather than
+/// held until all pairs are compared.
+fn compare_with_progress(
+    dicts_a: &[DigestRecord],
+    dicts_b: &[DigestRecord],
+    options: &Options,
+    report: &mut (dyn FnMut(Progress) + Send),
+    writer: &mut dyn Write,
+) -> Result<()> {
+    let min_similarity = options.metric.min_similarity(options.threshold);
+    let start = Instant::now();
+    let mut stream = if options.sort.is_some() || options.format == Format::Pretty {
+        None
+    } else {
+        Some(MatchStream::new(options, writer))
+    };
+    // Matches of all pairs, if they can not be written as they are found
+    let mut held = vec![];
+    let mut num_matches = 0;
+    let mut seen = HashSet::new();
+    // Error of writing a batch, which stops the comparison
+    let mut failed = None;
+    let compared = compare_digests(dicts_a, dicts_b, min_similarity, options.top, Some(report), &mut |matches| {
+        let matches = options.pairs.apply(matches, &mut seen);
+        num_matches += matches.len();
+        match &mut stream {
+            Some(stream) => stream.write(&matches).map_err(|err| {
+                failed = Some(err);
+                LZJDError::Cancelled
+            }),
+            None => {
+                held.extend(matches);
+                Ok(())
+            }
+        }
+    });
+    if let Some(err) = failed {
+        return Err(err);
+    }
+    compared?;
+    debug!(
+        "Compared {} with {} digests in {:.2?}, {} matches",
+        dicts_a.len(),
+        dicts_b.len(),
+        start.elapsed(),
+        num_matches
+    );
+
+    match stream {
+        Some(stream) => stream.finish(),
+        None => write_matches(&held, options, writer),
+    }
+}
+
+/// Writes the best matches among blacklist of each of files that meet the threshold of options,
+/// the number of them given by options or else one. Exits with OUTCOME_EXIT_CODE if there
+/// are any, so that scheduled scans can raise an alert.
+fn scan(
+    files: &[DigestRecord],
+    blacklist: &[DigestRecord],
+    options: &Options,
+    writer: &mut dyn Write,
+) -> Result<()> {
+    let bar = progress_bar(options.progress, "comparing [{bar:40}] {pos}/{len} pairs, ETA {eta}");
+    let mut report = |progress| {
+        if let Progress::PairsCompared { done, total } = progress {
+            bar.set_length(total);
+            bar.set_position(done);
+        }
+    };
+    let min_similarity = options.metric.min_similarity(options.threshold);
+    let top = options.top.unwrap_or(1);
+    let matches = compare_top(files, blacklist, min_similarity, top, None, Some(&mut report))?;
+    bar.finish_and_clear();
+
+    let flagged = matches.iter().map(|m| &m.a).collect::<HashSet<_>>().len();
+    info!(
+        "Scanned {} files against {} known-bad digests, found {} similar files",
+        files.len(),
+        blacklist.len(),
+        flagged
+    );
+    write_matches(&matches, options, writer)
+}
+
+/// Clusters the digests of which labels holds the expected group, like cluster does, and logs
+/// the homogeneity and completeness of the clusters versus the expected groups, and the
+/// V-measure, their harmonic mean. Digests without an expected group are left out.
+fn report_clustering(dicts: &[DigestRecord], labels: &HashMap<String, String>, options: &Options) -> Result<()> {
+    let (labeled, groups): (Vec<_>, Vec<&str>) = dicts
+        .iter()
+        .filter_map(|record| Some((record.clone(), labels.get(&record.label)?.as_str())))
+        .unzip();
+    if labeled.len() < dicts.len() {
+        warn!(
+            "{} of {} digests have no group in the labels file and are left out of the clustering report",
+            dicts.len() - labeled.len(),
+            dicts.len()
+        );
+    }
+
+    let min_similarity = options.metric.min_similarity(options.threshold);
+    let pairs = similar_pairs(&labeled, min_similarity, None, None)?;
+    let mut clusters = vec![0; labeled.len()];
+    for (cluster, members) in connected_components(labeled.len(), pairs.iter().map(|&(i, j, _)| (i, j)))
+        .iter()
+        .enumerate()
+    {
+        members.iter().for_each(|&i| clusters[i] = cluster);
+    }
+    let cluster_count = clusters.iter().collect::<HashSet<_>>().len();
+    let group_count = groups.iter().collect::<HashSet<_>>().len();
+
+    let (homogeneity, completeness) = homogeneity_completeness(&groups, &clusters);
+    let v_measure = if homogeneity + completeness > 0. {
+        2. * homogeneity * completeness / (homogeneity + completeness)
+    } else {
+        0.
+    };
+    info!(
+        "{} clusters of {} digests in {} groups at threshold {}: homogeneity {:.3}, completeness {:.3}, V-measure {:.3}",
+        cluster_count,
+        labeled.len(),
+        group_count,
+        options.threshold,
+        homogeneity,
+        completeness,
+        v_measure
+    );
+    Ok(())
+}
+
+/// Groups the digests of which the similarity meets the threshold of options,
+/// directly or through other digests, and writes the groups to writer,
+/// largest first. Digests that are in no group are only written if singletons is set.
+fn cluster(
+    dicts: &[DigestRecord],
+    singletons: bool,
+    options: &Options,
+    writer: &mut dyn Write,
+) -> Result<()> {
+    let bar = progress_bar(options.progress, "comparing [{bar:40}] {pos}/{len} pairs, ETA {eta}");
+    let mut report = |progress| {
+        if let Progress::PairsCompared { done, total } = progress {
+            bar.set_length(total);
+            bar.set_position(done);
+        }
+    };
+    let min_similarity = options.metric.min_similarity(options.threshold);
+    let pairs = similar_pairs(dicts, min_similarity, None, Some(&mut report))?;
+    bar.finish_and_clear();
+
+    let groups = connected_components(dicts.len(), pairs.iter().map(|&(i, j, _)| (i, j)));
+    let groups = groups
+        .iter()
+        .filter(|group| singletons || group.len() > 1)
+        .map(|group| group.iter().map(|&i| dicts[i].label.as_str()).collect::<Vec<_>>());
+    match options.format {
+        #[cfg(feature = "arrow")]
+        Format::Parquet => return Err(LZJDError::from(PARQUET_UNSUPPORTED).into()),
+        // One block of labels per group, separated by empty lines
+        Format::Text | Format::Sdhash | Format::Pretty => groups.enumerate().try_for_each(|(n, group)| {
+            if n > 0 {
+                writer.write_all(b"\n")?;
+            }
+            group
+                .iter()
+                .try_for_each(|label| writer.write_fmt(format_args!("{}\n", label)))
+        })?,
+        Format::Json | Format::Ndjson => {
+            let records = groups
+                .enumerate()
+                .map(|(n, group)| json!({ "group": n + 1, "members": group }));
+            write_json(records, options.format, writer)?;
+        }
+        Format::Csv => {
+            writer.write_all(b"group,name\r\n")?;
+            groups.enumerate().try_for_each(|(n, group)| {
+                group
+                    .iter()
+                    .try_for_each(|label| writer.write_fmt(format_args!("{},{}\r\n", n + 1, csv_field(label))))
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Assigns the digests in the files at paths, or on stdin for STDIN_PATH, to clusters as
+/// they are read, see StreamingClusters, and writes the cluster of each digest to writer
+/// right away, numbering clusters from 1. JSON is written as one object per line too, as
+/// an array could only be written once the input ends.
+fn cluster_online(paths: &[PathBuf], options: &Options, writer: &mut dyn Write) -> Result<()> {
+    #[cfg(feature = "arrow")]
+    if options.format == Format::Parquet {
+        return Err(LZJDError::from(PARQUET_UNSUPPORTED).into());
+    }
+    if options.format == Format::Csv {
+        writer.write_fmt(format_args!("name,cluster,{}\r\n", options.metric.name()))?;
+    }
+    let min_similarity = options.metric.min_similarity(options.threshold);
+    // Created with the settings of the first digest, which the other digests must share
+    let mut clusters: Option<StreamingClusters> = None;
+    let mut params: Option<DigestParams> = None;
+    for path in paths {
+        let mut lines = if path == Path::new(STDIN_PATH) {
+            DigestLines::new(path, Box::new(io::stdin().lock()), options.error_policy)
+        } else {
+            DigestLines::open(path, 0, options.error_policy)?
+        };
+        lines.params = params;
+        while let Some(record) = lines.next_digest()? {
+            let clusters = clusters.get_or_insert_with(|| {
+                let params = lines.params.unwrap_or_default();
+                StreamingClusters::new(params.algorithm, params.k, min_similarity)
+            });
+            let assignment = clusters.assign(record.label.as_str(), record.dict);
+            let cluster = assignment.cluster + 1;
+            let score = options.metric.percentage(assignment.similarity);
+            match options.format {
+                Format::Text | Format::Sdhash | Format::Pretty => {
+                    writer.write_fmt(format_args!("{}|{}|{:03}\n", record.label, cluster, score))?
+                }
+                Format::Json | Format::Ndjson => {
+                    let record = json!({
+                        "name": record.label,
+                        "cluster": cluster,
+                        options.metric.name(): options.metric.score(assignment.similarity),
+                        "new": assignment.new,
+                    });
+                    write_json(Some(record).into_iter(), Format::Ndjson, writer)?;
+                }
+                Format::Csv => writer.write_fmt(format_args!("{},{},{}\r\n", csv_field(&record.label), cluster, score))?,
+                #[cfg(feature = "arrow")]
+                Format::Parquet => unreachable!(),
+            }
+            // Consumers of pipelines act on each digest as it comes
+            writer.flush()?;
+        }
+        lines.report_skipped();
+        params = lines.params;
+    }
+    if let Some(clusters) = clusters {
+        info!("Assigned digests to {} clusters", clusters.len());
+    }
+    Ok(())
+}
+
+/// Groups the files of which the similarity meets the threshold of options, directly
+/// or through other files, and writes the groups to writer along with the sizes of the
+/// files. The largest file of each group is suggested to be kept, and the similarity of
+/// the others to it is included. The total size of the other files is reported on stderr.
+fn dedupe(digests: &[(PathBuf, LZDict)], options: &Options, writer: &mut dyn Write) -> Result<()> {
+    let dicts: Vec<DigestRecord> = digests
+        .iter()
+        .map(|(path, dict)| DigestRecord::new(path_to_label(path), dict.clone()))
+        .collect();
+    let sizes = digests
+        .iter()
+        .map(|(path, _)| Ok(fs::metadata(path)?.len()))
+        .collect::<Result<Vec<u64>>>()?;
+
+    let min_similarity = options.metric.min_similarity(options.threshold);
+    let pairs = similar_pairs(&dicts, min_similarity, None, None)?;
+    let groups: Vec<Vec<usize>> = connected_components(dicts.len(), pairs.iter().map(|&(i, j, _)| (i, j)))
+        .into_iter()
+        .filter(|group| group.len() > 1)
+        .map(|mut group| {
+            // Keep the largest file, or the first of the largest files
+            let keeper = (0..group.len()).rev().max_by_key(|&n| sizes[group[n]]).unwrap();
+            let keeper = group.remove(keeper);
+            group.insert(0, keeper);
+            group
+        })
+        .collect();
+
+    let metric = options.metric;
+    let similarity = |i: usize, keeper: usize| dicts[i].dict.similarity(&dicts[keeper].dict);
+    match options.format {
+        #[cfg(feature = "arrow")]
+        Format::Parquet => return Err(LZJDError::from(PARQUET_UNSUPPORTED).into()),
+        // One block per group, starting with the file to keep
+        Format::Text | Format::Sdhash | Format::Pretty => groups.iter().enumerate().try_for_each(|(n, group)| {
+            if n > 0 {
+                writer.write_all(b"\n")?;
+            }
+            writer.write_fmt(format_args!("keep|{}|{}\n", sizes[group[0]], dicts[group[0]].label))?;
+            group[1..].iter().try_for_each(|&i| {
+                writer.write_fmt(format_args!(
+                    "duplicate|{}|{}|{:03}\n",
+                    sizes[i],
+                    dicts[i].label,
+                    metric.percentage(similarity(i, group[0]))
+                ))
+            })
+        })?,
+        Format::Json | Format::Ndjson => {
+            let records = groups.iter().enumerate().map(|(n, group)| {
+                let keeper = group[0];
+                let duplicates: Vec<_> = group[1..]
+                    .iter()
+                    .map(|&i| json!({ "name": dicts[i].label, "size": sizes[i], metric.name(): metric.score(similarity(i, keeper)) }))
+                    .collect();
+                json!({
+                    "group": n + 1,
+                    "keep": { "name": dicts[keeper].label, "size": sizes[keeper] },
+                    "duplicates": duplicates,
+                })
+            });
+            write_json(records, options.format, writer)?;
+        }
+        Format::Csv => {
+            writer.write_fmt(format_args!("group,name,size,keep,{}\r\n", metric.name()))?;
+            groups.iter().enumerate().try_for_each(|(n, group)| {
+                group.iter().try_for_each(|&i| {
+                    writer.write_fmt(format_args!(
+                        "{},{},{},{},{}\r\n",
+                        n + 1,
+                        csv_field(&dicts[i].label),
+                        sizes[i],
+                        i == group[0],
+                        metric.percentage(similarity(i, group[0]))
+                    ))
+                })
+            })?;
+        }
+    }
+
+    let duplicates = groups.iter().flat_map(|group| &group[1..]);
+    info!(
+        "{} groups, {} duplicate files taking {}",
+        groups.len(),
+        duplicates.clone().count(),
+        HumanBytes(duplicates.map(|&i| sizes[i]).sum())
+    );
+    Ok(())
+}
+
+/// Writes the scores of all pairs of digests to writer as a dense matrix, in CSV unless
+/// the format of options is JSON, in which case a single object is written. With phylip,
+/// distances are written in the relaxed PHYLIP format instead, regardless of the metric.
+fn matrix(dicts: &[DigestRecord], phylip: bool, options: &Options, writer: &mut dyn Write) -> Result<()> {
+    let bar = progress_bar(options.progress, "comparing [{bar:40}] {pos}/{len} pairs, ETA {eta}");
+    let mut report = |progress| {
+        if let Progress::PairsCompared { done, total } = progress {
+            bar.set_length(total);
+            bar.set_position(done);
+        }
+    };
+    let similarities = similarity_matrix(dicts, None, Some(&mut report))?;
+    bar.finish_and_clear();
+
+    let metric = if phylip { Metric::Distance } else { options.metric };
+    let rows = dicts.iter().zip(&similarities).map(|(DigestRecord { label, .. }, row)| {
+        let scores: Vec<f64> = row.iter().map(|&similarity| metric.score(similarity)).collect();
+        (label, scores)
+    });
+    if phylip {
+        writer.write_fmt(format_args!("{}\n", dicts.len()))?;
+        for (label, scores) in rows {
+            // Names can not contain whitespace in PHYLIP files
+            let name: String = label
+                .chars()
+                .map(|c| if c.is_whitespace() { '_' } else { c })
+                .collect();
+            writer.write_all(name.as_bytes())?;
+            scores
+                .iter()
+                .try_for_each(|score| writer.write_fmt(format_args!(" {:.6}", score)))?;
+            writer.write_all(b"\n")?;
+        }
+        return Ok(());
+    }
+    match options.format {
+        Format::Json | Format::Ndjson => {
+            let (labels, scores): (Vec<_>, Vec<_>) = rows.unzip();
+            let record = json!({ "labels": labels, metric.name(): scores });
+            writer.write_fmt(format_args!("{}\n", record))?;
+        }
+        #[cfg(feature = "arrow")]
+        Format::Parquet => return Err(LZJDError::from(PARQUET_UNSUPPORTED).into()),
+        Format::Text | Format::Csv | Format::Sdhash | Format::Pretty => {
+            writer.write_all(b"name")?;
+            dicts
+                .iter()
+                .try_for_each(|record| writer.write_fmt(format_args!(",{}", csv_field(&record.label))))?;
+            writer.write_all(b"\r\n")?;
+            for (label, scores) in rows {
+                writer.write_all(csv_field(label).as_bytes())?;
+                scores
+                    .iter()
+                    .try_for_each(|score| writer.write_fmt(format_args!(",{:.6}", score)))?;
+                writer.write_all(b"\r\n")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes the number of hashes and estimated number of LZ phrases of the digests, and a
+/// histogram of the similarities of a sample of their pairs to writer, as text unless
+/// the format of options is JSON, in which case a single object is written.
+/// With per_digest, the numbers of every digest are written as well.
+fn stats(
+    dicts: &[DigestRecord],
+    sample: usize,
+    bins: usize,
+    per_digest: bool,
+    options: &Options,
+    writer: &mut dyn Write,
+) -> Result<()> {
+    const FRACTIONS: [f64; 5] = [0., 0.25, 0.5, 0.75, 1.];
+    let DigestParams { algorithm, k, .. } = options.params;
+
+    let phrases: Vec<f64> = dicts.iter().map(|record| estimated_phrases(&record.dict, k)).collect();
+    let saturated = dicts.iter().filter(|record| record.dict.len() >= k).count();
+    let sorted = |values: Vec<f64>| {
+        let mut values = values;
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        values
+    };
+    let entry_quantiles = quantiles(
+        &sorted(dicts.iter().map(|record| record.dict.len() as f64).collect()),
+        &FRACTIONS,
+    );
+    let phrase_quantiles = quantiles(&sorted(phrases.clone()), &FRACTIONS);
+
+    let pairs = sample_pairs(dicts.len(), sample, 0);
+    let bar = progress_bar(options.progress, "comparing [{bar:40}] {pos}/{len} pairs, ETA {eta}");
+    let mut report = |progress| {
+        if let Progress::PairsCompared { done, total } = progress {
+            bar.set_length(total);
+            bar.set_position(done);
+        }
+    };
+    let similarities = compare_pairs(dicts, &pairs, None, Some(&mut report))?;
+    bar.finish_and_clear();
+    let counts = histogram(similarities.iter().cloned(), bins);
+    let total_pairs = dicts.len() * dicts.len().saturating_sub(1) / 2;
+
+    if let Format::Json | Format::Ndjson = options.format {
+        let mut record = json!({
+            "algorithm": algorithm.name(),
+            "k": k,
+            "digests": dicts.len(),
+            "saturated": saturated,
+            "entries": entry_quantiles,
+            "phrases": phrase_quantiles,
+            "pairs": total_pairs,
+            "sampled_pairs": pairs.len(),
+            "histogram": counts,
+        });
+        if per_digest {
+            record["per_digest"] = dicts
+                .iter()
+                .zip(&phrases)
+                .map(|(DigestRecord { dict, label, .. }, phrases)| {
+                    json!({ "label": label, "entries": dict.len(), "phrases": phrases })
+                })
+                .collect();
+        }
+        writer.write_fmt(format_args!("{}\n", record))?;
+        return Ok(());
+    }
+
+    if per_digest {
+        for (DigestRecord { dict, label, .. }, phrases) in dicts.iter().zip(&phrases) {
+            writer.write_fmt(format_args!("{}|{}|{:.0}\n", label, dict.len(), phrases))?;
+        }
+        writer.write_all(b"\n")?;
+    }
+    writer.write_fmt(format_args!(
+        "{} digests, hash algorithm {}, digest size {}\n",
+        dicts.len(),
+        algorithm,
+        k
+    ))?;
+    if dicts.is_empty() {
+        return Ok(());
+    }
+    writer.write_fmt(format_args!(
+        "{} digests ({:.1}%) are full, so their inputs were larger than the digest size\n",
+        saturated,
+        100. * saturated as f64 / dicts.len() as f64
+    ))?;
+    for (name, values) in &[("hashes per digest", entry_quantiles), ("estimated LZ phrases", phrase_quantiles)] {
+        if let Some(values) = values {
+            writer.write_fmt(format_args!(
+                "{}: min {:.0}, 25% {:.0}, median {:.0}, 75% {:.0}, max {:.0}\n",
+                name, values[0], values[1], values[2], values[3], values[4]
+            ))?;
+        }
+    }
+    writer.write_fmt(format_args!(
+        "similarity of {} of {} pairs:\n",
+        pairs.len(),
+        total_pairs
+    ))?;
+    let largest = counts.iter().cloned().max().unwrap_or(0).max(1);
+    for (bin, count) in counts.iter().enumerate() {
+        let line = format!(
+            "{:.2}-{:.2} {:>8} {}",
+            bin as f64 / bins as f64,
+            (bin + 1) as f64 / bins as f64,
+            count,
+            "#".repeat(count * 40 / largest)
+        );
+        writer.write_fmt(format_args!("{}\n", line.trim_end()))?;
+    }
+    Ok(())
+}
+
+/// Reads the family of every label from the CSV file at path, of which each line holds
+/// a label and a family separated by the last comma. Fields may be quoted, empty lines
+/// and lines starting with # are skipped, as is a first line of "label,family".
+fn read_labels(path: &Path) -> Result<HashMap<String, String>> {
+    let unquote = |field: &str| {
+        let field = field.trim();
+        match field.strip_prefix('"').and_then(|field| field.strip_suffix('"')) {
+            Some(field) => field.replace("\"\"", "\""),
+            None => field.to_owned(),
+        }
+    };
+    let mut labels = HashMap::new();
+    for (n, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (label, family) = match line.rsplit_once(',') {
+            Some((label, family)) => (unquote(label), unquote(family)),
+            None => {
+                return Err(LZJDError::Msg {
+                    msg: format!("Expected a label and a family on line {} of {}", n + 1, path.display()),
+                }
+                .into())
+            }
+        };
+        if n == 0 && label == "label" && family == "family" {
+            continue;
+        }
+        labels.insert(label, family);
+    }
+    Ok(labels)
+}
+
+/// Compares all pairs of the digests of which labels holds the family, and writes how well
+/// their similarities separate pairs of the same family from pairs of different families
+/// to writer: the quantiles of both, the area under the ROC curve, the threshold that
+/// separates them best, and the lowest threshold at which at most max_false_positives
+/// of the pairs of different families match. Text is written unless the format of options
+/// is JSON, in which case a single object is written. If roc_path is given, the ROC curve
+/// is written to it as CSV. Pairs of empty digests have no similarity and are left out.
+fn eval(
+    dicts: Vec<DigestRecord>,
+    labels: &HashMap<String, String>,
+    max_false_positives: f64,
+    roc_path: Option<&Path>,
+    options: &Options,
+    writer: &mut dyn Write,
+) -> Result<()> {
+    const FRACTIONS: [f64; 5] = [0., 0.25, 0.5, 0.75, 1.];
+    let DigestParams { algorithm, k, .. } = options.params;
+
+    let total = dicts.len();
+    let (dicts, families): (Vec<_>, Vec<&str>) = dicts
+        .into_iter()
+        .filter_map(|record| {
+            let family = labels.get(&record.label)?;
+            Some((record, family.as_str()))
+        })
+        .unzip();
+    if dicts.len() < total {
+        warn!(
+            "{} of {} digests have no family in the labels file and are left out",
+            total - dicts.len(),
+            total
+        );
+    }
+
+    let bar = progress_bar(options.progress, "comparing [{bar:40}] {pos}/{len} pairs, ETA {eta}");
+    let mut report = |progress| {
+        if let Progress::PairsCompared { done, total } = progress {
+            bar.set_length(total);
+            bar.set_position(done);
+        }
+    };
+    let pairs = similar_pairs(&dicts, 0., None, Some(&mut report))?;
+    bar.finish_and_clear();
+    let (intra, inter): (Vec<_>, Vec<_>) = pairs
+        .into_iter()
+        .partition(|&(i, j, _)| families[i] == families[j]);
+    let sorted = |pairs: Vec<(usize, usize, f64)>| {
+        let mut similarities: Vec<f64> = pairs.into_iter().map(|(_, _, similarity)| similarity).collect();
+        similarities.sort_by(|a, b| a.total_cmp(b));
+        similarities
+    };
+    let (intra, inter) = (sorted(intra), sorted(inter));
+    if intra.is_empty() || inter.is_empty() {
+        return Err(LZJDError::from(
+            "Evaluating requires pairs of digests of the same family and pairs of different families",
+        )
+        .into());
+    }
+
+    let curve = roc(&intra, &inter);
+    if let Some(path) = roc_path {
+        let mut roc_writer = BufWriter::new(File::create(path)?);
+        roc_writer.write_all(b"threshold,true_positive_rate,false_positive_rate\n")?;
+        for (threshold, true_positive_rate, false_positive_rate) in &curve {
+            roc_writer.write_fmt(format_args!(
+                "{},{},{}\n",
+                threshold, true_positive_rate, false_positive_rate
+            ))?;
+        }
+        roc_writer.flush()?;
+    }
+    let area = auc(&curve);
+    let best = best_threshold(&curve);
+    let at_max_false_positives = threshold_at_false_positive_rate(&curve, max_false_positives);
+    let family_count = families.iter().collect::<HashSet<_>>().len();
+
+    if let Format::Json | Format::Ndjson = options.format {
+        let point = |point: Option<(f64, f64, f64)>| {
+            point.map(|(threshold, true_positive_rate, false_positive_rate)| {
+                json!({
+                    "threshold": threshold,
+                    "true_positive_rate": true_positive_rate,
+                    "false_positive_rate": false_positive_rate,
+                })
+            })
+        };
+        let record = json!({
+            "algorithm": algorithm.name(),
+            "k": k,
+            "digests": dicts.len(),
+            "families": family_count,
+            "intra_family": { "pairs": intra.len(), "similarity": quantiles(&intra, &FRACTIONS) },
+            "inter_family": { "pairs": inter.len(), "similarity": quantiles(&inter, &FRACTIONS) },
+            "auc": area,
+            "best_threshold": point(best),
+            "max_false_positive_rate": max_false_positives,
+            "threshold_at_max_false_positive_rate": point(at_max_false_positives),
+        });
+        writer.write_fmt(format_args!("{}\n", record))?;
+        return Ok(());
+    }
+
+    writer.write_fmt(format_args!(
+        "{} digests of {} families, hash algorithm {}, digest size {}\n",
+        dicts.len(),
+        family_count,
+        algorithm,
+        k
+    ))?;
+    for (name, similarities) in &[("intra-family", &intra), ("inter-family", &inter)] {
+        if let Some(values) = quantiles(similarities, &FRACTIONS) {
+            writer.write_fmt(format_args!(
+                "{} similarity of {} pairs: min {:.3}, 25% {:.3}, median {:.3}, 75% {:.3}, max {:.3}\n",
+                name,
+                similarities.len(),
+                values[0],
+                values[1],
+                values[2],
+                values[3],
+                values[4]
+            ))?;
+        }
+    }
+    writer.write_fmt(format_args!("area under the ROC curve: {:.4}\n", area))?;
+    let describe = |(threshold, true_positive_rate, false_positive_rate): (f64, f64, f64)| {
+        format!(
+            "{:.3}, matching {:.1}% of intra-family and {:.1}% of inter-family pairs",
+            threshold,
+            100. * true_positive_rate,
+            100. * false_positive_rate
+        )
+    };
+    if let Some(best) = best {
+        writer.write_fmt(format_args!("best threshold: {}\n", describe(best)))?;
+    }
+    match at_max_false_positives {
+        Some(point) => writer.write_fmt(format_args!(
+            "threshold at {}% inter-family matches: {}\n",
+            100. * max_false_positives,
+            describe(point)
+        ))?,
+        None => writer.write_fmt(format_args!(
+            "no threshold matches at most {}% of inter-family pairs\n",
+            100. * max_false_positives
+        ))?,
+    }
+    Ok(())
+}
+
+/// Reads pairs of names from the file at path, or from stdin if it is STDIN_PATH.
+/// Names are separated by a tab, or by | if the line has no tab, so that
+/// matches written by lzjd can be compared again. Empty lines are skipped.
+fn read_pairs(path: &Path) -> Result<Vec<(String, String)>> {
+    let reader: Box<dyn BufRead> = if path == Path::new(STDIN_PATH) {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(File::open(path)?))
+    };
+    let mut pairs = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let separator = if line.contains('\t') { '\t' } else { '|' };
+        let mut names = line.split(separator);
+        match (names.next(), names.next()) {
+            (Some(a), Some(b)) => pairs.push((a.trim().to_owned(), b.trim().to_owned())),
+            _ => {
+                return Err(LZJDError::Msg {
+                    msg: format!("Expected two names on line '{}' of {}", line, path.display()),
+                }
+                .into())
+            }
+        }
+    }
+    Ok(pairs)
+}
+
+/// Compares the pairs of names in pairs. Names are looked up among the labels of dicts first,
+/// and otherwise taken as paths of files, which are digested with the settings of options.
+fn compare_named_pairs(
+    mut dicts: Vec<DigestRecord>,
+    pairs: &[(String, String)],
+    options: &Options,
+    writer: &mut dyn Write,
+) -> Result<()> {
+    // Index in dicts of each name, the first digest winning if labels are not unique
+    let mut indices: HashMap<String, usize> = HashMap::new();
+    for (i, record) in dicts.iter().enumerate() {
+        indices.entry(record.label.to_owned()).or_insert(i);
+    }
+
+    let mut files = vec![];
+    for name in pairs.iter().flat_map(|(a, b)| [a, b]) {
+        if !indices.contains_key(name) && !files.iter().any(|file: &PathBuf| file == Path::new(name)) {
+            if !Path::new(name).is_file() {
+                return Err(LZJDError::Msg {
+                    msg: format!("'{}' is neither the label of a digest nor a file", name),
+                }
+                .into());
+            }
+            files.push(PathBuf::from(name));
+        }
+    }
+    for (path, dict) in digest_paths(&files, options)? {
+        indices.insert(path.to_string_lossy().into_owned(), dicts.len());
+        dicts.push(DigestRecord::new(path.to_string_lossy(), dict));
+    }
+
+    // Files that failed to digest are skipped along with their pairs, as reported by digest_paths
+    let (named, indexed): (Vec<_>, Vec<_>) = pairs
+        .iter()
+        .filter_map(|(a, b)| Some(((a, b), (*indices.get(a)?, *indices.get(b)?))))
+        .unzip();
+    let bar = progress_bar(options.progress, "comparing [{bar:40}] {pos}/{len} pairs, ETA {eta}");
+    let mut report = |progress| {
+        if let Progress::PairsCompared { done, total } = progress {
+            bar.set_length(total);
+            bar.set_position(done);
+        }
+    };
+    let similarities = compare_pairs(&dicts, &indexed, None, Some(&mut report))?;
+    bar.finish_and_clear();
+
+    let min_similarity = options.metric.min_similarity(options.threshold);
+    let matches: Vec<Match> = named
+        .into_iter()
+        .zip(similarities)
+        .filter(|&(_, similarity)| similarity >= min_similarity)
+        .map(|((a, b), similarity)| Match { a: a.to_owned(), b: b.to_owned(), similarity })
+        .collect();
+    write_matches(&matches, options, writer)
+}
+
+/// Drops the entries of dicts that have the same label and digest as an earlier entry,
+/// or with unique_digests, the same digest regardless of the label.
+/// The number of entries dropped is reported on stderr.
+fn merge(dicts: Vec<DigestRecord>, unique_digests: bool) -> Vec<DigestRecord> {
+    let total = dicts.len();
+    let mut seen = HashSet::new();
+    let merged: Vec<_> = dicts
+        .into_iter()
+        .filter(|record| {
+            let label = if unique_digests { None } else { Some(record.label.to_owned()) };
+            seen.insert((record.dict.to_vec(), label))
+        })
+        .collect();
+    info!("Dropped {} of {} entries", total - merged.len(), total);
+    merged
+}
+
+/// Writes dicts, created with params, as a bundle to writer, along with an index of them.
+/// The settings are kept in the metadata of the bundle as a header of a digest file.
+fn export_bundle(dicts: Vec<DigestRecord>, params: DigestParams, writer: &mut dyn Write) -> Result<()> {
+    let mut index = DigestIndex::new(params.algorithm, params.k);
+    for record in dicts {
+        index.insert(record.label, record.dict);
+    }
+    let mut info = BundleInfo::new(&index, concat!("lzjd ", env!("CARGO_PKG_VERSION")));
+    info.extra.insert("lzjd".to_owned(), params.to_string());
+    write_bundle(&index, &info, writer)?;
+    info!("Bundled {} digests", info.digests);
+    Ok(())
+}
+
+/// Reads the bundle at path, or on stdin for STDIN_PATH, checking it against its
+/// checksums, and returns its digests along with the settings they were created with
+fn import_bundle(path: &Path) -> Result<(DigestParams, Vec<DigestRecord>)> {
+    let reader: Box<dyn Read> = if path == Path::new(STDIN_PATH) {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(File::open(path).map_err(|e| LZJDError::from(e).in_file(path))?))
+    };
+    let (info, index) = read_bundle(reader).map_err(|e| e.in_file(path))?;
+    let params = match info.extra.get("lzjd").and_then(|header| parse_header(header)) {
+        Some(params) => params?,
+        None => DigestParams { algorithm: info.algorithm, k: info.k, ..DigestParams::default() },
+    };
+    info!(
+        "Bundle of {} digests was created by {} at {} seconds since the Unix epoch",
+        info.digests, info.tool_version, info.created
+    );
+    let dicts = (0..index.len() as u32)
+        .map(|id| DigestRecord::new(index.label(id), index.dict(id).clone()))
+        .collect();
+    Ok((params, dicts))
+}
+
+/// Generate the set of digests and do the all pairs comparison at the same time.
+/// Digest the given list of files.
+/// A path of "-" is read from stdin, and its digest is labeled options.stdin_label.
+fn hash_files(paths: &[PathBuf], options: &Options) -> Result<Vec<DigestRecord>> {
+    let (stdin, files): (Vec<PathBuf>, Vec<PathBuf>) =
+        paths.iter().cloned().partition(|path| path == Path::new(STDIN_PATH));
+    if stdin.len() > 1 {
+        return Err(LZJDError::from("Can only read stdin once").into());
+    }
+
+    let mut dicts = vec![];
+    if !stdin.is_empty() {
+        let DigestParams { algorithm, k, sampling, skip_high_entropy } = options.params;
+        let max = match sampling {
+            Sampling::Full => u64::MAX,
+            Sampling::Head(max) => max,
+            Sampling::Spread(_) => {
+                return Err(LZJDError::from("--sampling spread can not read stdin").into());
+            }
+        };
+        let mut reader = EntropyReader::new(io::stdin().lock().take(max), skip_high_entropy);
+        let dict = algorithm
+            .digest_reader_with_k(&mut reader, k)?
+            .with_high_entropy_fraction(reader.high_entropy_fraction());
+        warn_high_entropy(iter::once((Cow::from(options.stdin_label), &dict)));
+        dicts.push(DigestRecord::new(options.stdin_label, dict));
+    }
+
+    #[cfg(feature = "s3")]
+    let (objects, files): (Vec<PathBuf>, Vec<PathBuf>) = files.into_iter().partition(|path| is_object_url(path));
+    #[cfg(feature = "http")]
+    let (urls, files): (Vec<PathBuf>, Vec<PathBuf>) = files.into_iter().partition(|path| is_http_url(path));
+    dicts.extend(
+        digest_paths(&files, options)?
+            .into_iter()
+            .map(|(path, dict)| DigestRecord::new(path_to_label(&path), dict)),
+    );
+    #[cfg(feature = "s3")]
+    for url in &objects {
+        dicts.append(&mut hash_objects(&url.to_string_lossy(), options)?);
+    }
+    #[cfg(feature = "http")]
+    dicts.append(&mut hash_downloads(&urls, options)?);
+    Ok(dicts)
+}
+
+/// Digests the responses to GET requests of urls in parallel as they are downloaded,
+/// labeling them with their URLs
+#[cfg(feature = "http")]
+fn hash_downloads(urls: &[PathBuf], options: &Options) -> Result<Vec<DigestRecord>> {
+    if urls.is_empty() {
+        return Ok(vec![]);
+    }
+    let downloader = Downloader::new(options.download_limit, options.download_timeout)?;
+    let DigestParams { algorithm, k, sampling, skip_high_entropy } = options.params;
+    let results: Vec<_> = urls
+        .par_iter()
+        .map(|url| {
+            let url = url.to_string_lossy();
+            let dict = downloader.digest(&url, algorithm, k, sampling, skip_high_entropy);
+            (url.into_owned(), dict)
+        })
+        .collect();
+    let mut dicts = vec![];
+    for (url, dict) in results {
+        match dict {
+            Ok(dict) => dicts.push(DigestRecord::new(url, dict)),
+            Err(err) if options.error_policy == ErrorPolicy::Skip => {
+                report_failures(&[(PathBuf::from(url), err)]);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    debug!("Downloaded {} of {} URLs", dicts.len(), urls.len());
+    warn_high_entropy(dicts.iter().map(|record| (Cow::from(record.label.as_str()), &record.dict)));
+    Ok(dicts)
+}
+
+/// Whether input is digested from elsewhere than the local file system, so it is not walked
+#[cfg_attr(not(any(feature = "s3", feature = "http")), allow(unused_variables))]
+fn is_remote_input(input: &Path) -> bool {
+    #[cfg(feature = "s3")]
+    {
+        if is_object_url(input) {
+            return true;
+        }
+    }
+    #[cfg(feature = "http")]
+    {
+        if is_http_url(input) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Digests the objects below the s3:// URL url as they are downloaded,
+/// labeling them with their URLs
+#[cfg(feature = "s3")]
+fn hash_objects(url: &str, options: &Options) -> Result<Vec<DigestRecord>> {
+    let bar = progress_bar(
+        options.progress,
+        "downloading [{bar:40}] {pos}/{len} objects, {msg}, ETA {eta}",
+    );
+    let mut report = |progress| {
+        if let Progress::FilesDigested { done, total, bytes } = progress {
+            bar.set_length(total);
+            bar.set_position(done);
+            bar.set_message(format!("{}", HumanBytes(bytes)));
+        }
+    };
+    let (store, prefix) = object::open_url(url)?;
+    let directory_options = DirectoryOptions {
+        error_policy: options.error_policy,
+        algorithm: options.params.algorithm,
+        digest_size: Some(options.params.k),
+        sampling: options.params.sampling,
+        skip_high_entropy: options.params.skip_high_entropy,
+        ..Default::default()
+    };
+    let concurrency = options.concurrency.unwrap_or(scan::CONCURRENCY);
+    let batch = scan::block_on(object::digest_prefix(&*store, &prefix, &directory_options, concurrency, Some(&mut report)))??;
+    bar.finish_and_clear();
+    report_failures(&batch.failures);
+    let bucket = url[S3_SCHEME.len()..].split('/').next().unwrap_or_default();
+    let dicts: Vec<_> = batch
+        .items
+        .into_iter()
+        .map(|(location, dict)| DigestRecord::new(format!("{}{}/{}", S3_SCHEME, bucket, location), dict))
+        .collect();
+    debug!("Digested {} objects in {}", dicts.len(), url);
+    warn_high_entropy(dicts.iter().map(|record| (Cow::from(record.label.as_str()), &record.dict)));
+    Ok(dicts)
+}
+
+/// Digests each input directory of groups as a whole, by merging the digests of the files
+/// found in it, so that the digest holds the smallest hashes of the phrases of all of them.
+/// Inputs that are not directories are digested as they are.
+fn hash_merged_dirs(groups: &[(PathBuf, Vec<PathBuf>)], options: &Options) -> Result<Vec<DigestRecord>> {
+    let mut dicts = vec![];
+    for (input, paths) in groups {
+        if !input.is_dir() {
+            dicts.append(&mut hash_files(paths, options)?);
+            continue;
+        }
+        let digests = digest_paths(paths, options)?;
+        debug!("Merging the digests of {} files in {}", digests.len(), path_to_label(input));
+        let dict = LZDict::merge(digests.iter().map(|(_, dict)| dict), options.params.k);
+        dicts.push(DigestRecord::new(path_to_label(input), dict));
+    }
+    Ok(dicts)
+}
+
+/// Warns about the digests that are dominated by high-entropy input, like compressed or
+/// encrypted data, of which similarities are mostly noise, naming them at debug level
+fn warn_high_entropy<'a, I: IntoIterator<Item = (Cow<'a, str>, &'a LZDict)>>(dicts: I) {
+    let dominated: Vec<Cow<str>> = dicts
+        .into_iter()
+        .filter(|(_, dict)| dict.high_entropy_fraction().is_some_and(|fraction| fraction > DOMINANT_FRACTION))
+        .map(|(label, _)| label)
+        .collect();
+    if dominated.is_empty() {
+        return;
+    }
+    warn!(
+        "Found {} digests of mostly high-entropy data, like compressed or encrypted data, of which the similarities are not meaningful",
+        dominated.len()
+    );
+    for label in dominated {
+        debug!("Mostly high-entropy data: {}", label);
+    }
+}
+
+/// Digests the devices or disk images at paths, reading sectors that can not be read
+/// as zeros, and showing the offset up to which each was read if progress is enabled
+fn hash_raw(paths: &[PathBuf], options: &Options) -> Result<Vec<DigestRecord>> {
+    let mut dicts = vec![];
+    for path in paths {
+        if path == Path::new(STDIN_PATH) {
+            return Err(LZJDError::from("--raw can not read stdin").into());
+        }
+        let label = path_to_label(path).into_owned();
+        let bar = progress_bar(
+            options.progress,
+            "reading {msg} [{bar:40}] {bytes}/{total_bytes}, {bytes_per_sec}, ETA {eta}",
+        );
+        bar.set_message(label.clone());
+        let mut report = |progress| {
+            if let Progress::BytesProcessed(offset) = progress {
+                bar.set_position(offset);
+            }
+        };
+
+        let start = Instant::now();
+        let reader = match File::open(path).and_then(RawReader::new) {
+            Ok(reader) => reader,
+            Err(err) if options.error_policy == ErrorPolicy::Skip => {
+                report_failures(&[(path.clone(), err.into())]);
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        bar.set_length(reader.len());
+        let mut reader = reader.with_progress(&mut report);
+        let dict = options
+            .params
+            .algorithm
+            .digest_reader_with_k(&mut reader, options.params.k)?;
+        if !reader.bad_sectors().is_empty() {
+            warn!(
+                "Read {} unreadable sectors of {} as zeros",
+                reader.bad_sectors().len(),
+                label
+            );
+        }
+        debug!("Read {} bytes of {} in {:.2?}", reader.position(), label, start.elapsed());
+        drop(reader);
+        bar.finish_and_clear();
+        dicts.push(DigestRecord::new(label, dict));
+    }
+    Ok(dicts)
+}
+
+/// Digests each line of the files at paths, of which a path of "-" is read from stdin.
+/// Digests are labeled by the label of their file, or by label for stdin if given,
+/// followed by a colon and the line number.
+fn hash_lines(paths: &[PathBuf], label: Option<&str>, options: &Options) -> Result<Vec<DigestRecord>> {
+    if paths.iter().filter(|path| *path == Path::new(STDIN_PATH)).count() > 1 {
+        return Err(LZJDError::from("Can only read stdin once").into());
+    }
+
+    let mut dicts = vec![];
+    for path in paths {
+        let (prefix, digests) = if path == Path::new(STDIN_PATH) {
+            let digests = digest_lines(io::stdin().lock(), options.params.algorithm, options.params.k);
+            (label.map_or(Cow::Borrowed(STDIN_PATH), Cow::Borrowed), digests)
+        } else {
+            let digests = File::open(path)
+                .map_err(LZJDError::from)
+                .and_then(|file| digest_lines(BufReader::new(file), options.params.algorithm, options.params.k));
+            (path_to_label(path), digests)
+        };
+        let digests = match digests {
+            Ok(digests) => digests,
+            Err(err) if options.error_policy == ErrorPolicy::Skip => {
+                report_failures(&[(path.clone(), err)]);
+                continue;
+            }
+            Err(err) => return Err(err.in_file(path).into()),
+        };
+        debug!("Digested {} lines of {}", digests.len(), prefix);
+        dicts.extend(digests.into_iter().map(|(line, dict)| DigestRecord::new(format!("{}:{}", prefix, line), dict)));
+    }
+    Ok(dicts)
+}
+
+/// Digests the files in the archives at paths, of which a path of "-" is read from stdin.
+/// Digests are labeled by the path of the file within its archive, prefixed by the
+/// archive's label, or by label for stdin if given.
+#[cfg(feature = "archive")]
+fn hash_archives(
+    paths: &[PathBuf],
+    format: ArchiveFormat,
+    label: Option<&str>,
+    options: &Options,
+) -> Result<Vec<DigestRecord>> {
+    if paths.iter().filter(|path| *path == Path::new(STDIN_PATH)).count() > 1 {
+        return Err(LZJDError::from("Can only read stdin once").into());
+    }
+
+    let mut dicts = vec![];
+    for path in paths {
+        let (prefix, digests) = if path == Path::new(STDIN_PATH) {
+            let digests = digest_archive(
+                io::stdin().lock(),
+                format,
+                options.params.algorithm,
+                options.params.k,
+                &options.filter,
+            );
+            (label.map(str::to_owned), digests)
+        } else {
+            let digests = File::open(path).map_err(LZJDError::from).and_then(|file| {
+                digest_archive(
+                    BufReader::new(file),
+                    format,
+                    options.params.algorithm,
+                    options.params.k,
+                    &options.filter,
+                )
+            });
+            (Some(path_to_label(path).into_owned()), digests)
+        };
+        let digests = match digests {
+            Ok(digests) => digests,
+            Err(err) if options.error_policy == ErrorPolicy::Skip => {
+                report_failures(&[(path.clone(), err)]);
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        debug!("Digested {} files in {}", digests.len(), path_to_label(path));
+        dicts.extend(digests.into_iter().map(|(member, dict)| {
+            let member = path_to_label(&member);
+            let label = match &prefix {
+                Some(prefix) => format!("{}/{}", prefix, member),
+                None => member.into_owned(),
+            };
+            DigestRecord::new(label, dict)
+        }));
+    }
+    Ok(dicts)
+}
+
+/// Digests the files at paths that pass the filter of options, showing a progress bar
+/// if enabled. Failures are reported if the error policy of options is to skip them.
+fn digest_paths(files: &[PathBuf], options: &Options) -> Result<Vec<(PathBuf, LZDict)>> {
+    let bar = progress_bar(
+        options.progress,
+        "digesting [{bar:40}] {pos}/{len} files, {msg}, ETA {eta}",
+    );
+    bar.set_message(format!("{}/s", HumanBytes(0)));
+    let mut report = |progress| {
+        if let Progress::FilesDigested { done, total, bytes } = progress {
+            let rate = bytes as f64 / bar.elapsed().as_secs_f64().max(1e-3);
+            bar.set_length(total);
+            bar.set_position(done);
+            bar.set_message(format!("{}/s", HumanBytes(rate as u64)));
+        }
+    };
+    let selected = filter_files(files, &options.filter, options.error_policy)?;
+    report_failures(&selected.failures);
+    let files = selected.items;
+    debug!("Digesting {} of {} files", files.len(), selected.failures.len() + files.len());
+
+    let start = Instant::now();
+    #[cfg(feature = "async")]
+    let concurrent = match options.concurrency {
+        Some(concurrency) => Some(digest_concurrently(&files, options, concurrency, &mut report)?),
+        None => None,
+    };
+    #[cfg(not(feature = "async"))]
+    let concurrent = None;
+    let batch = match concurrent {
+        Some(batch) => batch,
+        None => digest_files(
+            &files,
+            options.error_policy,
+            options.params.algorithm,
+            options.params.k,
+            options.params.sampling,
+            options.params.skip_high_entropy,
+            Some(&mut report),
+        )?,
+    };
+    bar.finish_and_clear();
+    report_failures(&batch.failures);
+    debug!("Digested {} files in {:.2?}", batch.items.len(), start.elapsed());
+    warn_high_entropy(batch.items.iter().map(|(path, dict)| (path_to_label(path), dict)));
+    Ok(batch.items)
+}
+
+/// Digests files with many reads in flight at once, for files on network file systems
+#[cfg(feature = "async")]
+fn digest_concurrently(
+    files: &[PathBuf],
+    options: &Options,
+    concurrency: usize,
+    report: &mut (dyn FnMut(Progress) + Send),
+) -> Result<lzjd::files::Batch<(PathBuf, LZDict)>> {
+    let directory_options = DirectoryOptions {
+        error_policy: options.error_policy,
+        algorithm: options.params.algorithm,
+        digest_size: Some(options.params.k),
+        sampling: options.params.sampling,
+        skip_high_entropy: options.params.skip_high_entropy,
+        ..Default::default()
+    };
+    Ok(scan::block_on(scan::digest_files(files, &directory_options, concurrency, Some(report)))??)
+}
+
+/// Has the lzjd server of client digest and store the files at paths.
+/// Returns the settings the server digests with, along with the digests.
+#[cfg(feature = "remote")]
+fn remote_hash(
+    client: &Client,
+    paths: &[PathBuf],
+    options: &Options,
+) -> Result<(DigestParams, Vec<DigestRecord>)> {
+    let replies = send_files(paths, options, |label, reader| {
+        let (algorithm, k, dict) = client.digest(&label, reader)?;
+        Ok((
+            DigestParams {
+                algorithm,
+                k,
+                ..Default::default()
+            },
+            dict,
+            label,
+        ))
+    })?;
+    let params = replies.first().map(|reply| reply.0).unwrap_or_default();
+    let dicts = replies.into_iter().map(|(_, dict, label)| DigestRecord::new(label, dict)).collect();
+    Ok((params, dicts))
+}
+
+/// Looks up the files at paths in the store of the lzjd server of client
+#[cfg(feature = "remote")]
+fn remote_query(
+    client: &Client,
+    paths: &[PathBuf],
+    options: &Options,
+) -> Result<Vec<Match>> {
+    let min_similarity = options.metric.min_similarity(options.threshold);
+    let hits = send_files(paths, options, |label, reader| {
+        let hits = client.search(reader, min_similarity, options.top)?;
+        Ok(hits
+            .into_iter()
+            .map(|(_, found, similarity)| Match { a: label.clone(), b: found, similarity })
+            .collect::<Vec<_>>())
+    })?;
+    Ok(hits.into_iter().flatten().collect())
+}
+
+/// Calls send in parallel with the label and contents of each of the files
+/// at paths that passes the filter of options, and of stdin if it is one of them.
+/// Results are in the same order as paths.
+#[cfg(feature = "remote")]
+fn send_files<T, F>(paths: &[PathBuf], options: &Options, send: F) -> Result<Vec<T>>
+where
+    T: Send,
+    F: Fn(String, &mut dyn io::Read) -> lzjd::Result<T> + Sync,
+{
+    let (stdin, files): (Vec<PathBuf>, Vec<PathBuf>) =
+        paths.iter().cloned().partition(|path| path == Path::new(STDIN_PATH));
+    if stdin.len() > 1 {
+        return Err(LZJDError::from("Can only read stdin once").into());
+    }
+
+    let mut results = vec![];
+    if !stdin.is_empty() {
+        results.push(send(options.stdin_label.to_owned(), &mut io::stdin().lock())?);
+    }
+
+    let selected = filter_files(&files, &options.filter, options.error_policy)?;
+    report_failures(&selected.failures);
+    let sent: Vec<lzjd::Result<T>> = selected
+        .items
+        .par_iter()
+        .map(|path| send(path_to_label(path).into_owned(), &mut File::open(path)?))
+        .collect();
+    let mut failures = vec![];
+    for (path, result) in selected.items.into_iter().zip(sent) {
+        match result {
+            Ok(result) => results.push(result),
+            Err(e) if options.error_policy == ErrorPolicy::Skip => failures.push((path, e)),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    report_failures(&failures);
+    Ok(results)
+}
+
+/// Creates a progress bar on stderr, which is hidden if enabled is false
+fn progress_bar(enabled: bool, template: &str) -> ProgressBar {
+    if !enabled {
+        return ProgressBar::hidden();
+    }
+    let bar = ProgressBar::new(0);
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template(template)
+            .unwrap()
+            .progress_chars("=> "),
+    );
+    bar
+}
+
+/// Print out the given digests, created with params. The meta of the digests
+/// is only written to JSON records, as fields of their own.
+fn write_digests(
+    dicts: &[DigestRecord],
+    params: DigestParams,
+    format: Format,
+    encoding: Encoding,
+    writer: &mut dyn Write,
+) -> Result<()> {
+    let DigestParams {
+        algorithm,
+        k,
+        sampling,
+        skip_high_entropy,
+    } = params;
+    match format {
+        Format::Text | Format::Sdhash | Format::Pretty => {
+            writer.write_fmt(format_args!("# {}\n", params))?;
+        }
+        Format::Json | Format::Ndjson | Format::Csv => {}
+        #[cfg(feature = "arrow")]
+        Format::Parquet => {}
+    }
+    match format {
+        Format::Text | Format::Pretty => dicts.iter().try_for_each(|DigestRecord { label: name, dict, .. }| {
+            writer.write_fmt(format_args!("lzjd:{}:{}\n", name, dict.to_encoded_string(encoding)))
+        })?,
+        Format::Json | Format::Ndjson => {
+            let records = dicts.iter().map(|DigestRecord { label: name, dict, meta }| {
+                let mut record = json!({
+                    "name": name,
+                    "algorithm": algorithm.name(),
+                    "k": k,
+                    "digest": dict.to_encoded_string(encoding),
+                });
+                if sampling != Sampling::Full {
+                    record["sampling"] = json!(sampling.to_string());
+                }
+                if skip_high_entropy {
+                    record["entropy"] = json!("skip");
+                }
+                if let Some(fraction) = dict.high_entropy_fraction() {
+                    record["high_entropy"] = json!(fraction);
+                }
+                for (key, value) in meta.iter().filter(|(key, _)| !JSON_DIGEST_FIELDS.contains(&key.as_str())) {
+                    record[key] = json!(value);
+                }
+                record
+            });
+            write_json(records, format, writer)?;
+        }
+        Format::Csv => {
+            writer.write_all(b"name,algorithm,k,digest\r\n")?;
+            dicts.iter().try_for_each(|DigestRecord { label: name, dict, .. }| {
+                writer.write_fmt(format_args!(
+                    "{},{},{},{}\r\n",
+                    csv_field(name),
+                    algorithm,
+                    k,
+                    dict.to_encoded_string(encoding)
+                ))
+            })?;
+        }
+        Format::Sdhash => dicts.iter().try_for_each(|DigestRecord { label: name, dict, .. }| {
+            writer.write_fmt(format_args!(
+                "lzjd:03:{}:{}:{}:{}:{}\n",
+                name.len(),
+                name,
+                algorithm,
+                dict.len(),
+                dict.to_encoded_string(encoding)
+            ))
+        })?,
+        #[cfg(feature = "arrow")]
+        Format::Parquet => {
+            // The settings are kept in the metadata of the file, as in the header of text files
+            let metadata = iter::once(("lzjd".to_owned(), params.to_string())).collect();
+            writer.write_all(&columnar::write_digests(dicts, &metadata, vec![])?)?;
+        }
+    }
+    Ok(())
+}
+
+/// Print out the given comparison results, scored with options.metric,
+/// in the order and up to the number set by options
+fn write_matches(
+    matches: &[Match],
+    options: &Options,
+    writer: &mut dyn Write,
+) -> Result<()> {
+    let num_matches = matches.len();
+    let mut ordered: Cow<[Match]> = Cow::Borrowed(matches);
+    if let Some((key, descending)) = options.sort {
+        let by_name = |x: &Match, y: &Match| (&x.a, &x.b).cmp(&(&y.a, &y.b));
+        ordered.to_mut().sort_by(|a, b| {
+            let order = match key {
+                SortKey::Similarity => a.similarity.partial_cmp(&b.similarity).unwrap().then_with(|| by_name(a, b)),
+                SortKey::Name => by_name(a, b).then_with(|| a.similarity.partial_cmp(&b.similarity).unwrap()),
+            };
+            if descending {
+                order.reverse()
+            } else {
+                order
+            }
+        });
+    }
+
+    if options.format == Format::Pretty {
+        let matches = &ordered[..options.limit.unwrap_or(num_matches).min(num_matches)];
+        write_match_table(matches, options, writer)?;
+        if options.fail_on.is_some() {
+            writer.flush()?;
+            check_outcome(num_matches, options)?;
+        }
+        return Ok(());
+    }
+    let mut stream = MatchStream::new(options, writer);
+    stream.write(&ordered)?;
+    stream.finish()
+}
+
+/// Writes matches in the order they are given, batch by batch as they are found, so
+/// that comparisons do not need to hold all of them. Writes the same as write_matches
+/// does without sorting, for every format but the pretty one, of which the columns
+/// depend on all matches.
+struct MatchStream<'a, 'o> {
+    options: &'a Options<'o>,
+    writer: &'a mut dyn Write,
+    /// Whether the CSV header or the start of the JSON array was written
+    started: bool,
+    /// Number of matches given, and of those written within the limit of options
+    found: usize,
+    written: usize,
+    /// Writer of the Parquet file, of which the bytes are passed on to writer as they are written
+    #[cfg(feature = "arrow")]
+    parquet: Option<MatchWriter<Vec<u8>>>,
+}
+
+impl<'a, 'o> MatchStream<'a, 'o> {
+    fn new(options: &'a Options<'o>, writer: &'a mut dyn Write) -> Self {
+        debug_assert!(options.format != Format::Pretty);
+        Self {
+            options,
+            writer,
+            started: false,
+            found: 0,
+            written: 0,
+            #[cfg(feature = "arrow")]
+            parquet: None,
+        }
+    }
+
+    fn start(&mut self) -> Result<()> {
+        if !self.started {
+            self.started = true;
+            match self.options.format {
+                Format::Json => self.writer.write_all(b"[")?,
+                Format::Csv => self
+                    .writer
+                    .write_fmt(format_args!("a,b,{}\r\n", self.options.metric.name()))?,
+                #[cfg(feature = "arrow")]
+                Format::Parquet => {
+                    self.parquet = Some(MatchWriter::with_score_column(vec![], self.options.metric.name())?)
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, matches: &[Match]) -> Result<()> {
+        self.start()?;
+        self.found += matches.len();
+        let remaining = self.options.limit.map_or(matches.len(), |limit| limit - self.written);
+        let metric = self.options.metric;
+        for Match { a: name_a, b: name_b, similarity } in matches.iter().take(remaining) {
+            let writer = &mut *self.writer;
+            match self.options.format {
+                Format::Text | Format::Sdhash | Format::Pretty => writer.write_fmt(format_args!(
+                    "{}|{}|{:03}\n",
+                    name_a,
+                    name_b,
+                    metric.percentage(*similarity)
+                ))?,
+                Format::Json | Format::Ndjson => {
+                    if self.options.format == Format::Json && self.written > 0 {
+                        writer.write_all(b",")?;
+                    }
+                    let record = json!({
+                        "a": name_a,
+                        "b": name_b,
+                        metric.name(): metric.score(*similarity),
+                        "threshold": self.options.threshold,
+                    });
+                    serde_json::to_writer(&mut *writer, &record).map_err(io::Error::from)?;
+                    if self.options.format == Format::Ndjson {
+                        writer.write_all(b"\n")?;
+                    }
+                }
+                Format::Csv => writer.write_fmt(format_args!(
+                    "{},{},{}\r\n",
+                    csv_field(name_a),
+                    csv_field(name_b),
+                    metric.percentage(*similarity)
+                ))?,
+                #[cfg(feature = "arrow")]
+                Format::Parquet => {
+                    let m = Match {
+                        a: name_a.to_owned(),
+                        b: name_b.to_owned(),
+                        similarity: metric.score(*similarity),
+                    };
+                    self.parquet.as_mut().unwrap().write(&m)?;
+                }
+            }
+            self.written += 1;
+        }
+        #[cfg(feature = "arrow")]
+        if let Some(parquet) = &mut self.parquet {
+            self.writer.write_all(&mem::take(parquet.inner_mut()))?;
+        }
+        Ok(())
+    }
+
+    /// Ends the output, and exits with an outcome if options ask to fail on the number of matches
+    fn finish(mut self) -> Result<()> {
+        self.start()?;
+        if self.options.format == Format::Json {
+            self.writer.write_all(b"]\n")?;
+        }
+        #[cfg(feature = "arrow")]
+        if let Some(parquet) = self.parquet.take() {
+            self.writer.write_all(&parquet.finish()?)?;
+        }
+        if self.options.fail_on.is_some() {
+            // The output should be complete when lzjd exits with an outcome
+            self.writer.flush()?;
+            check_outcome(self.found, self.options)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes matches as a table with aligned columns and right-aligned scores, colored
+/// by the similarity band they fall in if options allow colors
+fn write_match_table(matches: &[Match], options: &Options, writer: &mut dyn Write) -> io::Result<()> {
+    if matches.is_empty() {
+        return Ok(());
+    }
+    let metric = options.metric;
+    let scores: Vec<String> = matches
+        .iter()
+        .map(|m| format!("{:.1}%", metric.score(m.similarity) * 100.))
+        .collect();
+    let header = ("A", "B", metric.name().to_uppercase());
+    let width = |column: &mut dyn Iterator<Item = &str>, header: &str| {
+        column.map(measure_text_width).chain(iter::once(header.len())).max().unwrap_or(0)
+    };
+    let width_a = width(&mut matches.iter().map(|m| m.a.as_str()), header.0);
+    let width_b = width(&mut matches.iter().map(|m| m.b.as_str()), header.1);
+    let width_score = width(&mut scores.iter().map(String::as_str), &header.2);
+
+    let style = |style: Style| style.force_styling(options.color);
+    writer.write_fmt(format_args!(
+        "{}  {}  {}\n",
+        style(Style::new().bold()).apply_to(pad_str(header.0, width_a, Alignment::Left, None)),
+        style(Style::new().bold()).apply_to(pad_str(header.1, width_b, Alignment::Left, None)),
+        style(Style::new().bold()).apply_to(pad_str(&header.2, width_score, Alignment::Right, None)),
+    ))?;
+    let (strong, moderate) = pretty_bands(options);
+    for (Match { a: name_a, b: name_b, similarity }, score) in matches.iter().zip(&scores) {
+        let band = if *similarity >= strong {
+            Style::new().green()
+        } else if *similarity >= moderate {
+            Style::new().yellow()
+        } else {
+            Style::new().red()
+        };
+        writer.write_fmt(format_args!(
+            "{}  {}  {}\n",
+            pad_str(name_a, width_a, Alignment::Left, None),
+            pad_str(name_b, width_b, Alignment::Left, None),
+            style(band).apply_to(pad_str(score, width_score, Alignment::Right, None)),
+        ))?;
+    }
+    Ok(())
+}
+
+/// Returns the similarities from which matches are colored green and yellow, spread
+/// over those that pass the threshold of options, so every color can be shown
+fn pretty_bands(options: &Options) -> (f64, f64) {
+    let min_similarity = options.metric.min_similarity(options.threshold);
+    let band = |fraction: f64| min_similarity + (1. - min_similarity) * fraction;
+    (band(PRETTY_BANDS.0), band(PRETTY_BANDS.1))
+}
+
+/// Returns an Outcome error if the number of matches is the outcome that options asks to fail on
+fn check_outcome(num_matches: usize, options: &Options) -> Result<()> {
+    match options.fail_on {
+        Some(FailOn::NoMatch) if num_matches == 0 => Err(Error::Outcome {
+            msg: "No matches found".to_owned(),
+        }),
+        Some(FailOn::Match) if num_matches > 0 => Err(Error::Outcome {
+            msg: format!("Found {} matches", num_matches),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Parses a threshold given as a percentage, like 50 or 49.5%,
+/// or as a fraction with a decimal point, like 0.5, into a fraction.
+/// Values without a decimal point are percentages, as in earlier versions.
+fn parse_threshold(threshold: &str) -> Result<f64> {
+    let invalid = || {
+        LZJDError::from("Threshold must be a fraction between 0 and 1, or a percentage between 0 and 100")
+    };
+    let (number, percent) = match threshold.strip_suffix('%') {
+        Some(number) => (number, true),
+        None => (threshold, !threshold.contains('.')),
+    };
+    let value: f64 = number.trim().parse().map_err(|_| invalid())?;
+    let fraction = if percent || value > 1. { value / 100. } else { value };
+    if (0. ..=1.).contains(&fraction) {
+        Ok(fraction)
+    } else {
+        Err(invalid().into())
+    }
+}
+
+/// Quotes field as described in RFC 4180 if it contains
+/// commas, double quotes or line breaks
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains(&[',', '"', '\r', '\n'][..]) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Writes records as a single JSON array, or as one JSON object per line
+fn write_json<I>(records: I, format: Format, writer: &mut dyn Write) -> io::Result<()>
+where
+    I: Iterator<Item = serde_json::Value>,
+{
+    if format == Format::Json {
+        serde_json::to_writer(&mut *writer, &records.collect::<Vec<_>>())?;
+        writer.write_all(b"\n")
+    } else {
+        records.into_iter().try_for_each(|record| {
+            serde_json::to_writer(&mut *writer, &record)?;
+            writer.write_all(b"\n")
+        })
+    }
+}
+
+/// Logs the files that were skipped
+fn report_failures(failures: &[(PathBuf, LZJDError)]) {
+    for (path, err) in failures {
+        warn!("Skipped {}: {}", path_to_label(path), err);
+    }
+}
+
+fn create_out_writer(out_path: &Option<PathBuf>) -> Result<DigestWriter<Box<dyn Write + Send>>> {
+    if let Some(path) = out_path {
+        let compress = path.extension() == Some(OsStr::new(ZSTD_EXTENSION));
+        DigestWriter::new(Box::new(File::create(path)?), compress)
+    } else {
+        DigestWriter::new(Box::new(io::stdout()), false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compare_in_blocks, compare_with_progress, csv_field, digest_memory, parse_size, parse_threshold, pretty_bands,
+        write_match_table, DigestLines, DigestParams, Format, Metric, Options, PairFilter, SortKey, STDIN_PATH,
+    };
+    #[cfg(feature = "zstd")]
+    use crate::{is_compressed, open_digest_file, read_hashes_from_file, DigestWriter, ZSTD_MAGIC};
+    use lzjd::compare::BATCH_ROWS;
+    use lzjd::files::ErrorPolicy;
+    use lzjd::filter::FileFilter;
+    use lzjd::lz_dict::Encoding;
+    use lzjd::{DigestRecord, HashAlgorithm, Match};
+    use std::borrow::Cow;
+    use std::collections::HashSet;
+    use std::fs;
+    #[cfg(feature = "zstd")]
+    use std::fs::OpenOptions;
+    #[cfg(feature = "zstd")]
+    use std::io::Read;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::{Arc, Mutex};
+    #[cfg(feature = "http")]
+    use std::time::Duration;
+
+    /// Writer of which the output can be inspected after handing it on
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl SharedOutput {
+        fn len(&self) -> usize {
+            self.0.lock().unwrap().len()
+        }
+    }
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Options of lzjd without flags
+    fn options() -> Options<'static> {
+        Options {
+            params: DigestParams::default(),
+            threshold: 0.,
+            metric: Metric::Similarity,
+            top: None,
+            sort: None,
+            limit: None,
+            pairs: PairFilter::default(),
+            error_policy: ErrorPolicy::FailFast,
+            filter: FileFilter::default(),
+            stdin_label: STDIN_PATH,
+            format: Format::Text,
+            encoding: Encoding::default(),
+            progress: false,
+            fail_on: None,
+            color: false,
+            #[cfg(feature = "async")]
+            concurrency: None,
+            #[cfg(feature = "http")]
+            download_limit: 1 << 30,
+            #[cfg(feature = "http")]
+            download_timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Digests of count inputs, every other one of which is the same
+    fn digests(count: usize) -> Vec<DigestRecord> {
+        (0..count)
+            .map(|i| {
+                let data = format!("sequence {}", i % 2).repeat(10);
+                let dict = HashAlgorithm::Crc32.digest_reader_with_k(data.as_bytes(), 64).unwrap();
+                DigestRecord::new(format!("digest{}", i), dict)
+            })
+            .collect()
+    }
+
+    /// Writes dicts to a digest file in the temporary directory, followed by trailer
+    fn digest_file(name: &str, dicts: &[DigestRecord], trailer: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("lzjd-test-{}-{}.lzjd", process::id(), name));
+        let mut text = String::new();
+        for record in dicts {
+            text += &format!("lzjd:{}:{}\n", record.label, record.dict.to_encoded_string(Encoding::default()));
+        }
+        fs::write(&path, text + trailer).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_compare_in_blocks() {
+        let dicts = digests(40);
+        let memory = digest_memory(&dicts[0]);
+        let path = digest_file("blocks", &dicts, "");
+        let expected = SharedOutput::default();
+        compare_with_progress(&dicts, &dicts, &options(), &mut |_| {}, &mut expected.clone()).unwrap();
+        let output = SharedOutput::default();
+        compare_in_blocks(&path, None, 4 * memory, &options(), &mut output.clone()).unwrap();
+        assert_eq!(*output.0.lock().unwrap(), *expected.0.lock().unwrap());
+
+        // The matches of every block are written before the next block is read,
+        // so those before a damaged line are written, unless they are sorted
+        let damaged = digest_file("damaged", &dicts, "lzjd:damaged\n");
+        let path_b = digest_file("b", &dicts[..2], "");
+        for (sort, written) in [(None, true), (Some((SortKey::Name, false)), false)] {
+            let options = Options { sort, ..options() };
+            let output = SharedOutput::default();
+            let result = compare_in_blocks(&damaged, Some(&path_b), 6 * memory, &options, &mut output.clone());
+            assert!(result.unwrap_err().to_string().contains("line 41, column 1"));
+            assert_eq!(output.len() > 0, written);
+        }
+        // Or skipped, in either way of reading blocks
+        let options = Options { error_policy: ErrorPolicy::Skip, ..options() };
+        let output = SharedOutput::default();
+        compare_in_blocks(&damaged, None, 4 * memory, &options, &mut output.clone()).unwrap();
+        assert_eq!(*output.0.lock().unwrap(), *expected.0.lock().unwrap());
+        compare_in_blocks(&damaged, Some(&path_b), 6 * memory, &options, &mut SharedOutput::default()).unwrap();
+        for path in [path, damaged, path_b] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    /// Writer that fails once more than its capacity is written, like a full disk
+    #[cfg(feature = "zstd")]
+    struct FullDisk(usize);
+
+    #[cfg(feature = "zstd")]
+    impl Write for FullDisk {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if buf.len() > self.0 {
+                return Err(std::io::Error::other("disk full"));
+            }
+            self.0 -= buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_compressed_digest_files() {
+        let dicts = digests(30);
+        let plain = digest_file("plain", &dicts, "");
+        let text = fs::read(&plain).unwrap();
+
+        // Appending to a compressed file adds a frame, and the frames are read as one
+        let compressed = plain.with_extension("lzjd.zst");
+        let _ = fs::remove_file(&compressed);
+        assert!(is_compressed(&compressed).unwrap(), "Missing files are recognized by extension");
+        for part in [&text[..1000], &text[1000..]] {
+            let file = OpenOptions::new().create(true).append(true).open(&compressed).unwrap();
+            let mut writer = DigestWriter::new(file, is_compressed(&compressed).unwrap()).unwrap();
+            writer.write_all(part).unwrap();
+            writer.finish().unwrap();
+        }
+        assert!(fs::read(&compressed).unwrap().starts_with(&ZSTD_MAGIC));
+        assert!(!is_compressed(&plain).unwrap());
+        let mut read = vec![];
+        open_digest_file(&compressed, 0).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, text);
+
+        // Files are recognized by their magic number rather than their extension
+        let renamed = plain.with_extension("txt");
+        fs::copy(&compressed, &renamed).unwrap();
+        assert!(is_compressed(&renamed).unwrap());
+        let (_, read) = read_hashes_from_file(&renamed, ErrorPolicy::FailFast).unwrap();
+        assert_eq!(read.len(), dicts.len());
+
+        // Positions are those of the decompressed lines
+        for position in [0, 999, 1000, 2500, text.len() as u64] {
+            let mut read = vec![];
+            open_digest_file(&compressed, position).unwrap().read_to_end(&mut read).unwrap();
+            assert_eq!(read, text[position as usize..]);
+        }
+
+        // Comparing in blocks reads the decompressed copy, and removes it afterwards
+        let memory = 4 * digest_memory(&dicts[0]);
+        let expected = SharedOutput::default();
+        compare_in_blocks(&plain, None, memory, &options(), &mut expected.clone()).unwrap();
+        let output = SharedOutput::default();
+        compare_in_blocks(&compressed, None, memory, &options(), &mut output.clone()).unwrap();
+        assert_eq!(*output.0.lock().unwrap(), *expected.0.lock().unwrap());
+        let copies = fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name();
+                name.to_string_lossy().starts_with(&format!("lzjd-blocks-{}-", process::id()))
+            })
+            .count();
+        assert_eq!(copies, 0);
+
+        for path in [plain, compressed, renamed] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_digest_writer_reports_incomplete_output() {
+        let data = "lzjd:a:AAAA\n".repeat(10_000);
+        for compress in [false, true] {
+            // Buffered output of which the end does not fit
+            let mut writer = DigestWriter::new(FullDisk(10), compress).unwrap();
+            writer.write_all(&data.as_bytes()[..200]).unwrap();
+            assert!(writer.finish().is_err());
+            let mut writer = DigestWriter::new(FullDisk(1 << 20), compress).unwrap();
+            writer.write_all(data.as_bytes()).unwrap();
+            assert!(writer.finish().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_digest_lines() {
+        let dicts = digests(3);
+        let digest = dicts[0].dict.to_encoded_string(Encoding::default());
+        let trailer = format!(
+            "lzjd:ünï:{}\n  lzjd:bad:AAA!\nmissing label\n# comment\n\nééé:x:AAAAAA==\nlzjx:y:{}\n",
+            digest, digest
+        );
+        let path = digest_file("lines", &dicts, &trailer);
+
+        let mut lines = DigestLines::open(&path, 0, ErrorPolicy::FailFast).unwrap();
+        let mut labels = vec![];
+        let err = loop {
+            match lines.next_digest() {
+                Ok(Some(record)) => labels.push(record.label),
+                Ok(None) => panic!("Malformed line was not detected"),
+                Err(e) => break e.to_string(),
+            }
+        };
+        assert_eq!(labels, ["digest0", "digest1", "digest2", "ünï"]);
+        // The column of the digest, after the indentation and label
+        assert!(err.contains("line 5, column 12: digest of bad"), "{}", err);
+
+        let mut lines = DigestLines::open(&path, 0, ErrorPolicy::Skip).unwrap();
+        let mut read = 0;
+        while lines.next_digest().unwrap().is_some() {
+            read += 1;
+        }
+        assert_eq!(read, 4);
+        // Lines without the lzjd: prefix are malformed, whatever they start with
+        assert_eq!(lines.skipped, [5, 6, 9, 10]);
+        assert_eq!(lines.line, 10);
+
+        // Headers that conflict with the digests are not skipped
+        let path_b = digest_file("header", &[], "# lzjd algorithm=crc32\n# lzjd algorithm=murmur3\n");
+        let mut lines = DigestLines::open(&path_b, 0, ErrorPolicy::Skip).unwrap();
+        let err = lines.next_digest().unwrap_err().to_string();
+        assert!(err.contains("line 2, column 1"), "{}", err);
+        for path in [path, path_b] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_compare_streams_matches() {
+        let dicts = digests(BATCH_ROWS + 10);
+        for (sort, format) in [
+            (None, Format::Text),
+            (None, Format::Csv),
+            (Some((SortKey::Name, false)), Format::Text),
+            (None, Format::Pretty),
+        ] {
+            let options = Options { top: Some(1), sort, format, ..options() };
+            let output = SharedOutput::default();
+            // Length of the output at every progress report
+            let lengths = Mutex::new(vec![]);
+            let mut report = |_| lengths.lock().unwrap().push(output.len());
+            compare_with_progress(&dicts, &dicts, &options, &mut report, &mut output.clone()).unwrap();
+
+            let lengths = lengths.into_inner().unwrap();
+            assert_eq!(lengths.len(), dicts.len());
+            let last = *lengths.last().unwrap();
+            if sort.is_none() && format != Format::Pretty {
+                // The matches of the first rows were written before the last rows were compared
+                assert!(last > 0 && last < output.len(), "{} of {}", last, output.len());
+                assert_eq!(lengths[BATCH_ROWS - 1], lengths[0]);
+            } else {
+                assert_eq!(last, 0);
+            }
+            let text = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+            assert!(text.contains("digest0") && text.contains("digest2"), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_pretty_bands() {
+        let matches = [Match { a: "a".into(), b: "b".into(), similarity: 0.9 }];
+        let color = |metric, threshold| {
+            let options = Options { metric, threshold, color: true, ..options() };
+            let mut output = vec![];
+            write_match_table(&matches, &options, &mut output).unwrap();
+            let table = String::from_utf8(output).unwrap();
+            // Green, yellow or red
+            ["32", "33", "31"].iter().position(|code| table.contains(&format!("\u{1b}[{}m", code))).unwrap()
+        };
+
+        // The default threshold of 1% keeps the bands near 75% and 40%
+        let default = Options { threshold: 0.01, ..options() };
+        let (strong, moderate) = pretty_bands(&default);
+        assert!((strong - 0.75).abs() < 0.01 && (moderate - 0.4).abs() < 0.01);
+        assert_eq!(color(Metric::Similarity, 0.01), 0);
+
+        // A higher threshold moves them up
+        let high = Options { threshold: 0.8, ..options() };
+        let (strong, moderate) = pretty_bands(&high);
+        assert!((strong - 0.95).abs() < 1e-9 && (moderate - 0.88).abs() < 1e-9);
+        assert_eq!(color(Metric::Similarity, 0.8), 1);
+        assert_eq!(color(Metric::Distance, 0.2), 1);
+        assert_eq!(color(Metric::Similarity, 0.89), 2);
+    }
+
+    #[test]
+    fn test_parse_threshold() {
+        assert_eq!(parse_threshold("0.29").unwrap(), 0.29);
+        assert_eq!(parse_threshold("29").unwrap(), 0.29);
+        assert_eq!(parse_threshold("29%").unwrap(), 0.29);
+        assert_eq!(parse_threshold("0.5%").unwrap(), 0.005);
+        assert_eq!(parse_threshold("0").unwrap(), 0.);
+        assert_eq!(parse_threshold("1.0").unwrap(), 1.);
+        assert_eq!(parse_threshold("100").unwrap(), 1.);
+        for invalid in ["101", "100.5%", "-0.1", "-5", "1e9", "NaN", "inf", "abc", "", "%", "0.5.1"] {
+            assert!(parse_threshold(invalid).is_err(), "{}", invalid);
+        }
+
+        // Thresholds given as fractions or percentages select the same scores, and
+        // distances pass from exactly the similarity where their score does
+        for percentage in 0..=100u8 {
+            let fraction = format!("{}.{:02}", percentage / 100, percentage % 100);
+            let threshold = parse_threshold(&fraction).unwrap();
+            assert_eq!(parse_threshold(&percentage.to_string()).unwrap(), threshold);
+            assert_eq!(Metric::Similarity.min_similarity(threshold), threshold);
+            let distance = Metric::Distance.min_similarity(threshold);
+            assert!(Metric::Distance.score(distance) <= threshold, "{}", threshold);
+            assert!(distance == 0. || Metric::Distance.score(distance.next_down()) > threshold, "{}", threshold);
+        }
+        // Thresholds apply to the scores before they are rounded for display
+        let threshold = |threshold| Metric::Similarity.min_similarity(parse_threshold(threshold).unwrap());
+        assert_eq!(Metric::Similarity.percentage(0.4945), 49);
+        assert!(0.4945 >= threshold("0.494") && 0.4945 < threshold("0.495"));
+        assert!(0.494 >= threshold("0.49") && 0.494 < threshold("0.5"));
+        assert!(0.3 >= Metric::Distance.min_similarity(0.7));
+    }
+
+    #[test]
+    fn test_csv_field() {
+        assert!(matches!(csv_field("a|b:c d"), Cow::Borrowed("a|b:c d")));
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("\""), "\"\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("crlf\r\n"), "\"crlf\r\n\"");
+
+        // Labels are quoted in the comparison results
+        let mut dicts = digests(2);
+        dicts[0].label = "a,\"b\"".into();
+        dicts[1].label = "c\nd".into();
+        let options = Options { format: Format::Csv, ..options() };
+        let output = SharedOutput::default();
+        compare_with_progress(&dicts, &dicts, &options, &mut |_| {}, &mut output.clone()).unwrap();
+        let text = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let score = Metric::Similarity.percentage(dicts[0].dict.similarity(&dicts[1].dict));
+        assert_eq!(text, format!("a,b,similarity\r\n\"a,\"\"b\"\"\",\"c\nd\",{}\r\n", score));
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("1K").unwrap(), 1 << 10);
+        assert_eq!(parse_size("1k").unwrap(), 1 << 10);
+        assert_eq!(parse_size("512M").unwrap(), 512 << 20);
+        assert_eq!(parse_size("512m").unwrap(), 512 << 20);
+        assert_eq!(parse_size("8G").unwrap(), 8 << 30);
+        assert_eq!(parse_size("2t").unwrap(), 2 << 40);
+        assert_eq!(parse_size("16777215T").unwrap(), 16777215 << 40);
+        assert_eq!(parse_size("18446744073709551615").unwrap(), u64::MAX);
+        for invalid in [
+            "",
+            "K",
+            "1X",
+            "1KB",
+            "1.5G",
+            "-1",
+            " 1K",
+            "1 K",
+            "16777216T",
+            "18446744073709551616",
+        ] {
+            assert!(parse_size(invalid).is_err(), "{}", invalid);
+        }
+        assert!(parse_size("1X").unwrap_err().to_string().contains("Invalid size '1X'"));
+    }
+
+    #[test]
+    fn test_pair_filter() {
+        let matches = |pairs: &[(&str, &str)]| -> Vec<Match> {
+            pairs
+                .iter()
+                .map(|&(a, b)| Match { a: a.into(), b: b.into(), similarity: 0.5 })
+                .collect()
+        };
+        let pairs = |matches: Vec<Match>| -> Vec<(String, String)> { matches.into_iter().map(|m| (m.a, m.b)).collect() };
+        let owned = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs.iter().map(|&(a, b)| (a.to_owned(), b.to_owned())).collect()
+        };
+        let found = matches(&[("b", "a"), ("a", "a"), ("a", "b"), ("c", "b"), ("b", "b"), ("b", "c")]);
+        let filter = |skip_self, canonical, unique| PairFilter { skip_self, canonical, unique };
+        let apply = |filter: PairFilter| pairs(filter.apply(found.clone(), &mut HashSet::new()));
+
+        assert_eq!(apply(filter(false, false, false)), pairs(found.clone()));
+        assert_eq!(apply(filter(true, false, false)), owned(&[("b", "a"), ("a", "b"), ("c", "b"), ("b", "c")]));
+        assert_eq!(
+            apply(filter(false, true, false)),
+            owned(&[("a", "b"), ("a", "a"), ("a", "b"), ("b", "c"), ("b", "b"), ("b", "c")])
+        );
+        // Symmetric duplicates are left out, keeping the order of the pair that came first
+        assert_eq!(apply(filter(false, false, true)), owned(&[("b", "a"), ("a", "a"), ("c", "b"), ("b", "b")]));
+        assert_eq!(apply(filter(true, true, true)), owned(&[("a", "b"), ("b", "c")]));
+
+        // Pairs seen in earlier batches are left out of later ones
+        let mut seen = HashSet::new();
+        let unique = filter(true, false, true);
+        assert_eq!(unique.apply(matches(&[("a", "b")]), &mut seen).len(), 1);
+        assert!(unique.apply(matches(&[("b", "a"), ("a", "b")]), &mut seen).is_empty());
+        assert_eq!(pairs(unique.apply(matches(&[("c", "a")]), &mut seen)), owned(&[("c", "a")]));
+    }
+
+    /// Tests of the digest file written by `lzjd serve --db`
+    #[cfg(any(feature = "server", feature = "grpc"))]
+    mod service_log {
+        use super::SharedOutput;
+        use crate::{parse_digest_line, DigestParams};
+        use lzjd::{service::Service, DigestStore, HashAlgorithm};
+        use std::io::Write;
+
+        #[test]
+        fn test_service_log_is_readable() {
+            let params = DigestParams::default();
+            let mut log = SharedOutput::default();
+            writeln!(log, "# {}", params).unwrap();
+            let service = Service::new(DigestStore::with_shards(1), HashAlgorithm::Murmur3, params.k)
+                .with_log(log.clone());
+            let labels = ["a", "with: colons:", " spaced ", "ünïcode"];
+            for label in &labels {
+                service.add(label.to_string(), &b"THIS IS A TEST SEQUENCE"[..]).unwrap();
+            }
+            for label in &["", "a\nlzjd:injected:AAAA", "a\r", "nul\0"] {
+                assert!(service.add(label.to_string(), &b"data"[..]).is_err());
+            }
+
+            // Every line is read back, and no line is injected
+            let text = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+            let mut read_params = None;
+            let read_labels: Vec<String> = text
+                .lines()
+                .enumerate()
+                .filter_map(|(n, line)| {
+                    parse_digest_line(line, &mut read_params)
+                        .unwrap_or_else(|e| panic!("line {}: {}", n + 1, e))
+                        .map(|record| record.label)
+                })
+                .collect();
+            assert_eq!(read_params, Some(params));
+            assert_eq!(read_labels, labels);
+        }
+    }
+}
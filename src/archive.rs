@@ -0,0 +1,161 @@
+//! Digesting of the members of tar and zip archives that are read as a stream,
+//! like archives piped to stdin, without extracting them.
+use crate::filter::{FileFilter, FileType};
+use crate::{HashAlgorithm, LZDict, LZJDError, Result};
+
+use log::trace;
+use std::io::Read;
+use std::path::PathBuf;
+
+/// Archive formats that can be read as a stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    /// Zip archives are read by their local headers, so
+    /// members are found without seeking to the central directory
+    Zip,
+}
+
+/// Digests the regular files in the archive read from reader that match filter,
+/// in the order they are stored. Returns their paths within the archive
+/// along with their digests, keeping the k smallest hashes of each.
+pub fn digest_archive<R: Read>(
+    reader: R,
+    format: ArchiveFormat,
+    algorithm: HashAlgorithm,
+    k: usize,
+    filter: &FileFilter,
+) -> Result<Vec<(PathBuf, LZDict)>> {
+    let mut digests = vec![];
+    let mut digest_member = |path: PathBuf, len: u64, member: &mut dyn Read| {
+        let digested = digest_member(member, len, algorithm, k, filter);
+        if let Some(dict) = digested.map_err(|e| e.in_file(&path))? {
+            trace!("Digested {}", path.display());
+            digests.push((path, dict));
+        }
+        Ok::<_, LZJDError>(())
+    };
+
+    match format {
+        ArchiveFormat::Tar => {
+            let mut archive = tar::Archive::new(reader);
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                if entry.header().entry_type().is_file() {
+                    let path = entry.path()?.into_owned();
+                    let len = entry.size();
+                    digest_member(path, len, &mut entry)?;
+                }
+            }
+        }
+        ArchiveFormat::Zip => {
+            let mut reader = reader;
+            while let Some(mut member) =
+                zip::read::read_zipfile_from_stream(&mut reader).map_err(zip_error)?
+            {
+                if member.is_file() {
+                    let path = PathBuf::from(member.name());
+                    let len = member.size();
+                    digest_member(path, len, &mut member)?;
+                }
+            }
+        }
+    }
+    Ok(digests)
+}
+
+/// Digests member if it matches filter, which is checked before reading past its first bytes
+fn digest_member(
+    member: &mut dyn Read,
+    len: u64,
+    algorithm: HashAlgorithm,
+    k: usize,
+    filter: &FileFilter,
+) -> Result<Option<LZDict>> {
+    if !filter.matches_size(len) {
+        return Ok(None);
+    }
+    let mut header = Vec::with_capacity(FileType::SNIFF_LEN);
+    member
+        .take(FileType::SNIFF_LEN as u64)
+        .read_to_end(&mut header)?;
+    if !filter.matches_type(FileType::detect(&header)) {
+        // The rest of the member is skipped by the archive readers
+        return Ok(None);
+    }
+    algorithm
+        .digest_reader_with_k(header.chain(member), k)
+        .map(Some)
+}
+
+fn zip_error(e: zip::result::ZipError) -> LZJDError {
+    match e {
+        zip::result::ZipError::Io(err) => LZJDError::Io { err },
+        e => LZJDError::Msg {
+            msg: format!("Invalid zip archive: {}", e),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::{digest_archive, ArchiveFormat};
+    use crate::filter::{FileFilter, FileType};
+    use crate::HashAlgorithm;
+    use std::io::{Cursor, Write};
+    use std::path::Path;
+
+    const MEMBERS: [(&str, &[u8]); 2] = [
+        ("dir/a.txt", b"THIS IS A TEST SEQUENCE"),
+        ("b.bin", b"MZ\x90\x00totally_different"),
+    ];
+
+    fn check(archive: &[u8], format: ArchiveFormat) {
+        let algorithm = HashAlgorithm::Murmur3;
+        let digests =
+            digest_archive(archive, format, algorithm, 1024, &FileFilter::default()).unwrap();
+        assert_eq!(digests.len(), MEMBERS.len());
+        for ((path, dict), (name, data)) in digests.iter().zip(&MEMBERS) {
+            assert_eq!(path, Path::new(name));
+            assert_eq!(
+                dict.to_vec(),
+                algorithm.digest_reader(*data).unwrap().to_vec()
+            );
+        }
+
+        let filter = FileFilter {
+            types: vec![FileType::Pe],
+            ..Default::default()
+        };
+        let digests = digest_archive(archive, format, algorithm, 1024, &filter).unwrap();
+        assert_eq!(digests.len(), 1);
+        assert_eq!(digests[0].0, Path::new("b.bin"));
+    }
+
+    #[test]
+    fn test_tar() {
+        let mut builder = tar::Builder::new(vec![]);
+        for (name, data) in &MEMBERS {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            builder.append_data(&mut header, name, *data).unwrap();
+        }
+        check(&builder.into_inner().unwrap(), ArchiveFormat::Tar);
+    }
+
+    #[test]
+    fn test_zip() {
+        let mut writer = zip::ZipWriter::new(Cursor::new(vec![]));
+        writer
+            .add_directory("dir/", zip::write::FileOptions::default())
+            .unwrap();
+        for (name, data) in &MEMBERS {
+            writer
+                .start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            writer.write_all(data).unwrap();
+        }
+        check(&writer.finish().unwrap().into_inner(), ArchiveFormat::Zip);
+    }
+}
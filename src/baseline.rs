@@ -0,0 +1,93 @@
+//! Baselines of content that is common to many files, like libraries and runtimes,
+//! of which the hashes are removed from digests before comparing them, so that
+//! shared boilerplate does not make unrelated files look similar.
+use crate::LZDict;
+
+use std::collections::HashMap;
+
+/// Hashes occurring in a set of digests of known files, like a clean installation
+#[derive(Debug, Clone, Default)]
+pub struct Baseline {
+    /// Sorted hashes
+    hashes: Vec<i32>,
+}
+
+impl Baseline {
+    /// Creates a baseline of the hashes that occur in at least a fraction min_share
+    /// of dicts, and in at least one of them
+    pub fn new<'a, I: IntoIterator<Item = &'a LZDict>>(dicts: I, min_share: f64) -> Self {
+        let mut counts: HashMap<i32, usize> = HashMap::new();
+        let mut total = 0;
+        for dict in dicts {
+            total += 1;
+            for &hash in dict.iter() {
+                *counts.entry(hash).or_insert(0) += 1;
+            }
+        }
+        let min_count = ((min_share * total as f64).ceil() as usize).max(1);
+        let mut hashes: Vec<i32> = counts
+            .into_iter()
+            .filter(|&(_, count)| count >= min_count)
+            .map(|(hash, _)| hash)
+            .collect();
+        hashes.sort_unstable();
+        Self { hashes }
+    }
+
+    /// Number of hashes in the baseline
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    pub fn contains(&self, hash: i32) -> bool {
+        self.hashes.binary_search(&hash).is_ok()
+    }
+
+    /// Returns dict without the hashes in the baseline, keeping all of the others
+    /// however many hashes dict was digested with
+    pub fn subtract(&self, dict: &LZDict) -> LZDict {
+        let hashes: Vec<_> = dict
+            .iter()
+            .copied()
+            .filter(|&hash| !self.contains(hash))
+            .collect();
+        LZDict::from_vec_with_k(hashes, dict.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::baseline::Baseline;
+    use crate::lz_dict::K;
+    use crate::LZDict;
+
+    #[test]
+    fn test_subtract() {
+        let baseline = vec![
+            LZDict::from(vec![1, 2, 3]),
+            LZDict::from(vec![2, 3, 4]),
+            LZDict::from(vec![3, 5]),
+        ];
+        let dict = LZDict::from(vec![1, 2, 3, 6]);
+
+        let any = Baseline::new(&baseline, 0.);
+        assert_eq!(any.len(), 5);
+        assert_eq!(any.subtract(&dict).to_vec(), vec![6]);
+
+        let most = Baseline::new(&baseline, 0.5);
+        assert_eq!(most.len(), 2);
+        assert_eq!(most.subtract(&dict).to_vec(), vec![1, 6]);
+
+        assert!(Baseline::new(&[], 0.5).is_empty());
+
+        // Digests with more than K hashes keep all of them but those in the baseline
+        let large = LZDict::from_vec_with_k((0..K as i32 * 2).collect(), K * 2);
+        let subtracted = any.subtract(&large);
+        assert_eq!(subtracted.len(), K * 2 - 5);
+        assert!(subtracted.iter().all(|&hash| hash == 0 || hash > 5));
+    }
+}
@@ -0,0 +1,296 @@
+//! Single-file bundles of a corpus of digests, for shipping it between machines,
+//! like to air-gapped lab machines, and detecting whether it arrived intact.
+//!
+//! A bundle holds a DigestIndex, which holds the labeled digests along with their
+//! postings, so that they need not be indexed again, and metadata: the settings the
+//! digests were created with, the time the bundle was created and the version of the
+//! tool that created it. The file starts with MAGIC, followed by sections, each of
+//! which is its name, the number of bytes of its data, the CRC-32 of the data and the
+//! data. Every section is checked against its checksum when the bundle is read.
+//!
+//! Checksums detect files that were damaged or cut short on their way, but not files
+//! that were changed on purpose, which would need a signature of the bundle.
+//!
+//! ```
+//! use lzjd::bundle::{read_bundle, write_bundle, BundleInfo};
+//! use lzjd::index::DigestIndex;
+//! use lzjd::HashAlgorithm;
+//!
+//! let mut index = DigestIndex::new(HashAlgorithm::Murmur3, 1024);
+//! let dict = HashAlgorithm::Murmur3.digest_reader(&b"THIS IS A TEST SEQUENCE"[..]).unwrap();
+//! index.insert("test", dict);
+//!
+//! let info = BundleInfo::new(&index, "example 1.0");
+//! let bundle = write_bundle(&index, &info, vec![]).unwrap();
+//! let (read_info, read_index) = read_bundle(&bundle[..]).unwrap();
+//! assert_eq!(read_info, info);
+//! assert_eq!(read_index.label(0), "test");
+//! ```
+use crate::index::DigestIndex;
+use crate::{HashAlgorithm, LZJDError, Result};
+
+use crc::crc32;
+use std::collections::BTreeMap;
+use std::io::{ErrorKind, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Start of bundle files, followed by the format version
+pub const MAGIC: &[u8; 8] = b"LZJDBDL\x01";
+
+/// Maximum number of bytes of the name of a section, beyond which the bundle is damaged
+const MAX_SECTION_NAME: usize = 255;
+
+/// Section of the metadata, as lines of key=value
+const METADATA_SECTION: &str = "metadata";
+
+/// Section of the index, in the format of index files
+const INDEX_SECTION: &str = "index";
+
+/// Metadata of a bundle
+#[derive(Debug, Clone, PartialEq)]
+pub struct BundleInfo {
+    pub algorithm: HashAlgorithm,
+    pub k: usize,
+    /// Number of digests in the bundle
+    pub digests: usize,
+    /// Seconds since the Unix epoch at which the bundle was created
+    pub created: u64,
+    /// Name and version of the tool that created the bundle
+    pub tool_version: String,
+    /// Other metadata, like settings of the digests that the tool keeps track of
+    pub extra: BTreeMap<String, String>,
+}
+
+impl BundleInfo {
+    /// Creates the metadata of a bundle of index, created now by tool_version
+    pub fn new<S: Into<String>>(index: &DigestIndex, tool_version: S) -> Self {
+        Self {
+            algorithm: index.algorithm(),
+            k: index.k(),
+            digests: index.len(),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs()),
+            tool_version: tool_version.into(),
+            extra: BTreeMap::new(),
+        }
+    }
+
+    /// Writes the metadata as lines of key=value, of which the extra keys come last
+    fn to_text(&self) -> String {
+        let mut text = format!(
+            "algorithm={}\nk={}\ndigests={}\ncreated={}\ntool_version={}\n",
+            self.algorithm.name(),
+            self.k,
+            self.digests,
+            self.created,
+            self.tool_version
+        );
+        for (key, value) in &self.extra {
+            text += &format!("{}={}\n", key, value);
+        }
+        text
+    }
+
+    /// Reads metadata written by to_text. Keys other than the fields are put in extra.
+    fn from_text(text: &str) -> Result<Self> {
+        let mut extra: BTreeMap<String, String> = text
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect();
+        let mut field = |key: &str| {
+            extra
+                .remove(key)
+                .ok_or_else(|| LZJDError::from(format!("Bundle metadata lacks {}", key).as_str()))
+        };
+        let number = |value: String| {
+            value
+                .parse::<u64>()
+                .map_err(|_| LZJDError::from("Invalid number in bundle metadata"))
+        };
+        Ok(Self {
+            algorithm: field("algorithm")?.parse()?,
+            k: number(field("k")?)? as usize,
+            digests: number(field("digests")?)? as usize,
+            created: number(field("created")?)?,
+            tool_version: field("tool_version")?,
+            extra,
+        })
+    }
+}
+
+/// Writes index to writer as a bundle with the metadata info, and returns writer
+pub fn write_bundle<W: Write>(index: &DigestIndex, info: &BundleInfo, mut writer: W) -> Result<W> {
+    if info.digests != index.len() {
+        return Err(LZJDError::from(
+            "Bundle metadata does not match the number of digests",
+        ));
+    }
+    if info.extra.keys().any(|key| key.contains(['=', '\n'])) {
+        return Err(LZJDError::from(
+            "Bundle metadata keys can not contain = or newlines",
+        ));
+    }
+    if info.extra.values().any(|value| value.contains('\n')) {
+        return Err(LZJDError::from(
+            "Bundle metadata values can not contain newlines",
+        ));
+    }
+    writer.write_all(MAGIC)?;
+    write_section(&mut writer, METADATA_SECTION, info.to_text().as_bytes())?;
+    let mut data = vec![];
+    index.write_to(&mut data)?;
+    write_section(&mut writer, INDEX_SECTION, &data)?;
+    writer.flush()?;
+    Ok(writer)
+}
+
+/// Reads a bundle written by write_bundle from reader, up to its end, and returns its
+/// metadata and index. Fails if a section does not match its checksum, if the bundle
+/// ends early, or if the index does not match the metadata.
+pub fn read_bundle<R: Read>(mut reader: R) -> Result<(BundleInfo, DigestIndex)> {
+    let mut magic = [0; 8];
+    reader
+        .read_exact(&mut magic)
+        .map_err(|_| LZJDError::from("Not an lzjd bundle"))?;
+    if &magic != MAGIC {
+        return Err(LZJDError::from("Not an lzjd bundle"));
+    }
+    let mut info = None;
+    let mut index = None;
+    while let Some((name, data)) = read_section(&mut reader)? {
+        match name.as_str() {
+            METADATA_SECTION => {
+                let text = String::from_utf8(data)
+                    .map_err(|_| LZJDError::from("Bundle metadata is not valid UTF-8"))?;
+                info = Some(BundleInfo::from_text(&text)?);
+            }
+            INDEX_SECTION => index = Some(DigestIndex::read_from(&data[..])?),
+            // Sections of later versions are skipped
+            _ => {}
+        }
+    }
+    let (info, index) = match (info, index) {
+        (Some(info), Some(index)) => (info, index),
+        _ => return Err(LZJDError::from("Bundle is incomplete")),
+    };
+    if (info.algorithm, info.k, info.digests) != (index.algorithm(), index.k(), index.len()) {
+        return Err(LZJDError::from("Bundle index does not match its metadata"));
+    }
+    Ok((info, index))
+}
+
+fn write_section<W: Write>(writer: &mut W, name: &str, data: &[u8]) -> Result<()> {
+    debug_assert!(name.len() <= MAX_SECTION_NAME);
+    writer.write_all(&(name.len() as u32).to_le_bytes())?;
+    writer.write_all(name.as_bytes())?;
+    writer.write_all(&(data.len() as u64).to_le_bytes())?;
+    writer.write_all(&crc32::checksum_ieee(data).to_le_bytes())?;
+    Ok(writer.write_all(data)?)
+}
+
+/// Reads the name and data of the next section, returning None at the end of the bundle
+fn read_section<R: Read>(reader: &mut R) -> Result<Option<(String, Vec<u8>)>> {
+    let mut word = [0; 4];
+    match reader.read_exact(&mut word) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let truncated = |e: std::io::Error| match e.kind() {
+        ErrorKind::UnexpectedEof => LZJDError::from("Bundle ends in the middle of a section"),
+        _ => e.into(),
+    };
+    // The lengths of a section are not covered by its checksum, so they are not
+    // trusted with allocating more than the bundle holds
+    let name_len = u32::from_le_bytes(word) as usize;
+    if name_len > MAX_SECTION_NAME {
+        return Err(LZJDError::from(
+            "Bundle is damaged: a section name is too long",
+        ));
+    }
+    let mut name = vec![];
+    reader
+        .by_ref()
+        .take(name_len as u64)
+        .read_to_end(&mut name)
+        .map_err(truncated)?;
+    if name.len() < name_len {
+        return Err(LZJDError::from("Bundle ends in the middle of a section"));
+    }
+    let name = String::from_utf8(name)
+        .map_err(|_| LZJDError::from("Bundle section name is not valid UTF-8"))?;
+    let mut len = [0; 8];
+    reader.read_exact(&mut len).map_err(truncated)?;
+    reader.read_exact(&mut word).map_err(truncated)?;
+    let mut data = vec![];
+    reader
+        .take(u64::from_le_bytes(len))
+        .read_to_end(&mut data)?;
+    if data.len() as u64 != u64::from_le_bytes(len) {
+        return Err(LZJDError::from("Bundle ends in the middle of a section"));
+    }
+    if crc32::checksum_ieee(&data) != u32::from_le_bytes(word) {
+        return Err(LZJDError::Msg {
+            msg: format!(
+                "Bundle section {} is damaged: its checksum does not match",
+                name
+            ),
+        });
+    }
+    Ok(Some((name, data)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bundle::{read_bundle, write_bundle, BundleInfo};
+    use crate::index::DigestIndex;
+    use crate::HashAlgorithm;
+
+    #[test]
+    fn test_bundle() {
+        let mut index = DigestIndex::new(HashAlgorithm::Crc32, 256);
+        for i in 0..10 {
+            let data = format!("THIS IS A TEST SEQUENCE {}", i).repeat(20);
+            let dict = HashAlgorithm::Crc32
+                .digest_reader_with_k(data.as_bytes(), 256)
+                .unwrap();
+            index.insert(format!("seq{}", i), dict);
+        }
+        let mut info = BundleInfo::new(&index, "lzjd 0.2.0");
+        info.extra.insert("params".into(), "crc32,k=256".into());
+        let bundle = write_bundle(&index, &info, vec![]).unwrap();
+
+        let (read_info, read_index) = read_bundle(&bundle[..]).unwrap();
+        assert_eq!(read_info, info);
+        assert_eq!(read_index.len(), 10);
+        for id in 0..10 {
+            assert_eq!(read_index.label(id), index.label(id));
+            assert_eq!(read_index.dict(id)[..], index.dict(id)[..]);
+        }
+        assert_eq!(read_index.search(index.dict(3), 0.9)[0].0, 3);
+
+        // A flipped bit in any section is detected
+        for position in [30, bundle.len() - 1] {
+            let mut damaged = bundle.clone();
+            damaged[position] ^= 1;
+            let err = read_bundle(&damaged[..]).unwrap_err().to_string();
+            assert!(err.contains("checksum"), "{}", err);
+        }
+        for len in [4, 40, bundle.len() - 1] {
+            assert!(read_bundle(&bundle[..len]).is_err());
+        }
+        // Damaged lengths fail without allocating what they say
+        let mut damaged = bundle.clone();
+        damaged[11] = 0xff;
+        let err = read_bundle(&damaged[..]).unwrap_err().to_string();
+        assert!(err.contains("too long"), "{}", err);
+        let mut damaged = bundle.clone();
+        let data_len = 8 + 4 + "metadata".len();
+        damaged[data_len + 7] = 0xff;
+        let err = read_bundle(&damaged[..]).unwrap_err().to_string();
+        assert!(err.contains("middle of a section"), "{}", err);
+        assert!(read_bundle(&b"not a bundle"[..]).is_err());
+    }
+}
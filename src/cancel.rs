@@ -0,0 +1,37 @@
+//! Defines a token used to cooperatively cancel long-running digest
+//! and comparison jobs from another thread.
+use crate::{LZJDError, Result};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Shared cancellation flag. Clones refer to the same flag, so one clone
+/// can be handed to a job while another is kept to cancel it.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests cancellation of every job holding a clone of this token.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Returns LZJDError::Cancelled if cancellation was requested.
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(LZJDError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Client of the HTTP endpoints of `lzjd serve`, for digesting and
+//! searching against a centrally maintained store.
+use crate::store::DigestId;
+use crate::{HashAlgorithm, LZDict, LZJDError, Result};
+
+use serde_json::Value;
+use std::convert::TryFrom;
+use std::io::Read;
+
+/// Connection to the HTTP endpoints of an lzjd server
+#[derive(Clone)]
+pub struct Client {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl Client {
+    /// Creates a client of the server at url, like `http://127.0.0.1:8080`
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_owned(),
+            agent: ureq::Agent::new(),
+        }
+    }
+
+    /// Has the server digest all data read from reader and store it under label.
+    /// Returns the digest along with the algorithm and k the server digests with.
+    pub fn digest<R: Read>(
+        &self,
+        label: &str,
+        reader: R,
+    ) -> Result<(HashAlgorithm, usize, LZDict)> {
+        let request = self
+            .agent
+            .post(&format!("{}/digest", self.url))
+            .query("label", label);
+        let reply = self.send(request, reader)?;
+        let algorithm = field(&reply, "algorithm", Value::as_str)?.parse()?;
+        let k = usize::try_from(field(&reply, "k", Value::as_u64)?)
+            .map_err(|_| invalid_reply("k to fit in usize"))?;
+        let dict = LZDict::from_base64_string(field(&reply, "digest", Value::as_str)?)
+            .map_err(|e| e.for_digest(label))?;
+        Ok((algorithm, k, dict))
+    }
+
+    /// Has the server digest all data read from reader and returns the stored digests
+    /// of which the similarity is at least threshold, at most top of them if given
+    pub fn search<R: Read>(
+        &self,
+        reader: R,
+        threshold: f64,
+        top: Option<usize>,
+    ) -> Result<Vec<(DigestId, String, f64)>> {
+        let mut request = self
+            .agent
+            .post(&format!("{}/search", self.url))
+            .query("threshold", &threshold.to_string());
+        if let Some(top) = top {
+            request = request.query("top", &top.to_string());
+        }
+        let reply = self.send(request, reader)?;
+        let hits = reply
+            .as_array()
+            .ok_or_else(|| invalid_reply("search results"))?;
+        hits.iter()
+            .map(|hit| {
+                Ok((
+                    field(hit, "id", Value::as_u64)? as DigestId,
+                    field(hit, "label", Value::as_str)?.to_owned(),
+                    field(hit, "similarity", Value::as_f64)?,
+                ))
+            })
+            .collect()
+    }
+
+    fn send<R: Read>(&self, request: ureq::Request, reader: R) -> Result<Value> {
+        let response = match request.send(reader) {
+            Ok(response) => response,
+            Err(ureq::Error::Status(status, response)) => {
+                // The server describes what went wrong in an error field
+                let reason = response
+                    .into_string()
+                    .ok()
+                    .and_then(|body| serde_json::from_str::<Value>(&body).ok())
+                    .and_then(|body| body["error"].as_str().map(str::to_owned))
+                    .unwrap_or_else(|| format!("status {}", status));
+                return Err(LZJDError::Msg {
+                    msg: format!("Server at {} refused request: {}", self.url, reason),
+                });
+            }
+            Err(e) => {
+                return Err(LZJDError::Msg {
+                    msg: format!("Could not reach server at {}: {}", self.url, e),
+                })
+            }
+        };
+        serde_json::from_str(&response.into_string()?).map_err(|_| invalid_reply("JSON"))
+    }
+}
+
+/// Extracts the field called name from reply using get
+fn field<'a, T>(reply: &'a Value, name: &str, get: fn(&'a Value) -> Option<T>) -> Result<T> {
+    get(&reply[name]).ok_or_else(|| invalid_reply(name))
+}
+
+fn invalid_reply(expected: &str) -> LZJDError {
+    LZJDError::Msg {
+        msg: format!("Invalid reply from server, expected {}", expected),
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use crate::client::Client;
+    use crate::server::serve_listener;
+    use crate::service::Service;
+    use crate::store::DigestStore;
+    use crate::HashAlgorithm;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_client() {
+        let service = Arc::new(Service::new(
+            DigestStore::with_shards(1),
+            HashAlgorithm::Murmur3,
+            1024,
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Arc::clone(&service);
+        thread::spawn(move || serve_listener(server, listener, 2));
+        let client = Client::new(&format!("http://{}/", addr));
+
+        let data = &b"THIS IS A TEST SEQUENCE"[..];
+        let (algorithm, k, dict) = client.digest("a b", data).unwrap();
+        assert_eq!((algorithm, k), (HashAlgorithm::Murmur3, 1024));
+        assert_eq!(*dict, *service.digest(data).unwrap());
+        client
+            .digest("other", &b"SOMETHING ELSE ENTIRELY"[..])
+            .unwrap();
+
+        let hits = client.search(data, 0.5, None).unwrap();
+        assert_eq!(hits, service.search(data, 0.5, None).unwrap());
+        assert_eq!(hits, [(0, "a b".to_owned(), 1.)]);
+        assert_eq!(client.search(data, 0., Some(1)).unwrap().len(), 1);
+
+        // The reason the server gives is passed on
+        let err = client.digest("a\nb", data).unwrap_err().to_string();
+        assert!(err.contains("refused request"), "{}", err);
+        let err = client.search(data, 2., None).unwrap_err().to_string();
+        assert!(err.contains("refused request"), "{}", err);
+    }
+
+    #[test]
+    fn test_unreachable() {
+        // Nothing listens on the port once the listener is dropped
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let client = Client::new(&format!("http://{}", addr));
+        let err = client.digest("a", &b"data"[..]).unwrap_err().to_string();
+        assert!(err.contains("Could not reach server"), "{}", err);
+        assert!(client.search(&b"data"[..], 0.5, None).is_err());
+    }
+}
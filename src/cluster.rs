@@ -0,0 +1,213 @@
+//! Grouping of digests into clusters of similar digests.
+//!
+//! connected_components groups a complete set of digests at once, while
+//! StreamingClusters assigns digests to clusters as they arrive, for inputs that
+//! never end, like the digests of a triage pipeline.
+use crate::index::{DigestIndex, IndexId};
+use crate::{HashAlgorithm, LZDict};
+
+/// Disjoint-set forest over the indices 0..n
+struct UnionFind {
+    parents: Vec<usize>,
+}
+
+impl UnionFind {
+    fn new(n: usize) -> Self {
+        Self {
+            parents: (0..n).collect(),
+        }
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.parents[i] != i {
+            // Path halving keeps the trees shallow
+            self.parents[i] = self.parents[self.parents[i]];
+            i = self.parents[i];
+        }
+        i
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        // The smallest index becomes the root, so that roots are stable
+        if a < b {
+            self.parents[b] = a;
+        } else {
+            self.parents[a] = b;
+        }
+    }
+}
+
+/// Returns the connected components of the graph of n nodes with the given edges,
+/// as lists of node indices in increasing order. Components are ordered by
+/// decreasing size, and components of the same size by their first index.
+/// Nodes without edges form components of their own.
+pub fn connected_components<I>(n: usize, edges: I) -> Vec<Vec<usize>>
+where
+    I: IntoIterator<Item = (usize, usize)>,
+{
+    let mut sets = UnionFind::new(n);
+    for (a, b) in edges {
+        sets.union(a, b);
+    }
+
+    let mut components: Vec<Vec<usize>> = vec![];
+    // Index in components of the component of each root
+    let mut component_of: Vec<Option<usize>> = vec![None; n];
+    for i in 0..n {
+        let root = sets.find(i);
+        match component_of[root] {
+            Some(c) => components[c].push(i),
+            None => {
+                component_of[root] = Some(components.len());
+                components.push(vec![i]);
+            }
+        }
+    }
+    // Stable, so components of the same size keep their order
+    components.sort_by_key(|component| std::cmp::Reverse(component.len()));
+    components
+}
+
+/// Identifier of a cluster of StreamingClusters, which counts the clusters in the order they were created
+pub type ClusterId = u32;
+
+/// Cluster that StreamingClusters::assign assigned a digest to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Assignment {
+    pub cluster: ClusterId,
+    /// Similarity of the digest to the representative of the cluster, which is 1
+    /// for digests that created a new cluster
+    pub similarity: f64,
+    /// Whether the digest created the cluster, and is its representative
+    pub new: bool,
+}
+
+/// Online clustering of digests: each digest joins the cluster of the representative
+/// it is most similar to, if that similarity is at least the threshold, and otherwise
+/// creates a new cluster of which it is the representative. Digests are never moved to
+/// another cluster later, so the clusters depend on the order of the digests, unlike
+/// those of connected_components. Representatives are kept in a DigestIndex, so that
+/// assigning a digest does not compare it to every cluster.
+///
+/// ```
+/// use lzjd::cluster::StreamingClusters;
+/// use lzjd::HashAlgorithm;
+///
+/// let mut clusters = StreamingClusters::new(HashAlgorithm::Murmur3, 1024, 0.5);
+/// let a = HashAlgorithm::Murmur3.digest_reader(&b"THIS IS A TEST SEQUENCE"[..]).unwrap();
+/// assert!(clusters.assign("a", a.clone()).new);
+/// assert_eq!(clusters.assign("copy of a", a).cluster, 0);
+/// assert_eq!(clusters.size(0), 2);
+/// ```
+#[derive(Debug)]
+pub struct StreamingClusters {
+    threshold: f64,
+    /// Representatives, of which the id is that of their cluster
+    representatives: DigestIndex,
+    sizes: Vec<u64>,
+}
+
+impl StreamingClusters {
+    /// Creates clusters of digests created with algorithm, keeping k hashes, which
+    /// join a cluster if their similarity to its representative is at least threshold
+    pub fn new(algorithm: HashAlgorithm, k: usize, threshold: f64) -> Self {
+        Self {
+            threshold,
+            representatives: DigestIndex::new(algorithm, k),
+            sizes: vec![],
+        }
+    }
+
+    pub fn threshold(&self) -> f64 {
+        self.threshold
+    }
+
+    /// Assigns a digest to a cluster, creating a new cluster if it is not similar enough
+    /// to any representative. Of equally similar representatives, the oldest is chosen.
+    pub fn assign<S: Into<String>>(&mut self, label: S, dict: LZDict) -> Assignment {
+        // Hits are sorted by similarity, and representatives of the same similarity by id
+        if let Some(&(id, similarity)) = self.representatives.search(&dict, self.threshold).first()
+        {
+            self.sizes[id as usize] += 1;
+            return Assignment {
+                cluster: id,
+                similarity,
+                new: false,
+            };
+        }
+        let id: IndexId = self.representatives.insert(label, dict);
+        self.sizes.push(1);
+        Assignment {
+            cluster: id,
+            similarity: 1.,
+            new: true,
+        }
+    }
+
+    /// Number of clusters
+    pub fn len(&self) -> usize {
+        self.sizes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sizes.is_empty()
+    }
+
+    /// Number of digests assigned to cluster, including its representative
+    pub fn size(&self, cluster: ClusterId) -> u64 {
+        self.sizes[cluster as usize]
+    }
+
+    /// Label of the representative of cluster
+    pub fn representative(&self, cluster: ClusterId) -> &str {
+        self.representatives.label(cluster)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cluster::{connected_components, StreamingClusters};
+    use crate::{HashAlgorithm, LZDict};
+
+    #[test]
+    fn test_connected_components() {
+        let components = connected_components(7, vec![(5, 6), (1, 3), (3, 4), (0, 6)]);
+        assert_eq!(components, vec![vec![0, 5, 6], vec![1, 3, 4], vec![2]]);
+
+        assert_eq!(
+            connected_components(3, vec![]),
+            vec![vec![0], vec![1], vec![2]]
+        );
+        assert!(connected_components(0, vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_streaming_clusters() {
+        let digest = |data: String| {
+            HashAlgorithm::Murmur3
+                .digest_reader(data.as_bytes())
+                .unwrap()
+        };
+        let base = |seed: u32| -> String {
+            (0..2000u32)
+                .map(|i| format!("{:x}", (i ^ seed).wrapping_mul(2654435761)))
+                .collect()
+        };
+        let mut clusters = StreamingClusters::new(HashAlgorithm::Murmur3, 1024, 0.5);
+        let a = clusters.assign("a", digest(base(1)));
+        assert_eq!((a.cluster, a.new, a.similarity), (0, true, 1.));
+        let b = clusters.assign("b", digest(base(2)));
+        assert_eq!((b.cluster, b.new), (1, true));
+        let similar: LZDict = digest(base(2) + "some more data at the end");
+        let c = clusters.assign("c", similar);
+        assert_eq!((c.cluster, c.new), (1, false));
+        assert!(c.similarity >= 0.5 && c.similarity < 1.);
+        let d = clusters.assign("d", digest(base(1)));
+        assert_eq!((d.cluster, d.new, d.similarity), (0, false, 1.));
+
+        assert_eq!(clusters.len(), 2);
+        assert_eq!((clusters.size(0), clusters.size(1)), (2, 2));
+        assert_eq!(clusters.representative(1), "b");
+    }
+}
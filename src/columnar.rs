@@ -0,0 +1,329 @@
+//! Writing of matches and digests as Arrow record batches and Parquet files, for
+//! analytics tools like DuckDB or Spark.
+//!
+//! Matches have the columns `a`, `b` and `similarity`. Digests have the columns `name`
+//! and `hashes`, a list of the hashes of the digest, followed by a column of strings
+//! for every key in the meta of any of the digests, which is null for digests without it.
+//!
+//! ```no_run
+//! use lzjd::columnar::MatchWriter;
+//! use lzjd::Match;
+//! use std::fs::File;
+//!
+//! let mut writer = MatchWriter::new(File::create("matches.parquet").unwrap()).unwrap();
+//! let m = Match { a: "a.bin".into(), b: "b.bin".into(), similarity: 0.8 };
+//! writer.write(&m).unwrap();
+//! writer.finish().unwrap();
+//! ```
+use crate::{DigestRecord, Match, Result};
+
+use arrow_array::builder::{
+    ArrayBuilder, Float64Builder, Int32Builder, ListBuilder, StringBuilder,
+};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::sync::Arc;
+
+/// Number of rows of the record batches passed to Parquet writers
+pub const BATCH_SIZE: usize = 8192;
+
+/// Schema of matches, with the similarities in the column score_column
+pub fn match_schema(score_column: &str) -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("a", DataType::Utf8, false),
+        Field::new("b", DataType::Utf8, false),
+        Field::new(score_column, DataType::Float64, false),
+    ]))
+}
+
+/// Schema of digests with the given meta keys
+pub fn digest_schema<'a, I: IntoIterator<Item = &'a str>>(meta_keys: I) -> SchemaRef {
+    let mut fields = vec![
+        Field::new("name", DataType::Utf8, false),
+        Field::new("hashes", DataType::List(Arc::new(hash_field())), false),
+    ];
+    fields.extend(
+        meta_keys
+            .into_iter()
+            .map(|key| Field::new(key, DataType::Utf8, true)),
+    );
+    Arc::new(Schema::new(fields))
+}
+
+fn hash_field() -> Field {
+    Field::new("item", DataType::Int32, false)
+}
+
+/// Converts matches into a record batch with the schema of match_schema("similarity")
+pub fn matches_to_batch(matches: &[Match]) -> Result<RecordBatch> {
+    let mut columns = MatchColumns::default();
+    matches.iter().for_each(|m| columns.append(m));
+    columns.finish(match_schema("similarity"))
+}
+
+/// Converts digests into a record batch with the schema of digest_schema, with
+/// the keys of the meta of all digests in sorted order
+pub fn digests_to_batch(records: &[DigestRecord]) -> Result<RecordBatch> {
+    digests_to_batch_with(
+        records,
+        digest_schema(meta_keys(records).iter().map(String::as_str)),
+    )
+}
+
+/// Sorted keys of the meta of all records
+fn meta_keys(records: &[DigestRecord]) -> BTreeSet<String> {
+    records
+        .iter()
+        .flat_map(|record| record.meta.keys().cloned())
+        .collect()
+}
+
+fn digests_to_batch_with(records: &[DigestRecord], schema: SchemaRef) -> Result<RecordBatch> {
+    let mut names = StringBuilder::new();
+    let mut hashes = ListBuilder::new(Int32Builder::new()).with_field(hash_field());
+    for record in records {
+        names.append_value(&record.label);
+        hashes.values().append_slice(&record.dict);
+        hashes.append(true);
+    }
+    let mut columns: Vec<ArrayRef> = vec![Arc::new(names.finish()), Arc::new(hashes.finish())];
+    for field in &schema.fields()[2..] {
+        let mut values = StringBuilder::new();
+        for record in records {
+            values.append_option(record.meta.get(field.name()));
+        }
+        columns.push(Arc::new(values.finish()));
+    }
+    Ok(RecordBatch::try_new(schema, columns)?)
+}
+
+/// Writes digests as a Parquet file to writer, with metadata as key-value metadata
+/// of the file, like the settings the digests were created with. Returns writer.
+pub fn write_digests<W: Write + Send>(
+    records: &[DigestRecord],
+    metadata: &BTreeMap<String, String>,
+    writer: W,
+) -> Result<W> {
+    let schema = digest_schema(meta_keys(records).iter().map(String::as_str));
+    let mut writer = ArrowWriter::try_new(writer, schema.clone(), Some(writer_properties()))?;
+    for (key, value) in metadata {
+        writer.append_key_value_metadata(parquet::file::metadata::KeyValue::new(
+            key.clone(),
+            value.clone(),
+        ));
+    }
+    for chunk in records.chunks(BATCH_SIZE) {
+        writer.write(&digests_to_batch_with(chunk, schema.clone())?)?;
+    }
+    // Writes the footer of the file
+    Ok(writer.into_inner()?)
+}
+
+fn writer_properties() -> WriterProperties {
+    WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build()
+}
+
+/// Columns of matches being built
+#[derive(Default)]
+struct MatchColumns {
+    a: StringBuilder,
+    b: StringBuilder,
+    similarity: Float64Builder,
+}
+
+impl MatchColumns {
+    fn append(&mut self, m: &Match) {
+        self.a.append_value(&m.a);
+        self.b.append_value(&m.b);
+        self.similarity.append_value(m.similarity);
+    }
+
+    fn len(&self) -> usize {
+        self.similarity.len()
+    }
+
+    fn finish(&mut self, schema: SchemaRef) -> Result<RecordBatch> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.a.finish()),
+            Arc::new(self.b.finish()),
+            Arc::new(self.similarity.finish()),
+        ];
+        Ok(RecordBatch::try_new(schema, columns)?)
+    }
+}
+
+/// Writes matches as a Parquet file, in batches of BATCH_SIZE, so that runs need not
+/// hold all matches in memory. The file is only complete once finish is called.
+pub struct MatchWriter<W: Write + Send> {
+    writer: ArrowWriter<W>,
+    schema: SchemaRef,
+    columns: MatchColumns,
+}
+
+impl<W: Write + Send> MatchWriter<W> {
+    /// Creates a writer of matches to writer
+    pub fn new(writer: W) -> Result<Self> {
+        Self::with_score_column(writer, "similarity")
+    }
+
+    /// Like new, but names the column of the similarities score_column, for
+    /// callers that write another score into it, like distances
+    pub fn with_score_column(writer: W, score_column: &str) -> Result<Self> {
+        let schema = match_schema(score_column);
+        Ok(Self {
+            writer: ArrowWriter::try_new(writer, schema.clone(), Some(writer_properties()))?,
+            schema,
+            columns: MatchColumns::default(),
+        })
+    }
+
+    /// Writes a match
+    pub fn write(&mut self, m: &Match) -> Result<()> {
+        self.columns.append(m);
+        if self.columns.len() >= BATCH_SIZE {
+            self.write_batch()?;
+        }
+        Ok(())
+    }
+
+    fn write_batch(&mut self) -> Result<()> {
+        let batch = self.columns.finish(self.schema.clone())?;
+        Ok(self.writer.write(&batch)?)
+    }
+
+    /// Returns the underlying writer. The bytes written to it so far may be taken
+    /// from it, like from a Vec<u8> that is passed on as it fills, but nothing
+    /// else may be written to it.
+    pub fn inner_mut(&mut self) -> &mut W {
+        self.writer.inner_mut()
+    }
+
+    /// Writes the remaining matches and the footer of the file, and returns the underlying writer
+    pub fn finish(mut self) -> Result<W> {
+        if self.columns.len() > 0 {
+            self.write_batch()?;
+        }
+        Ok(self.writer.into_inner()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::columnar::{digests_to_batch, write_digests, MatchWriter, BATCH_SIZE};
+    use crate::{DigestRecord, HashAlgorithm, Match};
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Float64Type, Int32Type};
+    use arrow_array::{Array, RecordBatch};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use std::collections::BTreeMap;
+    use std::fs::{self, File};
+
+    /// Reads back the Parquet file written into bytes, through a file as the reader needs one
+    fn read_parquet(bytes: &[u8], name: &str) -> (Vec<RecordBatch>, Option<String>) {
+        let path =
+            std::env::temp_dir().join(format!("lzjd-test-{}-{}.parquet", name, std::process::id()));
+        fs::write(&path, bytes).unwrap();
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap()).unwrap();
+        let params = builder
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()
+            .and_then(|metadata| metadata.iter().find(|kv| kv.key == "params"))
+            .and_then(|kv| kv.value.clone());
+        let batches = builder
+            .build()
+            .unwrap()
+            .map(|batch| batch.unwrap())
+            .collect();
+        fs::remove_file(&path).unwrap();
+        (batches, params)
+    }
+
+    #[test]
+    fn test_match_writer() {
+        let matches: Vec<_> = (0..BATCH_SIZE + 10)
+            .map(|i| Match {
+                a: format!("a{}", i),
+                b: format!("b,|{}", i),
+                similarity: i as f64 / 10_000.,
+            })
+            .collect();
+        let mut writer = MatchWriter::new(vec![]).unwrap();
+        let mut bytes = vec![];
+        for m in &matches {
+            writer.write(m).unwrap();
+            // Taking what was written so far leaves a valid file
+            bytes.append(writer.inner_mut());
+        }
+        bytes.extend(writer.finish().unwrap());
+
+        let (batches, _) = read_parquet(&bytes, "matches");
+        let read: Vec<_> = batches
+            .iter()
+            .flat_map(|batch| {
+                let a = batch.column_by_name("a").unwrap().as_string::<i32>();
+                let b = batch.column_by_name("b").unwrap().as_string::<i32>();
+                let similarity = batch
+                    .column_by_name("similarity")
+                    .unwrap()
+                    .as_primitive::<Float64Type>();
+                (0..batch.num_rows())
+                    .map(|i| Match {
+                        a: a.value(i).to_owned(),
+                        b: b.value(i).to_owned(),
+                        similarity: similarity.value(i),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(read, matches);
+    }
+
+    #[test]
+    fn test_write_digests() {
+        let mut records: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|&label| {
+                let data = label.repeat(100) + "THIS IS A TEST SEQUENCE";
+                DigestRecord::new(
+                    label,
+                    HashAlgorithm::Murmur3
+                        .digest_reader(data.as_bytes())
+                        .unwrap(),
+                )
+            })
+            .collect();
+        records[1].meta.insert("source".into(), "disk".into());
+        assert_eq!(digests_to_batch(&records).unwrap().num_columns(), 3);
+
+        let metadata: BTreeMap<_, _> = vec![("params".to_owned(), "murmur3".to_owned())]
+            .into_iter()
+            .collect();
+        let bytes = write_digests(&records, &metadata, vec![]).unwrap();
+        let (batches, params) = read_parquet(&bytes, "digests");
+        assert_eq!(params.as_deref(), Some("murmur3"));
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        let names = batch.column_by_name("name").unwrap().as_string::<i32>();
+        let hashes = batch.column_by_name("hashes").unwrap().as_list::<i32>();
+        let source = batch.column_by_name("source").unwrap().as_string::<i32>();
+        for (i, record) in records.iter().enumerate() {
+            assert_eq!(names.value(i), record.label);
+            let list = hashes.value(i);
+            assert_eq!(
+                list.as_primitive::<Int32Type>().values().to_vec(),
+                record.dict.to_vec()
+            );
+            assert_eq!(source.is_null(i), i != 1);
+        }
+        assert_eq!(source.value(1), "disk");
+    }
+}
@@ -0,0 +1,231 @@
+//! Comparison of digests with the settings of a service fixed up front, for callers that
+//! compare very many pairs. The intersection routine is chosen for the CPU once, and
+//! matches are collected in a buffer that is reused between queries.
+use crate::lz_dict::{intersection_len, GALLOP_RATIO};
+use crate::LZDict;
+
+/// Value that a Comparator reports for each pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Metric {
+    /// LZJD similarity, where pairs pass if it is at least the threshold
+    #[default]
+    Similarity,
+    /// LZJD distance, where pairs pass if it is at most the threshold
+    Distance,
+}
+
+/// Compares digests by metric, leaving out pairs that do not pass threshold
+///
+/// ```
+/// # use lzjd::comparator::{Comparator, Metric};
+/// # use lzjd::LZDict;
+/// let mut comparator = Comparator::new(Metric::Similarity, 0.5);
+/// let a = LZDict::from(vec![1, 2, 3, 4]);
+/// let b = LZDict::from(vec![1, 2, 3, 5]);
+/// assert_eq!(comparator.compare(&a, &b), Some(0.6));
+/// assert_eq!(comparator.matches(&a, [&b, &LZDict::from(vec![6])]), &[(0, 0.6)]);
+/// ```
+pub struct Comparator {
+    metric: Metric,
+    threshold: f64,
+    /// Counts the hashes that two digests share, the fastest way this CPU supports
+    intersection_len: fn(&[i32], &[i32]) -> usize,
+    /// Indices and scores of the matches of the last query
+    matches: Vec<(usize, f64)>,
+}
+
+impl Comparator {
+    /// Creates a comparator that reports metric for the pairs that pass threshold
+    pub fn new(metric: Metric, threshold: f64) -> Self {
+        Self {
+            metric,
+            threshold,
+            intersection_len: select_intersection_len(),
+            matches: Vec::new(),
+        }
+    }
+
+    pub fn metric(&self) -> Metric {
+        self.metric
+    }
+
+    pub fn threshold(&self) -> f64 {
+        self.threshold
+    }
+
+    /// Returns the metric of a and b, whether or not it passes the threshold
+    pub fn score(&self, a: &LZDict, b: &LZDict) -> f64 {
+        self.score_of((self.intersection_len)(a, b), a.len(), b.len())
+    }
+
+    /// Returns the metric of two digests of a_len and b_len hashes that share intersection
+    fn score_of(&self, intersection: usize, a_len: usize, b_len: usize) -> f64 {
+        // Computed like LZDict::jaccard_similarity, so that both give the same results
+        let similarity = intersection as f64 / (a_len + b_len - intersection) as f64;
+        match self.metric {
+            Metric::Similarity => similarity,
+            Metric::Distance => 1. - similarity,
+        }
+    }
+
+    fn passes(&self, score: f64) -> bool {
+        match self.metric {
+            Metric::Similarity => score >= self.threshold,
+            Metric::Distance => score <= self.threshold,
+        }
+    }
+
+    /// Returns the metric of a and b if it passes the threshold. Pairs of which the
+    /// lengths alone rule out the threshold are not intersected.
+    pub fn compare(&self, a: &LZDict, b: &LZDict) -> Option<f64> {
+        // The best score the lengths allow, of a pair where all hashes of the shorter
+        // digest are in the longer one, rounded exactly like the score itself
+        let short = a.len().min(b.len());
+        if short < a.len().max(b.len()) && !self.passes(self.score_of(short, a.len(), b.len())) {
+            return None;
+        }
+        let score = self.score(a, b);
+        self.passes(score).then_some(score)
+    }
+
+    /// Compares query with each of dicts, and returns the index in dicts and metric
+    /// of those that pass the threshold, in order. The returned slice is
+    /// overwritten by the next call, which reuses its memory.
+    pub fn matches<'a, I>(&mut self, query: &LZDict, dicts: I) -> &[(usize, f64)]
+    where
+        I: IntoIterator<Item = &'a LZDict>,
+    {
+        self.matches.clear();
+        for (i, dict) in dicts.into_iter().enumerate() {
+            if let Some(score) = self.compare(query, dict) {
+                self.matches.push((i, score));
+            }
+        }
+        &self.matches
+    }
+}
+
+/// Returns the fastest routine to count the hashes two digests share on this CPU
+fn select_intersection_len() -> fn(&[i32], &[i32]) -> usize {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            return avx2_intersection_len;
+        }
+    }
+    intersection_len
+}
+
+/// Counts the entries that a and b share like intersection_len, comparing blocks
+/// of 8 entries of both at once. Must only be selected if the CPU supports AVX2.
+#[cfg(target_arch = "x86_64")]
+fn avx2_intersection_len(a: &[i32], b: &[i32]) -> usize {
+    let (small, large) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    if small.len() * GALLOP_RATIO <= large.len() {
+        return intersection_len(small, large);
+    }
+    // Safety: select_intersection_len only selects this function if AVX2 is supported
+    unsafe { avx2::intersection_len(small, large) }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+    use crate::lz_dict::merge_intersection_len;
+    use std::arch::x86_64::*;
+
+    /// Counts the entries that the sorted and unique a and b share, by comparing every
+    /// entry of a block of 8 entries of a with a block of 8 entries of b at once, and then
+    /// moving on from the block that ends lower, or both if they end at the same entry.
+    /// Every pair of blocks is compared at most once, so no shared entry is counted twice.
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn intersection_len(a: &[i32], b: &[i32]) -> usize {
+        let (mut i, mut j, mut len) = (0, 0, 0);
+        while i + 8 <= a.len() && j + 8 <= b.len() {
+            let block_b = _mm256_loadu_si256(b.as_ptr().add(j) as *const __m256i);
+            let mut shared = _mm256_setzero_si256();
+            for &entry in &a[i..i + 8] {
+                let equal = _mm256_cmpeq_epi32(_mm256_set1_epi32(entry), block_b);
+                shared = _mm256_or_si256(shared, equal);
+            }
+            len += _mm256_movemask_ps(_mm256_castsi256_ps(shared)).count_ones() as usize;
+            let (last_a, last_b) = (a[i + 7], b[j + 7]);
+            if last_a <= last_b {
+                i += 8;
+            }
+            if last_a >= last_b {
+                j += 8;
+            }
+        }
+        len + merge_intersection_len(&a[i..], &b[j..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::comparator::{select_intersection_len, Comparator, Metric};
+    use crate::lz_dict::intersection_len;
+    use crate::LZDict;
+
+    #[test]
+    fn test_intersection_len() {
+        // xorshift64, for hashes spread like those of real digests
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut hashes = |n: usize, modulus: i64| -> Vec<i32> {
+            let mut hashes: Vec<i32> = (0..n)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    ((state >> 32) as i64 % modulus) as i32
+                })
+                .collect();
+            hashes.sort_unstable();
+            hashes.dedup();
+            hashes
+        };
+        let selected = select_intersection_len();
+        for (n_a, n_b, modulus) in [
+            (1024, 1024, 4096),
+            (1000, 37, 200),
+            (0, 10, 10),
+            (13, 1024, 1 << 40),
+            (1024, 700, 2000),
+        ] {
+            let (a, b) = (hashes(n_a, modulus), hashes(n_b, modulus));
+            assert_eq!(selected(&a, &b), intersection_len(&a, &b));
+            assert_eq!(selected(&b, &a), intersection_len(&a, &b));
+            assert_eq!(selected(&a, &a), a.len());
+        }
+    }
+
+    #[test]
+    fn test_comparator() {
+        let a = LZDict::from(vec![1, 2, 3, 4]);
+        let b = LZDict::from(vec![1, 2, 3, 5]);
+        let small = LZDict::from(vec![1]);
+
+        let mut comparator = Comparator::new(Metric::Distance, 0.5);
+        assert_eq!(comparator.compare(&a, &b), Some(1. - a.similarity(&b)));
+        // Ruled out by the lengths alone
+        assert_eq!(comparator.compare(&a, &small), None);
+        assert_eq!(comparator.score(&a, &small), 1. - a.similarity(&small));
+        assert_eq!(
+            comparator.matches(&a, [&small, &b, &a]).to_vec(),
+            vec![(1, 1. - 0.6), (2, 0.)]
+        );
+        assert!(comparator.matches(&small, [&a, &b]).is_empty());
+
+        // Exactly at the threshold, where 1. - 0.7 is not exactly 0.3
+        let comparator = Comparator::new(Metric::Distance, 0.7);
+        let long = LZDict::from((1..=10).collect::<Vec<_>>());
+        assert_eq!(
+            comparator.compare(&LZDict::from(vec![1, 2, 3]), &long),
+            Some(0.7)
+        );
+
+        let comparator = Comparator::new(Metric::Similarity, 0.);
+        let empty = LZDict::from(vec![]);
+        assert_eq!(comparator.compare(&a, &small), Some(0.25));
+        assert_eq!(comparator.compare(&empty, &a), Some(0.));
+    }
+}
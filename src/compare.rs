@@ -0,0 +1,668 @@
+//! Batch comparison of labeled LZ dictionaries.
+use crate::cancel::CancellationToken;
+use crate::progress::Progress;
+use crate::signature::Signature;
+use crate::{DigestRecord, Match, Result};
+
+use rayon::prelude::*;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::Mutex;
+
+/// Number of pairs compare_pairs compares between two cancellation checks or progress reports
+const PAIRS_CHUNK_SIZE: usize = 1024;
+
+/// Number of rows of which the matches are passed on at once when they are
+/// streamed, which bounds the matches held in memory to those of this many rows
+pub const BATCH_ROWS: usize = 256;
+
+/// Compares every dictionary in dicts_a with every dictionary in dicts_b
+/// in parallel and returns the label pairs of which the similarity
+/// is at least threshold, along with that similarity.
+/// If both slices point to the same object, only the above-diagonal
+/// elements of the comparison matrix are computed. The pairs are ordered by
+/// their index in dicts_a and then in dicts_b, however the work is scheduled.
+/// The job is aborted with LZJDError::Cancelled as soon as possible
+/// after cancel is cancelled. If given, progress is called with the
+/// number of pairs compared after each row of the matrix.
+pub fn compare_all(
+    dicts_a: &[DigestRecord],
+    dicts_b: &[DigestRecord],
+    threshold: f64,
+    cancel: Option<&CancellationToken>,
+    progress: Option<&mut (dyn FnMut(Progress) + Send)>,
+) -> Result<Vec<Match>> {
+    let mut matches = vec![];
+    compare_all_in_batches(
+        dicts_a,
+        dicts_b,
+        threshold,
+        dicts_a.len(),
+        cancel,
+        progress,
+        &mut |mut batch| {
+            matches.append(&mut batch);
+            Ok(())
+        },
+    )?;
+    Ok(matches)
+}
+
+/// Like compare_all, but rather than returning the matches, passes those of every
+/// batch_rows rows of dicts_a to sink as soon as the rows are compared, so that
+/// they can be written out while the next rows are compared. The batches are
+/// passed in order. If sink fails, the comparison stops with its error.
+pub fn compare_all_in_batches(
+    dicts_a: &[DigestRecord],
+    dicts_b: &[DigestRecord],
+    threshold: f64,
+    batch_rows: usize,
+    cancel: Option<&CancellationToken>,
+    progress: Option<&mut (dyn FnMut(Progress) + Send)>,
+    sink: &mut dyn FnMut(Vec<Match>) -> Result<()>,
+) -> Result<()> {
+    let same = std::ptr::eq(dicts_a, dicts_b);
+    let total = if same {
+        dicts_a.len() * dicts_a.len().saturating_sub(1) / 2
+    } else {
+        dicts_a.len() * dicts_b.len()
+    } as u64;
+    let prefilter = Prefilter::new(dicts_a, dicts_b, threshold);
+    let row = |i, a: &DigestRecord, v: &mut Vec<Match>| {
+        let j_start = if same { i + 1 } else { 0 };
+        dicts_b
+            .iter()
+            .enumerate()
+            .skip(j_start)
+            .filter(|&(j, _)| may_match(&prefilter, i, j, threshold))
+            .for_each(|(_, b)| {
+                let similarity = a.dict.similarity(&b.dict);
+                if similarity >= threshold {
+                    v.push(Match {
+                        a: a.label.to_owned(),
+                        b: b.label.to_owned(),
+                        similarity,
+                    });
+                }
+            });
+        dicts_b.len().saturating_sub(j_start) as u64
+    };
+    compare_rows_in_batches(dicts_a, batch_rows, total, cancel, progress, row, sink)
+}
+
+/// Like compare_all with both slices pointing to dicts, but returns
+/// the indices of the similar dictionaries rather than their labels.
+/// In every pair, the first index is smaller than the second.
+pub fn similar_pairs(
+    dicts: &[DigestRecord],
+    threshold: f64,
+    cancel: Option<&CancellationToken>,
+    progress: Option<&mut (dyn FnMut(Progress) + Send)>,
+) -> Result<Vec<(usize, usize, f64)>> {
+    similar_pairs_between(dicts, dicts, threshold, cancel, progress)
+}
+
+/// Like compare_all, but returns the indices of the similar dictionaries
+/// in dicts_a and dicts_b rather than their labels
+pub fn similar_pairs_between(
+    dicts_a: &[DigestRecord],
+    dicts_b: &[DigestRecord],
+    threshold: f64,
+    cancel: Option<&CancellationToken>,
+    progress: Option<&mut (dyn FnMut(Progress) + Send)>,
+) -> Result<Vec<(usize, usize, f64)>> {
+    let same = std::ptr::eq(dicts_a, dicts_b);
+    let total = if same {
+        dicts_a.len() * dicts_a.len().saturating_sub(1) / 2
+    } else {
+        dicts_a.len() * dicts_b.len()
+    } as u64;
+    let prefilter = Prefilter::new(dicts_a, dicts_b, threshold);
+    compare_rows(dicts_a, total, cancel, progress, |i, a, v| {
+        let j_start = if same { i + 1 } else { 0 };
+        dicts_b
+            .iter()
+            .enumerate()
+            .skip(j_start)
+            .filter(|&(j, _)| may_match(&prefilter, i, j, threshold))
+            .for_each(|(j, b)| {
+                let similarity = a.dict.similarity(&b.dict);
+                if similarity >= threshold {
+                    v.push((i, j, similarity));
+                }
+            });
+        dicts_b.len().saturating_sub(j_start) as u64
+    })
+}
+
+/// Returns the similarities of all pairs of dictionaries in dicts, as a
+/// symmetric matrix of which the diagonal is 1. Only the above-diagonal
+/// elements are computed; cancel and progress are used like compare_all does.
+pub fn similarity_matrix(
+    dicts: &[DigestRecord],
+    cancel: Option<&CancellationToken>,
+    progress: Option<&mut (dyn FnMut(Progress) + Send)>,
+) -> Result<Vec<Vec<f64>>> {
+    let total = (dicts.len() * dicts.len().saturating_sub(1) / 2) as u64;
+    let rows = compare_rows(dicts, total, cancel, progress, |i, a, v| {
+        v.push(
+            dicts[i + 1..]
+                .iter()
+                .map(|b| a.dict.similarity(&b.dict))
+                .collect::<Vec<_>>(),
+        );
+        (dicts.len() - i - 1) as u64
+    })?;
+
+    let mut matrix = vec![vec![1.; dicts.len()]; dicts.len()];
+    for (i, row) in rows.into_iter().enumerate() {
+        for (j, similarity) in row.into_iter().enumerate().map(|(n, s)| (i + 1 + n, s)) {
+            matrix[i][j] = similarity;
+            matrix[j][i] = similarity;
+        }
+    }
+    Ok(matrix)
+}
+
+/// Computes the similarities of the given pairs of indices into dicts in parallel,
+/// in the same order as pairs. Cancel and progress are used like compare_all does,
+/// but progress is reported after every PAIRS_CHUNK_SIZE pairs.
+pub fn compare_pairs(
+    dicts: &[DigestRecord],
+    pairs: &[(usize, usize)],
+    cancel: Option<&CancellationToken>,
+    progress: Option<&mut (dyn FnMut(Progress) + Send)>,
+) -> Result<Vec<f64>> {
+    let total = pairs.len() as u64;
+    let progress = progress.map(|progress| Mutex::new((0, progress)));
+    let chunks = pairs
+        .par_chunks(PAIRS_CHUNK_SIZE)
+        .map(|chunk| {
+            if let Some(cancel) = cancel {
+                cancel.check()?;
+            }
+            let similarities: Vec<f64> = chunk
+                .iter()
+                .map(|&(a, b)| dicts[a].dict.similarity(&dicts[b].dict))
+                .collect();
+            if let Some(progress) = &progress {
+                let mut progress = progress.lock().unwrap();
+                progress.0 += chunk.len() as u64;
+                let done = progress.0;
+                (progress.1)(Progress::PairsCompared { done, total });
+            }
+            Ok(similarities)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(chunks.into_iter().flatten().collect())
+}
+
+/// Like compare_all, but only returns the n most similar dictionaries
+/// in dicts_b of each dictionary in dicts_a, best match first.
+/// Candidates are kept in a heap of at most n elements per row.
+/// If both slices point to the same object, dictionaries are
+/// not compared with themselves.
+pub fn compare_top(
+    dicts_a: &[DigestRecord],
+    dicts_b: &[DigestRecord],
+    threshold: f64,
+    n: usize,
+    cancel: Option<&CancellationToken>,
+    progress: Option<&mut (dyn FnMut(Progress) + Send)>,
+) -> Result<Vec<Match>> {
+    let mut matches = vec![];
+    compare_top_in_batches(
+        dicts_a,
+        dicts_b,
+        threshold,
+        n,
+        dicts_a.len(),
+        cancel,
+        progress,
+        &mut |mut batch| {
+            matches.append(&mut batch);
+            Ok(())
+        },
+    )?;
+    Ok(matches)
+}
+
+/// Like compare_top, but passes the matches of every batch_rows rows
+/// of dicts_a to sink as compare_all_in_batches does
+#[allow(clippy::too_many_arguments)]
+pub fn compare_top_in_batches(
+    dicts_a: &[DigestRecord],
+    dicts_b: &[DigestRecord],
+    threshold: f64,
+    n: usize,
+    batch_rows: usize,
+    cancel: Option<&CancellationToken>,
+    progress: Option<&mut (dyn FnMut(Progress) + Send)>,
+    sink: &mut dyn FnMut(Vec<Match>) -> Result<()>,
+) -> Result<()> {
+    let same = std::ptr::eq(dicts_a, dicts_b);
+    let row_len = dicts_b.len().saturating_sub(same as usize);
+    let total = (dicts_a.len() * row_len) as u64;
+    let prefilter = Prefilter::new(dicts_a, dicts_b, threshold);
+    let row = |i, a: &DigestRecord, v: &mut Vec<Match>| {
+        let mut heap = BinaryHeap::with_capacity(n + 1);
+        dicts_b
+            .iter()
+            .enumerate()
+            .filter(|&(j, _)| !same || i != j)
+            .for_each(|(j, b)| {
+                // Once the heap is full, a match must beat its worst one
+                let min_similarity = match heap.peek() {
+                    Some(&Candidate(worst, _)) if heap.len() == n => threshold.max(worst),
+                    _ => threshold,
+                };
+                if !may_match(&prefilter, i, j, min_similarity) {
+                    return;
+                }
+                let similarity = a.dict.similarity(&b.dict);
+                if similarity >= threshold {
+                    heap.push(Candidate(similarity, j));
+                    if heap.len() > n {
+                        heap.pop();
+                    }
+                }
+            });
+        v.extend(
+            heap.into_sorted_vec()
+                .into_iter()
+                .map(|Candidate(similarity, j)| Match {
+                    a: a.label.to_owned(),
+                    b: dicts_b[j].label.to_owned(),
+                    similarity,
+                }),
+        );
+        row_len as u64
+    };
+    compare_rows_in_batches(dicts_a, batch_rows, total, cancel, progress, row, sink)
+}
+
+/// Signatures of the dictionaries of a batch comparison, which rule out
+/// pairs of which the similarity is below the threshold before comparing them
+struct Prefilter {
+    signatures_a: Vec<Signature>,
+    /// None if dicts_a and dicts_b are the same
+    signatures_b: Option<Vec<Signature>>,
+}
+
+impl Prefilter {
+    /// Returns None if no pair can be ruled out at threshold
+    fn new(dicts_a: &[DigestRecord], dicts_b: &[DigestRecord], threshold: f64) -> Option<Self> {
+        if threshold <= 0. {
+            return None;
+        }
+        let signatures = |dicts: &[DigestRecord]| -> Vec<Signature> {
+            dicts
+                .par_iter()
+                .map(|record| Signature::new(&record.dict))
+                .collect()
+        };
+        Some(Self {
+            signatures_a: signatures(dicts_a),
+            signatures_b: if std::ptr::eq(dicts_a, dicts_b) {
+                None
+            } else {
+                Some(signatures(dicts_b))
+            },
+        })
+    }
+}
+
+/// Returns false if the similarity of dictionary i of dicts_a and dictionary j of dicts_b
+/// is certainly below min_similarity
+fn may_match(prefilter: &Option<Prefilter>, i: usize, j: usize, min_similarity: f64) -> bool {
+    prefilter.as_ref().is_none_or(|prefilter| {
+        let signatures_b = prefilter
+            .signatures_b
+            .as_ref()
+            .unwrap_or(&prefilter.signatures_a);
+        prefilter.signatures_a[i].max_similarity(&signatures_b[j]) >= min_similarity
+    })
+}
+
+/// Match of a row with the dictionary at index .1, ordered such
+/// that the max-heap of BinaryHeap keeps the worst match on top.
+/// Ties are won by the earlier dictionary.
+struct Candidate(f64, usize);
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.0.total_cmp(&self.0).then(self.1.cmp(&other.1))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+/// Runs row in parallel for every dictionary in dicts_a, collecting
+/// the matches it pushes in order. Row returns the number of
+/// pairs it compared, which is reported to progress.
+fn compare_rows<T, F>(
+    dicts_a: &[DigestRecord],
+    total: u64,
+    cancel: Option<&CancellationToken>,
+    progress: Option<&mut (dyn FnMut(Progress) + Send)>,
+    row: F,
+) -> Result<Vec<T>>
+where
+    T: Send,
+    F: Fn(usize, &DigestRecord, &mut Vec<T>) -> u64 + Sync,
+{
+    let mut rows = vec![];
+    compare_rows_in_batches(
+        dicts_a,
+        dicts_a.len(),
+        total,
+        cancel,
+        progress,
+        row,
+        &mut |mut batch| {
+            rows.append(&mut batch);
+            Ok(())
+        },
+    )?;
+    Ok(rows)
+}
+
+/// Like compare_rows, but runs the rows batch_rows at a time,
+/// passing what every batch pushes to sink before running the next
+fn compare_rows_in_batches<T, F>(
+    dicts_a: &[DigestRecord],
+    batch_rows: usize,
+    total: u64,
+    cancel: Option<&CancellationToken>,
+    progress: Option<&mut (dyn FnMut(Progress) + Send)>,
+    row: F,
+    sink: &mut dyn FnMut(Vec<T>) -> Result<()>,
+) -> Result<()>
+where
+    T: Send,
+    F: Fn(usize, &DigestRecord, &mut Vec<T>) -> u64 + Sync,
+{
+    // Holds the number of pairs compared so far, locked
+    // while reporting so that reports are in order
+    let progress = progress.map(|progress| Mutex::new((0, progress)));
+    for start in (0..dicts_a.len()).step_by(batch_rows.max(1)) {
+        let end = dicts_a.len().min(start + batch_rows.max(1));
+        let batch: Result<Vec<T>> = dicts_a[start..end]
+            .par_iter()
+            .enumerate()
+            .try_fold(Vec::new, |mut v, (i, a)| {
+                if let Some(cancel) = cancel {
+                    cancel.check()?;
+                }
+                let compared = row(start + i, a, &mut v);
+                if let Some(progress) = &progress {
+                    let mut progress = progress.lock().unwrap();
+                    progress.0 += compared;
+                    let done = progress.0;
+                    (progress.1)(Progress::PairsCompared { done, total });
+                }
+                Ok(v)
+            })
+            // Rayon reduces adjacent ranges of rows, left before right,
+            // so the rows end up in order whichever threads ran them
+            .try_reduce(Vec::new, |mut v, mut r| {
+                v.append(&mut r);
+                Ok(v)
+            });
+        sink(batch?)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cancel::CancellationToken;
+    use crate::compare::{
+        compare_all, compare_all_in_batches, compare_pairs, compare_top, compare_top_in_batches,
+        similar_pairs, similar_pairs_between, similarity_matrix,
+    };
+    use crate::crc32::CRC32BuildHasher;
+    use crate::progress::Progress;
+    use crate::{DigestRecord, LZDict, LZJDError};
+    use std::sync::Mutex;
+
+    fn dicts() -> Vec<DigestRecord> {
+        let build_hasher = CRC32BuildHasher;
+        [
+            &b"THIS IS A TEST SEQUENCE"[..],
+            &b"THIS IS A DIFFERENT TEST SEQUENCE"[..],
+            &b"totally_different"[..],
+        ]
+        .iter()
+        .map(|s| {
+            DigestRecord::new(
+                String::from_utf8(s.to_vec()).unwrap(),
+                LZDict::from_bytes_stream(s.iter().cloned(), &build_hasher),
+            )
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_compare_all() {
+        let dicts = dicts();
+
+        let matches = compare_all(&dicts, &dicts, 0., None, None).unwrap();
+        assert_eq!(
+            matches.len(),
+            3,
+            "Only above-diagonal pairs should be compared"
+        );
+
+        let matches = compare_all(&dicts, &dicts[..1], 1., None, None).unwrap();
+        assert_eq!(matches.len(), 1, "Only the identical pair should match");
+
+        let pairs = similar_pairs(&dicts, 0., None, None).unwrap();
+        assert_eq!(
+            pairs.iter().map(|&(i, j, _)| (i, j)).collect::<Vec<_>>(),
+            [(0, 1), (0, 2), (1, 2)]
+        );
+        let pairs = similar_pairs_between(&dicts[..2], &dicts[1..], 0.5, None, None).unwrap();
+        assert_eq!(
+            pairs.iter().map(|&(i, j, _)| (i, j)).collect::<Vec<_>>(),
+            [(0, 0), (1, 0)]
+        );
+    }
+
+    #[test]
+    fn test_order() {
+        let dicts: Vec<_> = dicts().into_iter().cycle().take(60).collect();
+        let expected: Vec<_> = (0..dicts.len())
+            .flat_map(|i| (i + 1..dicts.len()).map(move |j| (i, j)))
+            .collect();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        for _ in 0..10 {
+            let pairs = pool.install(|| similar_pairs(&dicts, 0., None, None).unwrap());
+            assert_eq!(
+                pairs.iter().map(|&(i, j, _)| (i, j)).collect::<Vec<_>>(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_batches() {
+        let dicts: Vec<_> = dicts().into_iter().cycle().take(10).collect();
+        let expected = compare_all(&dicts, &dicts, 0.5, None, None).unwrap();
+        let mut batches = vec![];
+        compare_all_in_batches(&dicts, &dicts, 0.5, 3, None, None, &mut |batch| {
+            batches.push(batch);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(batches.len(), 4);
+        assert_eq!(batches.concat(), expected);
+
+        // Matches are passed on before the later rows are compared
+        let events = Mutex::new(vec![]);
+        let mut report = |p| events.lock().unwrap().push(Some(p));
+        compare_top_in_batches(
+            &dicts,
+            &dicts,
+            0.,
+            1,
+            5,
+            None,
+            Some(&mut report),
+            &mut |batch| {
+                assert_eq!(batch.len(), 5);
+                events.lock().unwrap().push(None);
+                Ok(())
+            },
+        )
+        .unwrap();
+        let events = events.into_inner().unwrap();
+        assert_eq!(events.len(), 12);
+        assert_eq!(events[5], None);
+        assert_eq!(events[11], None);
+
+        // A failing sink stops the comparison
+        let mut calls = 0;
+        let result = compare_all_in_batches(&dicts, &dicts, 0., 1, None, None, &mut |_| {
+            calls += 1;
+            Err(LZJDError::from("full"))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_similarity_matrix() {
+        let dicts = dicts();
+
+        let matrix = similarity_matrix(&dicts, None, None).unwrap();
+        for (i, row) in matrix.iter().enumerate() {
+            assert_eq!(row.len(), dicts.len());
+            assert_eq!(row[i], 1.);
+            for (j, &similarity) in row.iter().enumerate() {
+                assert_eq!(similarity, matrix[j][i]);
+                if i != j {
+                    assert_eq!(similarity, dicts[i].dict.similarity(&dicts[j].dict));
+                }
+            }
+        }
+        assert!(similarity_matrix(&[], None, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_prefilter() {
+        // Inputs sharing more or less of a common prefix
+        let build_hasher = CRC32BuildHasher;
+        let common: Vec<u8> = (0..20_000u64).map(|i| (i * i % 251) as u8).collect();
+        let dicts: Vec<DigestRecord> = (0..20u64)
+            .map(|n| {
+                let data: Vec<u8> = common[..n as usize * 1000]
+                    .iter()
+                    .cloned()
+                    .chain((0..20_000u64).map(|i| (i * (n + 7) % 253) as u8))
+                    .collect();
+                DigestRecord::new(n.to_string(), LZDict::from_bytes(&data, &build_hasher))
+            })
+            .collect();
+        for &threshold in &[0.05, 0.2, 0.5] {
+            let expected: Vec<(usize, usize, f64)> = (0..dicts.len())
+                .flat_map(|i| (i + 1..dicts.len()).map(move |j| (i, j)))
+                .map(|(i, j)| (i, j, dicts[i].dict.similarity(&dicts[j].dict)))
+                .filter(|&(_, _, similarity)| similarity >= threshold)
+                .collect();
+            assert_eq!(
+                similar_pairs(&dicts, threshold, None, None).unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_compare_pairs() {
+        let dicts = dicts();
+
+        let pairs = [(2, 0), (1, 1), (0, 1)];
+        let similarities = compare_pairs(&dicts, &pairs, None, None).unwrap();
+        assert_eq!(
+            similarities,
+            pairs
+                .iter()
+                .map(|&(a, b)| dicts[a].dict.similarity(&dicts[b].dict))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(similarities[1], 1.);
+    }
+
+    #[test]
+    fn test_compare_top() {
+        let dicts = dicts();
+
+        let matches = compare_top(&dicts, &dicts, 0., 1, None, None).unwrap();
+        assert_eq!(matches.len(), 3, "Every row should have its best match");
+        assert_eq!(matches[0].b, dicts[1].label);
+        assert_eq!(matches[1].b, dicts[0].label);
+
+        let matches = compare_top(&dicts[..1], &dicts, 0., 2, None, None).unwrap();
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].b, dicts[0].label, "Best match should come first");
+        assert!(matches[0].similarity >= matches[1].similarity);
+
+        let matches = compare_top(&dicts, &dicts, 0., 0, None, None).unwrap();
+        assert!(matches.is_empty());
+    }
+
+    #[test]
+    fn test_progress() {
+        let dicts = dicts();
+        let mut reports = vec![];
+
+        compare_all(&dicts, &dicts, 0., None, Some(&mut |p| reports.push(p))).unwrap();
+        assert_eq!(
+            reports.last(),
+            Some(&Progress::PairsCompared { done: 3, total: 3 })
+        );
+
+        let mut bytes = 0;
+        LZDict::from_bytes_stream_with_progress(b"bits".iter().cloned(), &CRC32BuildHasher, |p| {
+            if let Progress::BytesProcessed(b) = p {
+                bytes = b;
+            }
+        });
+        assert_eq!(bytes, 4);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let dicts = dicts();
+        let cancel = CancellationToken::new();
+        cancel.clone().cancel();
+
+        match compare_all(&dicts, &dicts, 0., Some(&cancel), None) {
+            Err(LZJDError::Cancelled) => {}
+            r => panic!("Expected cancellation, got {:?}", r),
+        }
+        match LZDict::from_bytes_stream_cancellable(
+            b"bits".iter().cloned(),
+            &CRC32BuildHasher,
+            &cancel,
+        ) {
+            Err(LZJDError::Cancelled) => {}
+            r => panic!("Expected cancellation, got {:?}", r),
+        }
+    }
+}
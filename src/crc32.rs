@@ -1,5 +1,6 @@
 //! Defines a wrapper around crc::crc32::Digest, implementing std::hash::Hasher
 //! as well as a std::hash::BuildHasher which builds the hasher.
+use crate::hasher::ResettableHasher;
 use crc::crc32::{self, Hasher32};
 
 use std::hash::BuildHasher;
@@ -28,6 +29,12 @@ impl Hasher for CRC32Hasher {
     }
 }
 
+impl ResettableHasher for CRC32Hasher {
+    fn reset(&mut self) {
+        Hasher32::reset(&mut self.digest);
+    }
+}
+
 /// std::hash::BuildHasher that builds CRC32Hashers
 #[derive(Clone)]
 pub struct CRC32BuildHasher;
@@ -0,0 +1,186 @@
+//! Digesting of files served over HTTP(S) as they are downloaded, like the payloads
+//! listed in threat intelligence feeds, without staging them on local disk.
+use crate::entropy::EntropyReader;
+use crate::sampling::Sampling;
+use crate::{HashAlgorithm, LZDict, LZJDError, Result};
+
+use reqwest::blocking::Client;
+use reqwest::header::CONTENT_LENGTH;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::Duration;
+
+/// Number of bytes of a response above which its download fails, if no other limit is given
+pub const DEFAULT_MAX_SIZE: u64 = 1 << 30;
+
+/// Time after which connecting, or waiting for more of a response, fails if no other time is given
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Whether input is an http:// or https:// URL rather than a local path
+pub fn is_http_url(input: &Path) -> bool {
+    input
+        .to_str()
+        .is_some_and(|input| input.starts_with("https://") || input.starts_with("http://"))
+}
+
+/// Reader that fails once more than max bytes are read from inner
+struct LimitedReader<R> {
+    inner: R,
+    read: u64,
+    max: u64,
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        if self.read > self.max {
+            return Err(io::Error::other(format!(
+                "Response is larger than the limit of {} bytes",
+                self.max
+            )));
+        }
+        Ok(n)
+    }
+}
+
+/// Downloads and digests the responses to GET requests, failing
+/// responses that are larger than a limit or that take too long
+#[derive(Clone)]
+pub struct Downloader {
+    client: Client,
+    max_size: u64,
+}
+
+impl Downloader {
+    /// Creates a downloader that fails responses larger than max_size bytes, and connecting
+    /// or waiting for more of a response for longer than timeout
+    pub fn new(max_size: u64, timeout: Duration) -> Result<Self> {
+        let client = Client::builder()
+            .connect_timeout(timeout)
+            .timeout(timeout)
+            .user_agent(concat!("lzjd/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(|e| LZJDError::Msg {
+                msg: format!("Could not set up HTTP client: {}", e),
+            })?;
+        Ok(Self { client, max_size })
+    }
+
+    /// Digests the body of the response to a GET request of url as it streams in, using
+    /// algorithm and keeping the k smallest hashes. With Sampling::Head, the download stops
+    /// after the bytes to digest. Sampling::Spread needs to seek, so it is not supported.
+    pub fn digest(
+        &self,
+        url: &str,
+        algorithm: HashAlgorithm,
+        k: usize,
+        sampling: Sampling,
+        skip_high_entropy: bool,
+    ) -> Result<LZDict> {
+        let head = match sampling {
+            Sampling::Full => u64::MAX,
+            Sampling::Head(max) => max,
+            Sampling::Spread(_) => {
+                return Err(LZJDError::from("Spread sampling can not read downloads"))
+            }
+        };
+        let failed = |e: reqwest::Error| LZJDError::Msg {
+            msg: format!("Could not download {}: {}", url, e),
+        };
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map_err(failed)?;
+        // Bodies to digest in full that are announced to be too large are not downloaded at all
+        let len = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok()?.parse::<u64>().ok());
+        if len.is_some_and(|len| len > self.max_size && head > self.max_size) {
+            return Err(LZJDError::Msg {
+                msg: format!(
+                    "Response of {} is larger than the limit of {} bytes",
+                    url, self.max_size
+                ),
+            });
+        }
+
+        let limited = LimitedReader {
+            inner: response,
+            read: 0,
+            max: self.max_size,
+        };
+        let mut reader = EntropyReader::new(limited.take(head), skip_high_entropy);
+        let dict = algorithm.digest_reader_with_k(&mut reader, k)?;
+        Ok(dict.with_high_entropy_fraction(reader.high_entropy_fraction()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::download::{is_http_url, Downloader, DEFAULT_TIMEOUT};
+    use crate::sampling::Sampling;
+    use crate::HashAlgorithm;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::path::Path;
+    use std::thread;
+
+    /// Serves body to count requests on a local port, announcing its length if announce is set
+    fn serve(body: Vec<u8>, announce: bool, count: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(count) {
+                let mut stream = stream.unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request);
+                let header = match announce {
+                    true => format!("Content-Length: {}\r\n", body.len()),
+                    false => String::new(),
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\n{}Connection: close\r\n\r\n",
+                    header
+                );
+                let _ = stream.write_all(&body);
+            }
+        });
+        format!("http://{}/sample.bin", addr)
+    }
+
+    #[test]
+    fn test_digest() {
+        let body: Vec<u8> = (0..100_000u64).map(|i| (i * i % 251) as u8).collect();
+        let expected = HashAlgorithm::Murmur3.digest_reader(&body[..]).unwrap();
+        let url = serve(body.clone(), true, 3);
+        assert!(is_http_url(Path::new(&url)));
+        assert!(!is_http_url(Path::new("sample.bin")));
+
+        let digest = |max_size, sampling| {
+            Downloader::new(max_size, DEFAULT_TIMEOUT).unwrap().digest(
+                &url,
+                HashAlgorithm::Murmur3,
+                1024,
+                sampling,
+                false,
+            )
+        };
+        assert_eq!(*digest(1 << 20, Sampling::Full).unwrap(), *expected);
+        assert!(digest(1000, Sampling::Full).is_err());
+        // Only the bytes to digest count towards the limit
+        let head = HashAlgorithm::Murmur3.digest_reader(&body[..500]).unwrap();
+        assert_eq!(*digest(1000, Sampling::Head(500)).unwrap(), *head);
+        assert!(digest(1 << 20, Sampling::Spread(500)).is_err());
+
+        // Without a length to check up front, the download fails once it exceeds the limit
+        let url = serve(body, false, 1);
+        let downloader = Downloader::new(1000, DEFAULT_TIMEOUT).unwrap();
+        let result = downloader.digest(&url, HashAlgorithm::Murmur3, 1024, Sampling::Full, false);
+        assert!(result.unwrap_err().to_string().contains("limit"));
+    }
+}
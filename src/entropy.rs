@@ -0,0 +1,159 @@
+//! Detection of high-entropy input, like compressed or encrypted data, of which
+//! the LZ phrases are effectively random, so that digests of it match those of other
+//! high-entropy data by chance only. Such regions can be measured and skipped while digesting.
+use std::io::{self, ErrorKind, Read};
+
+/// Size of the blocks of which the entropy is measured
+pub const ENTROPY_BLOCK_SIZE: usize = 4096;
+
+/// Entropy in bits per byte above which a block is considered high-entropy.
+/// Blocks of random bytes measure about 7.95, and text and code well below 7.
+pub const MAX_ENTROPY: f64 = 7.5;
+
+/// Fraction of high-entropy input above which a digest is dominated by it,
+/// so that its similarities say little about the rest of the input
+pub const DOMINANT_FRACTION: f64 = 0.5;
+
+/// Returns the Shannon entropy of the bytes of block in bits per byte, between 0 and 8.
+/// Blocks of n bytes measure at most log2(n), so short blocks measure low.
+pub fn block_entropy(block: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for &byte in block {
+        counts[byte as usize] += 1;
+    }
+    let len = block.len() as f64;
+    -counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| count as f64 / len)
+        .map(|p| p * p.log2())
+        .sum::<f64>()
+}
+
+/// Reader that measures the entropy of the blocks of ENTROPY_BLOCK_SIZE bytes read from
+/// inner, and leaves out the high-entropy ones if it skips them
+pub struct EntropyReader<R> {
+    inner: R,
+    skip: bool,
+    max_entropy: f64,
+    /// Bytes of the current block, of which the first pos have been read
+    block: Vec<u8>,
+    pos: usize,
+    /// Number of bytes read from inner, and of those in high-entropy blocks
+    len: u64,
+    high_entropy_len: u64,
+}
+
+impl<R: Read> EntropyReader<R> {
+    /// Creates a reader of inner, which leaves out high-entropy blocks if skip is set
+    pub fn new(inner: R, skip: bool) -> Self {
+        Self {
+            inner,
+            skip,
+            max_entropy: MAX_ENTROPY,
+            block: Vec::with_capacity(ENTROPY_BLOCK_SIZE),
+            pos: 0,
+            len: 0,
+            high_entropy_len: 0,
+        }
+    }
+
+    /// Considers blocks with more than max_entropy bits per byte high-entropy instead of MAX_ENTROPY
+    pub fn with_max_entropy(self, max_entropy: f64) -> Self {
+        Self {
+            max_entropy,
+            ..self
+        }
+    }
+
+    /// Returns the reader of which the entropy is measured. Reading from it
+    /// directly leaves the bytes read out of the measurement.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Fraction of the bytes read so far that are in high-entropy blocks, 0 if none were read
+    pub fn high_entropy_fraction(&self) -> f64 {
+        match self.len {
+            0 => 0.,
+            len => self.high_entropy_len as f64 / len as f64,
+        }
+    }
+
+    /// Reads the next block, returning false at the end of inner
+    fn next_block(&mut self) -> io::Result<bool> {
+        self.block.resize(ENTROPY_BLOCK_SIZE, 0);
+        let mut filled = 0;
+        while filled < ENTROPY_BLOCK_SIZE {
+            match self.inner.read(&mut self.block[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.block.truncate(filled);
+        self.pos = 0;
+        self.len += filled as u64;
+        if block_entropy(&self.block) > self.max_entropy {
+            self.high_entropy_len += filled as u64;
+            if self.skip {
+                self.block.clear();
+            }
+        }
+        Ok(filled > 0)
+    }
+}
+
+impl<R: Read> Read for EntropyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.block.len() {
+            if !self.next_block()? {
+                return Ok(0);
+            }
+        }
+        let len = buf.len().min(self.block.len() - self.pos);
+        buf[..len].copy_from_slice(&self.block[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entropy::{block_entropy, EntropyReader, ENTROPY_BLOCK_SIZE};
+    use std::io::Read;
+
+    #[test]
+    fn test_entropy_reader() {
+        assert_eq!(block_entropy(&[]), 0.);
+        assert_eq!(block_entropy(&[7; 100]), 0.);
+        assert_eq!(block_entropy(&(0..=255).collect::<Vec<u8>>()), 8.);
+
+        // xorshift64, for bytes as random as those of encrypted data
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let noise: Vec<u8> = (0..ENTROPY_BLOCK_SIZE)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 32) as u8
+            })
+            .collect();
+        let text = b"THIS IS A TEST SEQUENCE ".repeat(ENTROPY_BLOCK_SIZE / 24 * 3);
+        let data = [&text[..], &noise].concat();
+
+        let mut read = vec![];
+        let mut reader = EntropyReader::new(&data[..], false);
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+        let fraction = reader.high_entropy_fraction();
+        assert!(fraction > 0.2 && fraction < 0.3, "{}", fraction);
+
+        let mut read = vec![];
+        let mut reader = EntropyReader::new(&data[..], true);
+        reader.read_to_end(&mut read).unwrap();
+        assert!(read.len() < data.len() && read.starts_with(&text[..ENTROPY_BLOCK_SIZE]));
+        assert_eq!(reader.high_entropy_fraction(), fraction);
+    }
+}
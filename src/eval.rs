@@ -0,0 +1,177 @@
+//! Evaluation of how well similarities separate the digests of files of the
+//! same family from those of different families, and of how well clusters of
+//! similar digests match the families, for comparing hash algorithms, digest
+//! sizes and thresholds on labeled data.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Returns the ROC curve of predicting that pairs are of the same family if their
+/// similarity is at least a threshold, given the similarities of pairs of the
+/// same family (intra) and of different families (inter). Points are
+/// (threshold, true positive rate, false positive rate), with a point for every
+/// distinct similarity in decreasing order, so the last point is (_, 1, 1).
+pub fn roc(intra: &[f64], inter: &[f64]) -> Vec<(f64, f64, f64)> {
+    let mut scores: Vec<(f64, bool)> = intra
+        .iter()
+        .map(|&similarity| (similarity, true))
+        .chain(inter.iter().map(|&similarity| (similarity, false)))
+        .collect();
+    scores.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let rate = |count: usize, total: usize| match total {
+        0 => 0.,
+        total => count as f64 / total as f64,
+    };
+    let (mut true_positives, mut false_positives) = (0, 0);
+    let mut points = vec![];
+    for (i, &(similarity, same_family)) in scores.iter().enumerate() {
+        if same_family {
+            true_positives += 1;
+        } else {
+            false_positives += 1;
+        }
+        // Pairs of equal similarity are all predicted alike
+        if scores.get(i + 1).is_none_or(|next| next.0 < similarity) {
+            points.push((
+                similarity,
+                rate(true_positives, intra.len()),
+                rate(false_positives, inter.len()),
+            ));
+        }
+    }
+    points
+}
+
+/// Area under a ROC curve as returned by roc, which is the probability
+/// that a pair of the same family is more similar than a pair of different
+/// families, counting ties as half
+pub fn auc(roc: &[(f64, f64, f64)]) -> f64 {
+    let mut previous = (0., 0.);
+    let mut area = 0.;
+    for &(_, true_positive_rate, false_positive_rate) in roc {
+        area += (false_positive_rate - previous.1) * (true_positive_rate + previous.0) / 2.;
+        previous = (true_positive_rate, false_positive_rate);
+    }
+    area
+}
+
+/// Returns the point of a ROC curve with the largest difference between the
+/// true and false positive rate (Youden's J), the highest threshold among equals
+pub fn best_threshold(roc: &[(f64, f64, f64)]) -> Option<(f64, f64, f64)> {
+    roc.iter().copied().fold(None, |best, point| match best {
+        Some(best) if best.1 - best.2 >= point.1 - point.2 => Some(best),
+        _ => Some(point),
+    })
+}
+
+/// Returns the point of a ROC curve with the highest true positive rate
+/// of which the false positive rate is at most max_false_positive_rate
+pub fn threshold_at_false_positive_rate(
+    roc: &[(f64, f64, f64)],
+    max_false_positive_rate: f64,
+) -> Option<(f64, f64, f64)> {
+    roc.iter()
+        .copied()
+        .take_while(|point| point.2 <= max_false_positive_rate)
+        .last()
+}
+
+/// Returns the homogeneity and completeness of a clustering of items against their
+/// classes, given the class and cluster of every item. Homogeneity is 1 if every cluster
+/// only holds items of a single class, and completeness is 1 if all items of every
+/// class are in a single cluster. Both are 1 if there are no items.
+pub fn homogeneity_completeness<A, B>(classes: &[A], clusters: &[B]) -> (f64, f64)
+where
+    A: Eq + Hash,
+    B: Eq + Hash,
+{
+    let mut joint: HashMap<(&A, &B), usize> = HashMap::new();
+    let mut class_sizes: HashMap<&A, usize> = HashMap::new();
+    let mut cluster_sizes: HashMap<&B, usize> = HashMap::new();
+    for (class, cluster) in classes.iter().zip(clusters) {
+        *joint.entry((class, cluster)).or_insert(0) += 1;
+        *class_sizes.entry(class).or_insert(0) += 1;
+        *cluster_sizes.entry(cluster).or_insert(0) += 1;
+    }
+    let total = classes.len().min(clusters.len()) as f64;
+    let entropy = |sizes: &mut dyn Iterator<Item = usize>| {
+        -sizes
+            .map(|size| size as f64 / total)
+            .map(|p| p * p.ln())
+            .sum::<f64>()
+    };
+    let class_entropy = entropy(&mut class_sizes.values().copied());
+    let cluster_entropy = entropy(&mut cluster_sizes.values().copied());
+    // H(class | cluster) and H(cluster | class)
+    let (mut class_given_cluster, mut cluster_given_class) = (0., 0.);
+    for ((class, cluster), &count) in &joint {
+        let p = count as f64 / total;
+        class_given_cluster -= p * (count as f64 / cluster_sizes[cluster] as f64).ln();
+        cluster_given_class -= p * (count as f64 / class_sizes[class] as f64).ln();
+    }
+    let score = |conditional: f64, entropy: f64| {
+        if entropy > 0. {
+            1. - conditional / entropy
+        } else {
+            1.
+        }
+    };
+    (
+        score(class_given_cluster, class_entropy),
+        score(cluster_given_class, cluster_entropy),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::eval::{
+        auc, best_threshold, homogeneity_completeness, roc, threshold_at_false_positive_rate,
+    };
+
+    #[test]
+    fn test_roc() {
+        let curve = roc(&[0.9, 0.8, 0.4], &[0.5, 0.4, 0.1, 0.]);
+        assert_eq!(
+            curve,
+            vec![
+                (0.9, 1. / 3., 0.),
+                (0.8, 2. / 3., 0.),
+                (0.5, 2. / 3., 0.25),
+                (0.4, 1., 0.5),
+                (0.1, 1., 0.75),
+                (0., 1., 1.),
+            ]
+        );
+        // 10 of the 12 pairs of an intra and an inter similarity are ordered, one is tied
+        assert!((auc(&curve) - 10.5 / 12.).abs() < 1e-12);
+        assert_eq!(best_threshold(&curve), Some((0.8, 2. / 3., 0.)));
+        assert_eq!(
+            threshold_at_false_positive_rate(&curve, 0.5),
+            Some((0.4, 1., 0.5))
+        );
+        assert_eq!(
+            threshold_at_false_positive_rate(&roc(&[0.1], &[0.2]), 0.),
+            None
+        );
+    }
+
+    #[test]
+    fn test_auc() {
+        assert_eq!(auc(&roc(&[0.9, 0.8], &[0.2, 0.1])), 1.);
+        assert_eq!(auc(&roc(&[0.2, 0.1], &[0.9, 0.8])), 0.);
+        assert_eq!(auc(&roc(&[0.5, 0.5], &[0.5])), 0.5);
+    }
+
+    #[test]
+    fn test_homogeneity_completeness() {
+        let classes = ["a", "a", "b", "b"];
+        assert_eq!(homogeneity_completeness(&classes, &[1, 1, 2, 2]), (1., 1.));
+        // Splitting a class keeps clusters pure, merging classes keeps them complete
+        let (homogeneity, completeness) = homogeneity_completeness(&classes, &[1, 2, 3, 3]);
+        assert_eq!(homogeneity, 1.);
+        assert!(completeness > 0. && completeness < 1.);
+        assert_eq!(homogeneity_completeness(&classes, &[1, 1, 1, 1]), (0., 1.));
+        assert_eq!(homogeneity_completeness::<&str, usize>(&[], &[]), (1., 1.));
+    }
+}
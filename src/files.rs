@@ -0,0 +1,455 @@
+//! Recursive directory traversal and parallel digesting of files.
+use crate::entropy::EntropyReader;
+use crate::hasher::AlgorithmDigester;
+use crate::lz_dict::K;
+use crate::progress::Progress;
+use crate::sampling::Sampling;
+use crate::{HashAlgorithm, LZDict, LZJDError, Result};
+
+use globset::GlobSet;
+use log::trace;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{self, BufRead, Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use walkdir::{DirEntry, WalkDir};
+
+/// Number of threads with which digest_files reads files, while the
+/// threads of the rayon pool hash the files read before
+pub const READER_THREADS: usize = 4;
+
+/// Number of sampled bytes of a file up to which digest_files reads the file
+/// at once in a reader thread. Larger files are read while hashing them.
+pub const MAX_READ_AHEAD: u64 = 4 * 1024 * 1024;
+
+/// What to do when a file cannot be read during a batch run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorPolicy {
+    /// Abort the whole run with the first error
+    #[default]
+    FailFast,
+    /// Skip the file and record the error in Batch::failures
+    Skip,
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = LZJDError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fail" => Ok(ErrorPolicy::FailFast),
+            "skip" => Ok(ErrorPolicy::Skip),
+            _ => Err(LZJDError::from(
+                "Unknown error policy, expected 'fail' or 'skip'",
+            )),
+        }
+    }
+}
+
+/// Outcome of a batch run: the successfully processed items,
+/// and the paths that were skipped along with the reason why.
+#[derive(Debug)]
+pub struct Batch<T> {
+    pub items: Vec<T>,
+    pub failures: Vec<(PathBuf, LZJDError)>,
+}
+
+impl<T> Default for Batch<T> {
+    fn default() -> Self {
+        Self {
+            items: vec![],
+            failures: vec![],
+        }
+    }
+}
+
+/// Options controlling directory traversal
+#[derive(Debug, Clone, Default)]
+pub struct DirectoryOptions {
+    /// Follow symbolic links while walking
+    pub follow_links: bool,
+    /// Maximum depth to descend to, unlimited if None
+    pub max_depth: Option<usize>,
+    /// Do not descend into directories on other file systems than the root
+    pub same_file_system: bool,
+    /// What to do with unreadable files and directories
+    pub error_policy: ErrorPolicy,
+    /// Hash algorithm used by digest_directory
+    pub algorithm: HashAlgorithm,
+    /// Number of hashes kept per digest by digest_directory, K if None
+    pub digest_size: Option<usize>,
+    /// Parts of each file digested by digest_directory
+    pub sampling: Sampling,
+    /// Leave high-entropy blocks out of the digests of digest_directory
+    pub skip_high_entropy: bool,
+    /// Only walk files matching one of these patterns, if given
+    pub include: Option<GlobSet>,
+    /// Skip files and directories matching one of these patterns, if given
+    pub exclude: Option<GlobSet>,
+}
+
+/// Returns the paths of all files in the directory tree rooted at path.
+/// If path is a file, only path itself is returned. The entries of every
+/// directory are walked in order of their names, so that the order of the
+/// paths does not depend on the file system.
+/// Patterns in options are matched against the paths below path,
+/// which start with path itself. The root is never filtered out.
+pub fn walk_directory<P: AsRef<Path>>(
+    path: P,
+    options: &DirectoryOptions,
+) -> Result<Batch<PathBuf>> {
+    let root = path.as_ref();
+    let mut walker = WalkDir::new(root)
+        .follow_links(options.follow_links)
+        .same_file_system(options.same_file_system)
+        .sort_by_file_name();
+    if let Some(max_depth) = options.max_depth {
+        walker = walker.max_depth(max_depth);
+    }
+
+    let excluded = |entry: &DirEntry| {
+        entry.depth() > 0
+            && options
+                .exclude
+                .as_ref()
+                .is_some_and(|exclude| exclude.is_match(entry.path()))
+    };
+    let included = |entry: &DirEntry| {
+        entry.depth() == 0
+            || options
+                .include
+                .as_ref()
+                .is_none_or(|include| include.is_match(entry.path()))
+    };
+
+    walker
+        .into_iter()
+        .filter_entry(|entry| !excluded(entry))
+        .try_fold(Batch::default(), |mut batch, entry| {
+            match entry {
+                Ok(entry) => {
+                    if (entry.file_type().is_file()
+                        || (entry.path_is_symlink() && entry.path().is_file()))
+                        && included(&entry)
+                    {
+                        batch.items.push(entry.into_path());
+                    }
+                }
+                Err(e) if options.error_policy == ErrorPolicy::Skip => {
+                    let path = e.path().unwrap_or(root).to_owned();
+                    batch.failures.push((path, io::Error::from(e).into()));
+                }
+                Err(e) => return Err(io::Error::from(e).into()),
+            }
+            Ok(batch)
+        })
+}
+
+/// A file as read by a reader thread of digest_files
+pub(crate) enum ReadFile {
+    /// The sampled bytes of the file
+    Bytes(Vec<u8>),
+    /// The file is larger than MAX_READ_AHEAD, or not a regular file,
+    /// so it is read while hashing it. Holds the number of sampled bytes.
+    Stream(u64),
+}
+
+impl ReadFile {
+    /// Number of sampled bytes of the file
+    pub(crate) fn len(&self) -> u64 {
+        match self {
+            ReadFile::Bytes(bytes) => bytes.len() as u64,
+            ReadFile::Stream(len) => *len,
+        }
+    }
+}
+
+pub(crate) fn read_file(path: &Path, sampling: Sampling) -> Result<ReadFile> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let len = sampling.sampled_len(metadata.len());
+    if !metadata.is_file() || len > MAX_READ_AHEAD {
+        return Ok(ReadFile::Stream(len));
+    }
+    let mut bytes = Vec::with_capacity(len as usize);
+    match sampling {
+        Sampling::Full => file.take(len).read_to_end(&mut bytes)?,
+        sampling => sampling.reader(file)?.read_to_end(&mut bytes)?,
+    };
+    Ok(ReadFile::Bytes(bytes))
+}
+
+pub(crate) fn digest_file(
+    path: &Path,
+    read: ReadFile,
+    digester: &mut AlgorithmDigester,
+    sampling: Sampling,
+    skip_high_entropy: bool,
+) -> Result<(PathBuf, LZDict)> {
+    let reader: Box<dyn Read> = match (read, sampling) {
+        (ReadFile::Bytes(bytes), _) => Box::new(Cursor::new(bytes)),
+        (ReadFile::Stream(_), Sampling::Full) => Box::new(File::open(path)?),
+        (ReadFile::Stream(_), sampling) => Box::new(sampling.reader(File::open(path)?)?),
+    };
+    let mut reader = EntropyReader::new(reader, skip_high_entropy);
+    let dict = digester.digest_reader(&mut reader)?;
+    Ok((
+        path.to_owned(),
+        dict.with_high_entropy_fraction(reader.high_entropy_fraction()),
+    ))
+}
+
+/// Digests the parts of the given files selected by sampling in parallel
+/// using algorithm, keeping the k smallest hashes of each file. The fraction of
+/// high-entropy input of each file is measured, and left out if skip_high_entropy is set.
+/// Results are in the same order as paths. If given, progress is called
+/// with the number of files and bytes digested after each file.
+///
+/// Files are read by READER_THREADS threads of their own and passed to the threads of
+/// the rayon pool through a bounded channel, so that reading from slow disks overlaps
+/// with hashing. Files larger than MAX_READ_AHEAD are read by the hashing threads,
+/// which each reuse one digester for all files they hash.
+pub fn digest_files(
+    paths: &[PathBuf],
+    policy: ErrorPolicy,
+    algorithm: HashAlgorithm,
+    k: usize,
+    sampling: Sampling,
+    skip_high_entropy: bool,
+    progress: Option<&mut (dyn FnMut(Progress) + Send)>,
+) -> Result<Batch<(PathBuf, LZDict)>> {
+    let total = paths.len() as u64;
+    // Holds the number of files and bytes digested so far,
+    // locked while reporting so that reports are in order
+    let progress = progress.map(|progress| Mutex::new((0, 0, progress)));
+    let digest = |digester: &mut AlgorithmDigester, path: &PathBuf, read: Result<ReadFile>| {
+        let len = read.as_ref().map_or(0, ReadFile::len);
+        let result =
+            read.and_then(|read| digest_file(path, read, digester, sampling, skip_high_entropy));
+        if result.is_ok() {
+            trace!("Digested {}", path.display());
+        }
+        if let Some(progress) = &progress {
+            let mut progress = progress.lock().unwrap();
+            progress.0 += 1;
+            progress.1 += len;
+            let (done, bytes) = (progress.0, progress.1);
+            (progress.2)(Progress::FilesDigested { done, total, bytes });
+        }
+        result
+    };
+
+    // Set on the first error if the run fails fast, so that no more files are read
+    let failed = AtomicBool::new(false);
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::sync_channel(rayon::current_num_threads());
+    let mut results: Vec<(usize, Result<(PathBuf, LZDict)>)> = thread::scope(|scope| {
+        for _ in 0..READER_THREADS.min(paths.len()) {
+            let sender = sender.clone();
+            let (failed, next) = (&failed, &next);
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= paths.len() || failed.load(Ordering::Relaxed) {
+                    break;
+                }
+                if sender.send((i, read_file(&paths[i], sampling))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+        receiver
+            .into_iter()
+            .par_bridge()
+            .map_init(
+                || algorithm.digester(k),
+                |digester, (i, read)| {
+                    let result = digest(digester, &paths[i], read);
+                    if result.is_err() && policy == ErrorPolicy::FailFast {
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                    (i, result)
+                },
+            )
+            .collect()
+    });
+    results.sort_unstable_by_key(|&(i, _)| i);
+
+    let mut batch = Batch::default();
+    for (i, result) in results {
+        match result {
+            Ok(item) => batch.items.push(item),
+            Err(e) if policy == ErrorPolicy::Skip => batch.failures.push((paths[i].to_owned(), e)),
+            Err(e) => return Err(e.in_file(&paths[i])),
+        }
+    }
+    Ok(batch)
+}
+
+/// Reads a list of paths from reader, separated by newlines or, if
+/// nul_delimited, by NUL bytes. Empty entries are skipped, as are carriage
+/// returns at the end of newline-delimited entries.
+pub fn read_path_list<R: BufRead>(reader: R, nul_delimited: bool) -> Result<Vec<PathBuf>> {
+    let delimiter = if nul_delimited { b'\0' } else { b'\n' };
+    reader
+        .split(delimiter)
+        .filter_map(|entry| match entry {
+            Ok(mut entry) => {
+                if !nul_delimited && entry.last() == Some(&b'\r') {
+                    entry.pop();
+                }
+                if entry.is_empty() {
+                    None
+                } else {
+                    Some(bytes_to_path(entry))
+                }
+            }
+            Err(e) => Some(Err(e.into())),
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn bytes_to_path(bytes: Vec<u8>) -> Result<PathBuf> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+
+    Ok(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+#[cfg(not(unix))]
+fn bytes_to_path(bytes: Vec<u8>) -> Result<PathBuf> {
+    String::from_utf8(bytes)
+        .map(PathBuf::from)
+        .map_err(|_| LZJDError::from("Path in list is not valid UTF-8"))
+}
+
+/// Digests all files in the directory tree rooted at path in parallel.
+pub fn digest_directory<P: AsRef<Path>>(
+    path: P,
+    options: &DirectoryOptions,
+) -> Result<Batch<(PathBuf, LZDict)>> {
+    let mut walk = walk_directory(path, options)?;
+    let mut batch = digest_files(
+        &walk.items,
+        options.error_policy,
+        options.algorithm,
+        options.digest_size.unwrap_or(K),
+        options.sampling,
+        options.skip_high_entropy,
+        None,
+    )?;
+    walk.failures.append(&mut batch.failures);
+    batch.failures = walk.failures;
+    Ok(batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::files::{
+        digest_directory, digest_files, read_path_list, walk_directory, DirectoryOptions,
+        ErrorPolicy,
+    };
+    use crate::lz_dict::K;
+    use crate::sampling::Sampling;
+    use crate::HashAlgorithm;
+    use globset::{Glob, GlobSetBuilder};
+    use std::fs::File;
+    use std::path::Path;
+
+    #[test]
+    fn test_digest_directory() {
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let dicts = digest_directory(&src, &DirectoryOptions::default())
+            .unwrap()
+            .items;
+
+        assert!(dicts.iter().any(|(path, _)| path == &src.join("lib.rs")));
+        assert!(dicts.iter().all(|(_, dict)| !dict.is_empty()));
+
+        let options = DirectoryOptions {
+            max_depth: Some(0),
+            ..Default::default()
+        };
+        assert!(digest_directory(&src, &options).unwrap().items.is_empty());
+    }
+
+    #[test]
+    fn test_patterns() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let glob_set = |patterns: &[&str]| {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in patterns {
+                builder.add(Glob::new(pattern).unwrap());
+            }
+            Some(builder.build().unwrap())
+        };
+
+        let options = DirectoryOptions {
+            include: glob_set(&["*.rs"]),
+            exclude: glob_set(&["*/src", "*/target"]),
+            ..Default::default()
+        };
+        let paths = walk_directory(root, &options).unwrap().items;
+        assert!(paths.contains(&root.join("benches").join("lzjd.rs")));
+        assert!(paths.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(paths
+            .iter()
+            .all(|path| path.extension().unwrap() == "rs" && !path.starts_with(root.join("src"))));
+    }
+
+    #[test]
+    fn test_read_path_list() {
+        let paths = read_path_list(&b"a.bin\r\n\nb c.bin\n"[..], false).unwrap();
+        assert_eq!(paths, vec![Path::new("a.bin"), Path::new("b c.bin")]);
+
+        let paths = read_path_list(&b"a\nb\0\0c"[..], true).unwrap();
+        assert_eq!(paths, vec![Path::new("a\nb"), Path::new("c")]);
+    }
+
+    #[test]
+    fn test_error_policy() {
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let paths = vec![
+            src.join("lib.rs"),
+            src.join("missing.rs"),
+            src.join("lz_dict.rs"),
+        ];
+
+        assert!(digest_files(
+            &paths,
+            ErrorPolicy::FailFast,
+            HashAlgorithm::Murmur3,
+            K,
+            Sampling::Full,
+            false,
+            None
+        )
+        .is_err());
+
+        let batch = digest_files(
+            &paths,
+            ErrorPolicy::Skip,
+            HashAlgorithm::Murmur3,
+            K,
+            Sampling::Full,
+            false,
+            None,
+        )
+        .unwrap();
+        assert_eq!(batch.items.len(), 2);
+        assert_eq!(batch.failures.len(), 1);
+        assert_eq!(batch.failures[0].0, src.join("missing.rs"));
+        // Files read by the reader threads are digested like those read while hashing
+        for (path, dict) in &batch.items {
+            let file = File::open(path).unwrap();
+            let expected = HashAlgorithm::Murmur3.digest_reader(file).unwrap();
+            assert_eq!(**dict, *expected);
+        }
+        assert_eq!(batch.items[1].0, src.join("lz_dict.rs"));
+    }
+}
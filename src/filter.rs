@@ -0,0 +1,208 @@
+//! Selection of files to digest by size and by type, as detected from their magic bytes.
+use crate::files::{Batch, ErrorPolicy};
+use crate::{LZJDError, Result};
+
+use rayon::prelude::*;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// File types that can be recognized by their first bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    /// Windows executable, starting with an MZ header
+    Pe,
+    /// ELF executable or library
+    Elf,
+    /// Zip archive, including formats based on it like jar and docx
+    Zip,
+    /// UTF-8 or ASCII text without NUL bytes
+    Text,
+}
+
+impl FileType {
+    /// Number of bytes at the start of a file that are used to detect its type
+    pub const SNIFF_LEN: usize = 1024;
+
+    /// Detects the type of a file from its first SNIFF_LEN bytes,
+    /// or all of its bytes if it is shorter than that.
+    /// Returns None for empty files and unrecognized binary data.
+    pub fn detect(header: &[u8]) -> Option<FileType> {
+        if header.starts_with(b"MZ") {
+            Some(FileType::Pe)
+        } else if header.starts_with(b"\x7fELF") {
+            Some(FileType::Elf)
+        } else if header.starts_with(b"PK\x03\x04")
+            || header.starts_with(b"PK\x05\x06")
+            || header.starts_with(b"PK\x07\x08")
+        {
+            Some(FileType::Zip)
+        } else if !header.is_empty() && !header.contains(&0) && is_utf8_prefix(header) {
+            Some(FileType::Text)
+        } else {
+            None
+        }
+    }
+
+    /// Detects the type of the file at path
+    pub fn of_file<P: AsRef<Path>>(path: P) -> Result<Option<FileType>> {
+        let mut header = Vec::with_capacity(Self::SNIFF_LEN);
+        File::open(path)?
+            .take(Self::SNIFF_LEN as u64)
+            .read_to_end(&mut header)?;
+        Ok(Self::detect(&header))
+    }
+}
+
+/// Whether bytes is valid UTF-8, except that it may be cut off
+/// in the middle of its last character
+fn is_utf8_prefix(bytes: &[u8]) -> bool {
+    match std::str::from_utf8(bytes) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    }
+}
+
+impl FromStr for FileType {
+    type Err = LZJDError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pe" => Ok(FileType::Pe),
+            "elf" => Ok(FileType::Elf),
+            "zip" => Ok(FileType::Zip),
+            "text" => Ok(FileType::Text),
+            _ => Err(LZJDError::Msg {
+                msg: format!("Unknown file type '{}', expected pe, elf, zip or text", s),
+            }),
+        }
+    }
+}
+
+/// Criteria that files need to meet to be digested
+#[derive(Debug, Clone, Default)]
+pub struct FileFilter {
+    /// Minimum size in bytes, if any
+    pub min_size: Option<u64>,
+    /// Maximum size in bytes, if any
+    pub max_size: Option<u64>,
+    /// Types of files to accept, or any file if empty
+    pub types: Vec<FileType>,
+}
+
+impl FileFilter {
+    /// Whether no file is ever rejected by this filter
+    pub fn is_empty(&self) -> bool {
+        self.min_size.is_none() && self.max_size.is_none() && self.types.is_empty()
+    }
+
+    /// Whether the file at path meets the criteria.
+    /// The file is only opened if its size matches and types is not empty.
+    pub fn matches<P: AsRef<Path>>(&self, path: P) -> Result<bool> {
+        let path = path.as_ref();
+        if !self.matches_size(fs::metadata(path)?.len()) {
+            return Ok(false);
+        }
+        if self.types.is_empty() {
+            return Ok(true);
+        }
+        Ok(self.matches_type(FileType::of_file(path)?))
+    }
+
+    /// Whether a file of len bytes meets the size criteria
+    pub fn matches_size(&self, len: u64) -> bool {
+        !(self.min_size.is_some_and(|min| len < min) || self.max_size.is_some_and(|max| len > max))
+    }
+
+    /// Whether a file of the given type, as detected by FileType::detect, meets the type criteria
+    pub fn matches_type(&self, file_type: Option<FileType>) -> bool {
+        self.types.is_empty() || file_type.is_some_and(|file_type| self.types.contains(&file_type))
+    }
+}
+
+/// Returns the paths that match filter, in the same order as paths.
+/// Files are checked in parallel.
+pub fn filter_files(
+    paths: &[PathBuf],
+    filter: &FileFilter,
+    policy: ErrorPolicy,
+) -> Result<Batch<PathBuf>> {
+    if filter.is_empty() {
+        return Ok(Batch {
+            items: paths.to_vec(),
+            failures: vec![],
+        });
+    }
+    let results: Vec<Result<bool>> = paths.par_iter().map(|path| filter.matches(path)).collect();
+    paths
+        .iter()
+        .zip(results)
+        .try_fold(Batch::default(), |mut batch, (path, result)| {
+            match result {
+                Ok(true) => batch.items.push(path.to_owned()),
+                Ok(false) => {}
+                Err(e) if policy == ErrorPolicy::Skip => batch.failures.push((path.to_owned(), e)),
+                Err(e) => return Err(e),
+            }
+            Ok(batch)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::files::ErrorPolicy;
+    use crate::filter::{filter_files, FileFilter, FileType};
+    use std::path::Path;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(FileType::detect(b"MZ\x90\x00"), Some(FileType::Pe));
+        assert_eq!(FileType::detect(b"\x7fELF\x02\x01"), Some(FileType::Elf));
+        assert_eq!(FileType::detect(b"PK\x03\x04\x14\x00"), Some(FileType::Zip));
+        assert_eq!(
+            FileType::detect("caf\u{e9}".as_bytes()),
+            Some(FileType::Text)
+        );
+        assert_eq!(
+            FileType::detect(&"caf\u{e9}".as_bytes()[..4]),
+            Some(FileType::Text)
+        );
+        assert_eq!(FileType::detect(b"text\x00"), None);
+        assert_eq!(FileType::detect(b"\xff\xfe"), None);
+        assert_eq!(FileType::detect(b""), None);
+    }
+
+    #[test]
+    fn test_filter_files() {
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let paths = vec![
+            src.join("lib.rs"),
+            src.join("filter.rs"),
+            src.join("missing.rs"),
+        ];
+
+        let filter = FileFilter {
+            max_size: Some(1),
+            ..Default::default()
+        };
+        assert!(filter_files(&paths, &filter, ErrorPolicy::FailFast).is_err());
+        let batch = filter_files(&paths, &filter, ErrorPolicy::Skip).unwrap();
+        assert!(batch.items.is_empty());
+        assert_eq!(batch.failures.len(), 1);
+
+        let filter = FileFilter {
+            types: vec![FileType::Text],
+            ..Default::default()
+        };
+        let batch = filter_files(&paths[..2], &filter, ErrorPolicy::FailFast).unwrap();
+        assert_eq!(batch.items, &paths[..2]);
+
+        let filter = FileFilter {
+            types: vec![FileType::Elf, FileType::Pe],
+            ..Default::default()
+        };
+        let batch = filter_files(&paths[..2], &filter, ErrorPolicy::FailFast).unwrap();
+        assert!(batch.items.is_empty());
+    }
+}
@@ -0,0 +1,272 @@
+//! gRPC front-end of a digest Service, offering the same operations as the
+//! HTTP front-end. The interface is described in proto/lzjd.proto; the
+//! messages below mirror it, and the server is generated by build.rs.
+use crate::service::{check_label, Service, DEFAULT_THRESHOLD};
+use crate::store::DigestId;
+use crate::{LZDict, LZJDError, Result};
+
+use std::convert::TryFrom;
+use std::sync::Arc;
+use tokio::task::block_in_place;
+use tonic::{Request, Response, Status};
+
+include!(concat!(env!("OUT_DIR"), "/lzjd.Lzjd.rs"));
+
+pub use self::lzjd_server::LzjdServer;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DigestRequest {
+    #[prost(string, tag = "1")]
+    pub label: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DigestReply {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(string, tag = "2")]
+    pub label: String,
+    #[prost(string, tag = "3")]
+    pub algorithm: String,
+    #[prost(uint64, tag = "4")]
+    pub k: u64,
+    #[prost(string, tag = "5")]
+    pub digest: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetRequest {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CompareRequest {
+    #[prost(uint64, tag = "1")]
+    pub a: u64,
+    #[prost(uint64, tag = "2")]
+    pub b: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CompareReply {
+    #[prost(double, tag = "1")]
+    pub similarity: f64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SearchRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    pub data: Vec<u8>,
+    /// Defaults to DEFAULT_THRESHOLD
+    #[prost(double, optional, tag = "2")]
+    pub threshold: Option<f64>,
+    /// Returns all matches if not given
+    #[prost(uint64, optional, tag = "3")]
+    pub top: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SearchReply {
+    #[prost(message, repeated, tag = "1")]
+    pub matches: Vec<Match>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Match {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(string, tag = "2")]
+    pub label: String,
+    #[prost(double, tag = "3")]
+    pub similarity: f64,
+}
+
+type RpcResult<T> = std::result::Result<Response<T>, Status>;
+
+/// Implementation of the generated Lzjd service on top of a Service
+pub struct GrpcService {
+    service: Arc<Service>,
+}
+
+impl GrpcService {
+    pub fn new(service: Arc<Service>) -> Self {
+        Self { service }
+    }
+
+    fn digest_reply(&self, id: DigestId, label: String, dict: &LZDict) -> DigestReply {
+        DigestReply {
+            id: id as u64,
+            label,
+            algorithm: self.service.algorithm().name().to_owned(),
+            k: self.service.k() as u64,
+            digest: dict.to_string(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl lzjd_server::Lzjd for GrpcService {
+    async fn digest(&self, request: Request<DigestRequest>) -> RpcResult<DigestReply> {
+        let DigestRequest { label, data } = request.into_inner();
+        check_label(&label).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        // Digesting is CPU bound, so keep it from stalling other requests
+        let (id, dict) =
+            block_in_place(|| self.service.add(label.clone(), &data[..])).map_err(internal)?;
+        Ok(Response::new(self.digest_reply(id, label, &dict)))
+    }
+
+    async fn get(&self, request: Request<GetRequest>) -> RpcResult<DigestReply> {
+        let id = request.into_inner().id as DigestId;
+        match self.service.get(id) {
+            Some((label, dict)) => Ok(Response::new(self.digest_reply(id, label, &dict))),
+            None => Err(Status::not_found("No such digest")),
+        }
+    }
+
+    async fn compare(&self, request: Request<CompareRequest>) -> RpcResult<CompareReply> {
+        let CompareRequest { a, b } = request.into_inner();
+        match self.service.compare(a as DigestId, b as DigestId) {
+            Some(similarity) => Ok(Response::new(CompareReply { similarity })),
+            None => Err(Status::not_found("No such digest")),
+        }
+    }
+
+    async fn search(&self, request: Request<SearchRequest>) -> RpcResult<SearchReply> {
+        let SearchRequest {
+            data,
+            threshold,
+            top,
+        } = request.into_inner();
+        let threshold = threshold.unwrap_or(DEFAULT_THRESHOLD);
+        if !(0. ..=1.).contains(&threshold) {
+            return Err(Status::invalid_argument(
+                "Threshold must be a fraction between 0 and 1",
+            ));
+        }
+        let hits = block_in_place(|| {
+            self.service.search(
+                &data[..],
+                threshold,
+                top.map(|top| usize::try_from(top).unwrap_or(usize::MAX)),
+            )
+        })
+        .map_err(internal)?;
+        let matches = hits
+            .into_iter()
+            .map(|(id, label, similarity)| Match {
+                id: id as u64,
+                label,
+                similarity,
+            })
+            .collect();
+        Ok(Response::new(SearchReply { matches }))
+    }
+}
+
+fn internal(e: LZJDError) -> Status {
+    Status::internal(e.to_string())
+}
+
+/// Serves service over gRPC on addr using num_threads worker threads.
+/// Only returns if the server could not be started or failed.
+pub fn serve(service: Arc<Service>, addr: &str, num_threads: usize) -> Result<()> {
+    let socket_addr = addr.parse().map_err(|_| LZJDError::Msg {
+        msg: format!("Invalid address to listen on: {}", addr),
+    })?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(num_threads.max(1))
+        .enable_all()
+        .build()?;
+    runtime
+        .block_on(
+            tonic::transport::Server::builder()
+                .add_service(LzjdServer::new(GrpcService::new(service)))
+                .serve(socket_addr),
+        )
+        .map_err(|e| LZJDError::Msg {
+            msg: format!("Could not serve on {}: {}", addr, e),
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grpc::lzjd_server::Lzjd;
+    use crate::grpc::{CompareRequest, DigestRequest, GetRequest, GrpcService, SearchRequest};
+    use crate::service::Service;
+    use crate::store::DigestStore;
+    use crate::HashAlgorithm;
+    use std::sync::Arc;
+    use tonic::{Code, Request};
+
+    #[test]
+    fn test_service() {
+        let service = Service::new(DigestStore::new(), HashAlgorithm::Murmur3, 1024);
+        let grpc = GrpcService::new(Arc::new(service));
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let digest = grpc
+                .digest(Request::new(DigestRequest {
+                    label: "a".to_owned(),
+                    data: b"THIS IS A TEST SEQUENCE".to_vec(),
+                }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!((digest.label.as_str(), digest.k), ("a", 1024));
+
+            let got = grpc
+                .get(Request::new(GetRequest { id: digest.id }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(got, digest);
+
+            let compared = grpc
+                .compare(Request::new(CompareRequest {
+                    a: digest.id,
+                    b: digest.id,
+                }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(compared.similarity, 1.);
+
+            let found = grpc
+                .search(Request::new(SearchRequest {
+                    data: b"THIS IS A TEST SEQUENCE".to_vec(),
+                    threshold: Some(0.5),
+                    top: None,
+                }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(found.matches.len(), 1);
+            assert_eq!(found.matches[0].id, digest.id);
+
+            let missing = grpc.get(Request::new(GetRequest { id: 42 })).await;
+            assert_eq!(missing.unwrap_err().code(), Code::NotFound);
+            let invalid = grpc
+                .search(Request::new(SearchRequest {
+                    threshold: Some(2.),
+                    ..Default::default()
+                }))
+                .await;
+            assert_eq!(invalid.unwrap_err().code(), Code::InvalidArgument);
+            let unlabeled = grpc
+                .digest(Request::new(DigestRequest {
+                    label: String::new(),
+                    data: b"THIS IS A TEST SEQUENCE".to_vec(),
+                }))
+                .await;
+            assert_eq!(unlabeled.unwrap_err().code(), Code::InvalidArgument);
+        });
+    }
+}
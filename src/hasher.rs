@@ -0,0 +1,148 @@
+//! Defines an extension of std::hash::Hasher for hashers that can be
+//! reused after calling finish.
+
+use crate::crc32::CRC32BuildHasher;
+use crate::lz_dict::{Digester, K};
+use crate::murmur3::Murmur3BuildHasher;
+use crate::siphash::SipBuildHasher;
+#[cfg(feature = "xxhash")]
+use crate::xxhash::XxBuildHasher;
+use crate::{LZDict, LZJDError, Result};
+
+use std::fmt;
+use std::hash::Hasher;
+use std::io::Read;
+use std::str::FromStr;
+
+/// Hasher that can be reset to the state it was in when it was built.
+/// Digesting resets the hasher after each LZ sequence it finds, which
+/// avoids building a new hasher for hashers with non-trivial setup.
+pub trait ResettableHasher: Hasher {
+    fn reset(&mut self);
+}
+
+/// Hash algorithms supported for digesting files. Digests can only be
+/// compared with digests created using the same algorithm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Murmur3,
+    Crc32,
+    #[cfg(feature = "xxhash")]
+    XxHash,
+    SipHash,
+}
+
+impl HashAlgorithm {
+    /// Name of the algorithm, as accepted by from_str
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Murmur3 => "murmur3",
+            HashAlgorithm::Crc32 => "crc32",
+            #[cfg(feature = "xxhash")]
+            HashAlgorithm::XxHash => "xxhash",
+            HashAlgorithm::SipHash => "siphash",
+        }
+    }
+
+    /// Digests all data read from reader using this algorithm
+    pub fn digest_reader<R: Read>(self, reader: R) -> Result<LZDict> {
+        self.digest_reader_with_k(reader, K)
+    }
+
+    /// Like digest_reader, but keeps the k smallest hashes instead of K.
+    pub fn digest_reader_with_k<R: Read>(self, reader: R, k: usize) -> Result<LZDict> {
+        self.digester(k).digest_reader(reader)
+    }
+
+    /// Creates a digester that digests many inputs like digest_reader_with_k does,
+    /// reusing its allocations between them
+    pub fn digester(self, k: usize) -> AlgorithmDigester {
+        match self {
+            HashAlgorithm::Murmur3 => {
+                AlgorithmDigester::Murmur3(Digester::new(&Murmur3BuildHasher).resetting().with_k(k))
+            }
+            HashAlgorithm::Crc32 => AlgorithmDigester::Crc32(Digester::new(&CRC32BuildHasher).resetting().with_k(k)),
+            #[cfg(feature = "xxhash")]
+            HashAlgorithm::XxHash => AlgorithmDigester::XxHash(Digester::new(&XxBuildHasher).resetting().with_k(k)),
+            HashAlgorithm::SipHash => AlgorithmDigester::SipHash(Digester::new(&SipBuildHasher).resetting().with_k(k)),
+        }
+    }
+}
+
+/// lz_dict::Digester of one of the hash algorithms, as created by HashAlgorithm::digester
+pub enum AlgorithmDigester {
+    Murmur3(Digester<'static, Murmur3BuildHasher>),
+    Crc32(Digester<'static, CRC32BuildHasher>),
+    #[cfg(feature = "xxhash")]
+    XxHash(Digester<'static, XxBuildHasher>),
+    SipHash(Digester<'static, SipBuildHasher>),
+}
+
+impl AlgorithmDigester {
+    /// Digests all data read from reader
+    pub fn digest_reader<R: Read>(&mut self, reader: R) -> Result<LZDict> {
+        match self {
+            AlgorithmDigester::Murmur3(digester) => digester.digest_reader(reader),
+            AlgorithmDigester::Crc32(digester) => digester.digest_reader(reader),
+            #[cfg(feature = "xxhash")]
+            AlgorithmDigester::XxHash(digester) => digester.digest_reader(reader),
+            AlgorithmDigester::SipHash(digester) => digester.digest_reader(reader),
+        }
+    }
+
+    /// Digests bytes
+    pub fn digest_bytes(&mut self, bytes: &[u8]) -> LZDict {
+        match self {
+            AlgorithmDigester::Murmur3(digester) => digester.digest_bytes(bytes),
+            AlgorithmDigester::Crc32(digester) => digester.digest_bytes(bytes),
+            #[cfg(feature = "xxhash")]
+            AlgorithmDigester::XxHash(digester) => digester.digest_bytes(bytes),
+            AlgorithmDigester::SipHash(digester) => digester.digest_bytes(bytes),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = LZJDError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "murmur3" => Ok(HashAlgorithm::Murmur3),
+            "crc32" => Ok(HashAlgorithm::Crc32),
+            #[cfg(feature = "xxhash")]
+            "xxhash" => Ok(HashAlgorithm::XxHash),
+            #[cfg(not(feature = "xxhash"))]
+            "xxhash" => Err(LZJDError::from("Hash algorithm 'xxhash' needs the xxhash feature of lzjd")),
+            "siphash" => Ok(HashAlgorithm::SipHash),
+            _ => Err(LZJDError::Msg {
+                msg: format!("Unknown hash algorithm '{}'", s),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hasher::HashAlgorithm;
+
+    #[test]
+    fn test_algorithms() {
+        let data = b"THIS IS A TEST SEQUENCE";
+        for name in &["murmur3", "crc32", #[cfg(feature = "xxhash")] "xxhash", "siphash"] {
+            let algorithm: HashAlgorithm = name.parse().unwrap();
+            assert_eq!(algorithm.to_string(), *name);
+
+            let a = algorithm.digest_reader(&data[..]).unwrap();
+            let b = algorithm.digest_reader(&data[..]).unwrap();
+            assert_eq!(a.similarity(&b), 1.);
+        }
+        assert!("md5".parse::<HashAlgorithm>().is_err());
+    }
+}
@@ -0,0 +1,948 @@
+//! Inverted index from the hashes of digests to the digests that hold them, for
+//! searching large corpora without comparing the query to every digest.
+//!
+//! The hashes of a digest are min-hashes, so each hash acts as a bucket of locality
+//! sensitive hashing: two digests can only be similar if they share a bucket, and
+//! the more similar they are, the more buckets they share. Searches look up the
+//! buckets of the hashes of the query, and only compare it to the digests in them.
+//!
+//! An index can be saved to a file, to which digests inserted later are appended
+//! by flush, without writing the rest of the index again. The file holds segments,
+//! each with the digests inserted since the previous one and their postings, the
+//! sorted hashes along with the ids of the digests holding each of them, so that
+//! opening an index only reads it, and does not rebuild it. A flush that was
+//! interrupted leaves a partial segment at the end of the file, which open drops.
+//!
+//! Searches look up every segment, so segments are merged as they are added, each
+//! into the one before it once it holds at least half as many postings, which keeps
+//! their number at about the logarithm of the number of flushes. compact rewrites the
+//! file with a single segment, so that opening it does not merge them again.
+//!
+//! Searches that need not find every similar digest can look up fewer buckets with
+//! ann_search, trading recall for latency, of which recall measures the effect.
+//!
+//! With the `rayon` feature, insert_all builds the postings of many digests at once on
+//! all threads, splitting the hashes into shards by their value and merging the shards.
+#[cfg(feature = "rayon")]
+use crate::{DigestRecord, Progress};
+use crate::{HashAlgorithm, LZDict, LZJDError, Result};
+
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "rayon")]
+use std::sync::Mutex;
+
+/// Identifier of a digest in a DigestIndex, which counts the digests in the order they were inserted
+pub type IndexId = u32;
+
+/// Start of index files, followed by the format version
+const MAGIC: &[u8; 8] = b"LZJDIDX\x01";
+
+/// Start of each segment of an index file
+const SEGMENT_MAGIC: &[u8; 4] = b"SEG\x00";
+
+/// Number of shards that insert_all splits the hashes into
+#[cfg(feature = "rayon")]
+pub const BUILD_SHARDS: usize = 256;
+
+/// Number of digests of which insert_all splits the hashes in one go, after which it reports progress
+#[cfg(feature = "rayon")]
+const BUILD_CHUNK_SIZE: usize = 1024;
+
+/// Postings of the digests of a segment, in compressed sparse row form: the ids of
+/// the digests holding hashes[i] are ids[offsets[i]..offsets[i + 1]]
+#[derive(Debug)]
+struct Segment {
+    hashes: Vec<i32>,
+    offsets: Vec<u32>,
+    ids: Vec<IndexId>,
+}
+
+impl Segment {
+    /// Builds the postings of dicts, of which the first has id first_id
+    fn build(dicts: &[LZDict], first_id: IndexId) -> Result<Self> {
+        let pairs = dicts
+            .iter()
+            .zip(first_id..)
+            .flat_map(|(dict, id)| dict.iter().map(move |&hash| (hash, id)))
+            .collect();
+        Self::from_pairs(pairs)
+    }
+
+    /// Builds the postings of the hashes and ids of the digests holding them
+    fn from_pairs(mut pairs: Vec<(i32, IndexId)>) -> Result<Self> {
+        pairs.sort_unstable();
+        let mut segment = Segment {
+            hashes: vec![],
+            offsets: vec![],
+            ids: Vec::with_capacity(pairs.len()),
+        };
+        for (hash, id) in pairs {
+            if segment.hashes.last() != Some(&hash) {
+                segment.hashes.push(hash);
+                segment.offsets.push(to_u32(segment.ids.len())?);
+            }
+            segment.ids.push(id);
+        }
+        segment.offsets.push(to_u32(segment.ids.len())?);
+        Ok(segment)
+    }
+
+    /// Like build, but on all threads. The hashes are split into shards of ranges of
+    /// hashes, of which the postings are built apart and then concatenated. The ranges
+    /// are quantiles of a sample of the hashes rather than fixed prefixes, as the hashes
+    /// kept in digests are the smallest ones, so most of them share their highest bits.
+    #[cfg(feature = "rayon")]
+    fn build_parallel(
+        dicts: &[LZDict],
+        first_id: IndexId,
+        progress: Option<&mut (dyn FnMut(Progress) + Send)>,
+    ) -> Result<Self> {
+        let splitters = splitters(dicts, BUILD_SHARDS);
+        let total = dicts.len() as u64;
+        let progress = progress.map(|progress| Mutex::new((0, progress)));
+        let chunks: Vec<Vec<Vec<(i32, IndexId)>>> = dicts
+            .par_chunks(BUILD_CHUNK_SIZE)
+            .enumerate()
+            .map(|(n, chunk)| {
+                let mut shards = vec![vec![]; splitters.len() + 1];
+                let chunk_id = first_id + (n * BUILD_CHUNK_SIZE) as IndexId;
+                for (dict, id) in chunk.iter().zip(chunk_id..) {
+                    for &hash in dict.iter() {
+                        shards[splitters.partition_point(|&splitter| splitter <= hash)]
+                            .push((hash, id));
+                    }
+                }
+                if let Some(progress) = &progress {
+                    let mut progress = progress.lock().unwrap();
+                    progress.0 += chunk.len() as u64;
+                    let done = progress.0;
+                    (progress.1)(Progress::DigestsIndexed { done, total });
+                }
+                shards
+            })
+            .collect();
+
+        let mut shards = vec![vec![]; splitters.len() + 1];
+        for chunk in chunks {
+            for (shard, pairs) in shards.iter_mut().zip(chunk) {
+                shard.push(pairs);
+            }
+        }
+        let shards: Vec<Segment> = shards
+            .into_par_iter()
+            .map(|parts| Segment::from_pairs(parts.concat()))
+            .collect::<Result<_>>()?;
+        // The offsets of the shards are moved by at most this many postings
+        to_u32(shards.iter().map(|shard| shard.ids.len()).sum())?;
+
+        let mut segment = Segment {
+            hashes: vec![],
+            offsets: vec![],
+            ids: vec![],
+        };
+        for shard in shards {
+            let base = to_u32(segment.ids.len())?;
+            segment.hashes.extend(shard.hashes);
+            let starts = &shard.offsets[..shard.offsets.len() - 1];
+            segment
+                .offsets
+                .extend(starts.iter().map(|&offset| base + offset));
+            segment.ids.extend(shard.ids);
+        }
+        segment.offsets.push(to_u32(segment.ids.len())?);
+        Ok(segment)
+    }
+
+    /// Ids of the digests of the segment that hold hash
+    fn postings(&self, hash: i32) -> &[IndexId] {
+        match self.hashes.binary_search(&hash) {
+            Ok(i) => self.postings_at(i),
+            Err(_) => &[],
+        }
+    }
+
+    /// Ids of the digests of the segment that hold hashes[i]
+    fn postings_at(&self, i: usize) -> &[IndexId] {
+        &self.ids[self.offsets[i] as usize..self.offsets[i + 1] as usize]
+    }
+
+    /// Merges the postings of self and later, of which the ids all follow those of self
+    fn merge(&self, later: &Segment) -> Result<Self> {
+        let mut merged = Segment {
+            hashes: Vec::with_capacity(self.hashes.len().max(later.hashes.len())),
+            offsets: vec![],
+            ids: Vec::with_capacity(self.ids.len() + later.ids.len()),
+        };
+        let (mut i, mut j) = (0, 0);
+        while let Some(&hash) = self
+            .hashes
+            .get(i)
+            .into_iter()
+            .chain(later.hashes.get(j))
+            .min()
+        {
+            merged.hashes.push(hash);
+            merged.offsets.push(to_u32(merged.ids.len())?);
+            if self.hashes.get(i) == Some(&hash) {
+                merged.ids.extend_from_slice(self.postings_at(i));
+                i += 1;
+            }
+            if later.hashes.get(j) == Some(&hash) {
+                merged.ids.extend_from_slice(later.postings_at(j));
+                j += 1;
+            }
+        }
+        merged.offsets.push(to_u32(merged.ids.len())?);
+        Ok(merged)
+    }
+}
+
+/// Searchable collection of labeled digests, created with the same settings
+#[derive(Debug)]
+pub struct DigestIndex {
+    algorithm: HashAlgorithm,
+    k: usize,
+    labels: Vec<String>,
+    dicts: Vec<LZDict>,
+    segments: Vec<Segment>,
+    /// Postings of the digests that are not in a segment yet
+    pending: HashMap<i32, Vec<IndexId>>,
+    /// Number of digests in segments
+    segmented: usize,
+    /// File that flush appends segments to, and its path
+    file: Option<(BufWriter<File>, PathBuf)>,
+}
+
+impl DigestIndex {
+    /// Creates an empty index of digests created with algorithm, keeping k hashes
+    pub fn new(algorithm: HashAlgorithm, k: usize) -> Self {
+        Self {
+            algorithm,
+            k,
+            labels: vec![],
+            dicts: vec![],
+            segments: vec![],
+            pending: HashMap::new(),
+            segmented: 0,
+            file: None,
+        }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    /// Number of digests in the index
+    pub fn len(&self) -> usize {
+        self.dicts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dicts.is_empty()
+    }
+
+    /// Label of the digest with id
+    pub fn label(&self, id: IndexId) -> &str {
+        &self.labels[id as usize]
+    }
+
+    /// Digest with id
+    pub fn dict(&self, id: IndexId) -> &LZDict {
+        &self.dicts[id as usize]
+    }
+
+    /// Adds a digest to the index and returns its id. It is only written to the
+    /// file of the index by the next flush. Panics if the index holds 2^32 digests already.
+    pub fn insert<S: Into<String>>(&mut self, label: S, dict: LZDict) -> IndexId {
+        let id = IndexId::try_from(self.dicts.len()).expect("Index holds 2^32 digests already");
+        for &hash in dict.iter() {
+            self.pending.entry(hash).or_default().push(id);
+        }
+        self.labels.push(label.into());
+        self.dicts.push(dict);
+        id
+    }
+
+    /// Returns the ids of the digests that share at least one hash with query,
+    /// along with the number of hashes they share
+    pub fn candidates(&self, query: &LZDict) -> HashMap<IndexId, u32> {
+        let mut shared = HashMap::new();
+        for &hash in query.iter() {
+            for &id in self.postings(hash) {
+                *shared.entry(id).or_insert(0) += 1;
+            }
+        }
+        shared
+    }
+
+    /// Ids of the digests holding hash, in segments and pending
+    fn postings(&self, hash: i32) -> impl Iterator<Item = &IndexId> {
+        let pending = self.pending.get(&hash).map_or(&[][..], |ids| &ids[..]);
+        let postings = self
+            .segments
+            .iter()
+            .map(move |segment| segment.postings(hash));
+        postings.chain(Some(pending)).flatten()
+    }
+
+    /// Returns the ids and similarities of the digests of which the similarity to
+    /// query is at least threshold, most similar first. Digests that share no hash
+    /// with query have a similarity of 0, so they are only returned for a threshold of 0.
+    pub fn search(&self, query: &LZDict, threshold: f64) -> Vec<(IndexId, f64)> {
+        let mut hits: Vec<(IndexId, f64)> = if threshold > 0. {
+            self.candidates(query)
+                .into_keys()
+                .map(|id| (id, query.similarity(self.dict(id))))
+                .filter(|&(_, similarity)| similarity >= threshold)
+                .collect()
+        } else {
+            (0..self.len() as IndexId)
+                .map(|id| (id, query.similarity(self.dict(id))))
+                .collect()
+        };
+        sort_hits(&mut hits);
+        hits
+    }
+
+    /// Returns the ids and similarities of at most k digests that are similar to query,
+    /// most similar first, looking up only the buckets of the probes smallest hashes of
+    /// query. A digest of which the similarity to query is J holds each hash of query
+    /// with a chance of about J, so similar digests are likely found with few probes,
+    /// and less similar ones need more. With as many probes as query has hashes, the
+    /// result is the k most similar digests of search, at the latency of search.
+    pub fn ann_search(&self, query: &LZDict, k: usize, probes: usize) -> Vec<(IndexId, f64)> {
+        let candidates: HashSet<IndexId> = query
+            .iter()
+            .take(probes)
+            .flat_map(|&hash| self.postings(hash))
+            .copied()
+            .collect();
+        let mut hits: Vec<(IndexId, f64)> = candidates
+            .into_iter()
+            .map(|id| (id, query.similarity(self.dict(id))))
+            .collect();
+        sort_hits(&mut hits);
+        hits.truncate(k);
+        hits
+    }
+
+    /// Returns the mean recall of ann_search with k and probes for queries, like a sample
+    /// of the digests in the index: the share of the k most similar digests of each query
+    /// that it finds, as found by comparing the query with every digest. Digests as
+    /// similar as the least similar of those count as found too, and digests that share
+    /// no hash with a query are not neighbors of it.
+    pub fn recall(&self, queries: &[LZDict], k: usize, probes: usize) -> f64 {
+        let recalls: Vec<f64> = queries
+            .iter()
+            .filter_map(|query| {
+                let mut exact = self.search(query, f64::MIN_POSITIVE);
+                exact.truncate(k);
+                let &(_, least) = exact.last()?;
+                let found = self
+                    .ann_search(query, k, probes)
+                    .iter()
+                    .filter(|&&(_, similarity)| similarity >= least)
+                    .count();
+                Some(found.min(exact.len()) as f64 / exact.len() as f64)
+            })
+            .collect();
+        if recalls.is_empty() {
+            return 1.;
+        }
+        recalls.iter().sum::<f64>() / recalls.len() as f64
+    }
+
+    /// Writes the index to a new file at path, which later flushes append to
+    pub fn save<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut file = BufWriter::new(File::create(path)?);
+        self.write_header(&mut file)?;
+        // All digests are written as a single segment
+        let segment = Segment::build(&self.dicts, 0)?;
+        write_segment(&mut file, &self.labels, &self.dicts, &segment)?;
+        file.flush()?;
+        self.segments = vec![segment];
+        self.pending.clear();
+        self.segmented = self.dicts.len();
+        self.file = Some((file, path.to_owned()));
+        Ok(())
+    }
+
+    /// Writes the index to writer in the format of index files, with all digests in a
+    /// single segment, like to embed it in other files. Unlike save, it does not make
+    /// later flushes append to writer.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<()> {
+        self.write_header(&mut writer)?;
+        let segment = Segment::build(&self.dicts, 0)?;
+        write_segment(&mut writer, &self.labels, &self.dicts, &segment)?;
+        Ok(writer.flush()?)
+    }
+
+    fn write_header<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(MAGIC)?;
+        write_bytes(writer, self.algorithm.name().as_bytes())?;
+        write_u32(writer, to_u32(self.k)?)
+    }
+
+    /// Opens the index saved at path, which later flushes append to. If the file ends
+    /// in a partial segment, like when a flush was interrupted, the file is truncated
+    /// to the segments before it, and the digests of that flush are left out.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut index = Self::read_header(&mut reader)?;
+        let mut complete = reader.stream_position()?;
+        loop {
+            match read_segment(&mut reader, &mut index) {
+                Ok(true) => complete = reader.stream_position()?,
+                Ok(false) => break,
+                Err(LZJDError::Io { err }) if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+        }
+        index.segmented = index.dicts.len();
+        let file = OpenOptions::new().append(true).open(path)?;
+        if file.metadata()?.len() > complete {
+            file.set_len(complete)?;
+        }
+        index.file = Some((BufWriter::new(file), path.to_owned()));
+        Ok(index)
+    }
+
+    /// Reads an index in the format of index files from reader, up to its end
+    pub fn read_from<R: Read>(mut reader: R) -> Result<Self> {
+        let mut index = Self::read_header(&mut reader)?;
+        loop {
+            match read_segment(&mut reader, &mut index) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(LZJDError::Io { err }) if err.kind() == ErrorKind::UnexpectedEof => {
+                    return Err(LZJDError::from(
+                        "Index file ends in the middle of a segment",
+                    ));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        index.segmented = index.dicts.len();
+        Ok(index)
+    }
+
+    /// Reads the start of an index file, returning an empty index with its settings
+    fn read_header<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(LZJDError::from("Not an lzjd index file"));
+        }
+        let algorithm = String::from_utf8(read_bytes(reader)?)
+            .map_err(|_| LZJDError::from("Invalid hash algorithm in index"))?
+            .parse()?;
+        let k = read_u32(reader)? as usize;
+        Ok(Self::new(algorithm, k))
+    }
+
+    /// Moves the digests inserted since the last flush into a segment of their own,
+    /// and appends it to the file of the index if it was saved or opened
+    pub fn flush(&mut self) -> Result<()> {
+        if self.segmented == self.dicts.len() {
+            return Ok(());
+        }
+        let segment = Segment::build(&self.dicts[self.segmented..], to_u32(self.segmented)?)?;
+        if let Some((file, _)) = &mut self.file {
+            let range = self.segmented..;
+            write_segment(
+                file,
+                &self.labels[range.clone()],
+                &self.dicts[range],
+                &segment,
+            )?;
+            file.flush()?;
+        }
+        self.push_segment(segment)?;
+        self.pending.clear();
+        self.segmented = self.dicts.len();
+        Ok(())
+    }
+
+    /// Inserts records and flushes them like insert and flush would, but builds their
+    /// postings on all threads, for building large indexes. Digests inserted before and
+    /// not flushed yet are flushed first. The meta of records is not kept. If given,
+    /// progress is called after the hashes of every 1024 digests were split into shards.
+    #[cfg(feature = "rayon")]
+    pub fn insert_all(
+        &mut self,
+        records: Vec<DigestRecord>,
+        progress: Option<&mut (dyn FnMut(Progress) + Send)>,
+    ) -> Result<()> {
+        self.flush()?;
+        // Ids of digests are 32 bits
+        to_u32(self.dicts.len() + records.len())?;
+        for record in records {
+            self.labels.push(record.label);
+            self.dicts.push(record.dict);
+        }
+        if self.segmented == self.dicts.len() {
+            return Ok(());
+        }
+        let range = self.segmented..;
+        let segment = Segment::build_parallel(
+            &self.dicts[range.clone()],
+            to_u32(self.segmented)?,
+            progress,
+        )?;
+        if let Some((file, _)) = &mut self.file {
+            write_segment(
+                file,
+                &self.labels[range.clone()],
+                &self.dicts[range],
+                &segment,
+            )?;
+            file.flush()?;
+        }
+        self.push_segment(segment)?;
+        self.segmented = self.dicts.len();
+        Ok(())
+    }
+
+    /// Adds segment after the others, merging it into the last one while it holds
+    /// at least half as many postings, and they fit in a segment together
+    fn push_segment(&mut self, mut segment: Segment) -> Result<()> {
+        while let Some(last) = self.segments.last() {
+            let fits = to_u32(last.ids.len() + segment.ids.len()).is_ok();
+            if segment.ids.len() * 2 < last.ids.len() || !fits {
+                break;
+            }
+            segment = last.merge(&segment)?;
+            self.segments.pop();
+        }
+        self.segments.push(segment);
+        Ok(())
+    }
+
+    /// Merges all segments into one, including the digests inserted since the last
+    /// flush, and rewrites the file of the index, if it was saved or opened, with that
+    /// single segment. The new file is written next to it and renamed over it, so an
+    /// interrupted compaction leaves the file as it was.
+    pub fn compact(&mut self) -> Result<()> {
+        let segment = Segment::build(&self.dicts, 0)?;
+        if let Some((_, path)) = &self.file {
+            let mut temp = path.clone().into_os_string();
+            temp.push(".compact");
+            let mut file = BufWriter::new(File::create(&temp)?);
+            self.write_header(&mut file)?;
+            write_segment(&mut file, &self.labels, &self.dicts, &segment)?;
+            file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            fs::rename(&temp, path)?;
+            let file = BufWriter::new(OpenOptions::new().append(true).open(path)?);
+            self.file = Some((file, path.clone()));
+        }
+        self.segments = vec![segment];
+        self.pending.clear();
+        self.segmented = self.dicts.len();
+        Ok(())
+    }
+
+    /// Number of segments that searches look up, which merging keeps
+    /// at about the logarithm of the number of flushes
+    pub fn num_segments(&self) -> usize {
+        self.segments.len()
+    }
+}
+
+/// Sorts hits by descending similarity, and hits of the same similarity by id
+fn sort_hits(hits: &mut [(IndexId, f64)]) {
+    hits.sort_by(|(id_a, a), (id_b, b)| b.total_cmp(a).then(id_a.cmp(id_b)));
+}
+
+/// Returns shards - 1 hashes that split a sample of the hashes of dicts into shards of
+/// about equal size, without duplicates
+#[cfg(feature = "rayon")]
+fn splitters(dicts: &[LZDict], shards: usize) -> Vec<i32> {
+    const SAMPLE_PER_SHARD: usize = 64;
+    let num_hashes: usize = dicts.iter().map(|dict| dict.len()).sum();
+    let step = (num_hashes / (shards * SAMPLE_PER_SHARD)).max(1);
+    let mut sample: Vec<i32> = dicts
+        .iter()
+        .flat_map(|dict| dict.iter().copied())
+        .step_by(step)
+        .collect();
+    if sample.is_empty() {
+        return vec![];
+    }
+    sample.sort_unstable();
+    let mut splitters: Vec<i32> = (1..shards)
+        .map(|i| sample[i * sample.len() / shards])
+        .collect();
+    splitters.dedup();
+    splitters
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> Result<()> {
+    Ok(writer.write_all(&value.to_le_bytes())?)
+}
+
+/// Converts a length to the 32 bits in which segments and index files hold it
+fn to_u32(len: usize) -> Result<u32> {
+    u32::try_from(len).map_err(|_| LZJDError::Msg {
+        msg: format!("Length {} is too large for an index segment", len),
+    })
+}
+
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<()> {
+    write_u32(writer, to_u32(bytes.len())?)?;
+    Ok(writer.write_all(bytes)?)
+}
+
+/// Writes values as their number followed by the values as bytes, which to_bytes
+/// gives in little-endian order
+fn write_values<W: Write, T: Copy, F: Fn(T) -> [u8; 4]>(
+    writer: &mut W,
+    values: &[T],
+    to_bytes: F,
+) -> Result<()> {
+    write_u32(writer, to_u32(values.len())?)?;
+    for &value in values {
+        writer.write_all(&to_bytes(value))?;
+    }
+    Ok(())
+}
+
+fn write_segment<W: Write>(
+    writer: &mut W,
+    labels: &[String],
+    dicts: &[LZDict],
+    segment: &Segment,
+) -> Result<()> {
+    writer.write_all(SEGMENT_MAGIC)?;
+    write_u32(writer, to_u32(dicts.len())?)?;
+    for (label, dict) in labels.iter().zip(dicts) {
+        write_bytes(writer, label.as_bytes())?;
+        write_values(writer, &dict[..], i32::to_le_bytes)?;
+    }
+    write_values(writer, &segment.hashes, i32::to_le_bytes)?;
+    write_values(writer, &segment.offsets, u32::to_le_bytes)?;
+    write_values(writer, &segment.ids, u32::to_le_bytes)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Reads bytes written by write_bytes
+fn read_bytes<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    let len = read_u32(reader)?;
+    read_bytes_of(reader, len as u64)
+}
+
+/// Reads values written by write_values, of 32 bits each
+fn read_values<R: Read, T, F: Fn([u8; 4]) -> T>(reader: &mut R, from_bytes: F) -> Result<Vec<T>> {
+    let len = read_u32(reader)?;
+    let bytes = read_bytes_of(reader, len as u64 * 4)?;
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|chunk| from_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect())
+}
+
+/// Reads len bytes. They are read as far as the reader goes rather than into a buffer
+/// of len bytes, so that a damaged length fails at the end of the file instead of
+/// allocating whatever it says.
+fn read_bytes_of<R: Read>(reader: &mut R, len: u64) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    reader.by_ref().take(len).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < len {
+        return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    Ok(bytes)
+}
+
+/// Reads the next segment into index, returning false at the end of the file.
+/// A segment that ends early, like one of which the writing was interrupted, fails
+/// with an UnexpectedEof error, and leaves index as it was.
+fn read_segment<R: Read>(reader: &mut R, index: &mut DigestIndex) -> Result<bool> {
+    let mut magic = vec![];
+    reader
+        .by_ref()
+        .take(SEGMENT_MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    if magic.is_empty() {
+        return Ok(false);
+    }
+    let first_id = to_u32(index.dicts.len())?;
+    let mut read = || -> Result<()> {
+        if magic.len() < SEGMENT_MAGIC.len() {
+            return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+        }
+        if magic != SEGMENT_MAGIC {
+            return Err(LZJDError::from("Invalid segment in index file"));
+        }
+        for _ in 0..read_u32(reader)? {
+            let label = String::from_utf8(read_bytes(reader)?)
+                .map_err(|_| LZJDError::from("Label in index is not valid UTF-8"))?;
+            // Digests keep the k they were created with, which may exceed K
+            let hashes = read_values(reader, i32::from_le_bytes)?;
+            let k = hashes.len();
+            let dict = LZDict::from_vec_with_k(hashes, k);
+            index.labels.push(label);
+            index.dicts.push(dict);
+        }
+        let segment = Segment {
+            hashes: read_values(reader, i32::from_le_bytes)?,
+            offsets: read_values(reader, u32::from_le_bytes)?,
+            ids: read_values(reader, u32::from_le_bytes)?,
+        };
+        // Postings are looked up by binary search and sliced by their offsets
+        let valid = segment.offsets.len() == segment.hashes.len() + 1
+            && segment.hashes.windows(2).all(|pair| pair[0] < pair[1])
+            && segment.offsets.windows(2).all(|pair| pair[0] <= pair[1])
+            && segment.offsets.last().map(|&end| end as usize) == Some(segment.ids.len())
+            && segment
+                .ids
+                .iter()
+                .all(|&id| id >= first_id && (id as usize) < index.dicts.len());
+        if !valid {
+            return Err(LZJDError::from("Invalid postings in index file"));
+        }
+        index.push_segment(segment)?;
+        Ok(())
+    };
+    if let Err(e) = read() {
+        index.labels.truncate(first_id as usize);
+        index.dicts.truncate(first_id as usize);
+        return Err(e);
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::DigestIndex;
+    use crate::lz_dict::K;
+    use crate::{HashAlgorithm, LZDict};
+    use std::fs;
+
+    fn digest(seed: u64, len: usize) -> LZDict {
+        let data: Vec<u8> = (0..len as u64)
+            .map(|i| ((i * seed) % 251) as u8 ^ (i / 7) as u8)
+            .collect();
+        HashAlgorithm::Murmur3.digest_reader(&data[..]).unwrap()
+    }
+
+    #[test]
+    fn test_index() {
+        let dicts: Vec<LZDict> = (1..=20).map(|seed| digest(seed, 20_000)).collect();
+        let mut index = DigestIndex::new(HashAlgorithm::Murmur3, 1024);
+        for (i, dict) in dicts[..10].iter().enumerate() {
+            assert_eq!(index.insert(format!("d{}", i), dict.clone()), i as u32);
+        }
+        let brute_force = |query: &LZDict, n: usize, threshold: f64| {
+            let mut hits: Vec<(u32, f64)> = dicts[..n]
+                .iter()
+                .enumerate()
+                .map(|(id, dict)| (id as u32, query.similarity(dict)))
+                .filter(|&(_, similarity)| similarity >= threshold)
+                .collect();
+            hits.sort_by(|(id_a, a), (id_b, b)| b.total_cmp(a).then(id_a.cmp(id_b)));
+            hits
+        };
+        for query in &dicts {
+            assert_eq!(index.search(query, 0.01), brute_force(query, 10, 0.01));
+        }
+        assert_eq!(index.search(&dicts[3], 0.).len(), 10);
+
+        let path = std::env::temp_dir().join(format!("lzjd-test-index-{}", std::process::id()));
+        index.save(&path).unwrap();
+        for (i, dict) in dicts[10..15].iter().enumerate() {
+            index.insert(format!("d{}", 10 + i), dict.clone());
+        }
+        // Pending digests are searched before they are flushed
+        assert_eq!(
+            index.search(&dicts[12], 0.01),
+            brute_force(&dicts[12], 15, 0.01)
+        );
+        index.flush().unwrap();
+        for (i, dict) in dicts[15..].iter().enumerate() {
+            index.insert(format!("d{}", 15 + i), dict.clone());
+        }
+        index.flush().unwrap();
+        // The second segment holds half as many digests as the first, so they are merged
+        assert_eq!(index.num_segments(), 2);
+        for query in &dicts {
+            assert_eq!(index.search(query, 0.01), brute_force(query, 20, 0.01));
+        }
+
+        let mut opened = DigestIndex::open(&path).unwrap();
+        assert_eq!(
+            (opened.algorithm(), opened.k(), opened.len()),
+            (HashAlgorithm::Murmur3, 1024, 20)
+        );
+        assert_eq!(opened.num_segments(), 2);
+        assert_eq!(opened.label(17), "d17");
+        for query in &dicts {
+            assert_eq!(opened.search(query, 0.01), brute_force(query, 20, 0.01));
+        }
+        // Digests inserted after opening are appended to the file
+        opened.insert("again", dicts[0].clone());
+        opened.flush().unwrap();
+        drop(opened);
+        let opened = DigestIndex::open(&path).unwrap();
+        assert_eq!(opened.len(), 21);
+        assert_eq!(opened.search(&dicts[0], 0.99).len(), 2);
+
+        // An interrupted append is detected, and dropped by open
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 10)
+            .unwrap();
+        let err = DigestIndex::read_from(&fs::read(&path).unwrap()[..]).unwrap_err();
+        assert!(err.to_string().contains("middle of a segment"));
+        let mut opened = DigestIndex::open(&path).unwrap();
+        assert_eq!(opened.len(), 20);
+        opened.insert("again", dicts[0].clone());
+        opened.flush().unwrap();
+        drop(opened);
+        let mut opened = DigestIndex::open(&path).unwrap();
+        assert_eq!(opened.len(), 21);
+        assert_eq!(opened.search(&dicts[0], 0.99).len(), 2);
+
+        // Compaction rewrites the file with a single segment
+        opened.insert("pending", dicts[1].clone());
+        opened.compact().unwrap();
+        assert_eq!(opened.num_segments(), 1);
+        let file = fs::read(&path).unwrap();
+        assert_eq!(file.windows(4).filter(|w| w == b"SEG\x00").count(), 1);
+        opened.insert("after", dicts[2].clone());
+        opened.flush().unwrap();
+        drop(opened);
+        let opened = DigestIndex::open(&path).unwrap();
+        assert_eq!((opened.len(), opened.label(22)), (23, "after"));
+        assert_eq!(opened.search(&dicts[1], 0.99).len(), 2);
+        fs::remove_file(&path).unwrap();
+
+        // A segment of which the offsets decrease is invalid
+        let mut index = DigestIndex::new(HashAlgorithm::Murmur3, 1024);
+        index.insert("a", LZDict::from(vec![1, 2, 3]));
+        let mut bytes = Vec::new();
+        index.write_to(&mut bytes).unwrap();
+        // The last values are the 4 offsets and the 3 ids, each preceded by their number
+        let offsets = bytes.len() - 16 - 16;
+        bytes[offsets..offsets + 4].copy_from_slice(&5u32.to_le_bytes());
+        let err = DigestIndex::read_from(&bytes[..]).unwrap_err();
+        assert!(err.to_string().contains("Invalid postings"));
+
+        // A damaged length is an error rather than an allocation of its size
+        let mut bytes = Vec::new();
+        DigestIndex::new(HashAlgorithm::Murmur3, 1024)
+            .write_to(&mut bytes)
+            .unwrap();
+        bytes[8..12].copy_from_slice(&[0xff; 4]);
+        assert!(DigestIndex::read_from(&bytes[..]).is_err());
+    }
+
+    #[test]
+    fn test_round_trip_with_large_k() {
+        let data: Vec<u8> = (0..200_000u64)
+            .map(|i| ((i * 7) % 251) as u8 ^ (i / 7) as u8)
+            .collect();
+        let dict = HashAlgorithm::Murmur3
+            .digest_reader_with_k(&data[..], 4096)
+            .unwrap();
+        assert!(dict.len() > K);
+        let mut index = DigestIndex::new(HashAlgorithm::Murmur3, 4096);
+        index.insert("large", dict.clone());
+        let mut bytes = Vec::new();
+        index.write_to(&mut bytes).unwrap();
+        let read = DigestIndex::read_from(&bytes[..]).unwrap();
+        assert_eq!(**read.dict(0), *dict);
+        assert_eq!(read.search(&dict, 0.99), vec![(0, 1.)]);
+    }
+
+    #[test]
+    fn test_ann_search() {
+        // Families of digests of variants of the same data, which differ in a few places
+        let mut index = DigestIndex::new(HashAlgorithm::Murmur3, 1024);
+        let mut queries = vec![];
+        for family in 0..10u64 {
+            for variant in 0..5u64 {
+                let mut data: Vec<u8> = (0..20_000u64)
+                    .map(|i| ((i * (family + 3)) % 251) as u8 ^ (i / 7) as u8)
+                    .collect();
+                for i in 0..variant {
+                    data[(i * 3989 + variant * 211) as usize % 20_000] ^= 0xff;
+                }
+                let dict = HashAlgorithm::Murmur3.digest_reader(&data[..]).unwrap();
+                if variant == 0 {
+                    queries.push(dict.clone());
+                }
+                index.insert(format!("{}-{}", family, variant), dict);
+            }
+        }
+
+        for query in &queries {
+            let exact = index.search(query, f64::MIN_POSITIVE);
+            assert_eq!(
+                index.ann_search(query, 5, 1024),
+                &exact[..5.min(exact.len())]
+            );
+            let hits = index.ann_search(query, 5, 4);
+            assert!(hits.len() <= 5);
+            assert_eq!(hits[0].1, 1.);
+        }
+        assert_eq!(index.recall(&queries, 5, 1024), 1.);
+        let recall = index.recall(&queries, 5, 4);
+        assert!(recall > 0.8 && recall <= 1., "{}", recall);
+        assert_eq!(index.ann_search(&queries[0], 5, 0), vec![]);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_insert_all() {
+        use crate::index::Segment;
+        use crate::{DigestRecord, Progress};
+
+        let records: Vec<_> = (0..1500)
+            .map(|seed| DigestRecord::new(format!("d{}", seed), digest(seed, 1000)))
+            .collect();
+        let dicts: Vec<LZDict> = records.iter().map(|record| record.dict.clone()).collect();
+        let mut index = DigestIndex::new(HashAlgorithm::Murmur3, 1024);
+        index.insert("first", digest(7, 1000));
+        let mut last = None;
+        index
+            .insert_all(records, Some(&mut |progress| last = Some(progress)))
+            .unwrap();
+        assert_eq!(
+            last,
+            Some(Progress::DigestsIndexed {
+                done: 1500,
+                total: 1500
+            })
+        );
+        assert_eq!(index.len(), 1501);
+        // The segment of the first digest is merged into the one built on all threads
+        assert_eq!(index.num_segments(), 1);
+        assert_eq!(index.label(1500), "d1499");
+
+        let expected = Segment::build(&index.dicts, 0).unwrap();
+        let built = &index.segments[0];
+        assert_eq!(built.hashes, expected.hashes);
+        assert_eq!(built.offsets, expected.offsets);
+        assert_eq!(built.ids, expected.ids);
+        assert_eq!(index.search(&dicts[5], 0.99)[0], (6, 1.));
+    }
+}
@@ -0,0 +1,183 @@
+//! Lossless conversion between file paths and the textual labels
+//! digests are stored under.
+//!
+//! Labels are the path as UTF-8, in which `%`, control characters and
+//! anything that is not valid Unicode are percent-escaped: on Unix,
+//! such bytes are written as `%XX`, on Windows, unpaired surrogates
+//! are written as `%uXXXX`.
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+fn needs_escape(c: char) -> bool {
+    c == '%' || c.is_control()
+}
+
+fn push_escaped(label: &mut String, s: &str) {
+    for c in s.chars() {
+        if needs_escape(c) {
+            let mut buf = [0; 4];
+            for b in c.encode_utf8(&mut buf).bytes() {
+                label.push_str(&format!("%{:02X}", b));
+            }
+        } else {
+            label.push(c);
+        }
+    }
+}
+
+/// Converts path into a label. Paths that are valid UTF-8 and do not
+/// contain characters that need escaping are borrowed.
+pub fn path_to_label(path: &Path) -> Cow<'_, str> {
+    if let Some(s) = path.to_str() {
+        if !s.chars().any(needs_escape) {
+            return Cow::Borrowed(s);
+        }
+    }
+    Cow::Owned(escape_os_str(path))
+}
+
+#[cfg(unix)]
+fn escape_os_str(path: &Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut label = String::new();
+    let mut bytes = path.as_os_str().as_bytes();
+    while !bytes.is_empty() {
+        match std::str::from_utf8(bytes) {
+            Ok(s) => {
+                push_escaped(&mut label, s);
+                break;
+            }
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                push_escaped(&mut label, std::str::from_utf8(valid).unwrap());
+                let invalid_len = e.error_len().unwrap_or(rest.len());
+                for b in &rest[..invalid_len] {
+                    label.push_str(&format!("%{:02X}", b));
+                }
+                bytes = &rest[invalid_len..];
+            }
+        }
+    }
+    label
+}
+
+#[cfg(windows)]
+fn escape_os_str(path: &Path) -> String {
+    use std::os::windows::ffi::OsStrExt;
+
+    let mut label = String::new();
+    for c in std::char::decode_utf16(path.as_os_str().encode_wide()) {
+        match c {
+            Ok(c) => push_escaped(&mut label, c.encode_utf8(&mut [0; 4])),
+            Err(e) => label.push_str(&format!("%u{:04X}", e.unpaired_surrogate())),
+        }
+    }
+    label
+}
+
+/// Converts a label created by path_to_label back into a path.
+/// Malformed escape sequences are kept as-is.
+pub fn label_to_path(label: &str) -> PathBuf {
+    if !label.contains('%') {
+        return PathBuf::from(label);
+    }
+    unescape(label)
+}
+
+fn parse_hex(s: &str) -> Option<u16> {
+    if s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        u16::from_str_radix(s, 16).ok()
+    } else {
+        None
+    }
+}
+
+#[cfg(unix)]
+fn unescape(label: &str) -> PathBuf {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut bytes = Vec::with_capacity(label.len());
+    let mut rest = label;
+    while let Some(i) = rest.find('%') {
+        bytes.extend_from_slice(&rest.as_bytes()[..i]);
+        rest = &rest[i..];
+        match rest.get(1..3).and_then(parse_hex) {
+            Some(b) => {
+                bytes.push(b as u8);
+                rest = &rest[3..];
+            }
+            None => {
+                bytes.push(b'%');
+                rest = &rest[1..];
+            }
+        }
+    }
+    bytes.extend_from_slice(rest.as_bytes());
+    PathBuf::from(OsStr::from_bytes(&bytes))
+}
+
+#[cfg(windows)]
+fn unescape(label: &str) -> PathBuf {
+    use std::ffi::OsString;
+    use std::os::windows::ffi::OsStringExt;
+
+    let mut wide = Vec::with_capacity(label.len());
+    let mut utf8 = Vec::new();
+    let mut rest = label;
+    let flush = |utf8: &mut Vec<u8>, wide: &mut Vec<u16>| {
+        wide.extend(String::from_utf8_lossy(utf8).encode_utf16());
+        utf8.clear();
+    };
+    while let Some(i) = rest.find('%') {
+        utf8.extend_from_slice(&rest.as_bytes()[..i]);
+        rest = &rest[i..];
+        if let Some(w) = rest
+            .get(2..6)
+            .filter(|_| rest[1..].starts_with('u'))
+            .and_then(parse_hex)
+        {
+            flush(&mut utf8, &mut wide);
+            wide.push(w);
+            rest = &rest[6..];
+        } else if let Some(b) = rest.get(1..3).and_then(parse_hex) {
+            utf8.push(b as u8);
+            rest = &rest[3..];
+        } else {
+            utf8.push(b'%');
+            rest = &rest[1..];
+        }
+    }
+    utf8.extend_from_slice(rest.as_bytes());
+    flush(&mut utf8, &mut wide);
+    PathBuf::from(OsString::from_wide(&wide))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::label::{label_to_path, path_to_label};
+    use std::path::Path;
+
+    #[test]
+    fn test_round_trip() {
+        for path in &["dir/file.bin", "100%.txt", "new\nline", "ünïcödé"] {
+            let label = path_to_label(Path::new(path));
+            assert!(!label.contains('\n'));
+            assert_eq!(label_to_path(&label), Path::new(path));
+        }
+        assert_eq!(path_to_label(Path::new("100%.txt")), "100%25.txt");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_round_trip() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(OsStr::from_bytes(b"seized/\xff\xfeimage\xc3"));
+        let label = path_to_label(path);
+        assert_eq!(label, "seized/%FF%FEimage%C3");
+        assert_eq!(label_to_path(&label), path);
+    }
+}
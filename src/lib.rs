@@ -3,42 +3,19 @@
 //! See also: https://github.com/EdwardRaff/jLZJD
 //!
 //! Any core::hash::BuildHasher is supported, just pass a &BuildHasher to LZDict::from_bytes_stream.
+//! Other settings, like the digest size or skipping high-entropy input, are set with lz_dict::LZDictOptions.
 //! For convenience, this crate provides a wrapper around the crc32 hasher which implements BuildHasher.
 //!
+//! To simply digest or compare files using the murmur3 hasher, use hash_file and compare_files.
+//! To get the distance of two streams without handling their digests, use stream_distance.
+//! The hashers of HashAlgorithm compute the same digests on every platform, and digests
+//! are written as base64 of little-endian i32 hashes, so they can be compared across machines.
+//!
 //! ## Example
 //! ```
 //! # use lzjd::lz_dict::LZDict;
-//! # use crc::crc32::{self, Hasher32};
-//! # use std::hash::BuildHasher;
-//! # use std::hash::Hasher;
-//! # pub struct CRC32Hasher {
-//! #   digest: crc::crc32::Digest,
-//! # }
-//! #
-//! # impl CRC32Hasher {
-//! #     fn new() -> Self {
-//! #       Self {
-//! #           digest: crc32::Digest::new(crc::crc32::IEEE),
-//! #       }
-//! #   }
-//! # }
-//! # impl Hasher for CRC32Hasher {
-//! #     fn write(&mut self, bytes: &[u8]) {
-//! #         Hasher32::write(&mut self.digest, bytes);
-//! #     }
-//! #     fn finish(&self) -> u64 {
-//! #         u64::from(self.digest.sum32())
-//! #     }
-//! # }
-//! # #[derive(Clone)]
-//! # pub struct CRC32BuildHasher;
-//! #
-//! # impl BuildHasher for CRC32BuildHasher {
-//! #   type Hasher = CRC32Hasher;
-//! #   fn build_hasher(&self) -> Self::Hasher {
-//! #       CRC32Hasher::new()
-//! #    }
-//! # }
+//! use lzjd::crc32::CRC32BuildHasher;
+//!
 //! let stream_a = b"bitsandpieces".iter().cloned();
 //! let stream_b = b"doctestbits".iter().cloned();
 //! let k = 1024;
@@ -53,11 +30,24 @@
 //! assert_eq!(lzjd, 0.5714285714285714);
 //! ```
 
-#[macro_use]
-extern crate failure_derive;
-
+pub use crate::cancel::CancellationToken;
+#[cfg(all(feature = "rayon", feature = "walkdir"))]
+pub use crate::files::digest_directory;
+pub use crate::hasher::HashAlgorithm;
 pub use crate::lz_dict::LZDict;
-use std::io;
+#[cfg(all(feature = "rayon", feature = "walkdir"))]
+pub use crate::pipeline::gen_compare;
+pub use crate::progress::Progress;
+pub use crate::store::DigestStore;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::hash::BuildHasher;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::thread;
 
 /// LZ dictionary implementation
 pub mod lz_dict;
@@ -65,37 +55,251 @@ pub mod lz_dict;
 pub mod crc32;
 /// murmur3 wrapper;
 pub mod murmur3;
+/// xxHash wrapper;
+#[cfg(feature = "xxhash")]
+pub mod xxhash;
+/// SipHash wrapper;
+pub mod siphash;
+/// Hasher extensions
+pub mod hasher;
+/// Thread-safe digest store
+pub mod store;
+/// Prometheus metrics of the digest service
+pub mod metrics;
+/// Persistent inverted index of digest hashes
+pub mod index;
+/// Digest service shared by network front-ends
+#[cfg(feature = "base64")]
+pub mod service;
+/// HTTP front-end of the digest service
+#[cfg(feature = "server")]
+pub mod server;
+/// gRPC front-end of the digest service
+#[cfg(feature = "grpc")]
+pub mod grpc;
+/// Client of the HTTP front-end
+#[cfg(feature = "remote")]
+pub mod client;
+/// Cooperative cancellation
+pub mod cancel;
+/// Similarity bounds for skipping dissimilar pairs
+pub mod signature;
+/// Comparison of many pairs with fixed settings
+pub mod comparator;
+/// Batch comparison
+#[cfg(feature = "rayon")]
+pub mod compare;
+/// Summary statistics of digests
+pub mod stats;
+/// Evaluation of similarities on labeled data
+pub mod eval;
+/// Subtraction of common content from digests
+pub mod baseline;
+/// Grouping of similar digests
+pub mod cluster;
+/// File and directory digesting
+#[cfg(all(feature = "rayon", feature = "walkdir"))]
+pub mod files;
+/// File selection by size and type
+#[cfg(all(feature = "rayon", feature = "walkdir"))]
+pub mod filter;
+/// Digesting and comparing files in one go
+#[cfg(all(feature = "rayon", feature = "walkdir"))]
+pub mod pipeline;
+/// Concurrent walking and digesting on network file systems
+#[cfg(feature = "async")]
+pub mod scan;
+/// Digesting of objects in object stores
+#[cfg(feature = "s3")]
+pub mod object;
+/// Digesting of downloads over HTTP(S)
+#[cfg(feature = "http")]
+pub mod download;
+/// Adapter to the traits of the RustCrypto digest crate
+#[cfg(feature = "digest")]
+pub mod rust_crypto;
+/// Arrow and Parquet export of matches and digests
+#[cfg(feature = "arrow")]
+pub mod columnar;
+/// Digesting of streamed tar and zip archives
+#[cfg(all(feature = "rayon", feature = "walkdir", feature = "archive"))]
+pub mod archive;
+/// Digesting of each line of text and NDJSON files
+pub mod records;
+/// Digests of sliding windows
+pub mod window;
+/// Portable single-file bundles of digests
+pub mod bundle;
+/// Storage of large comparison results
+pub mod results;
+/// Fault-tolerant reading of devices and disk images
+pub mod raw;
+/// Sampling of large files
+pub mod sampling;
+/// Detection and skipping of high-entropy input
+pub mod entropy;
+/// Self-test against committed vectors
+#[cfg(feature = "base64")]
+pub mod selftest;
+/// Progress reporting
+pub mod progress;
+/// Path labels
+pub mod label;
 
-#[derive(Debug, Fail)]
+/// Errors of lzjd. Errors concerning a file, a line of a file or a digest wrap
+/// the error that occurred along with where it occurred, and return it as their source.
+#[derive(Debug)]
+#[non_exhaustive]
 pub enum LZJDError {
-    #[fail(display = "IO error: {}", err)]
     Io {
-        #[cause]
         err: io::Error,
     },
-    #[fail(display = "Decode error: {}", err)]
+    #[cfg(feature = "base64")]
     Base64 {
-        #[cause]
         err: base64::DecodeError,
     },
-    #[fail(display = "Bincode error: {}", err)]
-    Bincode {
-        #[cause]
-        err: bincode::Error,
+    #[cfg(feature = "s3")]
+    ObjectStore {
+        err: object_store::Error,
+    },
+    #[cfg(feature = "arrow")]
+    Parquet {
+        err: parquet::errors::ParquetError,
+    },
+    Msg {
+        msg: String,
+    },
+    Cancelled,
+    /// Error reading or digesting the file at path
+    File {
+        path: PathBuf,
+        err: Box<LZJDError>,
+    },
+    /// Error in a line of the file at path, counting lines from 1
+    Line {
+        path: PathBuf,
+        line: usize,
+        err: Box<LZJDError>,
     },
-    #[fail(display = "Error: {}", msg)]
-    Msg { msg: String },
+    /// Error at a column of a line of the file at path, counting lines and columns from 1
+    Column {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        err: Box<LZJDError>,
+    },
+    /// Error concerning the digest labeled label
+    Digest {
+        label: String,
+        err: Box<LZJDError>,
+    },
+}
+
+impl LZJDError {
+    /// Wraps self in an error about the file at path
+    pub fn in_file<P: Into<PathBuf>>(self, path: P) -> Self {
+        LZJDError::File {
+            path: path.into(),
+            err: Box::new(self),
+        }
+    }
+
+    /// Wraps self in an error about a line of the file at path
+    pub fn at_line<P: Into<PathBuf>>(self, path: P, line: usize) -> Self {
+        LZJDError::Line {
+            path: path.into(),
+            line,
+            err: Box::new(self),
+        }
+    }
+
+    /// Wraps self in an error about a column of a line of the file at path
+    pub fn at_column<P: Into<PathBuf>>(self, path: P, line: usize, column: usize) -> Self {
+        LZJDError::Column {
+            path: path.into(),
+            line,
+            column,
+            err: Box::new(self),
+        }
+    }
+
+    /// Wraps self in an error about the digest labeled label
+    pub fn for_digest<S: Into<String>>(self, label: S) -> Self {
+        LZJDError::Digest {
+            label: label.into(),
+            err: Box::new(self),
+        }
+    }
+}
+
+impl fmt::Display for LZJDError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LZJDError::Io { err } => write!(f, "IO error: {}", err),
+            #[cfg(feature = "base64")]
+            LZJDError::Base64 { err } => write!(f, "Decode error: {}", err),
+            #[cfg(feature = "s3")]
+            LZJDError::ObjectStore { err } => write!(f, "Object store error: {}", err),
+            #[cfg(feature = "arrow")]
+            LZJDError::Parquet { err } => write!(f, "Parquet error: {}", err),
+            LZJDError::Msg { msg } => write!(f, "Error: {}", msg),
+            LZJDError::Cancelled => f.write_str("Operation cancelled"),
+            LZJDError::File { path, err } => write!(f, "{}: {}", path.display(), err),
+            LZJDError::Line { path, line, err } => {
+                write!(f, "{}, line {}: {}", path.display(), line, err)
+            }
+            LZJDError::Column { path, line, column, err } => {
+                write!(f, "{}, line {}, column {}: {}", path.display(), line, column, err)
+            }
+            LZJDError::Digest { label, err } => write!(f, "digest of {}: {}", label, err),
+        }
+    }
+}
+
+impl Error for LZJDError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LZJDError::Io { err } => Some(err),
+            #[cfg(feature = "base64")]
+            LZJDError::Base64 { err } => Some(err),
+            #[cfg(feature = "s3")]
+            LZJDError::ObjectStore { err } => Some(err),
+            #[cfg(feature = "arrow")]
+            LZJDError::Parquet { err } => Some(err),
+            LZJDError::Msg { .. } | LZJDError::Cancelled => None,
+            LZJDError::File { err, .. }
+            | LZJDError::Line { err, .. }
+            | LZJDError::Column { err, .. }
+            | LZJDError::Digest { err, .. } => Some(&**err),
+        }
+    }
 }
 
+#[cfg(feature = "base64")]
 impl From<base64::DecodeError> for LZJDError {
     fn from(err: base64::DecodeError) -> Self {
         LZJDError::Base64 { err }
     }
 }
 
-impl From<bincode::Error> for LZJDError {
-    fn from(err: bincode::Error) -> Self {
-        LZJDError::Bincode { err }
+#[cfg(feature = "s3")]
+impl From<object_store::Error> for LZJDError {
+    fn from(err: object_store::Error) -> Self {
+        LZJDError::ObjectStore { err }
+    }
+}
+
+#[cfg(feature = "arrow")]
+impl From<parquet::errors::ParquetError> for LZJDError {
+    fn from(err: parquet::errors::ParquetError) -> Self {
+        LZJDError::Parquet { err }
+    }
+}
+
+#[cfg(feature = "arrow")]
+impl From<arrow_schema::ArrowError> for LZJDError {
+    fn from(err: arrow_schema::ArrowError) -> Self {
+        LZJDError::Parquet { err: err.into() }
     }
 }
 
@@ -115,11 +319,75 @@ impl<'a> From<&'a str> for LZJDError {
 
 pub type Result<T> = std::result::Result<T, LZJDError>;
 
+/// A labeled digest, as a line of a digest file holds one
+#[derive(Debug, Clone)]
+pub struct DigestRecord {
+    /// Label of the input, like its path
+    pub label: String,
+    pub dict: LZDict,
+    /// Further attributes of the input, like the fields of the JSON records
+    /// of digest files that lzjd does not use itself
+    pub meta: BTreeMap<String, String>,
+}
+
+impl DigestRecord {
+    /// Creates a record of dict labeled label, without further attributes
+    pub fn new<S: Into<String>>(label: S, dict: LZDict) -> Self {
+        DigestRecord {
+            label: label.into(),
+            dict,
+            meta: BTreeMap::new(),
+        }
+    }
+}
+
+/// A pair of labeled digests of which the similarity met the threshold of a comparison
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match {
+    /// Label of the first digest
+    pub a: String,
+    /// Label of the second digest
+    pub b: String,
+    /// Similarity of the digests, between 0 and 1
+    pub similarity: f64,
+}
+
+/// Digests the file at path using the murmur3 hasher.
+pub fn hash_file<P: AsRef<Path>>(path: P) -> Result<LZDict> {
+    let path = path.as_ref();
+    File::open(path)
+        .map_err(LZJDError::from)
+        .and_then(|file| HashAlgorithm::Murmur3.digest_reader(file))
+        .map_err(|e| e.in_file(path))
+}
+
+/// Digests the files at a and b and returns their LZ-similarity.
+pub fn compare_files<P: AsRef<Path>, Q: AsRef<Path>>(a: P, b: Q) -> Result<f64> {
+    Ok(hash_file(a)?.similarity(&hash_file(b)?))
+}
+
+/// Digests everything read from reader_a and reader_b, each on its own thread,
+/// and returns the LZ-distance of their digests.
+pub fn stream_distance<A, B, H>(reader_a: A, reader_b: B, build_hasher: &H) -> Result<f64>
+where
+    A: Read + Send,
+    B: Read,
+    H: BuildHasher + Sync,
+{
+    thread::scope(|scope| {
+        let dict_a = scope.spawn(|| LZDict::from_reader(reader_a, build_hasher));
+        let dict_b = LZDict::from_reader(reader_b, build_hasher)?;
+        let dict_a = dict_a
+            .join()
+            .map_err(|_| LZJDError::from("Digesting thread panicked"))??;
+        Ok(dict_a.dist(&dict_b))
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use crate::crc32::CRC32BuildHasher;
     use crate::*;
-    use std::f64::EPSILON;
 
     #[test]
     fn test_optimized_dist() {
@@ -137,25 +405,53 @@ mod tests {
 
         let dist = dict_a.dist(&dict_b);
         assert!(
-            dist.abs() < EPSILON, // dist(a, b) == 0
+            dist.abs() < f64::EPSILON, // dist(a, b) == 0
             "Distance of equal sequences (a and b) should equal 0, was {}",
             dist
         );
         let dist = dict_a.dist(&dict_c);
         assert!(
-            (1. - dist).abs() < EPSILON, // dist(a, c) == 1
+            (1. - dist).abs() < f64::EPSILON, // dist(a, c) == 1
             "Distance of totally different sequences (a and c) should equal 1, was {}",
             dist
         );
         let dist = dict_a.dist(&dict_d);
         assert!(
-            (0.409_090_909_090_909_06 - dist).abs() < EPSILON, // dist(a, d) == 0.409_090_909_090_909_06
+            (0.409_090_909_090_909_06 - dist).abs() < f64::EPSILON, // dist(a, d) == 0.409_090_909_090_909_06
             "Distance of a and d should equal 0.40909090909090906, was {}",
             dist
         );
         assert!(
-            (dict_a.dist(&dict_d) - dict_d.dist(&dict_a)).abs() < EPSILON, // dist(a,d) == dist(d,a)
+            (dict_a.dist(&dict_d) - dict_d.dist(&dict_a)).abs() < f64::EPSILON, // dist(a,d) == dist(d,a)
             "Distance of a and d should be equal to distance of d and a"
         );
     }
+
+    #[test]
+    fn test_stream_distance() {
+        let build_hasher = CRC32BuildHasher;
+        let a: Vec<u8> = (0..100_000u64).map(|i| (i * i % 251) as u8).collect();
+        let b: Vec<u8> = (0..100_000u64).map(|i| (i * i % 241) as u8).collect();
+
+        let expected = LZDict::from_bytes(&a, &build_hasher).dist(&LZDict::from_bytes(&b, &build_hasher));
+        assert_eq!(stream_distance(&a[..], &b[..], &build_hasher).unwrap(), expected);
+        assert_eq!(stream_distance(&a[..], &a[..], &build_hasher).unwrap(), 0.);
+    }
+
+    #[test]
+    fn test_compare_files() {
+        let lib_rs = concat!(env!("CARGO_MANIFEST_DIR"), "/src/lib.rs");
+        let lz_dict_rs = concat!(env!("CARGO_MANIFEST_DIR"), "/src/lz_dict.rs");
+
+        let similarity = compare_files(lib_rs, lib_rs).unwrap();
+        assert!((1. - similarity).abs() < f64::EPSILON);
+
+        let similarity = compare_files(lib_rs, lz_dict_rs).unwrap();
+        assert!(similarity < 1.);
+
+        let missing = concat!(env!("CARGO_MANIFEST_DIR"), "/does/not/exist");
+        let err = hash_file(missing).unwrap_err();
+        assert!(err.to_string().starts_with(missing));
+        assert!(err.source().is_some());
+    }
 }
@@ -1,36 +1,110 @@
+use crate::cancel::CancellationToken;
+use crate::entropy::EntropyReader;
+use crate::hasher::ResettableHasher;
+use crate::progress::Progress;
 use crate::Result;
+use crate::LZJDError;
 use core::hash::BuildHasher;
 use core::hash::Hasher;
 use core::ops::Deref;
-use std::collections::HashSet;
+#[cfg(feature = "base64")]
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+use std::collections::{BTreeSet, HashSet, VecDeque};
+#[cfg(feature = "base64")]
+use std::str::FromStr;
+
+/// Default number of hashes kept in a LZ dictionary
+pub const K: usize = 1024;
+
+/// Number of hashes kept by LZDictOptions::short_input. Records of a few hundred
+/// bytes have fewer LZ phrases than K, so their digests are not truncated at K anyway,
+/// and a smaller k keeps digests of many records small.
+pub const SHORT_K: usize = 64;
+
+/// Length of the byte n-grams that LZDictOptions::short_input adds to digests
+pub const SHORT_NGRAM: usize = 3;
+
+/// Number of hashes kept by LZDict::from_bytes_stream and its variants,
+/// which have kept 1000 hashes since before K became the default
+pub const STREAM_K: usize = 1000;
+
+/// Number of distinct sequence hashes remembered while digesting, by default.
+/// Inputs with fewer sequences than this are digested exactly.
+pub const MAX_SEEN: usize = 1 << 20;
+
+/// Size of the blocks in which LZDict::from_reader reads its input
+pub const READ_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Ratio of the lengths of two dictionaries from which their intersection is
+/// found by galloping through the larger one, rather than by merging them
+pub(crate) const GALLOP_RATIO: usize = 16;
 
 /// A sorted list of the k smallest LZSet hashes
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct LZDict {
     // Once const generics are stablilized, entries can be an array
     // and the crate can become no_std
     entries: Vec<i32>,
+    /// Number of bytes digested and of LZ phrases found in them,
+    /// if the dictionary was digested from input
+    input_stats: Option<(u64, u64)>,
+    /// Fraction of the input in high-entropy blocks, if it was measured
+    high_entropy: Option<f64>,
 }
 
 impl LZDict {
-    /// Converts a base64 string into a Vec<i64> and wraps a LZDict around it.
+    /// Converts a base64 string, as written by the Display implementation,
+    /// into a Vec<i32> and wraps a LZDict around it.
+    #[cfg(feature = "base64")]
     pub fn from_base64_string(b64: &str) -> Result<Self> {
-        let bytes = base64::decode(b64)?;
-        let mut entries = vec![];
-        for i in 0..bytes.len() / 8 {
-            let vec = bytes
-                .iter()
-                .cloned()
-                .skip(i * 8)
-                .take(8)
-                .fold(vec![], |mut v, b| {
-                    v.push(b);
-                    v
-                });
-            entries.push(bincode::deserialize(&vec)?);
-        }
-
-        Ok(Self { entries })
+        Self::from_le_bytes(&base64::decode(b64)?)
+    }
+
+    /// Converts a string in any of the encodings of Encoding into a LZDict,
+    /// detecting the encoding from the characters of s.
+    #[cfg(feature = "base64")]
+    pub fn from_encoded_string(s: &str) -> Result<Self> {
+        match Encoding::detect(s) {
+            Encoding::Base64 => Self::from_base64_string(s),
+            Encoding::Base64Url => {
+                Self::from_le_bytes(&base64::decode_config(s, base64::URL_SAFE_NO_PAD)?)
+            }
+            Encoding::Hex => Self::from_le_bytes(&decode_hex(s)?),
+        }
+    }
+
+    /// Encodes the hashes as little-endian 32-bit integers in encoding.
+    /// Encoding::Base64 gives the same string as the Display implementation.
+    #[cfg(feature = "base64")]
+    pub fn to_encoded_string(&self, encoding: Encoding) -> String {
+        let bytes: Vec<u8> = self.iter().flat_map(|hash| hash.to_le_bytes()).collect();
+        match encoding {
+            Encoding::Base64 => base64::encode(&bytes),
+            Encoding::Base64Url => base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD),
+            Encoding::Hex => bytes.iter().map(|byte| format!("{:02x}", byte)).collect(),
+        }
+    }
+
+    /// Wraps a LZDict around the little-endian 32-bit hashes of bytes
+    #[cfg(feature = "base64")]
+    fn from_le_bytes(bytes: &[u8]) -> Result<Self> {
+        if !bytes.len().is_multiple_of(4) {
+            return Err(LZJDError::Msg {
+                msg: format!("Digest of {} bytes does not consist of 4-byte hashes", bytes.len()),
+            });
+        }
+        let entries = bytes
+            .chunks(4)
+            .map(|chunk| i32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect();
+
+        Ok(Self {
+            entries,
+            input_stats: None,
+            high_entropy: None,
+        })
     }
     /// Creates a LZ dictionary containing the smallest k hashes
     /// of LZ sequences obtained from seq_iter.
@@ -42,9 +116,11 @@ impl LZDict {
     {
         let mut dict: Vec<(usize, u8)> = Vec::new();
         let mut last_matching_index: usize = 0;
+        let mut len: u64 = 0;
         dict.push((0, 0));
 
         for item in seq_iter {
+            len += 1;
             if let Some(index) = dict.iter().position(
                 |(lmi, i)| lmi == &last_matching_index && i == &item
             ) {
@@ -64,7 +140,7 @@ impl LZDict {
             hasher = build_hasher.build_hasher();
 
             if let Err(insert_at) = hashes.binary_search(&hash) {
-                if hashes.len() < 1024 {
+                if hashes.len() < K {
                     hashes.insert(insert_at, hash); // Insert current hash
                 } else if hash < *hashes.last().unwrap() {
                     hashes.pop(); // Remove greatest hash
@@ -74,7 +150,11 @@ impl LZDict {
             }
         }
 
-        LZDict { entries: hashes }
+        LZDict {
+            entries: hashes,
+            input_stats: Some((len, dict.len() as u64 - 1)),
+            high_entropy: None,
+        }
     }
 
     fn hash_entry<H: Hasher>(index: usize, dict: &Vec<(usize, u8)>, hasher: &mut H) {
@@ -86,66 +166,269 @@ impl LZDict {
         hasher.write_u8(entry.1);
     }
 
+    /// Creates a LZ dictionary from the bytes of seq_iter, keeping STREAM_K hashes.
+    /// Shorthand for LZDictOptions::new(build_hasher).k(STREAM_K).build_from(IterReader::new(seq_iter)).
     pub fn from_bytes_stream<I, H>(seq_iter: I, build_hasher: &H) -> Self
         where
             I: Iterator<Item=u8>,
             H: BuildHasher,
     {
-        let mut dict = HashSet::new();
-        let mut hasher = build_hasher.build_hasher();
+        LZDictOptions::new(build_hasher)
+            .k(STREAM_K)
+            .build_from(IterReader::new(seq_iter))
+            .expect("Digesting without a cancellation token cannot fail")
+    }
 
-        for byte in seq_iter {
-            hasher.write_u8(byte);
-            let hash = hasher.finish() as i32;
-            if dict.insert(hash) {
-                hasher = build_hasher.build_hasher();
-            }
-        }
+    /// Like from_bytes_stream, but periodically reports the number of bytes
+    /// processed so far to progress.
+    pub fn from_bytes_stream_with_progress<I, H, F>(
+        seq_iter: I,
+        build_hasher: &H,
+        mut progress: F,
+    ) -> Self
+        where
+            I: Iterator<Item=u8>,
+            H: BuildHasher,
+            F: FnMut(Progress),
+    {
+        LZDictOptions::new(build_hasher)
+            .k(STREAM_K)
+            .progress(&mut progress)
+            .build_from(IterReader::new(seq_iter))
+            .expect("Digesting without a cancellation token cannot fail")
+    }
 
-        let mut dict: Vec<_> = dict.iter().cloned().collect();
-        dict.sort();
+    /// Like from_bytes_stream, but periodically checks cancel and
+    /// returns LZJDError::Cancelled once it has been cancelled.
+    pub fn from_bytes_stream_cancellable<I, H>(
+        seq_iter: I,
+        build_hasher: &H,
+        cancel: &CancellationToken,
+    ) -> Result<Self>
+        where
+            I: Iterator<Item=u8>,
+            H: BuildHasher,
+    {
+        LZDictOptions::new(build_hasher)
+            .k(STREAM_K)
+            .cancel(cancel)
+            .build_from(IterReader::new(seq_iter))
+    }
 
-        LZDict { entries: dict.iter().cloned().take(1000).collect() }
+    /// Creates a LZ dictionary from a slice of bytes.
+    pub fn from_bytes<H: BuildHasher>(bytes: &[u8], build_hasher: &H) -> Self {
+        LZDictOptions::new(build_hasher)
+            .build_from(bytes)
+            .expect("Reading a slice cannot fail")
     }
 
-    fn intersection_len(&self, other: &Self) -> usize {
-        let mut i = 0;
-        let mut j = 0;
-        let mut len = 0;
-        while i < self.len() && j < other.len() {
-            let self_entry = self[i];
-            let other_entry = other[j];
-            if self_entry <= other_entry {
-                i += 1;
-            }
-            if self_entry >= other_entry {
-                j += 1;
-            }
-            if self_entry == other_entry {
-                len += 1;
+    /// Like from_bytes_stream, but keeps the k smallest hashes instead of STREAM_K.
+    pub fn from_bytes_stream_with_k<I, H>(seq_iter: I, build_hasher: &H, k: usize) -> Self
+        where
+            I: Iterator<Item=u8>,
+            H: BuildHasher,
+    {
+        LZDictOptions::new(build_hasher)
+            .k(k)
+            .build_from(IterReader::new(seq_iter))
+            .expect("Digesting without a cancellation token cannot fail")
+    }
+
+    /// Creates a LZ dictionary from all bytes read from reader.
+    /// Reads are done in blocks of READ_BLOCK_SIZE bytes, so reader
+    /// does not need to be buffered.
+    pub fn from_reader<R, H>(reader: R, build_hasher: &H) -> Result<Self>
+        where
+            R: Read,
+            H: BuildHasher,
+    {
+        LZDictOptions::new(build_hasher).build_from(reader)
+    }
+
+    /// Like from_reader, but keeps the k smallest hashes instead of K.
+    pub fn from_reader_with_k<R, H>(reader: R, build_hasher: &H, k: usize) -> Result<Self>
+        where
+            R: Read,
+            H: BuildHasher,
+    {
+        LZDictOptions::new(build_hasher).k(k).build_from(reader)
+    }
+
+    /// Like from_bytes, but resets a single hasher instead of
+    /// building a new one for every LZ sequence.
+    pub fn from_bytes_resetting<H>(bytes: &[u8], build_hasher: &H) -> Self
+        where
+            H: BuildHasher,
+            H::Hasher: ResettableHasher,
+    {
+        LZDictOptions::new(build_hasher)
+            .resetting()
+            .build_from(bytes)
+            .expect("Reading a slice cannot fail")
+    }
+
+    /// Like from_reader, but resets a single hasher instead of
+    /// building a new one for every LZ sequence.
+    pub fn from_reader_resetting<R, H>(reader: R, build_hasher: &H) -> Result<Self>
+        where
+            R: Read,
+            H: BuildHasher,
+            H::Hasher: ResettableHasher,
+    {
+        LZDictOptions::new(build_hasher).resetting().build_from(reader)
+    }
+
+    /// Like from_reader_resetting, but keeps the k smallest hashes instead of K.
+    pub fn from_reader_resetting_with_k<R, H>(reader: R, build_hasher: &H, k: usize) -> Result<Self>
+        where
+            R: Read,
+            H: BuildHasher,
+            H::Hasher: ResettableHasher,
+    {
+        LZDictOptions::new(build_hasher).resetting().k(k).build_from(reader)
+    }
+
+    /// Like From<Vec<i32>>, but keeps the k smallest hashes instead of K,
+    /// for hashes of digests that were created with a larger k.
+    pub fn from_vec_with_k(mut entries: Vec<i32>, k: usize) -> Self {
+        entries.sort();
+        entries.dedup();
+        entries.truncate(k);
+        let dict = Self {
+            entries,
+            input_stats: None,
+            high_entropy: None,
+        };
+        debug_assert!(dict.verify(k).is_ok());
+        dict
+    }
+
+    /// Merges dictionaries into one holding the k smallest hashes of all of them.
+    /// If each holds the k smallest hashes of an LZ set, the result holds
+    /// the k smallest hashes of the union of the sets.
+    pub fn merge<'a, I: IntoIterator<Item = &'a LZDict>>(dicts: I, k: usize) -> Self {
+        let mut entries: Vec<i32> = dicts.into_iter().flat_map(|dict| dict.iter().copied()).collect();
+        entries.sort_unstable();
+        entries.dedup();
+        entries.truncate(k);
+        let dict = Self {
+            entries,
+            input_stats: None,
+            high_entropy: None,
+        };
+        debug_assert!(dict.verify(k).is_ok());
+        dict
+    }
+
+    /// Checks that the dictionary is a valid digest with at most k hashes: that its hashes
+    /// are sorted and unique, as comparisons assume. Digests decoded from base64 are
+    /// not checked, so those from untrusted sources should be verified before comparing them.
+    pub fn verify(&self, k: usize) -> Result<()> {
+        if self.len() > k {
+            return Err(LZJDError::Msg {
+                msg: format!("Digest has {} hashes, more than k={}", self.len(), k),
+            });
+        }
+        match self.windows(2).position(|pair| pair[0] >= pair[1]) {
+            Some(i) if self[i] == self[i + 1] => Err(LZJDError::Msg {
+                msg: format!("Digest contains duplicate hash {} at index {}", self[i], i + 1),
+            }),
+            Some(i) => Err(LZJDError::Msg {
+                msg: format!("Digest hashes are not sorted at index {}", i + 1),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Number of LZ phrases the input was split into while digesting it, a measure of
+    /// its complexity. None if the dictionary was not digested from input, like one
+    /// decoded from base64, as only the smallest hashes of the phrases are kept.
+    pub fn phrase_count(&self) -> Option<u64> {
+        self.input_stats.map(|(_, phrases)| phrases)
+    }
+
+    /// Estimates how many times smaller LZ78 compression would make the input,
+    /// from the number of phrases it was split into, each of which LZ78 encodes
+    /// as the index of an earlier phrase and a byte. Random data has a ratio
+    /// below 1, and redundant data a large one. None if phrase_count is None
+    /// or the input was empty.
+    pub fn estimate_compression_ratio(&self) -> Option<f64> {
+        match self.input_stats? {
+            (_, 0) => None,
+            (len, phrases) => {
+                let phrases = phrases as f64;
+                let compressed_bits = phrases * (phrases.log2().ceil() + 8.);
+                Some(len as f64 * 8. / compressed_bits)
             }
         }
-        len
+    }
+
+    /// Fraction of the input that is in high-entropy blocks, like compressed or encrypted
+    /// data, as measured by entropy::EntropyReader while digesting it. None if it was not
+    /// measured, which only files::digest_files does.
+    pub fn high_entropy_fraction(&self) -> Option<f64> {
+        self.high_entropy
+    }
+
+    /// Records the fraction of the input that is in high-entropy blocks
+    pub fn with_high_entropy_fraction(self, fraction: f64) -> Self {
+        Self {
+            high_entropy: Some(fraction),
+            ..self
+        }
     }
 
     /// Calculates the jaccard similarity of the entries two dictionaries
     /// which is defined as the length of the intersection over the length of the union.
     pub fn jaccard_similarity(&self, other: &Self) -> f64 {
-        let intersection_len = self.intersection_len(other);
-
-        let union_len = self.len() + other.len() - intersection_len;
+        jaccard_similarity(self, other)
+    }
 
+    /// Estimates the jaccard similarity of the sets of all hashes of the inputs of two
+    /// dictionaries of up to k hashes each, from the k smallest hashes of their union.
+    /// Unlike jaccard_similarity, which compares the kept hashes as they are, this is
+    /// unbiased when the inputs have more hashes than k and differ in size, as the kept
+    /// hashes of the larger one span a smaller range. For inputs with fewer hashes than k,
+    /// both give the exact similarity.
+    pub fn bottom_k_similarity(&self, other: &Self, k: usize) -> f64 {
+        let (a, b) = (&self[..], &other[..]);
+        let (mut i, mut j) = (0, 0);
+        let (mut union_len, mut intersection_len) = (0, 0);
+        while union_len < k && (i < a.len() || j < b.len()) {
+            match (a.get(i), b.get(j)) {
+                (Some(x), Some(y)) if x == y => {
+                    intersection_len += 1;
+                    i += 1;
+                    j += 1;
+                }
+                (Some(x), Some(y)) if x < y => i += 1,
+                (Some(_), None) => i += 1,
+                _ => j += 1,
+            }
+            union_len += 1;
+        }
         intersection_len as f64 / union_len as f64
     }
 
-    /// Encodes the contents of the dictionary to base64 and returns it as a string.
-    pub fn to_string(&self) -> String {
-        let bytes: Vec<u8> = self
-            .iter()
-            .map(|hash| bincode::serialize(&hash).unwrap())
-            .flatten()
-            .collect();
-        base64::encode(&bytes)
+    /// Calculates the similarity of a dictionary of the k smallest hashes and one of
+    /// the other_k smallest hashes, by comparing the smallest min(k, other_k) hashes of
+    /// both, which are the dictionaries that digesting with that k would have given.
+    /// Comparing them as they are would count the extra hashes of the larger one as
+    /// differences, biasing the similarity down. Returns the similarity and that k.
+    pub fn compare_compat(&self, k: usize, other: &Self, other_k: usize) -> (f64, usize) {
+        let k = k.min(other_k);
+        let similarity = jaccard_similarity(
+            &self[..self.len().min(k)],
+            &other[..other.len().min(k)],
+        );
+        (similarity, k)
+    }
+
+    /// Keeps only the k smallest hashes, so that the dictionary can be compared with
+    /// dictionaries of k hashes
+    pub fn truncated(mut self, k: usize) -> Self {
+        self.entries.truncate(k);
+        self
     }
 
     /// Calculates the LZ-distance of two LZ Dictionaries
@@ -157,6 +440,668 @@ impl LZDict {
     pub fn similarity(&self, other: &LZDict) -> f64 {
         self.jaccard_similarity(other)
     }
+
+    /// Calculates the similarity of two LZ Dictionaries as an integer score
+    /// between 0 and 100, as sdhash scores matches. See similarity_score.
+    pub fn score(&self, other: &LZDict) -> u8 {
+        similarity_score(self.similarity(other))
+    }
+}
+
+/// Converts a similarity between 0 and 1 into an integer score between 0 and 100,
+/// like those of sdhash, so that thresholds on them carry over. Similarities are
+/// rounded to the nearest percentage, except that only identical digests score 100.
+pub fn similarity_score(similarity: f64) -> u8 {
+    let score = (similarity * 100.).round().clamp(0., 100.) as u8;
+    if score == 100 && similarity < 1. {
+        99
+    } else {
+        score
+    }
+}
+
+/// Returns the lowest similarity of which similarity_score is at least score,
+/// so that comparisons can leave out pairs that score lower while they run
+pub fn min_similarity_for_score(score: u8) -> f64 {
+    match score {
+        0 => 0.,
+        100.. => 1.,
+        score => {
+            let mut similarity = (score as f64 - 0.5) / 100.;
+            // Rounding of the multiplication by 100 decides at the boundary
+            while similarity_score(similarity) < score {
+                similarity = similarity.next_up();
+            }
+            while similarity_score(similarity.next_down()) >= score {
+                similarity = similarity.next_down();
+            }
+            similarity
+        }
+    }
+}
+
+/// Settings with which to digest input into a LZDict, for the combinations
+/// that the from_* constructors of LZDict do not cover.
+///
+/// ```
+/// # use lzjd::lz_dict::LZDictOptions;
+/// # use lzjd::murmur3::Murmur3BuildHasher;
+/// let dict = LZDictOptions::new(&Murmur3BuildHasher)
+///     .k(256)
+///     .resetting()
+///     .skip_high_entropy()
+///     .build_from(&b"THIS IS A TEST SEQUENCE"[..])
+///     .unwrap();
+/// assert_eq!(dict.high_entropy_fraction(), Some(0.));
+/// ```
+pub struct LZDictOptions<'a, H: BuildHasher> {
+    build_hasher: &'a H,
+    /// Prepares the hasher for the next sequence
+    restart: fn(&mut H::Hasher, &H),
+    k: usize,
+    /// Length of the n-grams added to the digest, 0 for none
+    ngram: usize,
+    /// Whether high-entropy blocks are skipped, if their fraction is measured
+    entropy: Option<bool>,
+    /// Number of seen sequences at which those that cannot be in the digest are forgotten
+    max_seen: usize,
+    cancel: Option<&'a CancellationToken>,
+    progress: Option<&'a mut dyn FnMut(Progress)>,
+}
+
+impl<'a, H: BuildHasher> LZDictOptions<'a, H> {
+    /// Options that digest with the hashers of build_hasher, keeping K hashes,
+    /// building a new hasher for every LZ sequence
+    pub fn new(build_hasher: &'a H) -> Self {
+        Self {
+            build_hasher,
+            restart: |hasher, build_hasher| *hasher = build_hasher.build_hasher(),
+            k: K,
+            ngram: 0,
+            entropy: None,
+            max_seen: MAX_SEEN,
+            cancel: None,
+            progress: None,
+        }
+    }
+
+    /// Keeps the k smallest hashes instead of K
+    pub fn k(self, k: usize) -> Self {
+        Self { k, ..self }
+    }
+
+    /// Resets a single hasher instead of building a new one for every LZ sequence
+    pub fn resetting(self) -> Self
+        where
+            H::Hasher: ResettableHasher,
+    {
+        Self {
+            restart: |hasher, _| hasher.reset(),
+            ..self
+        }
+    }
+
+    /// Adds the hashes of every n-gram of n bytes of the input to those of its LZ phrases,
+    /// or none if n is 0. The LZ phrases of a short input are few, and an edit near its
+    /// start changes how all of the rest is split into phrases, while it only changes
+    /// the n-grams around it. Hashing every n-gram takes n times as long as digesting
+    /// without them, so they are meant for short inputs.
+    pub fn ngrams(self, n: usize) -> Self {
+        Self { ngram: n, ..self }
+    }
+
+    /// Settings for short records, like log lines, messages or function bodies of up to
+    /// a kilobyte or so: keeps SHORT_K hashes and adds n-grams of SHORT_NGRAM bytes.
+    /// Compare the digests with LZDict::bottom_k_similarity with SHORT_K, as with n-grams,
+    /// most records have more than SHORT_K hashes, of which the plain similarity is biased.
+    pub fn short_input(self) -> Self {
+        self.k(SHORT_K).ngrams(SHORT_NGRAM)
+    }
+
+    /// Measures the fraction of the input in high-entropy blocks,
+    /// as returned by LZDict::high_entropy_fraction
+    pub fn measure_entropy(self) -> Self {
+        Self {
+            entropy: Some(self.entropy.unwrap_or(false)),
+            ..self
+        }
+    }
+
+    /// Leaves high-entropy blocks out of the digest, and measures their fraction
+    pub fn skip_high_entropy(self) -> Self {
+        Self {
+            entropy: Some(true),
+            ..self
+        }
+    }
+
+    /// Bounds the memory used while digesting to max_seen LZ sequences instead of MAX_SEEN,
+    /// or twice k if that is more. Once that many are seen, those with a hash above the
+    /// k smallest so far are forgotten, as they cannot be in the digest anymore. They are
+    /// counted as new when they are seen again, so the digests of inputs with more distinct
+    /// sequences than the bound differ from exact ones. usize::MAX digests exactly.
+    pub fn max_seen(self, max_seen: usize) -> Self {
+        Self { max_seen, ..self }
+    }
+
+    /// Checks cancel between blocks of input, failing with
+    /// LZJDError::Cancelled once it has been cancelled
+    pub fn cancel(self, cancel: &'a CancellationToken) -> Self {
+        Self {
+            cancel: Some(cancel),
+            ..self
+        }
+    }
+
+    /// Reports the number of bytes processed so far to progress
+    /// between blocks of input, and once all of it is processed
+    pub fn progress(self, progress: &'a mut dyn FnMut(Progress)) -> Self {
+        Self {
+            progress: Some(progress),
+            ..self
+        }
+    }
+
+    /// Digests all bytes read from reader. Reads are done in blocks of
+    /// READ_BLOCK_SIZE bytes, so reader does not need to be buffered.
+    /// Byte iterators can be read with IterReader.
+    pub fn build_from<R: Read>(mut self, reader: R) -> Result<LZDict> {
+        let mut state = DigestState {
+            restart: self.restart,
+            ngram: self.ngram,
+            max_seen: self.max_seen,
+            ..DigestState::new(self.build_hasher).with_k(self.k)
+        };
+        let mut reader = EntropyReader::new(reader, self.entropy == Some(true));
+        let mut buf = vec![0; READ_BLOCK_SIZE];
+        let mut bytes_processed = 0;
+        loop {
+            if let Some(cancel) = self.cancel {
+                cancel.check()?;
+            }
+            if let Some(progress) = self.progress.as_mut() {
+                progress(Progress::BytesProcessed(bytes_processed));
+            }
+            let read = match self.entropy {
+                Some(_) => reader.read(&mut buf),
+                // Reading the inner reader directly saves copying blocks
+                None => reader.get_mut().read(&mut buf),
+            };
+            match read {
+                Ok(0) => break,
+                Ok(n) => {
+                    state.write(&buf[..n]);
+                    bytes_processed += n as u64;
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if let Some(progress) = self.progress.as_mut() {
+            progress(Progress::BytesProcessed(bytes_processed));
+        }
+
+        let dict = state.finish();
+        Ok(match self.entropy {
+            Some(_) => dict.with_high_entropy_fraction(reader.high_entropy_fraction()),
+            None => dict,
+        })
+    }
+}
+
+/// Reader of the bytes of an iterator, so that byte streams can be digested
+/// with LZDictOptions::build_from
+pub struct IterReader<I> {
+    iter: I,
+}
+
+impl<I: Iterator<Item = u8>> IterReader<I> {
+    pub fn new(iter: I) -> Self {
+        Self { iter }
+    }
+}
+
+impl<I: Iterator<Item = u8>> Read for IterReader<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut len = 0;
+        for (slot, byte) in buf.iter_mut().zip(&mut self.iter) {
+            *slot = byte;
+            len += 1;
+        }
+        Ok(len)
+    }
+}
+
+/// Writer that digests all bytes written to it, so that anything that writes to a
+/// Write, like io::copy, serializers or decompressors, can produce a digest.
+/// Writes never fail, and bytes do not need to be written in blocks of any size.
+///
+/// ```
+/// # use lzjd::lz_dict::{LZDict, LZDictSink};
+/// # use lzjd::murmur3::Murmur3BuildHasher;
+/// use std::io::Write;
+///
+/// let mut sink = LZDictSink::new(&Murmur3BuildHasher).resetting();
+/// write!(sink, "THIS IS A {} SEQUENCE", "TEST").unwrap();
+/// let dict = sink.finish();
+/// assert_eq!(*dict, *LZDict::from_bytes_resetting(b"THIS IS A TEST SEQUENCE", &Murmur3BuildHasher));
+/// ```
+pub struct LZDictSink<'a, H: BuildHasher> {
+    state: DigestState<'a, H>,
+}
+
+impl<'a, H: BuildHasher> LZDictSink<'a, H> {
+    /// Creates a sink that digests with the hashers of build_hasher, keeping K hashes,
+    /// building a new hasher for every LZ sequence
+    pub fn new(build_hasher: &'a H) -> Self {
+        Self {
+            state: DigestState::new(build_hasher),
+        }
+    }
+
+    /// Keeps the k smallest hashes instead of K. Must be set before writing.
+    pub fn with_k(self, k: usize) -> Self {
+        debug_assert_eq!(self.state.len, 0);
+        Self {
+            state: self.state.with_k(k),
+        }
+    }
+
+    /// Resets a single hasher instead of building a new one for every LZ sequence.
+    /// Must be set before writing.
+    pub fn resetting(self) -> Self
+        where
+            H::Hasher: ResettableHasher,
+    {
+        debug_assert_eq!(self.state.len, 0);
+        Self {
+            state: DigestState {
+                restart: |hasher, _| hasher.reset(),
+                ..self.state
+            },
+        }
+    }
+
+    /// Digests bytes, like writing them does
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.state.write(bytes);
+    }
+
+    /// Number of bytes written so far
+    pub fn len(&self) -> u64 {
+        self.state.len
+    }
+
+    /// Whether no bytes have been written yet
+    pub fn is_empty(&self) -> bool {
+        self.state.len == 0
+    }
+
+    /// Returns the digest of all bytes written
+    pub fn finish(self) -> LZDict {
+        self.state.finish()
+    }
+}
+
+impl<H: BuildHasher> Write for LZDictSink<'_, H> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Digester of many inputs one after another with the same settings. Unlike the
+/// from_* constructors, it reuses its set of seen LZ sequences and its read buffer
+/// between inputs, so digesting many small inputs does not allocate them for each.
+/// The set keeps the capacity needed by the largest input digested so far.
+///
+/// ```
+/// # use lzjd::lz_dict::{Digester, LZDict};
+/// # use lzjd::murmur3::Murmur3BuildHasher;
+/// let mut digester = Digester::new(&Murmur3BuildHasher).resetting();
+/// for record in [&b"first record"[..], b"second record"] {
+///     let dict = digester.digest_bytes(record);
+///     assert_eq!(*dict, *LZDict::from_bytes_resetting(record, &Murmur3BuildHasher));
+/// }
+/// ```
+pub struct Digester<'a, H: BuildHasher> {
+    build_hasher: &'a H,
+    /// Prepares the hasher for the next sequence
+    restart: fn(&mut H::Hasher, &H),
+    k: usize,
+    /// Emptied set of seen sequences, kept for its capacity
+    seen: HashSet<i32>,
+    /// Buffer of READ_BLOCK_SIZE bytes, allocated on first use
+    buf: Vec<u8>,
+}
+
+impl<'a, H: BuildHasher> Digester<'a, H> {
+    /// Creates a digester that digests with the hashers of build_hasher, keeping K hashes,
+    /// building a new hasher for every LZ sequence
+    pub fn new(build_hasher: &'a H) -> Self {
+        Self {
+            build_hasher,
+            restart: |hasher, build_hasher| *hasher = build_hasher.build_hasher(),
+            k: K,
+            seen: HashSet::new(),
+            buf: Vec::new(),
+        }
+    }
+
+    /// Keeps the k smallest hashes instead of K
+    pub fn with_k(self, k: usize) -> Self {
+        Self { k, ..self }
+    }
+
+    /// Resets a single hasher instead of building a new one for every LZ sequence
+    pub fn resetting(self) -> Self
+        where
+            H::Hasher: ResettableHasher,
+    {
+        Self {
+            restart: |hasher, _| hasher.reset(),
+            ..self
+        }
+    }
+
+    /// Digests all bytes read from reader. Reads are done in blocks of
+    /// READ_BLOCK_SIZE bytes, so reader does not need to be buffered.
+    pub fn digest_reader<R: Read>(&mut self, mut reader: R) -> Result<LZDict> {
+        let mut state = self.state();
+        self.buf.resize(READ_BLOCK_SIZE, 0);
+        loop {
+            match reader.read(&mut self.buf) {
+                Ok(0) => break,
+                Ok(n) => state.write(&self.buf[..n]),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.finish(state);
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(self.finish(state))
+    }
+
+    /// Digests bytes
+    pub fn digest_bytes(&mut self, bytes: &[u8]) -> LZDict {
+        let mut state = self.state();
+        state.write(bytes);
+        self.finish(state)
+    }
+
+    /// Creates the state of digesting the next input, which takes the set of seen sequences
+    fn state(&mut self) -> DigestState<'a, H> {
+        DigestState {
+            restart: self.restart,
+            seen: mem::take(&mut self.seen),
+            ..DigestState::new(self.build_hasher).with_k(self.k)
+        }
+    }
+
+    /// Finishes the digest of state, taking back the set of seen sequences
+    fn finish(&mut self, mut state: DigestState<'a, H>) -> LZDict {
+        self.seen = mem::take(&mut state.seen);
+        self.seen.clear();
+        state.finish()
+    }
+}
+
+/// Length of the intersection of a and b over the length of their union
+fn jaccard_similarity(a: &[i32], b: &[i32]) -> f64 {
+    let intersection_len = intersection_len(a, b);
+
+    let union_len = a.len() + b.len() - intersection_len;
+
+    intersection_len as f64 / union_len as f64
+}
+
+pub(crate) fn intersection_len(a: &[i32], b: &[i32]) -> usize {
+    let (small, large) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    if small.len() * GALLOP_RATIO <= large.len() {
+        gallop_intersection_len(small, large)
+    } else {
+        merge_intersection_len(small, large)
+    }
+}
+
+pub(crate) fn merge_intersection_len(a: &[i32], b: &[i32]) -> usize {
+    let mut i = 0;
+    let mut j = 0;
+    let mut len = 0;
+    while i < a.len() && j < b.len() {
+        let a_entry = a[i];
+        let b_entry = b[j];
+        if a_entry <= b_entry {
+            i += 1;
+        }
+        if a_entry >= b_entry {
+            j += 1;
+        }
+        if a_entry == b_entry {
+            len += 1;
+        }
+    }
+    len
+}
+
+/// Counts the entries that small shares with large by searching for each entry of small
+/// in large, from where the previous search ended, in steps that double until they pass
+/// the entry. Takes O(small.len() * log(large.len() / small.len())) comparisons.
+fn gallop_intersection_len(small: &[i32], large: &[i32]) -> usize {
+    let mut start = 0;
+    let mut len = 0;
+    for &entry in small {
+        if start >= large.len() {
+            break;
+        }
+        let mut step = 1;
+        while start + step < large.len() && large[start + step] < entry {
+            step *= 2;
+        }
+        let end = (start + step + 1).min(large.len());
+        match large[start..end].binary_search(&entry) {
+            Ok(i) => {
+                len += 1;
+                start += i + 1;
+            }
+            Err(i) => start += i,
+        }
+    }
+    len
+}
+
+/// Encodes the hashes as little-endian 32-bit integers in base64, which is
+/// the canonical form of a digest, the same on every platform
+#[cfg(feature = "base64")]
+impl fmt::Display for LZDict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.to_encoded_string(Encoding::Base64))
+    }
+}
+
+/// Textual encoding of the little-endian bytes of the hashes of a digest
+#[cfg(feature = "base64")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// Standard base64 with padding, the canonical form
+    #[default]
+    Base64,
+    /// URL-safe base64 without padding, which uses '-' and '_' instead of '+' and '/'
+    Base64Url,
+    /// Lowercase hexadecimal
+    Hex,
+}
+
+#[cfg(feature = "base64")]
+impl Encoding {
+    /// Detects the encoding of the digest s. Strings without characters that tell
+    /// the two base64 encodings apart decode the same with either of them.
+    /// Hex strings of whole hashes are also valid base64 of different hashes,
+    /// so those are taken to be hex.
+    pub fn detect(s: &str) -> Self {
+        if s.contains(['-', '_']) {
+            Encoding::Base64Url
+        } else if s.contains(['+', '/', '=']) {
+            Encoding::Base64
+        } else if s.len().is_multiple_of(8) && s.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            Encoding::Hex
+        } else if s.len().is_multiple_of(4) {
+            Encoding::Base64
+        } else {
+            Encoding::Base64Url
+        }
+    }
+}
+
+#[cfg(feature = "base64")]
+impl FromStr for Encoding {
+    type Err = LZJDError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "base64" => Ok(Encoding::Base64),
+            "base64url" => Ok(Encoding::Base64Url),
+            "hex" => Ok(Encoding::Hex),
+            _ => Err(LZJDError::from(
+                "Unknown encoding, expected 'base64', 'base64url' or 'hex'",
+            )),
+        }
+    }
+}
+
+/// Decodes the hexadecimal string s, of either case
+#[cfg(feature = "base64")]
+fn decode_hex(s: &str) -> Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return Err(LZJDError::from("Hex digest has an odd number of digits"));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| LZJDError::Msg {
+                msg: format!("Invalid hex digits '{}' in digest", &s[i..i + 2]),
+            })
+        })
+        .collect()
+}
+
+/// The k smallest LZ sequence hashes found so far, along with
+/// the hasher of the sequence that is currently being matched
+struct DigestState<'a, H: BuildHasher> {
+    build_hasher: &'a H,
+    hasher: H::Hasher,
+    /// Number of bytes written
+    len: u64,
+    /// Number of sequences completed
+    phrases: u64,
+    /// Prepares hasher for the next sequence
+    restart: fn(&mut H::Hasher, &H),
+    /// Hashes of the sequences seen
+    seen: HashSet<i32>,
+    /// Number of hashes in seen at which those above smallest are removed from it
+    max_seen: usize,
+    smallest: BTreeSet<i32>,
+    /// Number of hashes to keep in smallest
+    k: usize,
+    /// Length of the n-grams of which the hashes are added to the LZ phrases, 0 for none
+    ngram: usize,
+    /// Last ngram bytes written
+    window: VecDeque<u8>,
+}
+
+impl<'a, H: BuildHasher> DigestState<'a, H> {
+    /// Creates a state that builds a new hasher for every sequence
+    fn new(build_hasher: &'a H) -> Self {
+        Self {
+            build_hasher,
+            hasher: build_hasher.build_hasher(),
+            len: 0,
+            phrases: 0,
+            restart: |hasher, build_hasher| *hasher = build_hasher.build_hasher(),
+            seen: HashSet::new(),
+            max_seen: MAX_SEEN,
+            smallest: BTreeSet::new(),
+            k: K,
+            ngram: 0,
+            window: VecDeque::new(),
+        }
+    }
+
+    /// Keeps the k smallest hashes instead of K
+    fn with_k(self, k: usize) -> Self {
+        Self { k, ..self }
+    }
+
+    fn write_u8(&mut self, byte: u8) {
+        if self.ngram > 0 {
+            self.write_ngram(byte);
+        }
+        self.hasher.write_u8(byte);
+        self.len += 1;
+        let hash = self.hasher.finish() as i32;
+        if self.seen.insert(hash) {
+            self.phrases += 1;
+            self.insert_smallest(hash);
+            (self.restart)(&mut self.hasher, self.build_hasher);
+
+            // At least twice k, as the k smallest stay in seen
+            if self.seen.len() >= self.max_seen.max(2 * self.k) {
+                self.forget_seen();
+            }
+        }
+    }
+
+    /// Removes the sequences from seen that cannot be in the digest anymore,
+    /// like LZW implementations do with their dictionary when it is full
+    fn forget_seen(&mut self) {
+        match self.smallest.last() {
+            Some(&last) => self.seen.retain(|&hash| hash <= last),
+            None => self.seen.clear(),
+        }
+    }
+
+    /// Adds the hash of the n-gram that ends with byte. N-grams are hashed like LZ phrases,
+    /// so an n-gram that is also a phrase has the same hash.
+    fn write_ngram(&mut self, byte: u8) {
+        if self.window.len() == self.ngram {
+            self.window.pop_front();
+        }
+        self.window.push_back(byte);
+        if self.window.len() == self.ngram {
+            let mut hasher = self.build_hasher.build_hasher();
+            self.window.iter().for_each(|&byte| hasher.write_u8(byte));
+            self.insert_smallest(hasher.finish() as i32);
+        }
+    }
+
+    fn insert_smallest(&mut self, hash: i32) {
+        if self.smallest.len() < self.k {
+            self.smallest.insert(hash);
+        } else if self.smallest.last().is_some_and(|&last| hash < last) && self.smallest.insert(hash) {
+            self.smallest.pop_last();
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u8(byte);
+        }
+    }
+
+    fn finish(self) -> LZDict {
+        let dict = LZDict {
+            entries: self.smallest.into_iter().collect(),
+            input_stats: Some((self.len, self.phrases)),
+            high_entropy: None,
+        };
+        debug_assert!(dict.verify(self.k).is_ok());
+        dict
+    }
 }
 
 impl Deref for LZDict {
@@ -168,10 +1113,8 @@ impl Deref for LZDict {
 }
 
 impl From<Vec<i32>> for LZDict {
-    fn from(mut entries: Vec<i32>) -> Self {
-        entries.sort();
-        entries.truncate(1024);
-        Self { entries }
+    fn from(entries: Vec<i32>) -> Self {
+        Self::from_vec_with_k(entries, K)
     }
 }
 
@@ -184,8 +1127,17 @@ impl From<LZDict> for Vec<i32> {
 #[cfg(test)]
 mod tests {
     use crate::crc32::CRC32BuildHasher;
-    use crate::lz_dict::LZDict;
-    use std::f64::EPSILON;
+    use crate::lz_dict::{
+        intersection_len, min_similarity_for_score, similarity_score, DigestState, Digester, IterReader, LZDict,
+        LZDictOptions, LZDictSink, K, MAX_SEEN, READ_BLOCK_SIZE, SHORT_K, STREAM_K,
+    };
+    use crate::murmur3::Murmur3BuildHasher;
+    #[cfg(feature = "base64")]
+    use crate::lz_dict::Encoding;
+    use proptest::prelude::*;
+    use std::collections::{BTreeSet, HashSet};
+    use std::hash::{BuildHasher, Hasher};
+    use std::io::{self, Read, Write};
     use std::iter::*;
 
     fn is_sorted_and_unique<T: PartialOrd>(list: &[T]) -> bool {
@@ -214,6 +1166,473 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_from_bytes_and_reader() {
+        let sequence: Vec<u8> = (0..200_000u64).map(|i| (i * i % 251) as u8).collect();
+        let build_hasher = CRC32BuildHasher;
+
+        let from_stream = LZDict::from_bytes_stream(sequence.iter().cloned(), &build_hasher);
+        let from_bytes = LZDict::from_bytes(&sequence, &build_hasher);
+        let from_reader = LZDict::from_reader(&sequence[..], &build_hasher).unwrap();
+        let resetting = LZDict::from_reader_resetting(&sequence[..], &build_hasher).unwrap();
+
+        assert_eq!(*from_stream, from_bytes[..STREAM_K]);
+        assert_eq!(*from_bytes, *from_reader);
+        assert_eq!(*from_bytes, *resetting);
+    }
+
+    #[test]
+    fn test_k_smallest() {
+        let sequence: Vec<u8> = (0..100_000u64).map(|i| (i * i % 251) as u8).collect();
+        let build_hasher = CRC32BuildHasher;
+        let lz_dict = LZDict::from_bytes(&sequence, &build_hasher);
+
+        // Reference implementation keeping every hash
+        let mut all = HashSet::new();
+        let mut hasher = build_hasher.build_hasher();
+        for byte in sequence {
+            hasher.write_u8(byte);
+            if all.insert(hasher.finish() as i32) {
+                hasher = build_hasher.build_hasher();
+            }
+        }
+        let mut all: Vec<_> = all.into_iter().collect();
+        all.sort();
+        all.truncate(K);
+
+        assert!(all.len() == K);
+        assert_eq!(*lz_dict, all);
+    }
+
+    #[test]
+    fn test_max_seen() {
+        let sequence = pseudo_random_bytes(100_000);
+        let build_hasher = Murmur3BuildHasher;
+
+        // Reference implementation forgetting the sequences above the K smallest at max_seen
+        let reference = |max_seen: usize| {
+            let mut seen = HashSet::new();
+            let mut all = BTreeSet::new();
+            let mut hasher = build_hasher.build_hasher();
+            for &byte in &sequence {
+                hasher.write_u8(byte);
+                let hash = hasher.finish() as i32;
+                if seen.insert(hash) {
+                    all.insert(hash);
+                    hasher = build_hasher.build_hasher();
+                    if seen.len() >= max_seen {
+                        let last = *all.iter().nth(K - 1).unwrap();
+                        seen.retain(|&hash| hash <= last);
+                    }
+                }
+            }
+            all.into_iter().take(K).collect::<Vec<_>>()
+        };
+
+        let exact = LZDict::from_bytes(&sequence, &build_hasher);
+        assert!(exact.phrase_count().unwrap() > 20_000);
+        assert_eq!(*exact, reference(usize::MAX));
+
+        for &max_seen in &[4_000, 20_000] {
+            let bounded = LZDictOptions::new(&build_hasher)
+                .max_seen(max_seen)
+                .build_from(&sequence[..])
+                .unwrap();
+            assert_eq!(*bounded, reference(max_seen));
+            assert_ne!(*bounded, *exact);
+        }
+    }
+
+    #[test]
+    fn test_max_seen_default() {
+        // Enough pseudo-random bytes for more distinct LZ sequences than MAX_SEEN
+        let sequence = pseudo_random_bytes(6 << 20);
+        let mut state = DigestState::new(&Murmur3BuildHasher);
+        for block in sequence.chunks(READ_BLOCK_SIZE) {
+            state.write(block);
+            assert!(state.seen.len() < MAX_SEEN);
+        }
+        assert!(state.phrases > MAX_SEEN as u64);
+        assert_eq!(state.finish().len(), K);
+    }
+
+    /// Returns len bytes of a linear congruential generator, which have many distinct LZ sequences
+    fn pseudo_random_bytes(len: usize) -> Vec<u8> {
+        let mut state = 1u64;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_with_k() {
+        let sequence: Vec<u8> = (0..100_000u64).map(|i| (i * i % 251) as u8).collect();
+        let build_hasher = CRC32BuildHasher;
+        let lz_dict = LZDict::from_bytes(&sequence, &build_hasher);
+
+        for &k in &[0, 1, 100, K] {
+            let small = LZDict::from_reader_with_k(&sequence[..], &build_hasher, k).unwrap();
+            assert_eq!(*small, lz_dict[..k]);
+        }
+        let large = LZDict::from_bytes_stream_with_k(sequence.iter().cloned(), &build_hasher, 2 * K);
+        assert_eq!(large.len(), 2 * K);
+        assert_eq!(large[..K], *lz_dict);
+    }
+
+    #[test]
+    fn test_options() {
+        let sequence: Vec<u8> = (0..100_000u64).map(|i| (i * i % 251) as u8).collect();
+        let build_hasher = CRC32BuildHasher;
+        let lz_dict = LZDict::from_reader_with_k(&sequence[..], &build_hasher, 100).unwrap();
+
+        let mut reports = 0;
+        let mut progress = |_| reports += 1;
+        let built = LZDictOptions::new(&build_hasher)
+            .k(100)
+            .resetting()
+            .measure_entropy()
+            .progress(&mut progress)
+            .build_from(IterReader::new(sequence.iter().cloned()))
+            .unwrap();
+        assert_eq!(*built, *lz_dict);
+        assert_eq!(built.high_entropy_fraction(), Some(0.));
+        assert_eq!(lz_dict.high_entropy_fraction(), None);
+        assert!(reports > 2);
+
+        // Every block of all 256 byte values is high-entropy
+        let uniform: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+        let skipped = LZDictOptions::new(&build_hasher)
+            .skip_high_entropy()
+            .build_from(&uniform[..])
+            .unwrap();
+        assert!(skipped.len() < 10);
+        assert!(skipped.high_entropy_fraction().unwrap() > 0.9);
+    }
+
+    #[test]
+    fn test_merge() {
+        let a = LZDict::from(vec![1, 3, 5, 7]);
+        let b = LZDict::from(vec![2, 3, 8]);
+        assert_eq!(*LZDict::merge(vec![&a, &b], K), vec![1, 2, 3, 5, 7, 8]);
+        assert_eq!(*LZDict::merge(vec![&a, &b], 4), vec![1, 2, 3, 5]);
+        assert!(LZDict::merge(vec![], K).is_empty());
+    }
+
+    #[test]
+    fn test_gallop_intersection() {
+        let large = LZDict::from((0..4096).map(|i| i * 3).collect::<Vec<_>>());
+        for small in [vec![], vec![-1], vec![0, 4, 9, 12288], vec![3, 6, 300, 301, 12285]] {
+            let small = LZDict::from(small);
+            let expected = small.iter().filter(|entry| large.contains(entry)).count();
+            assert_eq!(intersection_len(&small, &large), expected);
+            assert_eq!(intersection_len(&large, &small), expected);
+        }
+    }
+
+    #[test]
+    fn test_compare_compat() {
+        let sequence: Vec<u8> = (0..100_000u64).map(|i| (i * i % 251) as u8).collect();
+        let shifted = &sequence[20_000..];
+        let build_hasher = CRC32BuildHasher;
+        let small = LZDict::from_reader_with_k(&sequence[..], &build_hasher, 256).unwrap();
+        let large = LZDict::from_reader_with_k(shifted, &build_hasher, K).unwrap();
+        let expected = LZDict::from_reader_with_k(shifted, &build_hasher, 256).unwrap();
+
+        let (similarity, k) = small.compare_compat(256, &large, K);
+        assert_eq!(k, 256);
+        assert_eq!(similarity, small.similarity(&expected));
+        assert_eq!(large.compare_compat(K, &small, 256), (similarity, 256));
+        assert_eq!(*large.clone().truncated(256), *expected);
+        // Digests of inputs with fewer than k phrases are not cut short
+        let short = LZDict::from_reader_with_k(&b"ABABABAB"[..], &build_hasher, K).unwrap();
+        assert_eq!(short.compare_compat(K, &short, 256), (1., 256));
+    }
+
+    #[test]
+    fn test_phrase_count() {
+        let build_hasher = CRC32BuildHasher;
+        // Splits into the phrases A, B, AB, ABA, BA, BAA and the incomplete A
+        let lz_dict = LZDict::from_bytes(b"ABABABABABAAA", &build_hasher);
+        assert_eq!(lz_dict.phrase_count(), Some(6));
+        let lz78 = LZDict::from_bytes_stream_lz78(b"ABABABABABAAA".iter().cloned(), &build_hasher);
+        assert_eq!(lz78.phrase_count(), Some(6));
+
+        let redundant = vec![0; 100_000];
+        let noise: Vec<u8> = (0..100_000u64).map(|i| ((i * 2_654_435_761) >> 7) as u8).collect();
+        let ratio = |bytes: &[u8]| {
+            LZDict::from_bytes(bytes, &build_hasher)
+                .estimate_compression_ratio()
+                .unwrap()
+        };
+        assert!(ratio(&redundant) > 10. * ratio(&noise));
+
+        assert_eq!(LZDict::from(lz_dict.to_vec()).phrase_count(), None);
+        assert_eq!(LZDict::from_bytes(b"", &build_hasher).estimate_compression_ratio(), None);
+    }
+
+    #[test]
+    fn test_score() {
+        assert_eq!(similarity_score(0.), 0);
+        assert_eq!(similarity_score(0.004), 0);
+        assert_eq!(similarity_score(0.005), 1);
+        assert_eq!(similarity_score(0.5), 50);
+        assert_eq!(similarity_score(0.996), 99);
+        assert_eq!(similarity_score(1.), 100);
+        for score in 0..=100 {
+            let min = min_similarity_for_score(score);
+            assert!(similarity_score(min) >= score);
+            assert!(min == 0. || similarity_score(min.next_down()) < score);
+        }
+
+        let a = LZDict::from(vec![1, 2, 3, 4]);
+        let b = LZDict::from(vec![1, 2, 3, 5]);
+        assert_eq!(a.score(&b), 60);
+        assert_eq!(a.score(&a), 100);
+    }
+
+    #[test]
+    fn test_bottom_k_similarity() {
+        // xorshift64, for hashes spread like those of real digests
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let hashes: Vec<i32> = (0..6000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 32) as i32
+            })
+            .collect();
+        // The 256 smallest hashes of a set of 1500 and one of 6000 that contains it,
+        // so that their similarity is 1/4
+        let a = LZDict::from(hashes[..1500].to_vec()).truncated(256);
+        let b = LZDict::from(hashes).truncated(256);
+        let estimate = a.bottom_k_similarity(&b, 256);
+        assert!((estimate - 0.25).abs() < 0.06, "{}", estimate);
+        // Comparing the kept hashes as they are underestimates the similarity of sets of different sizes
+        assert!(a.jaccard_similarity(&b) < 0.17, "{}", a.jaccard_similarity(&b));
+
+        // Digests of fewer than k hashes are compared exactly
+        let (c, d) = (LZDict::from(vec![1, 2, 3, 4]), LZDict::from(vec![1, 2, 3, 5]));
+        assert_eq!(c.bottom_k_similarity(&d, SHORT_K), c.jaccard_similarity(&d));
+        assert_eq!(c.bottom_k_similarity(&c, SHORT_K), 1.);
+    }
+
+    /// Records of two to four words with a typo each, in 100 groups of three
+    /// variants of the same words, labeled with their group
+    fn short_records() -> Vec<(usize, Vec<u8>)> {
+        let words = [
+            "the", "user", "request", "failed", "after", "timeout", "while", "reading", "from", "server",
+            "connection", "closed", "by", "remote", "host", "retry", "later", "invalid", "token", "session",
+            "expired", "login", "password", "error", "file", "not", "found", "on", "disk", "write",
+        ];
+        // xorshift64
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let mut records = vec![];
+        for group in 0..100 {
+            let len = 2 + next() % 3;
+            let base: Vec<&str> = (0..len).map(|_| words[(next() % words.len() as u64) as usize]).collect();
+            for _ in 0..3 {
+                let mut record = base.join(" ").into_bytes();
+                let pos = (next() % record.len() as u64) as usize;
+                let letter = b'a' + (next() % 26) as u8;
+                match next() % 3 {
+                    0 => drop(record.remove(pos)),
+                    1 => record.insert(pos, letter),
+                    _ => record[pos] = letter,
+                }
+                records.push((group, record));
+            }
+        }
+        records
+    }
+
+    /// Returns the fraction of pairs of different groups that score at least as high as
+    /// the 95% best scoring pairs of the same group, the area under the ROC curve,
+    /// and the mean score of pairs of different groups
+    fn separation(records: &[(usize, Vec<u8>)], score: impl Fn(usize, usize) -> f64) -> (f64, f64, f64) {
+        let (mut same, mut different) = (vec![], vec![]);
+        for i in 0..records.len() {
+            for j in i + 1..records.len() {
+                match records[i].0 == records[j].0 {
+                    true => same.push(score(i, j)),
+                    false => different.push(score(i, j)),
+                }
+            }
+        }
+        same.sort_by(f64::total_cmp);
+        let threshold = same[same.len() / 20];
+        let false_positives = different.iter().filter(|&&score| score >= threshold).count();
+        let wins: f64 = same
+            .iter()
+            .flat_map(|a| different.iter().map(move |b| if a > b { 1. } else if a == b { 0.5 } else { 0. }))
+            .sum();
+        (
+            false_positives as f64 / different.len() as f64,
+            wins / (same.len() * different.len()) as f64,
+            different.iter().sum::<f64>() / different.len() as f64,
+        )
+    }
+
+    #[test]
+    fn test_short_input() {
+        let records = short_records();
+        let vanilla: Vec<_> = records
+            .iter()
+            .map(|(_, record)| LZDict::from_bytes_resetting(record, &Murmur3BuildHasher))
+            .collect();
+        let short: Vec<_> = records
+            .iter()
+            .map(|(_, record)| {
+                LZDictOptions::new(&Murmur3BuildHasher)
+                    .resetting()
+                    .short_input()
+                    .build_from(&record[..])
+                    .unwrap()
+            })
+            .collect();
+        assert!(short.iter().all(|dict| dict.len() <= SHORT_K && dict.verify(SHORT_K).is_ok()));
+
+        let vanilla = separation(&records, |i, j| vanilla[i].similarity(&vanilla[j]));
+        let short = separation(&records, |i, j| short[i].bottom_k_similarity(&short[j], SHORT_K));
+        // The few LZ phrases of such short records are mostly shared by unrelated ones
+        assert!(short.0 < vanilla.0, "{:?} {:?}", short, vanilla);
+        assert!(short.1 > vanilla.1, "{:?} {:?}", short, vanilla);
+        assert!(short.2 < vanilla.2 * 0.6, "{:?} {:?}", short, vanilla);
+
+        // Without n-grams, only k differs
+        let dict = LZDictOptions::new(&Murmur3BuildHasher).resetting().short_input().ngrams(0).build_from(&records[0].1[..]);
+        assert_eq!(*dict.unwrap(), *LZDict::from_bytes_resetting(&records[0].1, &Murmur3BuildHasher));
+    }
+
+    #[test]
+    fn test_digester() {
+        let inputs: Vec<Vec<u8>> = (1..20u64)
+            .map(|n| (0..n * 150).map(|i| (i * i % (n + 200)) as u8).collect())
+            .collect();
+        let mut digester = Digester::new(&CRC32BuildHasher).with_k(256);
+        for input in inputs.iter().chain(inputs.iter().rev()) {
+            let expected = LZDict::from_reader_with_k(&input[..], &CRC32BuildHasher, 256).unwrap();
+            let dict = digester.digest_reader(&input[..]).unwrap();
+            assert_eq!(*dict, *expected);
+            assert_eq!(dict.phrase_count(), expected.phrase_count());
+            assert_eq!(*digester.digest_bytes(input), *expected);
+        }
+
+        // A failed read does not affect the next input
+        let failing = (&inputs[3][..]).chain(FailingReader);
+        assert!(digester.digest_reader(failing).is_err());
+        assert_eq!(*digester.digest_bytes(&inputs[0]), *LZDict::from_reader_with_k(&inputs[0][..], &CRC32BuildHasher, 256).unwrap());
+    }
+
+    /// Reader of which every read fails
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("read failed"))
+        }
+    }
+
+    #[test]
+    fn test_sink() {
+        let sequence: Vec<u8> = (0..100_000u64).map(|i| (i * i % 251) as u8).collect();
+        let expected = LZDict::from_reader_with_k(&sequence[..], &CRC32BuildHasher, 256).unwrap();
+
+        let mut sink = LZDictSink::new(&CRC32BuildHasher).with_k(256);
+        assert!(sink.is_empty());
+        for chunk in sequence.chunks(777) {
+            sink.write_all(chunk).unwrap();
+        }
+        assert_eq!(sink.len(), sequence.len() as u64);
+        let dict = sink.finish();
+        assert_eq!(*dict, *expected);
+        assert_eq!(dict.phrase_count(), expected.phrase_count());
+
+        let mut sink = LZDictSink::new(&CRC32BuildHasher).with_k(256);
+        io::copy(&mut &sequence[..], &mut sink).unwrap();
+        assert_eq!(*sink.finish(), *expected);
+    }
+
+    #[test]
+    #[cfg(feature = "base64")]
+    fn test_base64() {
+        let sequence: Vec<u8> = (0..10_000u64).map(|i| (i * i % 251) as u8).collect();
+        let lz_dict = LZDict::from_bytes(&sequence, &CRC32BuildHasher);
+
+        let decoded = LZDict::from_base64_string(&lz_dict.to_string()).unwrap();
+        assert_eq!(*decoded, *lz_dict);
+        // Hashes are written as little-endian i32 on every platform
+        assert_eq!(LZDict::from(vec![1, -2]).to_string(), "/v///wEAAAA=");
+        assert!(LZDict::from_base64_string("AAAAAAA=").is_err());
+        assert!(LZDict::from_base64_string("not base64").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "base64")]
+    fn test_encodings() {
+        let lz_dict = LZDict::from(vec![1, -2, 0x3e3f_ffff]);
+        let base64url = lz_dict.to_encoded_string(Encoding::Base64Url);
+        assert_eq!(base64url, "_v___wEAAAD__z8-");
+        let hex = lz_dict.to_encoded_string(Encoding::Hex);
+        assert_eq!(hex, "feffffff01000000ffff3f3e");
+        for encoding in [Encoding::Base64, Encoding::Base64Url, Encoding::Hex] {
+            let encoded = lz_dict.to_encoded_string(encoding);
+            assert_eq!(Encoding::detect(&encoded), encoding);
+            assert_eq!(*LZDict::from_encoded_string(&encoded).unwrap(), *lz_dict);
+        }
+        assert_eq!("base64url".parse::<Encoding>().unwrap(), Encoding::Base64Url);
+        assert!("base32".parse::<Encoding>().is_err());
+        assert!(LZDict::from_encoded_string("0100000g").is_err());
+        assert!(LZDict::from_encoded_string("").unwrap().is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "base64")]
+    fn test_verify() {
+        assert!(LZDict::from(vec![3, 1, 2, 1]).verify(3).is_ok());
+        assert!(LZDict::from(vec![1, 2, 3]).verify(2).is_err());
+        assert!(LZDict::from(vec![]).verify(0).is_ok());
+        // Decoded digests keep their entries as they are
+        let unsorted = LZDict::from_base64_string("AgAAAAEAAAA=").unwrap();
+        assert_eq!(*unsorted, vec![2, 1]);
+        assert!(unsorted.verify(K).is_err());
+        let duplicate = LZDict::from_base64_string("AQAAAAEAAAA=").unwrap();
+        assert!(duplicate.verify(K).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_digests_verify(bytes in proptest::collection::vec(any::<u8>(), 0..4096), k in 0..64usize) {
+            let lz_dict = LZDict::from_reader_with_k(&bytes[..], &CRC32BuildHasher, k).unwrap();
+            prop_assert!(lz_dict.verify(k).is_ok());
+        }
+
+        #[test]
+        fn prop_from_and_merge_verify(a in any::<Vec<i32>>(), b in any::<Vec<i32>>(), k in 0..K) {
+            let (a, b) = (LZDict::from(a), LZDict::from(b));
+            prop_assert!(a.verify(K).is_ok());
+            prop_assert!(LZDict::merge(vec![&a, &b], k).verify(k).is_ok());
+        }
+
+        #[test]
+        #[cfg(feature = "base64")]
+        fn prop_base64_round_trip(entries in any::<Vec<i32>>()) {
+            let lz_dict = LZDict::from(entries);
+            let decoded = LZDict::from_base64_string(&lz_dict.to_string()).unwrap();
+            prop_assert_eq!(&*decoded, &*lz_dict);
+            prop_assert!(decoded.verify(K).is_ok());
+        }
+    }
+
     #[test]
     fn test_jaccard_similarity() {
         const A_ENTRIES: [i32; 4] = [0, 1, 2, 3];
@@ -237,48 +1656,36 @@ mod tests {
         const INTERSECTION_A_E_LEN: usize = 0;
         const INTERSECTION_A_F_LEN: usize = 4;
 
-        let a = LZDict {
-            entries: A_ENTRIES.to_vec(),
-        };
-        let b = LZDict {
-            entries: B_ENTRIES.to_vec(),
-        };
-        let c = LZDict {
-            entries: C_ENTRIES.to_vec(),
-        };
-        let d = LZDict {
-            entries: D_ENTRIES.to_vec(),
-        };
-        let e = LZDict {
-            entries: E_ENTRIES.to_vec(),
-        };
-        let f = LZDict {
-            entries: F_ENTRIES.to_vec(),
-        };
+        let a = LZDict::from(A_ENTRIES.to_vec());
+        let b = LZDict::from(B_ENTRIES.to_vec());
+        let c = LZDict::from(C_ENTRIES.to_vec());
+        let d = LZDict::from(D_ENTRIES.to_vec());
+        let e = LZDict::from(E_ENTRIES.to_vec());
+        let f = LZDict::from(F_ENTRIES.to_vec());
 
         assert!(
             (a.jaccard_similarity(&a) - INTERSECTION_A_A_LEN as f64 / UNION_A_A_LEN as f64).abs()
-                < EPSILON
+                < f64::EPSILON
         );
         assert!(
             (a.jaccard_similarity(&b) - INTERSECTION_A_B_LEN as f64 / UNION_A_B_LEN as f64).abs()
-                < EPSILON
+                < f64::EPSILON
         );
         assert!(
             (a.jaccard_similarity(&c) - INTERSECTION_A_C_LEN as f64 / UNION_A_C_LEN as f64).abs()
-                < EPSILON
+                < f64::EPSILON
         );
         assert!(
             (a.jaccard_similarity(&d) - INTERSECTION_A_D_LEN as f64 / UNION_A_D_LEN as f64).abs()
-                < EPSILON
+                < f64::EPSILON
         );
         assert!(
             (a.jaccard_similarity(&e) - INTERSECTION_A_E_LEN as f64 / UNION_A_E_LEN as f64).abs()
-                < EPSILON
+                < f64::EPSILON
         );
         assert!(
             (a.jaccard_similarity(&f) - INTERSECTION_A_F_LEN as f64 / UNION_A_F_LEN as f64).abs()
-                < EPSILON
+                < f64::EPSILON
         );
     }
 }
@@ -0,0 +1,226 @@
+//! Metrics of a digest Service, written in the Prometheus text format so that
+//! the service can be monitored like any other.
+//!
+//! Exposed metrics:
+//! - `lzjd_digests_total` and `lzjd_digested_bytes_total`, the number of inputs and bytes
+//!   digested, of which the rates are the throughput
+//! - `lzjd_requests_in_progress`, the number of requests being handled
+//! - `lzjd_store_digests`, the number of digests in the store
+//! - `lzjd_request_duration_seconds`, a histogram of the time taken by requests, by operation
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Upper bounds in seconds of the buckets of the latency histograms
+pub const LATENCY_BUCKETS: [f64; 10] = [0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1., 5., 10.];
+
+/// Operations of the service, of which the latencies are tracked apart.
+/// Operations are numbered in the order of Operation::ALL.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    Digest,
+    Get,
+    Compare,
+    Search,
+}
+
+impl Operation {
+    const ALL: [Operation; 4] = [
+        Operation::Digest,
+        Operation::Get,
+        Operation::Compare,
+        Operation::Search,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::Digest => "digest",
+            Operation::Get => "get",
+            Operation::Compare => "compare",
+            Operation::Search => "search",
+        }
+    }
+}
+
+/// Histogram of latencies, with a count per bucket of LATENCY_BUCKETS and
+/// one for latencies above all of them
+#[derive(Default)]
+struct Histogram {
+    counts: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, seconds: f64) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add((seconds * 1e9) as u64, Ordering::Relaxed);
+    }
+}
+
+/// Counters of a service, which are updated concurrently by the threads serving it
+#[derive(Default)]
+pub struct Metrics {
+    digests: AtomicU64,
+    digested_bytes: AtomicU64,
+    in_progress: AtomicU64,
+    /// Histogram of each operation, in the order of Operation::ALL
+    latencies: [Histogram; 4],
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts an input of the given number of bytes as digested
+    pub fn add_digested(&self, bytes: u64) {
+        self.digests.fetch_add(1, Ordering::Relaxed);
+        self.digested_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts a request of operation as in progress until the returned timer is
+    /// dropped, which adds the time passed to the histogram of operation
+    pub fn start(&self, operation: Operation) -> Timer<'_> {
+        self.in_progress.fetch_add(1, Ordering::Relaxed);
+        Timer {
+            metrics: self,
+            operation,
+            start: Instant::now(),
+        }
+    }
+
+    /// Number of requests in progress
+    pub fn in_progress(&self) -> u64 {
+        self.in_progress.load(Ordering::Relaxed)
+    }
+
+    /// Writes the metrics in the Prometheus text format, along with the given number of
+    /// digests in the store, which the store keeps track of itself
+    pub fn render(&self, store_digests: usize) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = write!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n",
+                name = name,
+                kind = kind,
+                help = help,
+                value = value
+            );
+        };
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        metric(
+            "lzjd_digests_total",
+            "counter",
+            "Number of inputs digested.",
+            load(&self.digests),
+        );
+        metric(
+            "lzjd_digested_bytes_total",
+            "counter",
+            "Number of bytes digested.",
+            load(&self.digested_bytes),
+        );
+        metric(
+            "lzjd_requests_in_progress",
+            "gauge",
+            "Number of requests being handled.",
+            load(&self.in_progress),
+        );
+        metric(
+            "lzjd_store_digests",
+            "gauge",
+            "Number of digests in the store.",
+            store_digests as u64,
+        );
+
+        let name = "lzjd_request_duration_seconds";
+        let _ = write!(
+            out,
+            "# HELP {name} Time taken to handle requests, by operation.\n# TYPE {name} histogram\n",
+            name = name
+        );
+        for (operation, histogram) in Operation::ALL.iter().zip(&self.latencies) {
+            let operation = operation.name();
+            let mut count = 0;
+            let bounds = LATENCY_BUCKETS.iter().map(|bound| bound.to_string());
+            for (bound, bucket) in bounds.chain(Some("+Inf".to_owned())).zip(&histogram.counts) {
+                count += load(bucket);
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{operation=\"{}\",le=\"{}\"}} {}",
+                    name, operation, bound, count
+                );
+            }
+            let sum = load(&histogram.sum_nanos) as f64 / 1e9;
+            let _ = writeln!(out, "{}_sum{{operation=\"{}\"}} {}", name, operation, sum);
+            let _ = writeln!(
+                out,
+                "{}_count{{operation=\"{}\"}} {}",
+                name, operation, count
+            );
+        }
+        out
+    }
+}
+
+/// Request in progress, see Metrics::start
+pub struct Timer<'a> {
+    metrics: &'a Metrics,
+    operation: Operation,
+    start: Instant,
+}
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        self.metrics.latencies[self.operation as usize].observe(self.start.elapsed().as_secs_f64());
+        self.metrics.in_progress.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::{Metrics, Operation};
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.add_digested(100);
+        metrics.add_digested(28);
+        let timer = metrics.start(Operation::Search);
+        assert_eq!(metrics.in_progress(), 1);
+        drop(timer);
+        drop(metrics.start(Operation::Search));
+        assert_eq!(metrics.in_progress(), 0);
+
+        let text = metrics.render(5);
+        let value = |series: &str| {
+            text.lines()
+                .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+                .unwrap()
+                .to_owned()
+        };
+        assert_eq!(value("lzjd_digests_total"), "2");
+        assert_eq!(value("lzjd_digested_bytes_total"), "128");
+        assert_eq!(value("lzjd_store_digests"), "5");
+        assert_eq!(value("lzjd_requests_in_progress"), "0");
+        assert_eq!(
+            value("lzjd_request_duration_seconds_count{operation=\"search\"}"),
+            "2"
+        );
+        assert_eq!(
+            value("lzjd_request_duration_seconds_bucket{operation=\"search\",le=\"+Inf\"}"),
+            "2"
+        );
+        assert_eq!(
+            value("lzjd_request_duration_seconds_count{operation=\"digest\"}"),
+            "0"
+        );
+        assert!(text.contains("# TYPE lzjd_request_duration_seconds histogram\n"));
+    }
+}
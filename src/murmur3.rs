@@ -1,13 +1,110 @@
-use fasthash::FastHasher;
+//! Defines a murmur3 hasher implementing std::hash::Hasher
+//! as well as a std::hash::BuildHasher which builds the hasher.
+use crate::hasher::ResettableHasher;
+
 use std::hash::BuildHasher;
+use std::hash::Hasher;
+
+/// Hasher which buffers its input and hashes it with 32 bit murmur3 on finish.
+/// Produces the same hashes as fasthash::murmur3::Hasher32 does on little-endian
+/// machines, on all machines, and keeps its buffer when reset.
+#[derive(Default)]
+pub struct Murmur3Hasher {
+    bytes: Vec<u8>,
+}
+
+impl Hasher for Murmur3Hasher {
+    fn finish(&self) -> u64 {
+        u64::from(hash32(&self.bytes))
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+}
+
+impl ResettableHasher for Murmur3Hasher {
+    fn reset(&mut self) {
+        self.bytes.clear();
+    }
+}
+
+/// MurmurHash3_x86_32 with seed 0. The reference implementation reads blocks of 4 bytes
+/// as native-endian words, so that its hashes depend on the machine. This reads
+/// them as little-endian words, like the reference does on x86.
+pub fn hash32(bytes: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+    let mix = |k: u32| k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
 
+    let mut h = 0u32;
+    let mut blocks = bytes.chunks_exact(4);
+    for block in &mut blocks {
+        let k = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+        h = (h ^ mix(k)).rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        let k = tail
+            .iter()
+            .rev()
+            .fold(0u32, |k, &byte| (k << 8) | u32::from(byte));
+        h ^= mix(k);
+    }
+
+    h ^= bytes.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^ (h >> 16)
+}
+
+/// std::hash::BuildHasher that builds Murmur3Hashers
+#[derive(Clone)]
 pub struct Murmur3BuildHasher;
 
-/// std::hash::BuildHasher that builds Murmur3HasherExt hashers
 impl BuildHasher for Murmur3BuildHasher {
-    type Hasher = fasthash::murmur3::Hasher32;
+    type Hasher = Murmur3Hasher;
 
     fn build_hasher(&self) -> Self::Hasher {
-        fasthash::murmur3::Hasher32::new()
+        Murmur3Hasher::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hasher::ResettableHasher;
+    use crate::murmur3::{hash32, Murmur3BuildHasher};
+    use fasthash::FastHasher;
+    use std::hash::{BuildHasher, Hasher};
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn test_same_as_fasthash() {
+        let mut hasher = Murmur3BuildHasher.build_hasher();
+        let mut reference = fasthash::murmur3::Hasher32::new();
+        for byte in b"murmur3 reference sequence" {
+            hasher.write_u8(*byte);
+            reference.write_u8(*byte);
+            assert_eq!(hasher.finish(), reference.finish());
+        }
+
+        hasher.reset();
+        hasher.write(b"hello");
+        assert_eq!(hasher.finish(), 613_153_351);
+    }
+
+    #[test]
+    fn test_hash32() {
+        // Vectors of the reference implementation, which hold on every machine
+        assert_eq!(hash32(b""), 0);
+        assert_eq!(hash32(b"hello"), 0x248b_fa47);
+        assert_eq!(hash32(b"The quick brown fox jumps over the lazy dog"), 0x2e4f_f723);
+        #[cfg(target_endian = "little")]
+        for len in 0..16 {
+            let bytes: Vec<u8> = (0..len).map(|i: u8| i.wrapping_mul(37)).collect();
+            assert_eq!(hash32(&bytes), fasthash::murmur3::hash32(&bytes));
+        }
     }
 }
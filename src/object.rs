@@ -0,0 +1,324 @@
+//! Digesting of objects in object stores like S3 as they are downloaded, without
+//! staging them on local disk.
+use crate::entropy::EntropyReader;
+use crate::files::{Batch, DirectoryOptions, ErrorPolicy};
+use crate::lz_dict::K;
+use crate::progress::Progress;
+use crate::sampling::Sampling;
+use crate::{HashAlgorithm, LZDict, LZJDError, Result};
+
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
+use log::trace;
+use object_store::aws::AmazonS3Builder;
+use object_store::path::Path as ObjectPath;
+use object_store::ObjectStore;
+use std::error::Error;
+use std::io::{self, Read};
+use std::panic;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+
+/// Number of downloaded chunks of an object that are buffered ahead of hashing them
+pub const BUFFERED_CHUNKS: usize = 16;
+
+/// Scheme of the URLs of objects in S3
+pub const S3_SCHEME: &str = "s3://";
+
+/// Whether input is the URL of objects in an object store rather than a local path
+pub fn is_object_url(input: &Path) -> bool {
+    input
+        .to_str()
+        .is_some_and(|input| input.starts_with(S3_SCHEME))
+}
+
+/// Opens the bucket of url, like s3://bucket/prefix, with the credentials and region
+/// configured in the environment, like AWS_ACCESS_KEY_ID and AWS_REGION.
+/// Returns the store along with the prefix of the objects url refers to.
+pub fn open_url(url: &str) -> Result<(Box<dyn ObjectStore>, ObjectPath)> {
+    let key = match url.strip_prefix(S3_SCHEME) {
+        Some(rest) => rest.split_once('/').map_or("", |(_, key)| key),
+        None => return Err(LZJDError::from("Object URLs must start with s3://")),
+    };
+    let store = AmazonS3Builder::from_env().with_url(url).build()?;
+    Ok((Box::new(store), ObjectPath::from(key)))
+}
+
+/// Reader of the chunks of an object received from the task that downloads it
+struct ChunkReader<B> {
+    receiver: mpsc::Receiver<io::Result<B>>,
+    chunk: Option<B>,
+    /// Number of bytes of chunk read
+    pos: usize,
+}
+
+impl<B: AsRef<[u8]>> Read for ChunkReader<B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(chunk) = &self.chunk {
+                let rest = &chunk.as_ref()[self.pos..];
+                if !rest.is_empty() {
+                    let len = buf.len().min(rest.len());
+                    buf[..len].copy_from_slice(&rest[..len]);
+                    self.pos += len;
+                    return Ok(len);
+                }
+            }
+            match self.receiver.blocking_recv() {
+                Some(chunk) => {
+                    self.chunk = Some(chunk?);
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Digests the chunks of an object as they are downloaded, like those of
+/// GetResult::into_stream, keeping the k smallest hashes. Chunks are hashed on the
+/// blocking pool of the runtime. With Sampling::Head, the download stops after the
+/// bytes to digest. Sampling::Spread needs to seek, so it is not supported.
+pub async fn from_object_stream<S, B, E>(
+    mut stream: S,
+    algorithm: HashAlgorithm,
+    k: usize,
+    sampling: Sampling,
+    skip_high_entropy: bool,
+) -> Result<LZDict>
+where
+    S: Stream<Item = std::result::Result<B, E>> + Unpin,
+    B: AsRef<[u8]> + Send + 'static,
+    E: Into<Box<dyn Error + Send + Sync>>,
+{
+    let max = match sampling {
+        Sampling::Full => u64::MAX,
+        Sampling::Head(max) => max,
+        Sampling::Spread(_) => {
+            return Err(LZJDError::from(
+                "Spread sampling can not read streamed objects",
+            ))
+        }
+    };
+    let (sender, receiver) = mpsc::channel(BUFFERED_CHUNKS);
+    let digest = tokio::task::spawn_blocking(move || {
+        let chunks = ChunkReader {
+            receiver,
+            chunk: None,
+            pos: 0,
+        };
+        let mut reader = EntropyReader::new(chunks.take(max), skip_high_entropy);
+        let dict = algorithm.digest_reader_with_k(&mut reader, k)?;
+        Ok(dict.with_high_entropy_fraction(reader.high_entropy_fraction()))
+    });
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(io::Error::other);
+        let failed = chunk.is_err();
+        // Sending fails if the digest is done before the end of the object
+        if sender.send(chunk).await.is_err() || failed {
+            break;
+        }
+    }
+    drop(sender);
+    match digest.await {
+        Ok(result) => result,
+        Err(e) => panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// Digests all objects below prefix in store, or the object at prefix itself if there
+/// are none below it, downloading up to concurrency objects at once, with the settings of options. Patterns of options are matched against the
+/// locations of the objects. Results are in order of location. If given, progress is
+/// called with the number of objects and bytes digested after each object.
+pub async fn digest_prefix(
+    store: &dyn ObjectStore,
+    prefix: &ObjectPath,
+    options: &DirectoryOptions,
+    concurrency: usize,
+    mut progress: Option<&mut (dyn FnMut(Progress) + Send)>,
+) -> Result<Batch<(ObjectPath, LZDict)>> {
+    let matches = |location: &ObjectPath| {
+        let path = Path::new(location.as_ref());
+        options
+            .include
+            .as_ref()
+            .is_none_or(|include| include.is_match(path))
+            && !options
+                .exclude
+                .as_ref()
+                .is_some_and(|exclude| exclude.is_match(path))
+    };
+    let mut objects: Vec<_> = store
+        .list(Some(prefix))
+        .try_filter(|meta| futures::future::ready(matches(&meta.location)))
+        .try_collect()
+        .await?;
+    objects.sort_by(|a, b| a.location.cmp(&b.location));
+    if objects.is_empty() && !prefix.as_ref().is_empty() {
+        if let Ok(meta) = store.head(prefix).await {
+            objects.push(meta);
+        }
+    }
+
+    let (algorithm, sampling, skip_high_entropy) = (
+        options.algorithm,
+        options.sampling,
+        options.skip_high_entropy,
+    );
+    let k = options.digest_size.unwrap_or(K);
+    let total = objects.len() as u64;
+    let (mut done, mut bytes) = (0, 0);
+    let mut results = stream::iter(objects)
+        .map(|meta| async move {
+            let result = match store.get(&meta.location).await {
+                Ok(get) => {
+                    from_object_stream(get.into_stream(), algorithm, k, sampling, skip_high_entropy)
+                        .await
+                }
+                Err(e) => Err(e.into()),
+            };
+            (meta, result)
+        })
+        .buffered(concurrency.max(1));
+
+    let mut batch = Batch::default();
+    while let Some((meta, result)) = results.next().await {
+        match result {
+            Ok(dict) => {
+                trace!("Digested {}", meta.location);
+                batch.items.push((meta.location, dict));
+            }
+            Err(e) => {
+                let path = PathBuf::from(meta.location.as_ref());
+                match options.error_policy {
+                    ErrorPolicy::Skip => batch.failures.push((path, e)),
+                    ErrorPolicy::FailFast => return Err(e.in_file(path)),
+                }
+            }
+        }
+        if let Some(progress) = &mut progress {
+            done += 1;
+            bytes += sampling.sampled_len(meta.size);
+            progress(Progress::FilesDigested { done, total, bytes });
+        }
+    }
+    Ok(batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::files::{DirectoryOptions, ErrorPolicy};
+    use crate::object::{digest_prefix, from_object_stream, is_object_url};
+    use crate::sampling::Sampling;
+    use crate::scan::block_on;
+    use crate::HashAlgorithm;
+    use futures::stream;
+    use object_store::memory::InMemory;
+    use object_store::path::Path as ObjectPath;
+    use object_store::ObjectStore;
+    use std::io;
+    use std::path::Path;
+
+    #[test]
+    fn test_from_object_stream() {
+        let data: Vec<u8> = (0..100_000u64).map(|i| (i * i % 251) as u8).collect();
+        let expected = HashAlgorithm::Murmur3.digest_reader(&data[..]).unwrap();
+        let chunks = || {
+            stream::iter(
+                data.chunks(4093)
+                    .map(|chunk| Ok::<_, io::Error>(chunk.to_vec())),
+            )
+        };
+        let dict = block_on(from_object_stream(
+            chunks(),
+            HashAlgorithm::Murmur3,
+            1024,
+            Sampling::Full,
+            false,
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(*dict, *expected);
+
+        let head = HashAlgorithm::Murmur3.digest_reader(&data[..5000]).unwrap();
+        let dict = block_on(from_object_stream(
+            chunks(),
+            HashAlgorithm::Murmur3,
+            1024,
+            Sampling::Head(5000),
+            false,
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(*dict, *head);
+
+        let failing = stream::iter(vec![
+            Ok(vec![1, 2, 3]),
+            Err(io::Error::other("connection reset")),
+        ]);
+        assert!(block_on(from_object_stream(
+            failing,
+            HashAlgorithm::Murmur3,
+            1024,
+            Sampling::Full,
+            false
+        ))
+        .unwrap()
+        .is_err());
+    }
+
+    #[test]
+    fn test_digest_prefix() {
+        let store = InMemory::new();
+        block_on(async {
+            for (key, data) in [
+                ("samples/b", &b"second sample"[..]),
+                ("samples/a", b"first"),
+                ("other/c", b"x"),
+            ] {
+                store
+                    .put(&ObjectPath::from(key), data.to_vec().into())
+                    .await
+                    .unwrap();
+            }
+        })
+        .unwrap();
+        let options = DirectoryOptions {
+            error_policy: ErrorPolicy::Skip,
+            ..Default::default()
+        };
+        let mut reports = 0;
+        let batch = block_on(digest_prefix(
+            &store,
+            &ObjectPath::from("samples"),
+            &options,
+            2,
+            Some(&mut |_| reports += 1),
+        ))
+        .unwrap()
+        .unwrap();
+        let single = block_on(digest_prefix(
+            &store,
+            &ObjectPath::from("other/c"),
+            &options,
+            2,
+            None,
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(single.items.len(), 1);
+        let locations: Vec<_> = batch
+            .items
+            .iter()
+            .map(|(location, _)| location.as_ref())
+            .collect();
+        assert_eq!(locations, vec!["samples/a", "samples/b"]);
+        assert_eq!(reports, 2);
+        let expected = HashAlgorithm::Murmur3
+            .digest_reader(&b"second sample"[..])
+            .unwrap();
+        assert_eq!(*batch.items[1].1, *expected);
+
+        assert!(is_object_url(Path::new("s3://bucket/prefix")));
+        assert!(!is_object_url(Path::new("bucket/prefix")));
+    }
+}
@@ -0,0 +1,176 @@
+//! The digest-then-compare pipeline of `lzjd gen-compare`, for applications that
+//! want the similar pairs among a set of files without running the executable.
+use crate::compare::{compare_all_in_batches, compare_top_in_batches, BATCH_ROWS};
+use crate::files::{digest_files, walk_directory, DirectoryOptions};
+use crate::filter::{filter_files, FileFilter};
+use crate::label::path_to_label;
+use crate::lz_dict::K;
+use crate::progress::Progress;
+use crate::{DigestRecord, LZJDError, Match, Result};
+
+use std::path::{Path, PathBuf};
+
+/// Options of gen_compare
+#[derive(Debug, Clone, Default)]
+pub struct GenCompareOptions {
+    /// How inputs that are directories are walked, and how files are digested
+    pub directory: DirectoryOptions,
+    /// Criteria that files need to meet to be digested
+    pub filter: FileFilter,
+    /// Minimum similarity of the matches passed to the sink
+    pub threshold: f64,
+    /// Only pass the n most similar files of each file to the sink, if given
+    pub top: Option<usize>,
+}
+
+/// Digests the files at paths, and the files in the directory trees of those that
+/// are directories, and compares all pairs of them like `lzjd gen-compare`.
+/// Passes each match to sink, labeled by the paths of the files, in the order
+/// in which the files are walked, as soon as the row of the match is compared.
+/// With ErrorPolicy::Skip, the files that could not be read are skipped, and
+/// returned along with their errors.
+pub fn gen_compare<P: AsRef<Path>, F: FnMut(Match)>(
+    paths: &[P],
+    options: &GenCompareOptions,
+    mut sink: F,
+) -> Result<Vec<(PathBuf, LZJDError)>> {
+    let directory = &options.directory;
+    let mut files = vec![];
+    let mut failures = vec![];
+    for path in paths {
+        let mut walk = walk_directory(path, directory)?;
+        files.append(&mut walk.items);
+        failures.append(&mut walk.failures);
+    }
+    let mut selected = filter_files(&files, &options.filter, directory.error_policy)?;
+    failures.append(&mut selected.failures);
+    let mut digested = digest_files(
+        &selected.items,
+        directory.error_policy,
+        directory.algorithm,
+        directory.digest_size.unwrap_or(K),
+        directory.sampling,
+        directory.skip_high_entropy,
+        None,
+    )?;
+    failures.append(&mut digested.failures);
+
+    let dicts: Vec<_> = digested
+        .items
+        .into_iter()
+        .map(|(path, dict)| DigestRecord::new(path_to_label(&path), dict))
+        .collect();
+    compare_digests(
+        &dicts,
+        &dicts,
+        options.threshold,
+        options.top,
+        None,
+        &mut |batch| {
+            batch.into_iter().for_each(&mut sink);
+            Ok(())
+        },
+    )?;
+    Ok(failures)
+}
+
+/// The comparison of gen_compare, for applications that digest the files themselves,
+/// like `lzjd` does to also read stdin and remote inputs. Compares dicts_a with dicts_b
+/// like compare_all, or like compare_top if top is given, and passes the matches of
+/// every BATCH_ROWS rows to sink as soon as they are compared.
+pub fn compare_digests(
+    dicts_a: &[DigestRecord],
+    dicts_b: &[DigestRecord],
+    threshold: f64,
+    top: Option<usize>,
+    progress: Option<&mut (dyn FnMut(Progress) + Send)>,
+    sink: &mut dyn FnMut(Vec<Match>) -> Result<()>,
+) -> Result<()> {
+    match top {
+        Some(n) => compare_top_in_batches(
+            dicts_a, dicts_b, threshold, n, BATCH_ROWS, None, progress, sink,
+        ),
+        None => compare_all_in_batches(
+            dicts_a, dicts_b, threshold, BATCH_ROWS, None, progress, sink,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compare::BATCH_ROWS;
+    use crate::files::{DirectoryOptions, ErrorPolicy};
+    use crate::pipeline::{compare_digests, gen_compare, GenCompareOptions};
+    use crate::{DigestRecord, HashAlgorithm, Progress};
+    use std::path::Path;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_gen_compare() {
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let lib = src.join("lib.rs");
+        let options = GenCompareOptions {
+            directory: DirectoryOptions {
+                error_policy: ErrorPolicy::Skip,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut matches = vec![];
+        let failures = gen_compare(&[&src, &lib, &src.join("missing.rs")], &options, |m| {
+            matches.push(m)
+        })
+        .unwrap();
+        assert_eq!(failures.len(), 1);
+        // lib.rs is digested twice, as part of src and on its own
+        let lib = lib.to_str().unwrap();
+        assert!(matches
+            .iter()
+            .any(|m| m.a == lib && m.b == lib && m.similarity == 1.));
+        assert!(matches.iter().all(|m| m.similarity >= 0.));
+
+        let options = GenCompareOptions {
+            threshold: 1.,
+            top: Some(1),
+            ..options
+        };
+        let mut matches = vec![];
+        gen_compare(&[&src, &src], &options, |m| matches.push(m)).unwrap();
+        assert!(!matches.is_empty());
+        assert!(matches.iter().all(|m| m.a == m.b));
+    }
+
+    #[test]
+    fn test_compare_digests() {
+        let dicts: Vec<_> = (0..BATCH_ROWS + 10)
+            .map(|i| {
+                let data = format!("sequence {}", i % 2).repeat(10);
+                let dict = HashAlgorithm::Crc32
+                    .digest_reader_with_k(data.as_bytes(), 64)
+                    .unwrap();
+                DigestRecord::new(i.to_string(), dict)
+            })
+            .collect();
+        // The rows compared when each batch of matches is passed on
+        let rows = Mutex::new(0);
+        let mut batches = vec![];
+        let mut report = |progress| {
+            if let Progress::PairsCompared { .. } = progress {
+                *rows.lock().unwrap() += 1;
+            }
+        };
+        compare_digests(
+            &dicts,
+            &dicts,
+            1.,
+            Some(1),
+            Some(&mut report),
+            &mut |batch| {
+                batches.push((*rows.lock().unwrap(), batch.len()));
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(batches, [(BATCH_ROWS, BATCH_ROWS), (BATCH_ROWS + 10, 10)]);
+    }
+}
@@ -0,0 +1,16 @@
+//! Defines the progress reports passed to progress callbacks
+//! of long-running digest and comparison jobs.
+
+/// Progress of a digest or comparison job
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    /// Number of bytes digested so far
+    BytesProcessed(u64),
+    /// Number of files digested so far out of total,
+    /// and the number of bytes in the files digested
+    FilesDigested { done: u64, total: u64, bytes: u64 },
+    /// Number of pairs compared so far, out of total
+    PairsCompared { done: u64, total: u64 },
+    /// Number of digests of which the postings were built so far, out of total
+    DigestsIndexed { done: u64, total: u64 },
+}
@@ -0,0 +1,205 @@
+//! Reading of block devices and disk images, like evidence media,
+//! that may contain sectors that can not be read.
+use crate::Progress;
+
+use log::warn;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+
+/// Size of the sectors that are zero-filled if they can not be read
+pub const SECTOR_SIZE: u64 = 512;
+/// Number of bytes read at once while no read errors occur, a multiple of SECTOR_SIZE
+pub const BUFFER_SIZE: usize = 1 << 20;
+
+/// Reader of a device or image that reads it in sector-aligned blocks of BUFFER_SIZE bytes.
+/// If a block can not be read, its sectors are read one by one,
+/// and the sectors that still can not be read are read as zeros.
+pub struct RawReader<'a, R> {
+    inner: R,
+    len: u64,
+    position: u64,
+    buffer: Vec<u8>,
+    /// Range of buffer that has not been read yet
+    start: usize,
+    end: usize,
+    bad_sectors: Vec<u64>,
+    progress: Option<&'a mut dyn FnMut(Progress)>,
+}
+
+impl<'a, R: Read + Seek> RawReader<'a, R> {
+    /// Creates a reader of inner, from its start up to its current length.
+    /// Block devices report their length by seeking to their end.
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let len = inner.seek(SeekFrom::End(0))?;
+        inner.seek(SeekFrom::Start(0))?;
+        Ok(Self {
+            inner,
+            len,
+            position: 0,
+            buffer: vec![0; BUFFER_SIZE],
+            start: 0,
+            end: 0,
+            bad_sectors: vec![],
+            progress: None,
+        })
+    }
+
+    /// Reports the offset up to which inner was read to progress after every block
+    pub fn with_progress(mut self, progress: &'a mut dyn FnMut(Progress)) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Number of bytes that are read in total
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Offset up to which inner has been read
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Offsets of the sectors that could not be read and were read as zeros
+    pub fn bad_sectors(&self) -> &[u64] {
+        &self.bad_sectors
+    }
+
+    /// Reads the next block into buffer
+    fn fill_buffer(&mut self) -> io::Result<()> {
+        let len = (self.len - self.position).min(BUFFER_SIZE as u64) as usize;
+        self.start = 0;
+        self.end = len;
+        if read_fully(&mut self.inner, &mut self.buffer[..len]).is_err() {
+            for offset in (0..len).step_by(SECTOR_SIZE as usize) {
+                let sector_end = (offset + SECTOR_SIZE as usize).min(len);
+                let sector_position = self.position + offset as u64;
+                self.inner.seek(SeekFrom::Start(sector_position))?;
+                let sector = &mut self.buffer[offset..sector_end];
+                if let Err(e) = read_fully(&mut self.inner, sector) {
+                    warn!(
+                        "Could not read sector at offset {}, reading it as zeros: {}",
+                        sector_position, e
+                    );
+                    sector.iter_mut().for_each(|byte| *byte = 0);
+                    self.bad_sectors.push(sector_position);
+                }
+            }
+            self.inner
+                .seek(SeekFrom::Start(self.position + len as u64))?;
+        }
+        self.position += len as u64;
+        if let Some(progress) = self.progress.as_mut() {
+            progress(Progress::BytesProcessed(self.position));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for RawReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.start == self.end {
+            if self.position >= self.len {
+                return Ok(0);
+            }
+            self.fill_buffer()?;
+        }
+        let n = buf.len().min(self.end - self.start);
+        buf[..n].copy_from_slice(&self.buffer[self.start..self.start + n]);
+        self.start += n;
+        Ok(n)
+    }
+}
+
+/// Fills buf from reader, failing if it ends early
+fn read_fully<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<()> {
+    let mut done = 0;
+    while done < buf.len() {
+        match reader.read(&mut buf[done..]) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => done += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::raw::{RawReader, BUFFER_SIZE, SECTOR_SIZE};
+    use crate::{HashAlgorithm, Progress};
+    use std::io::{self, Cursor, Read, Seek, SeekFrom};
+    use std::ops::Range;
+
+    /// Image of which the bytes in bad can not be read
+    struct BadImage {
+        image: Cursor<Vec<u8>>,
+        bad: Range<u64>,
+    }
+
+    impl Read for BadImage {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let position = self.image.position();
+            if position < self.bad.end && position + buf.len() as u64 > self.bad.start {
+                return Err(io::Error::other("bad sector"));
+            }
+            self.image.read(buf)
+        }
+    }
+
+    impl Seek for BadImage {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.image.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_bad_sectors() {
+        let image: Vec<u8> = (0..BUFFER_SIZE * 2 + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let bad = BUFFER_SIZE as u64 + 2 * SECTOR_SIZE..BUFFER_SIZE as u64 + 3 * SECTOR_SIZE + 1;
+        let mut expected = image.clone();
+        expected[bad.start as usize..(bad.start + 2 * SECTOR_SIZE) as usize]
+            .iter_mut()
+            .for_each(|byte| *byte = 0);
+
+        let mut offsets = vec![];
+        let mut report = |progress| {
+            if let Progress::BytesProcessed(offset) = progress {
+                offsets.push(offset);
+            }
+        };
+        let mut reader = RawReader::new(BadImage {
+            image: Cursor::new(image.clone()),
+            bad: bad.clone(),
+        })
+        .unwrap()
+        .with_progress(&mut report);
+        assert_eq!(reader.len(), image.len() as u64);
+        let mut read = vec![];
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, expected);
+        assert_eq!(reader.bad_sectors(), &[bad.start, bad.start + SECTOR_SIZE]);
+        assert_eq!(reader.position(), image.len() as u64);
+        drop(reader);
+        assert_eq!(
+            offsets,
+            vec![
+                BUFFER_SIZE as u64,
+                2 * BUFFER_SIZE as u64,
+                image.len() as u64
+            ]
+        );
+
+        let reader = RawReader::new(Cursor::new(image.clone())).unwrap();
+        assert_eq!(
+            HashAlgorithm::Murmur3.digest_reader(reader).unwrap().to_vec(),
+            HashAlgorithm::Murmur3.digest_reader(&image[..]).unwrap().to_vec()
+        );
+    }
+}
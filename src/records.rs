@@ -0,0 +1,67 @@
+//! Digesting of each line of newline-delimited input, like log files and NDJSON,
+//! as a record of its own, for clustering log lines or messages without splitting
+//! them into separate files first.
+use crate::{HashAlgorithm, LZDict, Result};
+
+use std::io::BufRead;
+
+/// Digests each line read from reader, keeping the k smallest hashes of each.
+/// Returns the line numbers, counting from 1, along with the digests of the lines.
+/// Line endings, both "\n" and "\r\n", are not part of the digests,
+/// and empty lines are skipped. Lines do not need to be valid UTF-8.
+pub fn digest_lines<R: BufRead>(
+    mut reader: R,
+    algorithm: HashAlgorithm,
+    k: usize,
+) -> Result<Vec<(usize, LZDict)>> {
+    let mut digester = algorithm.digester(k);
+    let mut digests = vec![];
+    let mut buf = vec![];
+    let mut line = 0;
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            break;
+        }
+        line += 1;
+        let record = trim_line_ending(&buf);
+        if !record.is_empty() {
+            digests.push((line, digester.digest_bytes(record)));
+        }
+    }
+    Ok(digests)
+}
+
+fn trim_line_ending(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::records::digest_lines;
+    use crate::HashAlgorithm;
+
+    #[test]
+    fn test_digest_lines() {
+        let input =
+            b"{\"msg\": \"connection reset\"}\r\n\n{\"msg\": \"disk full\"}\n\xff\xfe no newline";
+        let algorithm = HashAlgorithm::Murmur3;
+        let digests = digest_lines(&input[..], algorithm, 1024).unwrap();
+        let lines: Vec<usize> = digests.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [1, 3, 4]);
+        let records: [&[u8]; 3] = [
+            b"{\"msg\": \"connection reset\"}",
+            b"{\"msg\": \"disk full\"}",
+            b"\xff\xfe no newline",
+        ];
+        for ((_, dict), record) in digests.iter().zip(&records) {
+            assert_eq!(
+                dict.to_vec(),
+                algorithm.digest_reader(*record).unwrap().to_vec()
+            );
+        }
+
+        assert!(digest_lines(&b""[..], algorithm, 1024).unwrap().is_empty());
+    }
+}
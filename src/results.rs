@@ -0,0 +1,405 @@
+//! Storage of the matches of large comparison runs, indexed by their similarity, so
+//! that the most similar pairs and the distribution of the similarities can be
+//! queried without holding all matches in memory.
+use crate::{LZJDError, Match, Result};
+
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of buckets matches are indexed in: one for each percentage of similarity,
+/// from 0 up to and including 100, of which the last only holds identical digests
+pub const BUCKETS: usize = 101;
+
+/// Number of ResultStores created by this process, to give their spill directories unique names
+static STORES: AtomicUsize = AtomicUsize::new(0);
+
+/// Container of matches that keeps them in memory up to a budget, and spills them to
+/// files on disk beyond it. Matches are indexed by the percentage of their similarity,
+/// rounded down, so that queries for the most similar pairs only read the buckets that
+/// hold them. Matches are spilled into a directory of the store's own, which is removed
+/// along with the spill files when the store is dropped.
+///
+/// ```
+/// use lzjd::results::ResultStore;
+/// use lzjd::Match;
+///
+/// let mut store = ResultStore::new(1 << 20);
+/// for i in 0..1000 {
+///     let similarity = i as f64 / 1000.;
+///     store.push(Match { a: format!("a{}", i), b: format!("b{}", i), similarity }).unwrap();
+/// }
+/// let top = store.percentile(99.).unwrap();
+/// assert_eq!(top.len(), 10);
+/// assert_eq!(top[0].similarity, 0.999);
+/// assert_eq!(store.histogram()[50], 10);
+/// ```
+pub struct ResultStore {
+    memory_budget: usize,
+    /// Estimated number of bytes taken by the matches in memory
+    memory: usize,
+    /// Matches in memory, per bucket
+    buckets: Vec<Vec<Match>>,
+    /// Number of matches per bucket, in memory and on disk
+    counts: Vec<u64>,
+    spilled: u64,
+    /// Directory of the spill files, which is created by the first spill
+    dir: PathBuf,
+    created_dir: bool,
+    /// Spill file of each bucket, once matches of the bucket are spilled
+    files: Vec<Option<BufWriter<File>>>,
+}
+
+impl ResultStore {
+    /// Creates a store that keeps about memory_budget bytes of matches in memory,
+    /// and spills matches beyond that into a directory in std::env::temp_dir()
+    pub fn new(memory_budget: usize) -> Self {
+        Self::with_spill_dir(memory_budget, std::env::temp_dir())
+    }
+
+    /// Like new, but spills into a new directory in parent, which is created when the
+    /// first match is spilled. Only that directory is removed when the store is dropped.
+    pub fn with_spill_dir<P: Into<PathBuf>>(memory_budget: usize, parent: P) -> Self {
+        let id = STORES.fetch_add(1, Ordering::Relaxed);
+        let dir = parent
+            .into()
+            .join(format!("lzjd-results-{}-{}", process::id(), id));
+        Self {
+            memory_budget,
+            memory: 0,
+            buckets: vec![vec![]; BUCKETS],
+            counts: vec![0; BUCKETS],
+            spilled: 0,
+            dir,
+            created_dir: false,
+            files: (0..BUCKETS).map(|_| None).collect(),
+        }
+    }
+
+    /// Adds a match, spilling the matches in memory if they exceed the memory budget
+    pub fn push(&mut self, m: Match) -> Result<()> {
+        let bucket = bucket(m.similarity);
+        self.counts[bucket] += 1;
+        self.memory += match_memory(&m);
+        self.buckets[bucket].push(m);
+        if self.memory > self.memory_budget {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Number of matches in the store
+    pub fn len(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Directory that matches are spilled into
+    pub fn spill_dir(&self) -> &PathBuf {
+        &self.dir
+    }
+
+    /// Number of matches that were spilled to disk
+    pub fn spilled(&self) -> u64 {
+        self.spilled
+    }
+
+    /// Number of matches per percentage of similarity, rounded down: element i
+    /// counts the matches of which the similarity is at least i% and below (i + 1)%
+    pub fn histogram(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// Writes the histogram as CSV, with a header and a line of the lowest similarity
+    /// of each bucket and its number of matches
+    pub fn write_histogram<W: Write>(&self, mut writer: W) -> Result<()> {
+        writeln!(writer, "similarity,count")?;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            writeln!(writer, "{:.2},{}", bucket as f64 / 100., count)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the matches of which the similarity is at least threshold, most similar first
+    pub fn at_least(&self, threshold: f64) -> Result<Vec<Match>> {
+        let mut matches = self.read_buckets(bucket(threshold))?;
+        matches.retain(|m| m.similarity >= threshold);
+        sort_descending(&mut matches);
+        Ok(matches)
+    }
+
+    /// Returns the matches in the top (100 - percentile)% of similarities, most similar
+    /// first, like the 0.1% most similar pairs for the 99.9th percentile. Of matches with
+    /// the same similarity at the boundary, those added first are returned. Only the
+    /// returned matches are held in memory, however many matches the buckets hold.
+    pub fn percentile(&self, percentile: f64) -> Result<Vec<Match>> {
+        let share = (100. - percentile.clamp(0., 100.)) / 100.;
+        // Rounding errors must not add a match, like 1000 * 0.01 = 10.000000000000002
+        let n = (self.len() as f64 * share - 1e-6).ceil().max(0.) as u64;
+        if n == 0 {
+            return Ok(vec![]);
+        }
+        // The lowest bucket from which on the buckets hold at least n matches
+        let mut above = 0;
+        let lowest = (0..BUCKETS)
+            .rev()
+            .find(|&bucket| {
+                above += self.counts[bucket];
+                above >= n
+            })
+            .unwrap_or(0);
+        // The buckets above lowest hold fewer than n matches, which are all returned
+        let mut matches = self.read_buckets(lowest + 1)?;
+        sort_descending(&mut matches);
+        let wanted = n as usize - matches.len();
+        // The worst of the best matches of lowest so far is on top
+        let mut best = BinaryHeap::with_capacity(wanted + 1);
+        let mut order = 0;
+        self.for_each_in_bucket(lowest, &mut |m| {
+            best.push(Ranked(m, order));
+            order += 1;
+            if best.len() > wanted {
+                best.pop();
+            }
+        })?;
+        matches.extend(best.into_sorted_vec().into_iter().map(|Ranked(m, _)| m));
+        Ok(matches)
+    }
+
+    /// Returns the matches in the buckets from lowest on, from disk and memory, in
+    /// the order in which they were added within each bucket
+    fn read_buckets(&self, lowest: usize) -> Result<Vec<Match>> {
+        let mut matches = vec![];
+        for bucket in lowest..BUCKETS {
+            self.for_each_in_bucket(bucket, &mut |m| matches.push(m))?;
+        }
+        Ok(matches)
+    }
+
+    /// Calls f with every match of bucket, from disk and memory, in the order in which
+    /// they were added, without reading the whole bucket into memory
+    fn for_each_in_bucket(&self, bucket: usize, f: &mut dyn FnMut(Match)) -> Result<()> {
+        if self.files[bucket].is_some() {
+            read_spilled(&self.bucket_path(bucket), f)?;
+        }
+        self.buckets[bucket].iter().cloned().for_each(f);
+        Ok(())
+    }
+
+    /// Appends the matches in memory to the spill files of their buckets
+    fn spill(&mut self) -> Result<()> {
+        if !self.created_dir {
+            // A new directory, so that no files of others are appended to or removed
+            fs::create_dir(&self.dir)?;
+            self.created_dir = true;
+        }
+        for bucket in 0..BUCKETS {
+            if self.buckets[bucket].is_empty() {
+                continue;
+            }
+            if self.files[bucket].is_none() {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.bucket_path(bucket))?;
+                self.files[bucket] = Some(BufWriter::new(file));
+            }
+            let writer = self.files[bucket].as_mut().unwrap();
+            for m in mem::take(&mut self.buckets[bucket]) {
+                write_spilled(writer, &m)?;
+                self.spilled += 1;
+            }
+            // Spill files are read while their writers are open
+            writer.flush()?;
+        }
+        self.memory = 0;
+        Ok(())
+    }
+
+    fn bucket_path(&self, bucket: usize) -> PathBuf {
+        self.dir.join(format!("{:03}.bin", bucket))
+    }
+}
+
+impl Drop for ResultStore {
+    fn drop(&mut self) {
+        for bucket in 0..BUCKETS {
+            if self.files[bucket].take().is_some() {
+                let _ = fs::remove_file(self.bucket_path(bucket));
+            }
+        }
+        if self.created_dir {
+            // Only removes the directory if nothing else was put in it
+            let _ = fs::remove_dir(&self.dir);
+        }
+    }
+}
+
+/// Match along with the order in which it was read, ordered such that the max-heap
+/// of BinaryHeap keeps the worst match on top: the least similar, and of those the
+/// one read last
+struct Ranked(Match, u64);
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        other
+            .0
+            .similarity
+            .total_cmp(&self.0.similarity)
+            .then(self.1.cmp(&other.1))
+    }
+}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Ranked {}
+
+/// Bucket of a match of the given similarity. The small offset keeps similarities
+/// like 0.29, of which the product with 100 is just below 29, in their own bucket.
+fn bucket(similarity: f64) -> usize {
+    ((similarity * 100. + 1e-9).floor().max(0.) as usize).min(BUCKETS - 1)
+}
+
+/// Estimates the number of bytes of memory a match takes
+fn match_memory(m: &Match) -> usize {
+    mem::size_of::<Match>() + m.a.len() + m.b.len()
+}
+
+fn sort_descending(matches: &mut [Match]) {
+    matches.sort_by(|x, y| y.similarity.total_cmp(&x.similarity));
+}
+
+/// Writes m as its similarity, followed by its labels, each prefixed by their length
+fn write_spilled<W: Write>(writer: &mut W, m: &Match) -> Result<()> {
+    writer.write_all(&m.similarity.to_le_bytes())?;
+    for label in [&m.a, &m.b] {
+        writer.write_all(&(label.len() as u64).to_le_bytes())?;
+        writer.write_all(label.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Reads the matches in the spill file at path, passing each to f
+fn read_spilled(path: &PathBuf, f: &mut dyn FnMut(Match)) -> Result<()> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut word = [0; 8];
+    loop {
+        match reader.read_exact(&mut word) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        let similarity = f64::from_le_bytes(word);
+        let mut labels = [String::new(), String::new()];
+        for label in &mut labels {
+            reader.read_exact(&mut word)?;
+            let mut bytes = vec![0; u64::from_le_bytes(word) as usize];
+            reader.read_exact(&mut bytes)?;
+            *label = String::from_utf8(bytes).map_err(|_| {
+                LZJDError::from("Spilled match has a label that is not valid UTF-8")
+            })?;
+        }
+        let [a, b] = labels;
+        f(Match { a, b, similarity });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::results::ResultStore;
+    use crate::Match;
+    use std::fs;
+
+    #[test]
+    fn test_result_store() {
+        // Files of others in the parent directory are left alone
+        let parent = std::env::temp_dir().join(format!("lzjd-test-results-{}", std::process::id()));
+        fs::create_dir_all(&parent).unwrap();
+        fs::write(parent.join("000.bin"), b"not spilled").unwrap();
+        let mut store = ResultStore::with_spill_dir(10_000, &parent);
+        let dir = store.spill_dir().clone();
+        let similarity = |i: u64| (i * 7919 % 10_000) as f64 / 10_000.;
+        for i in 0..10_000 {
+            let m = Match {
+                a: format!("a{}", i),
+                b: format!("b|\n{}", i),
+                similarity: similarity(i),
+            };
+            store.push(m).unwrap();
+        }
+        assert_eq!(store.len(), 10_000);
+        assert!(store.spilled() > 9000);
+        assert!(dir.exists());
+        assert_eq!(store.histogram().iter().sum::<u64>(), 10_000);
+        assert!(store.histogram()[..100].iter().all(|&count| count == 100));
+        let mut csv = vec![];
+        store.write_histogram(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), 102);
+        assert_eq!(csv.lines().nth(30), Some("0.29,100"));
+
+        let top = store.percentile(99.9).unwrap();
+        assert_eq!(top.len(), 10);
+        for (n, m) in top.iter().enumerate() {
+            assert_eq!(m.similarity, (9999 - n) as f64 / 10_000.);
+            let i: u64 = m.a[1..].parse().unwrap();
+            assert_eq!(m.b, format!("b|\n{}", i));
+            assert_eq!(similarity(i), m.similarity);
+        }
+        assert_eq!(store.percentile(0.).unwrap().len(), 10_000);
+        assert!(store.percentile(100.).unwrap().is_empty());
+
+        let above = store.at_least(0.505).unwrap();
+        assert_eq!(above.len(), 4950);
+        assert!(above.windows(2).all(|w| w[0].similarity >= w[1].similarity));
+
+        drop(store);
+        assert!(!dir.exists());
+        assert_eq!(fs::read(parent.join("000.bin")).unwrap(), b"not spilled");
+        fs::remove_dir_all(&parent).unwrap();
+    }
+
+    #[test]
+    fn test_percentile_of_one_bucket() {
+        // All matches are in the highest bucket, which is read without keeping it in memory
+        let mut store = ResultStore::with_spill_dir(1_000, std::env::temp_dir());
+        for i in 0..2000 {
+            let similarity = if i % 2 == 0 { 1. } else { 0.995 };
+            let m = Match {
+                a: format!("a{}", i),
+                b: format!("b{}", i),
+                similarity,
+            };
+            store.push(m).unwrap();
+        }
+        assert!(store.spilled() > 1000);
+        let top = store.percentile(90.).unwrap();
+        assert_eq!(top.len(), 200);
+        assert!(top.iter().all(|m| m.similarity == 1.));
+        // Of equally similar matches, those added first are returned, in order
+        let labels: Vec<_> = top.iter().map(|m| m.a.clone()).collect();
+        let expected: Vec<_> = (0..200).map(|i| format!("a{}", i * 2)).collect();
+        assert_eq!(labels, expected);
+        let all = store.percentile(0.).unwrap();
+        assert_eq!(all.len(), 2000);
+        assert_eq!(all[1999].a, "a1999");
+    }
+}
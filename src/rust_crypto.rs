@@ -0,0 +1,191 @@
+//! Adapter to the traits of the RustCrypto digest crate, so that code that is generic
+//! over hashers with update and finalize, like `D: Digest`, can produce LZJD digests.
+//!
+//! The output of LZJDHasher is the serialized digest: the K smallest hashes as
+//! little-endian 32-bit integers. As the output has a fixed size, digests of fewer
+//! than K hashes are padded with repeats of their largest hash, which
+//! decode_output leaves out again.
+//!
+//! ```
+//! use digest::Digest;
+//! use lzjd::rust_crypto::{decode_output, LZJDHasher};
+//! use lzjd::HashAlgorithm;
+//!
+//! let output = LZJDHasher::digest(b"THIS IS A TEST SEQUENCE");
+//! let dict = decode_output(&output);
+//! assert_eq!(*dict, *HashAlgorithm::Murmur3.digest_reader(&b"THIS IS A TEST SEQUENCE"[..]).unwrap());
+//! ```
+use crate::crc32::CRC32BuildHasher;
+use crate::lz_dict::{LZDictSink, K};
+use crate::murmur3::Murmur3BuildHasher;
+use crate::siphash::SipBuildHasher;
+#[cfg(feature = "xxhash")]
+use crate::xxhash::XxBuildHasher;
+use crate::{HashAlgorithm, LZDict};
+
+use digest::consts::U4096;
+use digest::{FixedOutput, FixedOutputReset, HashMarker, Output, OutputSizeUser, Reset, Update};
+
+// The output holds K 4-byte hashes
+const _: () = assert!(K * 4 == 4096);
+
+/// Sink of each of the hash algorithms, which all have a build hasher without state.
+/// The CRC32 hasher carries its lookup table, so it is boxed.
+enum Sink {
+    Murmur3(LZDictSink<'static, Murmur3BuildHasher>),
+    Crc32(Box<LZDictSink<'static, CRC32BuildHasher>>),
+    #[cfg(feature = "xxhash")]
+    XxHash(LZDictSink<'static, XxBuildHasher>),
+    SipHash(LZDictSink<'static, SipBuildHasher>),
+}
+
+impl Sink {
+    /// Creates a sink that digests the same as HashAlgorithm::digest_reader
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Murmur3 => Sink::Murmur3(LZDictSink::new(&Murmur3BuildHasher).resetting()),
+            HashAlgorithm::Crc32 => Sink::Crc32(Box::new(LZDictSink::new(&CRC32BuildHasher).resetting())),
+            #[cfg(feature = "xxhash")]
+            HashAlgorithm::XxHash => Sink::XxHash(LZDictSink::new(&XxBuildHasher).resetting()),
+            HashAlgorithm::SipHash => Sink::SipHash(LZDictSink::new(&SipBuildHasher).resetting()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Sink::Murmur3(sink) => sink.update(data),
+            Sink::Crc32(sink) => sink.update(data),
+            #[cfg(feature = "xxhash")]
+            Sink::XxHash(sink) => sink.update(data),
+            Sink::SipHash(sink) => sink.update(data),
+        }
+    }
+
+    fn finish(self) -> LZDict {
+        match self {
+            Sink::Murmur3(sink) => sink.finish(),
+            Sink::Crc32(sink) => (*sink).finish(),
+            #[cfg(feature = "xxhash")]
+            Sink::XxHash(sink) => sink.finish(),
+            Sink::SipHash(sink) => sink.finish(),
+        }
+    }
+}
+
+/// LZJD digesting with algorithm, keeping K hashes, through the traits of the
+/// digest crate. The default digests with HashAlgorithm::default().
+pub struct LZJDHasher {
+    algorithm: HashAlgorithm,
+    sink: Sink,
+}
+
+impl LZJDHasher {
+    /// Creates a hasher that digests with algorithm
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        Self {
+            algorithm,
+            sink: Sink::new(algorithm),
+        }
+    }
+
+    /// Returns the digest of all data passed to update, rather than its serialized form
+    pub fn finish(self) -> LZDict {
+        self.sink.finish()
+    }
+}
+
+impl Default for LZJDHasher {
+    fn default() -> Self {
+        Self::new(HashAlgorithm::default())
+    }
+}
+
+impl HashMarker for LZJDHasher {}
+
+impl OutputSizeUser for LZJDHasher {
+    type OutputSize = U4096;
+}
+
+impl Update for LZJDHasher {
+    fn update(&mut self, data: &[u8]) {
+        self.sink.update(data);
+    }
+}
+
+impl FixedOutput for LZJDHasher {
+    fn finalize_into(self, out: &mut Output<Self>) {
+        let dict = self.finish();
+        // Repeats of the largest hash do not change the digest, unlike any other padding
+        let padding = dict.last().copied().unwrap_or_default();
+        let hashes = dict.iter().copied().chain(std::iter::repeat(padding));
+        for (bytes, hash) in out.chunks_mut(4).zip(hashes) {
+            bytes.copy_from_slice(&hash.to_le_bytes());
+        }
+    }
+}
+
+impl Reset for LZJDHasher {
+    fn reset(&mut self) {
+        self.sink = Sink::new(self.algorithm);
+    }
+}
+
+impl FixedOutputReset for LZJDHasher {
+    fn finalize_into_reset(&mut self, out: &mut Output<Self>) {
+        let hasher = std::mem::replace(self, Self::new(self.algorithm));
+        hasher.finalize_into(out);
+    }
+}
+
+/// Converts the output of LZJDHasher back into a LZDict. The output of an empty
+/// input decodes to the digest of the single hash 0, as the two can not be told apart.
+pub fn decode_output(output: &[u8]) -> LZDict {
+    let hashes = output
+        .chunks_exact(4)
+        .map(|bytes| i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect::<Vec<_>>();
+    LZDict::from(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rust_crypto::{decode_output, LZJDHasher};
+    use crate::HashAlgorithm;
+    use digest::{Digest, FixedOutputReset, Update};
+
+    /// Digests data with any hasher of the digest crate, as generic code would
+    fn digest_with<D: Digest + FixedOutputReset>(hasher: &mut D, data: &[u8]) -> Vec<u8> {
+        for chunk in data.chunks(1000) {
+            Digest::update(hasher, chunk);
+        }
+        hasher.finalize_reset().to_vec()
+    }
+
+    #[test]
+    fn test_hasher() {
+        let data: Vec<u8> = (0..100_000u64).map(|i| (i * i % 251) as u8).collect();
+        let algorithms = [
+            HashAlgorithm::Murmur3,
+            HashAlgorithm::Crc32,
+            #[cfg(feature = "xxhash")]
+            HashAlgorithm::XxHash,
+            HashAlgorithm::SipHash,
+        ];
+        for algorithm in algorithms {
+            let expected = algorithm.digest_reader(&data[..]).unwrap();
+            let mut hasher = LZJDHasher::new(algorithm);
+            let output = digest_with(&mut hasher, &data);
+            assert_eq!(output.len(), 4096);
+            assert_eq!(*decode_output(&output), *expected);
+            // Finalizing resets the hasher
+            assert_eq!(digest_with(&mut hasher, &data), output);
+        }
+
+        let short = &b"THIS IS A TEST SEQUENCE"[..];
+        let expected = HashAlgorithm::default().digest_reader(short).unwrap();
+        let mut hasher = LZJDHasher::default();
+        Update::update(&mut hasher, short);
+        let output = hasher.finalize_fixed_reset();
+        assert_eq!(*decode_output(&output), *expected);
+    }
+}
@@ -0,0 +1,178 @@
+//! Sampling of large files, which digests only part of each file
+//! when digesting all of it would take too long.
+use crate::{LZJDError, Result};
+
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::iter;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Size of the chunks read from between the start and end of a file by Sampling::Spread
+pub const SPREAD_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Which parts of a file are digested. Files of at most the given number
+/// of bytes are digested in full by every strategy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Sampling {
+    /// The whole file
+    #[default]
+    Full,
+    /// The first bytes of the file
+    Head(u64),
+    /// A quarter of the bytes from the start of the file and a quarter from its end,
+    /// and the rest in chunks spaced evenly in between
+    Spread(u64),
+}
+
+impl Sampling {
+    /// Number of bytes digested of a file of len bytes
+    pub fn sampled_len(self, len: u64) -> u64 {
+        match self {
+            Sampling::Full => len,
+            Sampling::Head(max) | Sampling::Spread(max) => len.min(max),
+        }
+    }
+
+    /// Ranges of the bytes digested of a file of len bytes, in increasing order
+    pub fn ranges(self, len: u64) -> Vec<Range<u64>> {
+        let max = match self {
+            Sampling::Spread(max) if len > max => max,
+            _ => return iter::once(0..self.sampled_len(len)).collect(),
+        };
+        let tail = max / 4;
+        let middle = max / 2;
+        let chunk = SPREAD_CHUNK_SIZE.min(middle).max(1);
+        let chunks = middle / chunk;
+        // Bytes that do not fill a chunk are read from the start instead
+        let head = max - tail - chunks * chunk;
+
+        let stride = (len - head - tail) / chunks.max(1);
+        let chunks = (0..chunks).map(|i| {
+            let start = head + i * stride;
+            start..start + chunk
+        });
+        let mut ranges: Vec<_> = iter::once(0..head)
+            .chain(chunks)
+            .chain(iter::once(len - tail..len))
+            .collect();
+        ranges.retain(|range| !range.is_empty());
+        ranges
+    }
+
+    /// Reader of the bytes of inner that are digested, seeking past the rest
+    pub fn reader<R: Read + Seek>(self, mut inner: R) -> io::Result<SampledReader<R>> {
+        let len = inner.seek(SeekFrom::End(0))?;
+        let mut ranges = self.ranges(len);
+        ranges.reverse();
+        Ok(SampledReader {
+            inner,
+            ranges,
+            remaining: 0,
+        })
+    }
+}
+
+impl fmt::Display for Sampling {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sampling::Full => f.write_str("full"),
+            Sampling::Head(max) => write!(f, "head:{}", max),
+            Sampling::Spread(max) => write!(f, "spread:{}", max),
+        }
+    }
+}
+
+impl FromStr for Sampling {
+    type Err = LZJDError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || LZJDError::Msg {
+            msg: format!("Unknown sampling strategy '{}'", s),
+        };
+        if s == "full" {
+            return Ok(Sampling::Full);
+        }
+        let (strategy, max) = s.split_once(':').ok_or_else(invalid)?;
+        let max = max.parse().map_err(|_| invalid())?;
+        match strategy {
+            "head" => Ok(Sampling::Head(max)),
+            "spread" => Ok(Sampling::Spread(max)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Reader of the ranges of a file that are digested, created by Sampling::reader
+pub struct SampledReader<R> {
+    inner: R,
+    /// Ranges that have not been started yet, the next one last
+    ranges: Vec<Range<u64>>,
+    /// Number of bytes left in the current range
+    remaining: u64,
+}
+
+impl<R: Read + Seek> Read for SampledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            match self.ranges.pop() {
+                Some(range) => {
+                    self.inner.seek(SeekFrom::Start(range.start))?;
+                    self.remaining = range.end - range.start;
+                }
+                None => return Ok(0),
+            }
+        }
+        // Compared as u64, as remaining can exceed usize::MAX on 32-bit targets
+        let len = (buf.len() as u64).min(self.remaining) as usize;
+        let n = self.inner.read(&mut buf[..len])?;
+        if n == 0 {
+            // The file was truncated while reading it
+            self.remaining = 0;
+            self.ranges.clear();
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sampling::{Sampling, SPREAD_CHUNK_SIZE};
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn test_ranges() {
+        assert_eq!(Sampling::Full.ranges(100), vec![0..100]);
+        assert_eq!(Sampling::Head(10).ranges(100), vec![0..10]);
+        assert_eq!(Sampling::Spread(1000).ranges(100), vec![0..100]);
+        assert_eq!(Sampling::Spread(8).ranges(100), vec![0..2, 2..6, 98..100]);
+
+        let chunk = SPREAD_CHUNK_SIZE;
+        let ranges = Sampling::Spread(8 * chunk).ranges(100 * chunk);
+        assert_eq!(ranges.len(), 6);
+        assert_eq!(ranges[0], 0..2 * chunk);
+        assert_eq!(ranges[1], 2 * chunk..3 * chunk);
+        assert_eq!(ranges[2], 26 * chunk..27 * chunk);
+        assert_eq!(ranges[5], 98 * chunk..100 * chunk);
+        let total: u64 = ranges.iter().map(|range| range.end - range.start).sum();
+        assert_eq!(total, 8 * chunk);
+    }
+
+    #[test]
+    fn test_reader() {
+        let data: Vec<u8> = (0..100).collect();
+        let mut read = vec![];
+        Sampling::Spread(8)
+            .reader(Cursor::new(&data))
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, vec![0, 1, 2, 3, 4, 5, 98, 99]);
+
+        for sampling in ["full", "head:10", "spread:1048576"] {
+            assert_eq!(sampling.parse::<Sampling>().unwrap().to_string(), sampling);
+        }
+        assert!("tail:10".parse::<Sampling>().is_err());
+    }
+}
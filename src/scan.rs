@@ -0,0 +1,420 @@
+//! Directory walking and digesting that keep many file system requests in flight at
+//! once, for corpora on network file systems like NFS, SMB or FUSE-mounted object
+//! stores, where the latency of each request rather than bandwidth limits throughput.
+//!
+//! Directories are listed and files are read by tasks on the blocking pool of a tokio
+//! runtime, at most concurrency at a time. Files read are hashed on that pool too,
+//! by as many tasks at a time as the current rayon pool has threads.
+//! Results are the same as those of walk_directory and digest_files in files.
+use crate::files::{self, Batch, DirectoryOptions, ErrorPolicy, ReadFile};
+use crate::lz_dict::K;
+use crate::progress::Progress;
+use crate::{LZDict, LZJDError, Result};
+
+use log::trace;
+use std::fs::{self, Metadata};
+use std::future::Future;
+use std::io;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::{JoinError, JoinSet};
+
+/// Default number of directories listed or files read at once
+pub const CONCURRENCY: usize = 64;
+
+/// Runs future to completion on a new runtime, for calling the functions
+/// of this module from code that does not run on a tokio runtime itself
+pub fn block_on<F: Future>(future: F) -> Result<F::Output> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    Ok(runtime.block_on(future))
+}
+
+/// A directory to list, at depth below the root
+struct Directory {
+    path: PathBuf,
+    depth: usize,
+    /// Canonical and walked paths of the directory and the directories
+    /// above it, if symbolic links are followed, to detect loops
+    ancestors: Arc<Vec<(PathBuf, PathBuf)>>,
+}
+
+/// The files and subdirectories found in a directory
+#[derive(Default)]
+struct Listing {
+    files: Vec<PathBuf>,
+    directories: Vec<Directory>,
+    failures: Vec<(PathBuf, LZJDError)>,
+}
+
+/// Returns the paths of all files in the directory tree rooted at path,
+/// like files::walk_directory, listing up to concurrency directories at once.
+/// On other platforms than unix, options.same_file_system is ignored.
+pub async fn walk_directory<P: AsRef<Path>>(
+    path: P,
+    options: &DirectoryOptions,
+    concurrency: usize,
+) -> Result<Batch<PathBuf>> {
+    let root = path.as_ref().to_owned();
+    let options = Arc::new(options.clone());
+    let mut batch = Batch::default();
+
+    let follow_links = options.follow_links;
+    let stat = {
+        let root = root.clone();
+        tokio::task::spawn_blocking(move || -> io::Result<_> {
+            let canonical = match follow_links {
+                true => Some(root.canonicalize()?),
+                false => None,
+            };
+            Ok((fs::metadata(root)?, canonical))
+        })
+    };
+    let (metadata, canonical) = match joined(stat.await) {
+        Ok(stat) => stat,
+        Err(e) => {
+            fail(&mut batch, &options, root, e.into())?;
+            return Ok(batch);
+        }
+    };
+    if !metadata.is_dir() {
+        batch.items.push(root);
+        return Ok(batch);
+    }
+    let device = device(&metadata);
+
+    let mut pending = vec![];
+    if options.max_depth != Some(0) {
+        let ancestors = canonical.map(|canonical| (canonical, root.clone()));
+        pending.push(Directory {
+            path: root,
+            depth: 0,
+            ancestors: Arc::new(ancestors.into_iter().collect()),
+        });
+    }
+    let mut tasks = JoinSet::new();
+    loop {
+        while tasks.len() < concurrency.max(1) {
+            let Some(directory) = pending.pop() else {
+                break;
+            };
+            let options = options.clone();
+            tasks.spawn_blocking(move || list_directory(directory, &options, device));
+        }
+        let Some(listing) = tasks.join_next().await else {
+            break;
+        };
+        let mut listing = joined(listing);
+        batch.items.append(&mut listing.files);
+        pending.append(&mut listing.directories);
+        for (path, e) in listing.failures {
+            fail(&mut batch, &options, path, e)?;
+        }
+    }
+    // The order of walkdir, which walks the entries of each directory by name
+    batch.items.sort_unstable();
+    batch.failures.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(batch)
+}
+
+/// Records the error e concerning path in batch, or returns it if the walk fails fast
+fn fail<T>(
+    batch: &mut Batch<T>,
+    options: &DirectoryOptions,
+    path: PathBuf,
+    e: LZJDError,
+) -> Result<()> {
+    match options.error_policy {
+        ErrorPolicy::Skip => {
+            batch.failures.push((path, e));
+            Ok(())
+        }
+        ErrorPolicy::FailFast => Err(e.in_file(path)),
+    }
+}
+
+fn list_directory(
+    directory: Directory,
+    options: &DirectoryOptions,
+    device: Option<u64>,
+) -> Listing {
+    let mut listing = Listing::default();
+    let entries = match fs::read_dir(&directory.path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            listing.failures.push((directory.path, e.into()));
+            return listing;
+        }
+    };
+    let depth = directory.depth + 1;
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                listing.failures.push((directory.path.clone(), e.into()));
+                continue;
+            }
+        };
+        let path = entry.path();
+        if options
+            .exclude
+            .as_ref()
+            .is_some_and(|exclude| exclude.is_match(&path))
+        {
+            continue;
+        }
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(e) => {
+                listing.failures.push((path, e.into()));
+                continue;
+            }
+        };
+        let (is_file, is_dir) = if file_type.is_symlink() {
+            match fs::metadata(&path) {
+                Ok(target) => (target.is_file(), options.follow_links && target.is_dir()),
+                // Dangling links are only an error if they are to be followed
+                Err(e) if options.follow_links => {
+                    listing.failures.push((path, e.into()));
+                    continue;
+                }
+                Err(_) => (false, false),
+            }
+        } else {
+            (file_type.is_file(), file_type.is_dir())
+        };
+
+        if is_file {
+            if options
+                .include
+                .as_ref()
+                .is_none_or(|include| include.is_match(&path))
+            {
+                listing.files.push(path);
+            }
+        } else if is_dir && options.max_depth.is_none_or(|max_depth| depth < max_depth) {
+            match subdirectory(&directory, path.clone(), depth, options, device) {
+                Ok(Some(subdirectory)) => listing.directories.push(subdirectory),
+                Ok(None) => {}
+                Err(e) => listing.failures.push((path, e)),
+            }
+        }
+    }
+    listing
+}
+
+/// Returns the subdirectory at path of directory to list,
+/// None if it is on another file system than the root and options exclude those
+fn subdirectory(
+    directory: &Directory,
+    path: PathBuf,
+    depth: usize,
+    options: &DirectoryOptions,
+    device: Option<u64>,
+) -> Result<Option<Directory>> {
+    if options.same_file_system && self::device(&fs::metadata(&path)?) != device {
+        return Ok(None);
+    }
+    let ancestors = if options.follow_links {
+        let canonical = path.canonicalize()?;
+        if let Some((_, ancestor)) = directory.ancestors.iter().find(|a| a.0 == canonical) {
+            return Err(LZJDError::from(io::Error::other(format!(
+                "File system loop found: {} points to an ancestor {}",
+                path.display(),
+                ancestor.display()
+            ))));
+        }
+        let mut ancestors = directory.ancestors.to_vec();
+        ancestors.push((canonical, path.clone()));
+        Arc::new(ancestors)
+    } else {
+        directory.ancestors.clone()
+    };
+    Ok(Some(Directory {
+        path,
+        depth,
+        ancestors,
+    }))
+}
+
+#[cfg(unix)]
+fn device(metadata: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn device(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
+/// Digests the files at paths with the algorithm, digest size, sampling and entropy
+/// settings of options, like files::digest_files, reading up to concurrency files at once.
+/// Results are in the same order as paths. If given, progress is called with the number
+/// of files and bytes digested after each file.
+pub async fn digest_files(
+    paths: &[PathBuf],
+    options: &DirectoryOptions,
+    concurrency: usize,
+    mut progress: Option<&mut (dyn FnMut(Progress) + Send)>,
+) -> Result<Batch<(PathBuf, LZDict)>> {
+    let (algorithm, sampling, skip_high_entropy) = (
+        options.algorithm,
+        options.sampling,
+        options.skip_high_entropy,
+    );
+    let k = options.digest_size.unwrap_or(K);
+    let total = paths.len() as u64;
+    let (mut done, mut bytes) = (0, 0);
+    let hashers = Arc::new(Semaphore::new(rayon::current_num_threads()));
+
+    let mut results = Vec::with_capacity(paths.len());
+    let mut tasks = JoinSet::new();
+    let mut next = paths.iter().enumerate();
+    loop {
+        // Files being read or hashed hold at most concurrency * MAX_READ_AHEAD bytes
+        while tasks.len() < concurrency.max(1) {
+            let Some((i, path)) = next.next() else {
+                break;
+            };
+            let path = path.clone();
+            let hashers = hashers.clone();
+            tasks.spawn(async move {
+                let read = {
+                    let path = path.clone();
+                    joined(
+                        tokio::task::spawn_blocking(move || files::read_file(&path, sampling))
+                            .await,
+                    )
+                };
+                let len = read.as_ref().map_or(0, ReadFile::len);
+                let permit = hashers
+                    .acquire_owned()
+                    .await
+                    .expect("semaphore is never closed");
+                let result = joined(
+                    tokio::task::spawn_blocking(move || {
+                        let _permit = permit;
+                        read.and_then(|read| {
+                            files::digest_file(
+                                &path,
+                                read,
+                                &mut algorithm.digester(k),
+                                sampling,
+                                skip_high_entropy,
+                            )
+                        })
+                    })
+                    .await,
+                );
+                (i, len, result)
+            });
+        }
+        let Some(result) = tasks.join_next().await else {
+            break;
+        };
+        let (i, len, result) = joined(result);
+        match result {
+            Ok(_) => trace!("Digested {}", paths[i].display()),
+            Err(e) if options.error_policy == ErrorPolicy::FailFast => {
+                return Err(e.in_file(&paths[i]))
+            }
+            Err(_) => {}
+        }
+        if let Some(progress) = &mut progress {
+            done += 1;
+            bytes += len;
+            progress(Progress::FilesDigested { done, total, bytes });
+        }
+        results.push((i, result));
+    }
+    results.sort_unstable_by_key(|&(i, _)| i);
+
+    let mut batch = Batch::default();
+    for (i, result) in results {
+        match result {
+            Ok(item) => batch.items.push(item),
+            Err(e) => batch.failures.push((paths[i].to_owned(), e)),
+        }
+    }
+    Ok(batch)
+}
+
+/// Returns the output of a finished task, resuming its panic if it panicked
+fn joined<T>(result: std::result::Result<T, JoinError>) -> T {
+    match result {
+        Ok(output) => output,
+        Err(e) => panic::resume_unwind(e.into_panic()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::files::{self, DirectoryOptions, ErrorPolicy};
+    use crate::lz_dict::K;
+    use crate::scan::{block_on, digest_files, walk_directory};
+    use std::path::Path;
+
+    #[test]
+    fn test_same_as_files() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        for max_depth in [None, Some(0), Some(1), Some(2)] {
+            let options = DirectoryOptions {
+                max_depth,
+                ..Default::default()
+            };
+            let expected = files::walk_directory(root.join("src"), &options).unwrap();
+            let walk = block_on(walk_directory(root.join("src"), &options, 3))
+                .unwrap()
+                .unwrap();
+            assert_eq!(walk.items, expected.items);
+        }
+        let lib = root.join("src").join("lib.rs");
+        let walk = block_on(walk_directory(&lib, &Default::default(), 1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(walk.items, vec![lib.clone()]);
+
+        let paths = vec![lib, root.join("missing.rs"), root.join("README.md")];
+        let options = DirectoryOptions {
+            error_policy: ErrorPolicy::Skip,
+            ..Default::default()
+        };
+        let expected = files::digest_files(
+            &paths,
+            options.error_policy,
+            options.algorithm,
+            K,
+            options.sampling,
+            false,
+            None,
+        )
+        .unwrap();
+        let mut reports = 0;
+        let batch = block_on(digest_files(
+            &paths,
+            &options,
+            2,
+            Some(&mut |_| reports += 1),
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(reports, 3);
+        assert_eq!(batch.failures.len(), 1);
+        assert_eq!(batch.failures[0].0, paths[1]);
+        assert_eq!(batch.items.len(), expected.items.len());
+        for (item, expected) in batch.items.iter().zip(&expected.items) {
+            assert_eq!(item.0, expected.0);
+            assert_eq!(*item.1, *expected.1);
+        }
+
+        let options = DirectoryOptions::default();
+        assert!(block_on(digest_files(&paths, &options, 2, None))
+            .unwrap()
+            .is_err());
+    }
+}
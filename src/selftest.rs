@@ -0,0 +1,150 @@
+//! Self-test that digests reference inputs and checks the results against the
+//! vectors in selftest_vectors.txt, which are committed with the source, so that
+//! a build can be checked to compute the same digests as the build that computed them.
+//!
+//! Lines of the vectors file are comments starting with #, or one of
+//! - `digest INPUT ALGORITHM K DIGEST`: the digest of a reference input, - if it is empty
+//! - `similarity ALGORITHM K INPUT INPUT SIMILARITY`: the similarity of the digests of two inputs
+use crate::{HashAlgorithm, LZDict, LZJDError, Result};
+
+const VECTORS: &str = include_str!("selftest_vectors.txt");
+
+/// Names of the reference inputs, which are generated rather than bundled as files
+pub const REFERENCE_INPUTS: [&str; 5] = ["empty", "text", "squares", "squares-shifted", "noise"];
+
+/// Returns the reference input called name, if there is one
+pub fn reference_input(name: &str) -> Option<Vec<u8>> {
+    let squares = || (0..100_000u64).map(|i| (i * i % 251) as u8);
+    match name {
+        "empty" => Some(vec![]),
+        "text" => Some(b"THIS IS A TEST SEQUENCE".to_vec()),
+        "squares" => Some(squares().collect()),
+        // Shares most of its content with squares
+        "squares-shifted" => Some(squares().skip(20_000).chain(0..=255).collect()),
+        "noise" => {
+            // xorshift64, so that the input does not depend on other crates
+            let mut state = 0x2545_f491_4f6c_dd1du64;
+            Some(
+                (0..1 << 16)
+                    .map(|_| {
+                        state ^= state << 13;
+                        state ^= state >> 7;
+                        state ^= state << 17;
+                        (state >> 32) as u8
+                    })
+                    .collect(),
+            )
+        }
+        _ => None,
+    }
+}
+
+/// Outcome of a single check of the self-test
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    /// Description of what was checked
+    pub name: String,
+    /// Why the check failed, or None if it passed
+    pub failure: Option<String>,
+}
+
+impl Check {
+    fn new(name: String, failure: Option<String>) -> Self {
+        Self { name, failure }
+    }
+
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+/// Runs every check of the vectors file, failing only if the file itself is invalid
+pub fn run() -> Result<Vec<Check>> {
+    VECTORS
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        // Builds without the xxhash feature leave out its vectors
+        .filter(|line| {
+            cfg!(feature = "xxhash") || !line.split_whitespace().any(|field| field == "xxhash")
+        })
+        .flat_map(|line| match check_line(line) {
+            Ok(checks) => checks.into_iter().map(Ok).collect(),
+            Err(e) => vec![Err(e)],
+        })
+        .collect()
+}
+
+fn check_line(line: &str) -> Result<Vec<Check>> {
+    let invalid = || LZJDError::Msg {
+        msg: format!("Invalid self-test vector '{}'", line),
+    };
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    match fields[..] {
+        ["digest", input, algorithm, k, expected] => {
+            let name = format!("digest of {} with {} k={}", input, algorithm, k);
+            let dict = digest(input, algorithm.parse()?, k.parse().map_err(|_| invalid())?)?;
+            let digested = dict.to_string();
+            let expected = if expected == "-" { "" } else { expected };
+            let failure = if digested != expected {
+                Some(format!("expected {}, got {}", expected, digested))
+            } else {
+                None
+            };
+
+            // The digest must survive being written and read back
+            let round_trip = format!("base64 round trip of {} with {} k={}", input, algorithm, k);
+            let failure_round_trip = match LZDict::from_base64_string(&digested) {
+                Ok(decoded) if *decoded == *dict => None,
+                Ok(_) => Some("decoded digest differs".to_owned()),
+                Err(e) => Some(e.to_string()),
+            };
+            Ok(vec![
+                Check::new(name, failure),
+                Check::new(round_trip, failure_round_trip),
+            ])
+        }
+        ["similarity", algorithm, k, input_a, input_b, expected] => {
+            let name = format!(
+                "similarity of {} and {} with {} k={}",
+                input_a, input_b, algorithm, k
+            );
+            let algorithm = algorithm.parse()?;
+            let k = k.parse().map_err(|_| invalid())?;
+            let expected: f64 = expected.parse().map_err(|_| invalid())?;
+            let similarity =
+                digest(input_a, algorithm, k)?.similarity(&digest(input_b, algorithm, k)?);
+            let failure = if similarity != expected {
+                Some(format!("expected {:?}, got {:?}", expected, similarity))
+            } else {
+                None
+            };
+            Ok(vec![Check::new(name, failure)])
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn digest(input: &str, algorithm: HashAlgorithm, k: usize) -> Result<LZDict> {
+    let data = reference_input(input).ok_or_else(|| LZJDError::Msg {
+        msg: format!("Unknown reference input '{}'", input),
+    })?;
+    algorithm.digest_reader_with_k(&data[..], k)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::selftest::{reference_input, run, REFERENCE_INPUTS};
+
+    #[test]
+    fn test_run() {
+        let checks = run().unwrap();
+        assert!(checks.len() > REFERENCE_INPUTS.len());
+        for check in checks {
+            assert!(check.passed(), "{}: {:?}", check.name, check.failure);
+        }
+        assert!(REFERENCE_INPUTS
+            .iter()
+            .all(|name| reference_input(name).is_some()));
+    }
+}
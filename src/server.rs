@@ -0,0 +1,234 @@
+//! HTTP front-end of a digest Service.
+//!
+//! Endpoints:
+//! - `POST /digest?label=NAME` digests the request body and stores it under NAME
+//! - `GET /digest/{id}` returns a stored digest
+//! - `GET /compare?a=ID&b=ID` returns the similarity of two stored digests
+//! - `POST /search?threshold=T&top=N` digests the request body and returns the
+//!   stored digests of which the similarity is at least T, at most N of them
+//! - `GET /metrics` returns the metrics of the service in the Prometheus text format
+//!
+//! All other responses are JSON. Thresholds are fractions between 0 and 1.
+use crate::service::{check_label, Service, DEFAULT_THRESHOLD};
+use crate::store::DigestId;
+use crate::{LZDict, LZJDError, Result};
+
+use serde_json::{json, Value};
+use std::io::Read;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Response, Server};
+
+/// Content type of the Prometheus text format
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Serves service over HTTP on addr using num_threads worker threads.
+/// Only returns if the server could not be started.
+pub fn serve(service: Arc<Service>, addr: &str, num_threads: usize) -> Result<()> {
+    let listener = TcpListener::bind(addr).map_err(|e| LZJDError::Msg {
+        msg: format!("Could not listen on {}: {}", addr, e),
+    })?;
+    serve_listener(service, listener, num_threads)
+}
+
+/// Serves service over HTTP on listener like serve, for callers that
+/// bind the address themselves, for example to learn the port chosen for port 0
+pub fn serve_listener(
+    service: Arc<Service>,
+    listener: TcpListener,
+    num_threads: usize,
+) -> Result<()> {
+    let server = Arc::new(
+        Server::from_listener(listener, None).map_err(|e| LZJDError::Msg {
+            msg: format!("Could not serve: {}", e),
+        })?,
+    );
+
+    let workers: Vec<_> = (0..num_threads.max(1))
+        .map(|_| {
+            let server = Arc::clone(&server);
+            let service = Arc::clone(&service);
+            thread::spawn(move || {
+                while let Ok(mut request) = server.recv() {
+                    let method = request.method().as_str().to_owned();
+                    let url = request.url().to_owned();
+                    let path = url.split('?').next().unwrap_or_default();
+                    let response = if method == "GET" && path == "/metrics" {
+                        Response::from_string(service.render_metrics())
+                            .with_header(content_type(METRICS_CONTENT_TYPE))
+                    } else {
+                        let (status, body) = handle(&service, &method, &url, request.as_reader());
+                        Response::from_string(body.to_string())
+                            .with_status_code(status)
+                            .with_header(content_type("application/json"))
+                    };
+                    // The client may have gone away, which only concerns that client
+                    let _ = request.respond(response);
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(())
+}
+
+/// Handles a single request, returning the status code and body of the response
+pub fn handle(service: &Service, method: &str, url: &str, body: &mut dyn Read) -> (u16, Value) {
+    let (path, query) = match url.find('?') {
+        Some(i) => (&url[..i], &url[i + 1..]),
+        None => (url, ""),
+    };
+    let param = |name: &str| {
+        form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+
+    let result = match (method, path) {
+        ("POST", "/digest") => {
+            let label = param("label").unwrap_or_default();
+            if let Err(e) = check_label(&label) {
+                return error(400, &e.to_string());
+            }
+            service
+                .add(label.clone(), body)
+                .map(|(id, dict)| digest_record(service, id, &label, &dict))
+        }
+        ("GET", _) if path.starts_with("/digest/") => {
+            let id = match path["/digest/".len()..].parse::<DigestId>() {
+                Ok(id) => id,
+                Err(_) => return error(400, "Invalid digest id"),
+            };
+            match service.get(id) {
+                Some((label, dict)) => Ok(digest_record(service, id, &label, &dict)),
+                None => return error(404, "No such digest"),
+            }
+        }
+        ("GET", "/compare") => {
+            let id = |name| param(name).and_then(|id| id.parse::<DigestId>().ok());
+            let (a, b) = match (id("a"), id("b")) {
+                (Some(a), Some(b)) => (a, b),
+                _ => return error(400, "Invalid digest id"),
+            };
+            match service.compare(a, b) {
+                Some(similarity) => Ok(json!({ "similarity": similarity })),
+                None => return error(404, "No such digest"),
+            }
+        }
+        ("POST", "/search") => {
+            let threshold = match param("threshold").map(|t| t.parse::<f64>()) {
+                Some(Ok(threshold)) if (0. ..=1.).contains(&threshold) => threshold,
+                Some(_) => return error(400, "Threshold must be a fraction between 0 and 1"),
+                None => DEFAULT_THRESHOLD,
+            };
+            let top = match param("top").map(|t| t.parse::<usize>()) {
+                Some(Ok(top)) => Some(top),
+                Some(Err(_)) => return error(400, "Invalid top"),
+                None => None,
+            };
+            service.search(body, threshold, top).map(|hits| {
+                hits.into_iter()
+                    .map(|(id, label, similarity)| {
+                        json!({
+                            "id": id,
+                            "label": label,
+                            "similarity": similarity,
+                        })
+                    })
+                    .collect()
+            })
+        }
+        (_, "/digest") | (_, "/compare") | (_, "/search") | (_, "/metrics") => {
+            return error(405, "Method not allowed")
+        }
+        _ if path.starts_with("/digest/") => return error(405, "Method not allowed"),
+        _ => return error(404, "Not found"),
+    };
+    match result {
+        Ok(value) => (200, value),
+        Err(e) => error(500, &e.to_string()),
+    }
+}
+
+fn content_type(value: &str) -> Header {
+    Header::from_bytes(&b"Content-Type"[..], value.as_bytes()).unwrap()
+}
+
+fn digest_record(service: &Service, id: DigestId, label: &str, dict: &LZDict) -> Value {
+    json!({
+        "id": id,
+        "label": label,
+        "algorithm": service.algorithm().name(),
+        "k": service.k(),
+        "digest": dict.to_string(),
+    })
+}
+
+fn error(status: u16, msg: &str) -> (u16, Value) {
+    (status, json!({ "error": msg }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::handle;
+    use crate::service::Service;
+    use crate::store::DigestStore;
+    use crate::HashAlgorithm;
+
+    #[test]
+    fn test_handle() {
+        let service = Service::new(DigestStore::with_shards(1), HashAlgorithm::Murmur3, 1024);
+
+        let (status, body) = handle(
+            &service,
+            "POST",
+            "/digest?label=a%20b",
+            &mut &b"THIS IS A TEST SEQUENCE"[..],
+        );
+        assert_eq!(status, 200);
+        assert_eq!(body["label"], "a b");
+        let id = body["id"].as_u64().unwrap();
+
+        let (status, got) = handle(&service, "GET", &format!("/digest/{}", id), &mut &b""[..]);
+        assert_eq!(status, 200);
+        assert_eq!(got["digest"], body["digest"]);
+
+        let (status, hits) = handle(
+            &service,
+            "POST",
+            "/search?threshold=0.5&top=1",
+            &mut &b"THIS IS A TEST SEQUENCE"[..],
+        );
+        assert_eq!(status, 200);
+        assert_eq!(hits[0]["id"], id);
+        assert_eq!(hits[0]["similarity"], 1.);
+
+        let (status, body) = handle(
+            &service,
+            "GET",
+            &format!("/compare?a={}&b={}", id, id),
+            &mut &b""[..],
+        );
+        assert_eq!((status, body["similarity"].as_f64()), (200, Some(1.)));
+
+        assert_eq!(handle(&service, "GET", "/digest/42", &mut &b""[..]).0, 404);
+        for url in ["/digest", "/digest?label=", "/digest?label=a%0Alzjd:b:AAAA"] {
+            assert_eq!(handle(&service, "POST", url, &mut &b"data"[..]).0, 400);
+        }
+        assert_eq!(
+            handle(&service, "GET", "/compare?a=0", &mut &b""[..]).0,
+            400
+        );
+        assert_eq!(handle(&service, "GET", "/digest/x", &mut &b""[..]).0, 400);
+        assert_eq!(handle(&service, "GET", "/search", &mut &b""[..]).0, 405);
+        assert_eq!(
+            handle(&service, "POST", "/search?threshold=2", &mut &b""[..]).0,
+            400
+        );
+        assert_eq!(handle(&service, "POST", "/metrics", &mut &b""[..]).0, 405);
+        assert_eq!(handle(&service, "GET", "/", &mut &b""[..]).0, 404);
+    }
+}
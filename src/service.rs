@@ -0,0 +1,216 @@
+//! Transport-independent digest service, shared by the network front-ends.
+//! Digests data with fixed settings, keeps the results in a DigestStore
+//! and optionally appends them to a digest file so they survive restarts.
+//! The throughput and latencies of the service are tracked in its Metrics.
+use crate::metrics::{Metrics, Operation};
+use crate::store::{DigestId, DigestStore};
+use crate::{HashAlgorithm, LZDict, LZJDError, Result};
+
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+
+/// Threshold of searches that do not specify one
+pub const DEFAULT_THRESHOLD: f64 = 0.01;
+
+/// Checks that label can be stored: it must not be empty, and must not contain control
+/// characters like newlines, as a line of the digest file of the service would not be
+/// read back the same, or not at all
+pub fn check_label(label: &str) -> Result<()> {
+    if label.is_empty() {
+        return Err(LZJDError::from("Label must not be empty"));
+    }
+    if label.chars().any(char::is_control) {
+        return Err(LZJDError::from("Label must not contain control characters"));
+    }
+    Ok(())
+}
+
+/// Digest store along with the settings used to digest data for it
+pub struct Service {
+    store: DigestStore,
+    algorithm: HashAlgorithm,
+    k: usize,
+    /// Digest file that new entries are appended to
+    log: Option<Mutex<Box<dyn Write + Send>>>,
+    metrics: Metrics,
+}
+
+/// Reader that counts the bytes read through it
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+impl Service {
+    /// Creates a service digesting with algorithm, keeping k hashes per digest.
+    /// The store should only contain digests created with the same settings.
+    pub fn new(store: DigestStore, algorithm: HashAlgorithm, k: usize) -> Self {
+        Self {
+            store,
+            algorithm,
+            k,
+            log: None,
+            metrics: Metrics::new(),
+        }
+    }
+
+    /// Appends every digest added to the store to log,
+    /// as `lzjd:label:digest` lines
+    pub fn with_log<W: Write + Send + 'static>(self, log: W) -> Self {
+        Self {
+            log: Some(Mutex::new(Box::new(log))),
+            ..self
+        }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    pub fn store(&self) -> &DigestStore {
+        &self.store
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Returns the metrics of the service and the size of its store in the Prometheus text format
+    pub fn render_metrics(&self) -> String {
+        self.metrics.render(self.store.len())
+    }
+
+    /// Digests all data read from reader with the settings of the service
+    pub fn digest<R: Read>(&self, reader: R) -> Result<LZDict> {
+        let mut reader = CountingReader {
+            inner: reader,
+            count: 0,
+        };
+        let dict = self.algorithm.digest_reader_with_k(&mut reader, self.k)?;
+        self.metrics.add_digested(reader.count);
+        Ok(dict)
+    }
+
+    /// Digests all data read from reader and adds it to the store under label,
+    /// which must pass check_label
+    pub fn add<R: Read>(&self, label: String, reader: R) -> Result<(DigestId, LZDict)> {
+        check_label(&label)?;
+        let _timer = self.metrics.start(Operation::Digest);
+        let dict = self.digest(reader)?;
+        if let Some(log) = &self.log {
+            let mut log = log.lock().unwrap();
+            writeln!(log, "lzjd:{}:{}", label, dict)?;
+            log.flush()?;
+        }
+        let id = self.store.insert(label, dict.clone());
+        Ok((id, dict))
+    }
+
+    /// Returns the label and digest stored under id
+    pub fn get(&self, id: DigestId) -> Option<(String, LZDict)> {
+        let _timer = self.metrics.start(Operation::Get);
+        self.store.get(id)
+    }
+
+    /// Returns the similarity of the digests stored under a and b,
+    /// or None if either does not exist
+    pub fn compare(&self, a: DigestId, b: DigestId) -> Option<f64> {
+        let _timer = self.metrics.start(Operation::Compare);
+        let (_, a) = self.store.get(a)?;
+        let (_, b) = self.store.get(b)?;
+        Some(a.similarity(&b))
+    }
+
+    /// Digests all data read from reader and returns the stored digests of which the
+    /// similarity is at least threshold, at most top of them if given, best match first
+    pub fn search<R: Read>(
+        &self,
+        reader: R,
+        threshold: f64,
+        top: Option<usize>,
+    ) -> Result<Vec<(DigestId, String, f64)>> {
+        let _timer = self.metrics.start(Operation::Search);
+        let query = self.digest(reader)?;
+        let mut hits = self.store.search(&query, threshold);
+        if let Some(top) = top {
+            hits.truncate(top);
+        }
+        Ok(hits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::service::Service;
+    use crate::store::DigestStore;
+    use crate::HashAlgorithm;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    /// Writer of which the output can be inspected after handing it to a Service
+    #[derive(Clone, Default)]
+    struct SharedLog(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedLog {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_add_and_search() {
+        let log = SharedLog::default();
+        let service = Service::new(DigestStore::with_shards(2), HashAlgorithm::Crc32, 64)
+            .with_log(log.clone());
+
+        let (id, dict) = service
+            .add("a".to_owned(), &b"THIS IS A TEST SEQUENCE"[..])
+            .unwrap();
+        service
+            .add("b".to_owned(), &b"totally_different"[..])
+            .unwrap();
+        assert_eq!(service.get(id).unwrap().0, "a");
+        assert_eq!(service.compare(id, id), Some(1.));
+        assert_eq!(service.compare(id, 42), None);
+        assert!(dict.len() <= 64);
+
+        let hits = service
+            .search(&b"THIS IS A TEST SEQUENCE"[..], 0.5, None)
+            .unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].0, hits[0].2), (id, 1.));
+        assert_eq!(service.search(&b"x"[..], 0., Some(1)).unwrap().len(), 1);
+
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        assert!(log.starts_with("lzjd:a:"));
+        assert_eq!(log.lines().count(), 2);
+
+        for label in ["", "a\nlzjd:b:AAAA", "tab\t"] {
+            let err = service.add(label.to_owned(), &b"data"[..]).unwrap_err();
+            assert!(err.to_string().contains("Label must not"));
+        }
+        assert_eq!(service.store().len(), 2);
+
+        let metrics = service.render_metrics();
+        assert!(metrics.contains("\nlzjd_digests_total 4\n"));
+        assert!(metrics.contains("\nlzjd_digested_bytes_total 64\n"));
+        assert!(metrics.contains("\nlzjd_store_digests 2\n"));
+        assert!(metrics.contains("\nlzjd_request_duration_seconds_count{operation=\"search\"} 2\n"));
+    }
+}
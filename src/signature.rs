@@ -0,0 +1,96 @@
+//! Bit signatures of digests, which bound the similarity of two digests from above
+//! at a fraction of the cost of intersecting them, so that batch comparisons can
+//! skip pairs that can not meet their threshold.
+use crate::LZDict;
+
+/// Number of 64-bit words of a signature. Signatures only rule out pairs if
+/// most of their bits are clear, so they have several bits per hash of a full digest.
+pub const SIGNATURE_WORDS: usize = 64;
+
+const SIGNATURE_BITS: u32 = SIGNATURE_WORDS as u32 * 64;
+
+/// Set of the buckets that the hashes of a digest fall into
+#[derive(Debug, Clone)]
+pub struct Signature {
+    bits: [u64; SIGNATURE_WORDS],
+    /// Number of hashes of the digest
+    len: usize,
+}
+
+impl Signature {
+    pub fn new(dict: &LZDict) -> Self {
+        let mut bits = [0; SIGNATURE_WORDS];
+        for &hash in dict.iter() {
+            // The kept hashes are the smallest ones, so their high bits are mostly equal
+            let bucket =
+                (hash as u32).wrapping_mul(0x9e37_79b1) >> (32 - SIGNATURE_BITS.trailing_zeros());
+            bits[bucket as usize / 64] |= 1 << (bucket % 64);
+        }
+        Self {
+            bits,
+            len: dict.len(),
+        }
+    }
+
+    /// Returns a value that is at least the similarity of the digests of self and other.
+    /// A bucket that holds hashes of only one digest holds at least one hash that
+    /// the other digest does not share, which limits the size of their intersection.
+    pub fn max_similarity(&self, other: &Self) -> f64 {
+        let (mut only_self, mut only_other) = (0, 0);
+        for (a, b) in self.bits.iter().zip(&other.bits) {
+            only_self += (a & !b).count_ones() as usize;
+            only_other += (b & !a).count_ones() as usize;
+        }
+        let intersection = (self.len - only_self).min(other.len - only_other);
+        // Computed like LZDict::jaccard_similarity, so that the bound is exact if the intersection is
+        intersection as f64 / (self.len + other.len - intersection) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::signature::Signature;
+    use crate::LZDict;
+
+    #[test]
+    fn test_max_similarity() {
+        // xorshift64, for hashes spread like those of real digests
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut hashes = |n: usize| -> Vec<i32> {
+            (0..n)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    (state >> 32) as i32
+                })
+                .collect()
+        };
+        let dict = |mut hashes: Vec<i32>| {
+            hashes.sort_unstable();
+            hashes.dedup();
+            LZDict::from(hashes)
+        };
+
+        let shared = hashes(600);
+        let a = dict([&shared[..], &hashes(424)].concat());
+        let b = dict([&shared[..], &hashes(424)].concat());
+        let unrelated = dict(hashes(1024));
+        let small = dict(shared[..10].to_vec());
+
+        for (x, y) in [
+            (&a, &b),
+            (&a, &unrelated),
+            (&a, &small),
+            (&small, &unrelated),
+        ] {
+            let (sx, sy) = (Signature::new(x), Signature::new(y));
+            assert!(sx.max_similarity(&sy) >= x.similarity(y));
+            assert_eq!(sx.max_similarity(&sy), sy.max_similarity(&sx));
+        }
+        assert_eq!(Signature::new(&a).max_similarity(&Signature::new(&a)), 1.);
+        // Unrelated full digests are ruled out at moderate thresholds
+        assert!(Signature::new(&a).max_similarity(&Signature::new(&unrelated)) < 0.3);
+        assert!(Signature::new(&small).max_similarity(&Signature::new(&unrelated)) < 0.05);
+    }
+}
@@ -0,0 +1,42 @@
+//! Defines a wrapper around SipHash-2-4 with zero keys, implementing
+//! std::hash::Hasher as well as a std::hash::BuildHasher which builds the hasher.
+//! Unlike std's DefaultHasher, its hashes are stable across Rust releases.
+use crate::hasher::ResettableHasher;
+use siphasher::sip::SipHasher24;
+
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+
+/// Wrapper around siphasher::sip::SipHasher24 which implements ResettableHasher
+#[derive(Default)]
+pub struct SipHasher {
+    hasher: SipHasher24,
+}
+
+impl Hasher for SipHasher {
+    fn finish(&self) -> u64 {
+        self.hasher.finish()
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.hasher.write(bytes);
+    }
+}
+
+impl ResettableHasher for SipHasher {
+    fn reset(&mut self) {
+        self.hasher = SipHasher24::new();
+    }
+}
+
+/// std::hash::BuildHasher that builds SipHashers
+#[derive(Clone)]
+pub struct SipBuildHasher;
+
+impl BuildHasher for SipBuildHasher {
+    type Hasher = SipHasher;
+
+    fn build_hasher(&self) -> Self::Hasher {
+        SipHasher::default()
+    }
+}
@@ -0,0 +1,116 @@
+//! Summary statistics of digests and corpora of digests, for judging
+//! a corpus before comparing all pairs of its digests.
+use crate::LZDict;
+
+/// Estimates the number of distinct LZ phrases in the input of dict, a digest keeping
+/// at most k hashes. Digests with fewer than k hashes contain every phrase, so their
+/// length is exact. Otherwise the estimate follows from how much of the hash space
+/// the k smallest hashes span, as the hashes are spread evenly over it.
+pub fn estimated_phrases(dict: &LZDict, k: usize) -> f64 {
+    match dict.last() {
+        Some(&largest) if dict.len() >= k && k > 1 => {
+            // Fraction of the hash space up to and including the largest kept hash
+            let fraction = (f64::from(largest) - f64::from(i32::MIN) + 1.) / (1u64 << 32) as f64;
+            (dict.len() - 1) as f64 / fraction
+        }
+        _ => dict.len() as f64,
+    }
+}
+
+/// Returns the values at the given fractions of the sorted values,
+/// using the nearest rank, or None if values is empty
+pub fn quantiles(sorted: &[f64], fractions: &[f64]) -> Option<Vec<f64>> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let last = sorted.len() - 1;
+    Some(
+        fractions
+            .iter()
+            .map(|fraction| sorted[(fraction * last as f64).round() as usize])
+            .collect(),
+    )
+}
+
+/// Counts the values between 0 and 1 in each of bins intervals of equal width.
+/// The last interval includes 1.
+pub fn histogram<I: IntoIterator<Item = f64>>(values: I, bins: usize) -> Vec<usize> {
+    let mut counts = vec![0; bins];
+    if bins == 0 {
+        return counts;
+    }
+    for value in values {
+        let bin = ((value * bins as f64) as usize).min(bins - 1);
+        counts[bin] += 1;
+    }
+    counts
+}
+
+/// Returns all pairs of indices below n if there are at most count of them,
+/// or else count pairs chosen pseudo-randomly from seed. In every pair,
+/// the first index is smaller than the second. Pairs may repeat.
+pub fn sample_pairs(n: usize, count: usize, seed: u64) -> Vec<(usize, usize)> {
+    let total = n * n.saturating_sub(1) / 2;
+    if total <= count {
+        return (0..n)
+            .flat_map(|i| (i + 1..n).map(move |j| (i, j)))
+            .collect();
+    }
+    let mut state = seed;
+    let mut next = || {
+        // SplitMix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        (z ^ (z >> 31)) as usize % n
+    };
+    let mut pairs = Vec::with_capacity(count);
+    while pairs.len() < count {
+        let (a, b) = (next(), next());
+        if a != b {
+            pairs.push((a.min(b), a.max(b)));
+        }
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stats::{estimated_phrases, histogram, quantiles, sample_pairs};
+    use crate::LZDict;
+
+    #[test]
+    fn test_estimated_phrases() {
+        let dict = LZDict::from(vec![-5, 1, 7]);
+        assert_eq!(estimated_phrases(&dict, 1024), 3.);
+
+        // Two hashes spanning the lower half of the hash space estimate four phrases
+        let dict = LZDict::from(vec![i32::MIN, -1]);
+        assert_eq!(estimated_phrases(&dict, 2), 2.);
+        let dict = LZDict::from(vec![i32::MIN, i32::MIN + 1, -1]);
+        assert_eq!(estimated_phrases(&dict, 3), 4.);
+    }
+
+    #[test]
+    fn test_quantiles_and_histogram() {
+        let values = [0., 1., 2., 3., 4.];
+        assert_eq!(quantiles(&values, &[0., 0.5, 1.]), Some(vec![0., 2., 4.]));
+        assert_eq!(quantiles(&[], &[0.5]), None);
+
+        assert_eq!(
+            histogram(vec![0., 0.05, 0.5, 0.99, 1.], 4),
+            vec![2, 0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn test_sample_pairs() {
+        assert_eq!(sample_pairs(3, 10, 0), vec![(0, 1), (0, 2), (1, 2)]);
+
+        let pairs = sample_pairs(1000, 100, 42);
+        assert_eq!(pairs.len(), 100);
+        assert!(pairs.iter().all(|&(i, j)| i < j && j < 1000));
+        assert_eq!(pairs, sample_pairs(1000, 100, 42));
+    }
+}
@@ -0,0 +1,520 @@
+//! Defines a digest store that can be shared between threads, allowing
+//! inserts and queries to run concurrently.
+//!
+//! A store with a Retention only holds the digests of a window, like those inserted
+//! in the last 24 hours or the most recent million, for comparing live data against
+//! what was seen lately.
+//!
+//! similarity_join finds the similar pairs of digests of two stores, like today's
+//! uploads and a historical corpus, through an index of one of them.
+use crate::index::DigestIndex;
+use crate::lz_dict::K;
+use crate::{HashAlgorithm, LZDict};
+
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Identifier handed out by DigestStore::insert
+pub type DigestId = usize;
+
+/// Labels and digests by id, along with the times they were inserted if the store has a retention
+type Shard = HashMap<DigestId, (String, LZDict, Option<Instant>)>;
+
+/// Which digests a store keeps. Digests beyond either limit are removed, oldest first,
+/// as digests are inserted, and reads leave out digests older than max_age until then.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Retention {
+    /// Time after being inserted after which digests are removed
+    pub max_age: Option<Duration>,
+    /// Number of most recently inserted digests to keep
+    pub max_len: Option<usize>,
+}
+
+impl Retention {
+    /// Whether digests are kept until they are removed
+    pub fn is_unlimited(&self) -> bool {
+        self.max_age.is_none() && self.max_len.is_none()
+    }
+}
+
+/// Digests in the order they were inserted, to remove them by the retention of a store
+struct Window {
+    /// Ids and insertion times of digests, oldest first, which may include removed digests
+    inserted: VecDeque<(DigestId, Instant)>,
+    /// Number of digests in the store
+    len: usize,
+}
+
+/// Sync store of labeled LZ dictionaries.
+/// Entries are spread over a number of independently locked shards,
+/// so inserting threads only block queries on the shard they write to.
+pub struct DigestStore {
+    shards: Vec<RwLock<Shard>>,
+    next_id: AtomicUsize,
+    retention: Retention,
+    /// Window of the digests, if the store has a retention. Only writes lock it,
+    /// so that reads only contend with writes to the same shard.
+    window: Option<Mutex<Window>>,
+}
+
+impl DigestStore {
+    /// Creates an empty store with one shard per CPU.
+    pub fn new() -> Self {
+        Self::with_shards(thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+
+    /// Creates an empty store with the given number of shards.
+    pub fn with_shards(num_shards: usize) -> Self {
+        Self {
+            shards: (0..num_shards.max(1))
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+            next_id: AtomicUsize::new(0),
+            retention: Retention::default(),
+            window: None,
+        }
+    }
+
+    /// Sets the retention of the store, which removes digests that are older than
+    /// retention.max_age, or beyond the retention.max_len most recent digests.
+    /// Digests that are in the store already count as inserted now.
+    pub fn with_retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        if retention.is_unlimited() {
+            self.window = None;
+            return self;
+        }
+        let now = Instant::now();
+        let mut ids: Vec<DigestId> = self
+            .shards
+            .iter_mut()
+            .flat_map(|shard| {
+                let shard = shard.get_mut().unwrap();
+                shard.values_mut().for_each(|entry| entry.2 = Some(now));
+                shard.keys().copied().collect::<Vec<_>>()
+            })
+            .collect();
+        ids.sort_unstable();
+        let window = Window {
+            len: ids.len(),
+            inserted: ids.into_iter().map(|id| (id, now)).collect(),
+        };
+        self.window = Some(Mutex::new(window));
+        self.expire();
+        self
+    }
+
+    /// Retention of the store, which is unlimited unless set by with_retention
+    pub fn retention(&self) -> Retention {
+        self.retention
+    }
+
+    /// Removes the digests beyond the retention of the store, returning how many
+    /// were removed. Inserts call this, and reads leave out digests older than
+    /// max_age, so calling it is only needed to free the memory of digests that
+    /// expire while nothing is inserted.
+    pub fn expire(&self) -> usize {
+        match &self.window {
+            Some(window) => self.expire_window(&mut window.lock().unwrap()),
+            None => 0,
+        }
+    }
+
+    fn expire_window(&self, window: &mut Window) -> usize {
+        let now = Instant::now();
+        let mut removed = 0;
+        while let Some(&(id, inserted)) = window.inserted.front() {
+            let too_many = self.retention.max_len.is_some_and(|max| window.len > max);
+            let too_old = self
+                .retention
+                .max_age
+                .is_some_and(|max_age| now.duration_since(inserted) > max_age);
+            if !too_many && !too_old {
+                break;
+            }
+            window.inserted.pop_front();
+            // Digests that were removed by remove are only left in inserted
+            if self.shard(id).write().unwrap().remove(&id).is_some() {
+                window.len -= 1;
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    fn shard(&self, id: DigestId) -> &RwLock<Shard> {
+        &self.shards[id % self.shards.len()]
+    }
+
+    /// Time before which digests were inserted that are beyond the max_age of
+    /// the retention, if it has one
+    fn cutoff(&self) -> Option<Instant> {
+        let max_age = self.retention.max_age?;
+        Instant::now().checked_sub(max_age)
+    }
+
+    /// Adds a dictionary to the store and returns its id.
+    pub fn insert(&self, label: String, dict: LZDict) -> DigestId {
+        let window = match &self.window {
+            Some(window) => window,
+            None => {
+                let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                self.shard(id)
+                    .write()
+                    .unwrap()
+                    .insert(id, (label, dict, None));
+                return id;
+            }
+        };
+        // Writing the shard while holding the window keeps the window in the order of
+        // the ids, and makes every digest in it present in its shard until removed
+        let mut window = window.lock().unwrap();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let entry = (label, dict, Some(now));
+        self.shard(id).write().unwrap().insert(id, entry);
+        window.inserted.push_back((id, now));
+        window.len += 1;
+        self.expire_window(&mut window);
+        id
+    }
+
+    /// Returns a copy of the label and dictionary stored under id,
+    /// unless it is beyond the retention of the store.
+    pub fn get(&self, id: DigestId) -> Option<(String, LZDict)> {
+        let cutoff = self.cutoff();
+        let shard = self.shard(id).read().unwrap();
+        let (label, dict, inserted) = shard.get(&id)?;
+        if is_expired(*inserted, cutoff) {
+            return None;
+        }
+        Some((label.clone(), dict.clone()))
+    }
+
+    /// Removes the entry stored under id, returning it.
+    pub fn remove(&self, id: DigestId) -> Option<(String, LZDict)> {
+        let removed = match &self.window {
+            Some(window) => {
+                let mut window = window.lock().unwrap();
+                let removed = self.shard(id).write().unwrap().remove(&id);
+                if removed.is_some() {
+                    window.len -= 1;
+                }
+                removed
+            }
+            None => self.shard(id).write().unwrap().remove(&id),
+        };
+        removed.map(|(label, dict, _)| (label, dict))
+    }
+
+    /// Number of dictionaries in the store, leaving out those older than the
+    /// max_age of its retention, which takes looking at every one of them.
+    pub fn len(&self) -> usize {
+        let cutoff = self.cutoff();
+        self.shards
+            .iter()
+            .map(|shard| {
+                let shard = shard.read().unwrap();
+                match cutoff {
+                    Some(_) => shard
+                        .values()
+                        .filter(|entry| !is_expired(entry.2, cutoff))
+                        .count(),
+                    None => shard.len(),
+                }
+            })
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns id, label and similarity of every stored dictionary
+    /// of which the similarity to query is at least threshold,
+    /// ordered by descending similarity.
+    pub fn search(&self, query: &LZDict, threshold: f64) -> Vec<(DigestId, String, f64)> {
+        let cutoff = self.cutoff();
+        let mut hits = vec![];
+        for shard in &self.shards {
+            let shard = shard.read().unwrap();
+            for (id, (label, dict, inserted)) in shard.iter() {
+                if is_expired(*inserted, cutoff) {
+                    continue;
+                }
+                let similarity = query.similarity(dict);
+                if similarity >= threshold {
+                    hits.push((*id, label.to_owned(), similarity));
+                }
+            }
+        }
+        hits.sort_by(|a, b| {
+            b.2.partial_cmp(&a.2)
+                .unwrap_or(cmp::Ordering::Equal)
+                .then(a.0.cmp(&b.0))
+        });
+        hits
+    }
+
+    /// Copies of the entries of the store, ordered by id
+    fn entries(&self) -> Vec<(DigestId, String, LZDict)> {
+        let cutoff = self.cutoff();
+        let mut entries: Vec<_> = self
+            .shards
+            .iter()
+            .flat_map(|shard| {
+                let shard = shard.read().unwrap();
+                shard
+                    .iter()
+                    .filter(|(_, entry)| !is_expired(entry.2, cutoff))
+                    .map(|(&id, (label, dict, _))| (id, label.clone(), dict.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        entries.sort_unstable_by_key(|entry| entry.0);
+        entries
+    }
+}
+
+/// Whether a digest inserted at inserted was inserted before cutoff
+fn is_expired(inserted: Option<Instant>, cutoff: Option<Instant>) -> bool {
+    matches!((inserted, cutoff), (Some(inserted), Some(cutoff)) if inserted < cutoff)
+}
+
+/// Returns the ids of the pairs of digests of store_a and store_b of which the similarity
+/// is at least threshold, along with that similarity, ordered by the id in store_a and
+/// then by the id in store_b. Rather than comparing all pairs, the digests of store_b are
+/// put in a DigestIndex, so that each digest of store_a is only compared to the digests
+/// that share a hash with it, which makes store_b the one to pass the larger store as.
+/// If both are the same store, digests are not paired with themselves, and each pair is
+/// returned once, with the smallest id first. With the `rayon` feature, the digests of
+/// store_a are looked up on all threads. Digests inserted while the join runs may be left out.
+pub fn similarity_join(
+    store_a: &DigestStore,
+    store_b: &DigestStore,
+    threshold: f64,
+) -> Vec<(DigestId, DigestId, f64)> {
+    let same = std::ptr::eq(store_a, store_b);
+    let entries_b = store_b.entries();
+    // The index is not saved, so the settings it records do not matter
+    let mut index = DigestIndex::new(HashAlgorithm::default(), K);
+    let ids_b: Vec<DigestId> = entries_b
+        .into_iter()
+        .map(|(id, label, dict)| {
+            index.insert(label, dict);
+            id
+        })
+        .collect();
+
+    let entries_a = if same {
+        (0..index.len())
+            .map(|i| (ids_b[i], index.dict(i as u32).clone()))
+            .collect()
+    } else {
+        store_a
+            .entries()
+            .into_iter()
+            .map(|(id, _, dict)| (id, dict))
+            .collect::<Vec<_>>()
+    };
+    let join = |(id_a, dict): &(DigestId, LZDict)| -> Vec<(DigestId, DigestId, f64)> {
+        let mut pairs: Vec<_> = index
+            .search(dict, threshold)
+            .into_iter()
+            .map(|(i, similarity)| (*id_a, ids_b[i as usize], similarity))
+            .filter(|&(a, b, _)| !same || a < b)
+            .collect();
+        pairs.sort_unstable_by_key(|pair| pair.1);
+        pairs
+    };
+    #[cfg(feature = "rayon")]
+    let rows: Vec<_> = entries_a.par_iter().map(join).collect();
+    #[cfg(not(feature = "rayon"))]
+    let rows: Vec<_> = entries_a.iter().map(join).collect();
+    rows.into_iter().flatten().collect()
+}
+
+impl Default for DigestStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crc32::CRC32BuildHasher;
+    use crate::store::{similarity_join, DigestStore, Retention};
+    use crate::LZDict;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_concurrent_insert_and_search() {
+        let store = Arc::new(DigestStore::with_shards(4));
+        let query = LZDict::from_bytes_stream(
+            b"THIS IS A TEST SEQUENCE".iter().cloned(),
+            &CRC32BuildHasher,
+        );
+
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let store = Arc::clone(&store);
+                let query = query.clone();
+                thread::spawn(move || {
+                    for i in 0..16 {
+                        let seq = format!("THIS IS A TEST SEQUENCE {} {}", t, i);
+                        let dict = LZDict::from_bytes_stream(seq.bytes(), &CRC32BuildHasher);
+                        store.insert(seq, dict);
+                        store.search(&query, 0.5);
+                    }
+                })
+            })
+            .collect();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+
+        assert_eq!(store.len(), 8 * 16);
+        let hits = store.search(&query, 0.5);
+        assert!(!hits.is_empty());
+        assert!(hits.windows(2).all(|w| w[0].2 >= w[1].2));
+
+        let id = hits[0].0;
+        assert_eq!(store.get(id).map(|e| e.0), Some(hits[0].1.clone()));
+        assert!(store.remove(id).is_some());
+        assert!(store.get(id).is_none());
+    }
+
+    #[test]
+    fn test_retention() {
+        let dict = |i: usize| {
+            LZDict::from_bytes_stream(format!("SEQUENCE {}", i).bytes(), &CRC32BuildHasher)
+        };
+        let retention = Retention {
+            max_age: None,
+            max_len: Some(3),
+        };
+        let store = DigestStore::with_shards(2).with_retention(retention);
+        assert_eq!(store.retention(), retention);
+        let ids: Vec<_> = (0..5)
+            .map(|i| store.insert(i.to_string(), dict(i)))
+            .collect();
+        assert_eq!(store.len(), 3);
+        assert!(store.get(ids[1]).is_none());
+        assert!(store.get(ids[2]).is_some());
+        // Removed digests make room for as many others
+        store.remove(ids[3]);
+        store.insert("5".into(), dict(5));
+        assert_eq!(store.len(), 3);
+        assert!(store.get(ids[2]).is_some());
+
+        let store = DigestStore::with_shards(2);
+        (0..5).for_each(|i| {
+            store.insert(i.to_string(), dict(i));
+        });
+        let store = store.with_retention(Retention {
+            max_age: Some(Duration::from_millis(50)),
+            max_len: Some(4),
+        });
+        assert_eq!(store.len(), 4);
+        assert!(store.get(4).is_some());
+        thread::sleep(Duration::from_millis(100));
+        assert!(store.get(4).is_none());
+        assert_eq!(store.len(), 0);
+        assert!(store.search(&dict(4), 0.).is_empty());
+        assert!(store.is_empty());
+        // Reads leave out the expired digests, and expire removes them
+        assert_eq!(store.expire(), 4);
+        assert_eq!(store.expire(), 0);
+
+        // Concurrent inserts keep the most recent digests
+        let store = Arc::new(DigestStore::with_shards(4).with_retention(retention));
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    for i in 0..50 {
+                        store.insert(format!("{} {}", t, i), dict(i));
+                    }
+                })
+            })
+            .collect();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+        assert_eq!(store.len(), 3);
+        assert!((197..200).all(|id| store.get(id).is_some()));
+
+        // Digests inserted while others expire them all expire in turn
+        let store = Arc::new(DigestStore::with_shards(4).with_retention(Retention {
+            max_age: Some(Duration::from_millis(1)),
+            max_len: Some(10),
+        }));
+        let inserting: Vec<_> = (0..4)
+            .map(|t| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    for i in 0..200 {
+                        store.insert(format!("{} {}", t, i), dict(i));
+                    }
+                })
+            })
+            .collect();
+        let expiring: Vec<_> = (0..2)
+            .map(|_| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    for _ in 0..500 {
+                        store.expire();
+                    }
+                })
+            })
+            .collect();
+        inserting
+            .into_iter()
+            .chain(expiring)
+            .for_each(|h| h.join().unwrap());
+        thread::sleep(Duration::from_millis(10));
+        store.expire();
+        assert!(store.is_empty());
+        assert!(store
+            .shards
+            .iter()
+            .all(|shard| shard.read().unwrap().is_empty()));
+        assert_eq!(store.window.as_ref().unwrap().lock().unwrap().len, 0);
+    }
+
+    #[test]
+    fn test_similarity_join() {
+        let dict = |seq: &str| LZDict::from_bytes_stream(seq.bytes(), &CRC32BuildHasher);
+        let base = "THIS IS A TEST SEQUENCE OF SOME LENGTH, WHICH IS SIMILAR TO OTHERS";
+        let corpus = DigestStore::with_shards(3);
+        let old: Vec<_> = (0..20)
+            .map(|i| {
+                let seq = format!("{} {}", base, i);
+                corpus.insert(seq.clone(), dict(&seq))
+            })
+            .collect();
+        corpus.insert("other".into(), dict("SOMETHING ELSE ENTIRELY"));
+        let uploads = DigestStore::with_shards(2);
+        let new = uploads.insert("new".into(), dict(&format!("{} 7", base)));
+        uploads.insert("unrelated".into(), dict("NOTHING LIKE THE OTHERS"));
+
+        let pairs = similarity_join(&uploads, &corpus, 0.5);
+        assert_eq!(pairs.len(), 20);
+        assert!(pairs.iter().all(|&(a, _, s)| a == new && s >= 0.5));
+        assert!(pairs.windows(2).all(|w| w[0].1 < w[1].1));
+        assert!(pairs.contains(&(new, old[7], 1.)));
+        // Every pair above the threshold is found
+        let (_, query) = uploads.get(new).unwrap();
+        assert_eq!(corpus.search(&query, 0.5).len(), pairs.len());
+
+        let pairs = similarity_join(&corpus, &corpus, 0.5);
+        assert_eq!(pairs.len(), 20 * 19 / 2);
+        assert!(pairs.iter().all(|&(a, b, _)| a < b));
+        assert_eq!(similarity_join(&corpus, &corpus, 0.).len(), 21 * 20 / 2);
+    }
+}
@@ -0,0 +1,432 @@
+//! Digests of sliding windows over the input, for finding which parts of two
+//! inputs, like two firmware images, correspond to each other.
+use crate::hasher::AlgorithmDigester;
+use crate::{HashAlgorithm, LZDict, LZJDError, Result};
+
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read};
+
+/// Number of windows find_similar_regions starts within the length of a window
+pub const REGION_STEPS_PER_WINDOW: usize = 4;
+
+/// Iterator over the digests of windows of window bytes of the input, starting
+/// every step bytes, along with the offsets at which they start. The last window
+/// is the first that reaches the end of the input, which is shorter than window
+/// bytes if the input is. Windows without bytes that no earlier window has,
+/// like those of an empty input, are left out.
+pub struct WindowDigests<R> {
+    reader: R,
+    window: usize,
+    step: usize,
+    digester: AlgorithmDigester,
+    buf: Vec<u8>,
+    offset: u64,
+    /// Whether the first window has been read
+    started: bool,
+    done: bool,
+}
+
+impl<R: Read> WindowDigests<R> {
+    /// Digests the windows of reader with algorithm, keeping the k smallest hashes
+    /// of each. Fails if window or step is 0.
+    pub fn new(
+        reader: R,
+        window: usize,
+        step: usize,
+        algorithm: HashAlgorithm,
+        k: usize,
+    ) -> Result<Self> {
+        if window == 0 || step == 0 {
+            return Err(LZJDError::from("Window and step must be at least 1 byte"));
+        }
+        Ok(Self {
+            reader,
+            window,
+            step,
+            digester: algorithm.digester(k),
+            buf: Vec::with_capacity(window),
+            offset: 0,
+            started: false,
+            done: false,
+        })
+    }
+
+    /// Moves to the next window and reads it into buf.
+    /// Returns whether it has bytes that the previous window did not have.
+    fn advance(&mut self) -> Result<bool> {
+        if self.done {
+            return Ok(false);
+        }
+        if self.started {
+            let skip = self.step.saturating_sub(self.buf.len());
+            self.buf.drain(..self.step.min(self.buf.len()));
+            let skipped = self.fill(skip, true)?;
+            self.offset += (self.step - skip + skipped) as u64;
+            if skipped < skip {
+                self.done = true;
+                return Ok(false);
+            }
+        }
+        self.started = true;
+        let kept = self.buf.len();
+        let read = self.fill(self.window - kept, false)?;
+        if kept + read < self.window {
+            self.done = true;
+        }
+        Ok(read > 0)
+    }
+
+    /// Reads up to len bytes, appending them to buf unless they are discarded.
+    /// Returns the number of bytes read, which is less than len only at the end of the input.
+    fn fill(&mut self, len: usize, discard: bool) -> Result<usize> {
+        let start = self.buf.len();
+        self.buf.resize(start + len, 0);
+        let mut read = 0;
+        while read < len {
+            match self.reader.read(&mut self.buf[start + read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.buf.truncate(start);
+                    return Err(e.into());
+                }
+            }
+        }
+        self.buf
+            .truncate(if discard { start } else { start + read });
+        Ok(read)
+    }
+}
+
+impl<R: Read> Iterator for WindowDigests<R> {
+    type Item = Result<(u64, LZDict)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.advance() {
+            Ok(true) => Some(Ok((self.offset, self.digester.digest_bytes(&self.buf)))),
+            Ok(false) => None,
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Like WindowDigests, but parses each byte of the input only once, rather than once
+/// for every window that holds it. The input is digested in chunks of the greatest
+/// common divisor of window and step bytes, and the digest of a window merges those
+/// of its chunks, like LZDict::merge, so that a window costs a merge rather than
+/// parsing window bytes. The digests differ from those of WindowDigests, as LZ phrases
+/// do not cross the boundaries of chunks, and are most alike for large chunks: for a
+/// window that is a multiple of step, chunks are step bytes. Compare them with the
+/// digests of inputs digested in chunks of the same size.
+pub struct RollingWindowDigests<R> {
+    chunks: WindowDigests<R>,
+    window_chunks: usize,
+    step_chunks: usize,
+    k: usize,
+    /// Offsets and digests of the chunks of the current window
+    window: VecDeque<(u64, LZDict)>,
+    started: bool,
+    done: bool,
+}
+
+impl<R: Read> RollingWindowDigests<R> {
+    /// Digests the windows of reader with algorithm, keeping the k smallest hashes
+    /// of each. Fails if window or step is 0.
+    pub fn new(
+        reader: R,
+        window: usize,
+        step: usize,
+        algorithm: HashAlgorithm,
+        k: usize,
+    ) -> Result<Self> {
+        if window == 0 || step == 0 {
+            return Err(LZJDError::from("Window and step must be at least 1 byte"));
+        }
+        let chunk = gcd(window, step);
+        Ok(Self {
+            chunks: WindowDigests::new(reader, chunk, chunk, algorithm, k)?,
+            window_chunks: window / chunk,
+            step_chunks: step / chunk,
+            k,
+            window: VecDeque::with_capacity(window / chunk),
+            started: false,
+            done: false,
+        })
+    }
+
+    /// Moves to the next window and digests the chunks that it adds.
+    /// Returns whether it has chunks that the previous window did not have.
+    fn advance(&mut self) -> Result<bool> {
+        if self.done {
+            return Ok(false);
+        }
+        if self.started {
+            for _ in 0..self.step_chunks {
+                if self.window.pop_front().is_none() && self.chunks.next().transpose()?.is_none() {
+                    self.done = true;
+                    return Ok(false);
+                }
+            }
+        }
+        self.started = true;
+        let mut added = false;
+        while self.window.len() < self.window_chunks {
+            match self.chunks.next().transpose()? {
+                Some(chunk) => {
+                    self.window.push_back(chunk);
+                    added = true;
+                }
+                None => {
+                    self.done = true;
+                    break;
+                }
+            }
+        }
+        Ok(added)
+    }
+}
+
+impl<R: Read> Iterator for RollingWindowDigests<R> {
+    type Item = Result<(u64, LZDict)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.advance() {
+            Ok(true) => {
+                let offset = self.window[0].0;
+                let dict = LZDict::merge(self.window.iter().map(|(_, dict)| dict), self.k);
+                Some(Ok((offset, dict)))
+            }
+            Ok(false) => None,
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Compares every window of a with every window of b, digesting windows of window
+/// bytes every step bytes like RollingWindowDigests does, with algorithm and the k
+/// smallest hashes of each. Returns the offsets of the windows in a and b along with their
+/// score, as LZDict::score gives it, for all pairs: a matrix in row-major order,
+/// with a row per window of a, that can be drawn as a heatmap of the similarity.
+pub fn similarity_profile<A: Read, B: Read>(
+    a: A,
+    b: B,
+    window: usize,
+    step: usize,
+    algorithm: HashAlgorithm,
+    k: usize,
+) -> Result<Vec<(u64, u64, u8)>> {
+    let windows_b =
+        RollingWindowDigests::new(b, window, step, algorithm, k)?.collect::<Result<Vec<_>>>()?;
+    let mut profile = vec![];
+    for window_a in RollingWindowDigests::new(a, window, step, algorithm, k)? {
+        let (offset_a, dict_a) = window_a?;
+        profile.extend(
+            windows_b
+                .iter()
+                .map(|(offset_b, dict_b)| (offset_a, *offset_b, dict_a.score(dict_b))),
+        );
+    }
+    Ok(profile)
+}
+
+/// Finds where the input digested into needle appears in haystack, like a carved
+/// fragment in a disk image. Digests windows of window bytes of haystack, which is
+/// best about the length of the needle's input, with algorithm and the k smallest
+/// hashes of each, like the needle was digested. The windows overlap, starting every
+/// window / REGION_STEPS_PER_WINDOW bytes, so that the needle lies mostly within one
+/// of them wherever it is. Returns the offsets of the windows of which the similarity
+/// to needle is at least threshold, along with that similarity, in order of offset.
+/// A match usually shows up in a few consecutive windows, of which the similarity
+/// drops quickly with the distance between the start of the window and the needle,
+/// as the LZ phrases start at other bytes. Thresholds that are lower than those for
+/// comparing files, like 0.2, find such matches.
+pub fn find_similar_regions<R: Read>(
+    needle: &LZDict,
+    haystack: R,
+    window: usize,
+    threshold: f64,
+    algorithm: HashAlgorithm,
+    k: usize,
+) -> Result<Vec<(u64, f64)>> {
+    let step = (window / REGION_STEPS_PER_WINDOW).max(1);
+    let mut regions = vec![];
+    for window in WindowDigests::new(haystack, window, step, algorithm, k)? {
+        let (offset, dict) = window?;
+        let similarity = needle.similarity(&dict);
+        if similarity >= threshold {
+            regions.push((offset, similarity));
+        }
+    }
+    Ok(regions)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::window::{
+        find_similar_regions, similarity_profile, RollingWindowDigests, WindowDigests,
+    };
+    use crate::HashAlgorithm;
+    use crate::LZDict;
+
+    fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    fn offsets(len: usize, window: usize, step: usize) -> Vec<u64> {
+        let data = vec![0; len];
+        WindowDigests::new(&data[..], window, step, HashAlgorithm::Murmur3, 1024)
+            .unwrap()
+            .map(|window| window.unwrap().0)
+            .collect()
+    }
+
+    #[test]
+    fn test_window_digests() {
+        assert_eq!(offsets(10, 4, 4), [0, 4, 8]);
+        assert_eq!(offsets(10, 4, 3), [0, 3, 6]);
+        assert_eq!(offsets(10, 4, 2), [0, 2, 4, 6]);
+        assert_eq!(offsets(10, 2, 4), [0, 4, 8]);
+        assert_eq!(offsets(9, 2, 4), [0, 4, 8]);
+        assert_eq!(offsets(8, 2, 4), [0, 4]);
+        assert_eq!(offsets(3, 4, 2), [0]);
+        assert!(offsets(0, 4, 2).is_empty());
+        assert!(WindowDigests::new(&b""[..], 0, 1, HashAlgorithm::Murmur3, 1024).is_err());
+
+        let data = random_bytes(10_000, 1);
+        let algorithm = HashAlgorithm::Murmur3;
+        for window in WindowDigests::new(&data[..], 3000, 2000, algorithm, 1024).unwrap() {
+            let (offset, dict) = window.unwrap();
+            let offset = offset as usize;
+            let expected = algorithm
+                .digest_reader(&data[offset..(offset + 3000).min(data.len())])
+                .unwrap();
+            assert_eq!(dict.to_vec(), expected.to_vec());
+        }
+    }
+
+    #[test]
+    fn test_similarity_profile() {
+        // b holds the two halves of a in swapped order, after a block of its own
+        let a = random_bytes(64 * 1024, 2);
+        let b = [
+            &random_bytes(16 * 1024, 3)[..],
+            &a[32 * 1024..],
+            &a[..32 * 1024],
+        ]
+        .concat();
+        let profile = similarity_profile(
+            &a[..],
+            &b[..],
+            16 * 1024,
+            16 * 1024,
+            HashAlgorithm::Murmur3,
+            1024,
+        )
+        .unwrap();
+        assert_eq!(profile.len(), 4 * 5);
+        for (offset_a, offset_b, score) in profile {
+            let corresponding = offset_b == (offset_a + 32 * 1024) % (64 * 1024) + 16 * 1024;
+            if corresponding {
+                assert!(score > 80, "{} {} {}", offset_a, offset_b, score);
+            } else {
+                assert!(score < 10, "{} {} {}", offset_a, offset_b, score);
+            }
+        }
+    }
+
+    #[test]
+    fn test_find_similar_regions() {
+        let haystack = random_bytes(256 * 1024, 4);
+        let algorithm = HashAlgorithm::Murmur3;
+        let fragment = &haystack[100_000..108_192];
+        let needle = algorithm.digest_reader(fragment).unwrap();
+        let regions =
+            find_similar_regions(&needle, &haystack[..], fragment.len(), 0.2, algorithm, 1024)
+                .unwrap();
+        assert!(!regions.is_empty());
+        for (offset, _) in &regions {
+            assert!(
+                (100_000 - 4096..100_000 + 4096).contains(offset),
+                "{}",
+                offset
+            );
+        }
+        let (best, _) = regions
+            .iter()
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+            .unwrap();
+        assert!(best.abs_diff(100_000) <= 1024);
+
+        let unrelated = algorithm.digest_reader(&random_bytes(8192, 5)[..]).unwrap();
+        let regions =
+            find_similar_regions(&unrelated, &haystack[..], 8192, 0.1, algorithm, 1024).unwrap();
+        assert!(regions.is_empty());
+    }
+
+    #[test]
+    fn test_rolling_window_digests() {
+        let algorithm = HashAlgorithm::Murmur3;
+        for (len, window, step) in [
+            (10, 4, 4),
+            (10, 4, 3),
+            (10, 4, 2),
+            (10, 2, 4),
+            (9, 2, 4),
+            (8, 2, 4),
+            (3, 4, 2),
+            (0, 4, 2),
+        ] {
+            let data = random_bytes(len, 6);
+            let rolling = RollingWindowDigests::new(&data[..], window, step, algorithm, 1024)
+                .unwrap()
+                .map(|window| window.unwrap().0)
+                .collect::<Vec<_>>();
+            assert_eq!(
+                rolling,
+                offsets(len, window, step),
+                "{} {} {}",
+                len,
+                window,
+                step
+            );
+        }
+
+        let data = random_bytes(100_000, 7);
+        let rolling = RollingWindowDigests::new(&data[..], 16_384, 4096, algorithm, 1024)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(rolling.len(), offsets(100_000, 16_384, 4096).len());
+        for (offset, dict) in rolling {
+            let offset = offset as usize;
+            let chunks = data[offset..(offset + 16_384).min(data.len())]
+                .chunks(4096)
+                .map(|chunk| algorithm.digest_reader(chunk).unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(dict.to_vec(), LZDict::merge(&chunks, 1024).to_vec());
+        }
+    }
+}
@@ -0,0 +1,41 @@
+//! Defines a 32 bit xxHash hasher implementing std::hash::Hasher
+//! as well as a std::hash::BuildHasher which builds the hasher.
+use crate::hasher::ResettableHasher;
+
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+
+/// Hasher which buffers its input and hashes it with 32 bit xxHash on finish.
+/// Unlike murmur3, xxHash reads its input as little-endian words on every machine.
+#[derive(Default)]
+pub struct XxHasher {
+    bytes: Vec<u8>,
+}
+
+impl Hasher for XxHasher {
+    fn finish(&self) -> u64 {
+        u64::from(fasthash::xx::hash32(&self.bytes))
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+}
+
+impl ResettableHasher for XxHasher {
+    fn reset(&mut self) {
+        self.bytes.clear();
+    }
+}
+
+/// std::hash::BuildHasher that builds XxHashers
+#[derive(Clone)]
+pub struct XxBuildHasher;
+
+impl BuildHasher for XxBuildHasher {
+    type Hasher = XxHasher;
+
+    fn build_hasher(&self) -> Self::Hasher {
+        XxHasher::default()
+    }
+}